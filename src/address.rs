@@ -1,8 +1,14 @@
 //! Address
 
-use std::{fmt::Display, net::IpAddr, str::FromStr};
+use std::{
+    fmt::Display,
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
 
 use bytes::BufMut;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
 use tokio::io::{AsyncRead, AsyncReadExt};
 
 use crate::error::AddressError;
@@ -94,14 +100,102 @@ impl ServiceAddress {
     pub fn new(addr: Address, port: u16) -> Self {
         Self { addr, port }
     }
+
+    /// The socket address if no resolving is needed.
+    pub fn to_socket_addr(&self) -> Option<SocketAddr> {
+        match self.addr {
+            Address::Socket(ip) => Some(SocketAddr::new(ip, self.port)),
+            Address::Domain(_) => None,
+        }
+    }
+
+    /// Resolve into socket addresses, looking up domains with the system resolver.
+    pub async fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        match self.addr {
+            Address::Socket(ip) => Ok(vec![SocketAddr::new(ip, self.port)]),
+            Address::Domain(ref domain) => {
+                Ok(tokio::net::lookup_host((domain.as_str(), self.port))
+                    .await?
+                    .collect())
+            }
+        }
+    }
+}
+
+impl FromStr for ServiceAddress {
+    type Err = AddressError;
+
+    /// Parse `host:port`, IPv6 hosts must be in brackets like `[::1]:443`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AddressError::InvalidAddress(s.to_string());
+
+        let (addr, port) = match s.strip_prefix('[') {
+            Some(rest) => {
+                let (ip, port) = rest.split_once("]:").ok_or_else(invalid)?;
+                let ip: Ipv6Addr = ip.parse().map_err(|_| invalid())?;
+                (Address::Socket(IpAddr::V6(ip)), port)
+            }
+            None => {
+                let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+                if host.is_empty() || host.contains(':') {
+                    return Err(invalid());
+                }
+                (Address::from(host), port)
+            }
+        };
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+
+        Ok(Self::new(addr, port))
+    }
+}
+
+impl From<SocketAddr> for ServiceAddress {
+    fn from(value: SocketAddr) -> Self {
+        Self::new(Address::Socket(value.ip()), value.port())
+    }
 }
 
 impl Display for ServiceAddress {
+    /// `host:port` in authority form, with IPv6 hosts in brackets.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{}", self.addr, self.port)
+        match self.addr {
+            Address::Socket(IpAddr::V6(ip)) => write!(f, "[{}]:{}", ip, self.port),
+            _ => write!(f, "{}:{}", self.addr, self.port),
+        }
     }
 }
 
+/// Serialized as `host:port` like `Display`.
+impl Serialize for ServiceAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ServiceAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Longest DNS name in its text form.
+pub const MAX_DOMAIN_LEN: usize = 253;
+
+/// Check that a domain read off the wire is plausible: not empty, at most
+/// `MAX_DOMAIN_LEN` bytes and only printable ASCII.
+pub fn check_domain(domain: &str) -> Result<(), AddressError> {
+    if domain.is_empty()
+        || domain.len() > MAX_DOMAIN_LEN
+        || !domain.bytes().all(|b| b.is_ascii_graphic())
+    {
+        return Err(AddressError::InvalidAddress(domain.to_string()));
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Address {
     Socket(IpAddr),
@@ -122,7 +216,52 @@ impl Address {
         matches!(self, Self::Socket(_))
     }
 
+    /// Private or link-local ip: `10.0.0.0/8`, `172.16.0.0/12`,
+    /// `192.168.0.0/16`, `169.254.0.0/16`, `fc00::/7` and `fe80::/10`.
+    /// Domains are never private, they are not resolved here.
+    pub fn is_private(&self) -> bool {
+        match self {
+            Address::Socket(ip) => match ip.to_canonical() {
+                IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+                IpAddr::V6(ip) => {
+                    let first = ip.segments()[0];
+                    first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+                }
+            },
+            Address::Domain(_) => false,
+        }
+    }
+
+    /// Loopback ip, `127.0.0.0/8` or `::1`. Domains such as `localhost`
+    /// are not resolved and return false.
+    pub fn is_loopback(&self) -> bool {
+        match self {
+            Address::Socket(ip) => ip.to_canonical().is_loopback(),
+            Address::Domain(_) => false,
+        }
+    }
+
+    /// Ip inside `cidr`, false for domains. An IPv4-mapped IPv6 address is
+    /// matched as the IPv4 address.
+    pub fn in_cidr(&self, cidr: &IpCidr) -> bool {
+        match self {
+            Address::Socket(ip) => cidr.contains(&ip.to_canonical()),
+            Address::Domain(_) => false,
+        }
+    }
+
+    /// Read an address, rejecting implausible domains with `check_domain`.
     pub async fn read<R, C>(reader: &mut R) -> Result<Address, AddressError>
+    where
+        R: AsyncRead + Unpin,
+        C: AddrTypeConvert,
+    {
+        Self::read_with::<R, C>(reader, true).await
+    }
+
+    /// Read an address, `strict` turns the domain check on. Empty domains
+    /// are always rejected.
+    pub async fn read_with<R, C>(reader: &mut R, strict: bool) -> Result<Address, AddressError>
     where
         R: AsyncRead + Unpin,
         C: AddrTypeConvert,
@@ -133,22 +272,39 @@ impl Address {
                 let mut addr = [0u8; 4];
                 let _ = reader.read_exact(&mut addr).await?;
                 let ip = IpAddr::from(addr);
-                Ok(Address::Socket(ip.into()))
+                Ok(Address::Socket(ip))
             }
             AddrType::Ipv6 => {
                 let mut addr = [0u8; 16];
                 let _ = reader.read_exact(&mut addr).await?;
                 let ip = IpAddr::from(addr);
-                Ok(Address::Socket(ip.into()))
+                Ok(Address::Socket(ip))
             }
             AddrType::Fqdn => {
                 let str_len = reader.read_u8().await?;
                 let mut addr = vec![0u8; str_len as usize];
                 let _ = reader.read_exact(&mut addr).await?;
                 let addr = String::from_utf8(addr)?;
+                if strict {
+                    check_domain(&addr)?;
+                } else if addr.is_empty() {
+                    return Err(AddressError::InvalidAddress(addr));
+                }
                 Ok(Address::Domain(addr))
             }
-            AddrType::Unknown => return Err(AddressError::InvalidAddrType),
+            AddrType::Unknown => Err(AddressError::InvalidAddrType),
+        }
+    }
+
+    /// The IPv4 form of an IPv4-mapped IPv6 address, any other address as
+    /// it is.
+    pub fn unmap_v4(self) -> Address {
+        match self {
+            Address::Socket(IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => Address::Socket(IpAddr::V4(ip)),
+                None => self,
+            },
+            addr => addr,
         }
     }
 
@@ -192,6 +348,20 @@ impl FromStr for Address {
     }
 }
 
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
 impl<T: AsRef<str> + ToString> From<T> for Address {
     fn from(s: T) -> Self {
         match IpAddr::from_str(s.as_ref()) {
@@ -200,3 +370,257 @@ impl<T: AsRef<str> + ToString> From<T> for Address {
         }
     }
 }
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8` or `fc00::/7`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, AddressError> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(AddressError::InvalidAddress(format!("{}/{}", addr, prefix)));
+        }
+
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for IpCidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AddressError::InvalidAddress(s.to_string());
+
+        match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr = IpAddr::from_str(addr).map_err(|_| invalid())?;
+                let prefix = prefix.parse::<u8>().map_err(|_| invalid())?;
+                Self::new(addr, prefix).map_err(|_| invalid())
+            }
+            None => {
+                let addr = IpAddr::from_str(s).map_err(|_| invalid())?;
+                let prefix = if addr.is_ipv4() { 32 } else { 128 };
+                Self::new(addr, prefix)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_cidr() {
+        let cidr: IpCidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+
+        let cidr: IpCidr = "fc00::/7".parse().unwrap();
+        assert!(cidr.contains(&"fd00::1".parse().unwrap()));
+        assert!(!cidr.contains(&"fe80::1".parse().unwrap()));
+
+        let any: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&"8.8.8.8".parse().unwrap()));
+
+        let host: IpCidr = "8.8.8.8".parse().unwrap();
+        assert_eq!(host.prefix(), 32);
+        assert!(host.contains(&"8.8.8.8".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("example.com/8".parse::<IpCidr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_address_unmap_v4() {
+        use crate::vless::protocol::VlessAddrType;
+
+        let mapped: Ipv6Addr = "::ffff:1.2.3.4".parse().unwrap();
+        let mut buf = vec![3];
+        buf.extend_from_slice(&mapped.octets());
+
+        let addr = Address::read_with::<_, VlessAddrType>(&mut std::io::Cursor::new(&buf), true)
+            .await
+            .unwrap();
+        assert_eq!(addr, Address::Socket(IpAddr::V6(mapped)));
+        assert_eq!(addr.unmap_v4(), Address::Socket("1.2.3.4".parse().unwrap()));
+
+        // only mapped addresses change
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(Address::Socket(ip).unmap_v4(), Address::Socket(ip));
+        let ip: IpAddr = "::1.2.3.4".parse().unwrap();
+        assert_eq!(Address::Socket(ip).unmap_v4(), Address::Socket(ip));
+    }
+
+    #[tokio::test]
+    async fn test_address_read_domain() {
+        use crate::vless::protocol::VlessAddrType;
+
+        let read = |domain: &[u8], strict| {
+            let mut buf = vec![2, domain.len() as u8];
+            buf.extend_from_slice(domain);
+            async move {
+                Address::read_with::<_, VlessAddrType>(&mut std::io::Cursor::new(buf), strict).await
+            }
+        };
+
+        assert_eq!(
+            read(b"example.com", true).await.unwrap(),
+            Address::Domain("example.com".into())
+        );
+        assert_eq!(
+            read(&[b'a'; MAX_DOMAIN_LEN], true).await.unwrap(),
+            Address::Domain("a".repeat(MAX_DOMAIN_LEN))
+        );
+
+        for domain in [&b""[..], &[b'a'; 254], b"exa mple.com", b"\x01\x02"] {
+            assert!(matches!(
+                read(domain, true).await,
+                Err(AddressError::InvalidAddress(_))
+            ));
+            assert_eq!(read(domain, false).await.is_ok(), !domain.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_service_address_from_str() {
+        let addr: ServiceAddress = "127.0.0.1:8080".parse().unwrap();
+        assert_eq!(addr, ServiceAddress::new("127.0.0.1".into(), 8080));
+        assert!(addr.addr.is_ip());
+
+        let addr: ServiceAddress = "[::1]:443".parse().unwrap();
+        assert_eq!(addr, ServiceAddress::new("::1".into(), 443));
+        assert!(addr.addr.is_ip());
+        assert_eq!(addr.to_string(), "[::1]:443");
+
+        let addr: ServiceAddress = "example.com:443".parse().unwrap();
+        assert_eq!(
+            addr,
+            ServiceAddress::new(Address::Domain("example.com".into()), 443)
+        );
+
+        for s in [
+            "example.com",
+            "example.com:",
+            "example.com:http",
+            "example.com:65536",
+            ":80",
+            "::1:443",
+            "[::1]",
+            "[::1]443",
+            "[example.com]:443",
+        ] {
+            assert!(
+                matches!(
+                    s.parse::<ServiceAddress>(),
+                    Err(AddressError::InvalidAddress(_))
+                ),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_service_address_socket_addr() {
+        let sock: SocketAddr = "[::1]:8080".parse().unwrap();
+        let addr = ServiceAddress::from(sock);
+        assert_eq!(addr, ServiceAddress::new("::1".into(), 8080));
+        assert_eq!(addr.to_socket_addr(), Some(sock));
+        assert_eq!(addr.resolve().await.unwrap(), vec![sock]);
+
+        let addr = ServiceAddress::new("localhost".into(), 80);
+        assert_eq!(addr.to_socket_addr(), None);
+        let resolved = addr.resolve().await.unwrap();
+        assert!(!resolved.is_empty());
+        assert!(resolved
+            .iter()
+            .all(|a| a.ip().is_loopback() && a.port() == 80));
+    }
+
+    #[test]
+    fn test_address_ranges() {
+        let addr = |s: &str| Address::from(s);
+
+        assert!(addr("10.0.0.5").is_private());
+        assert!(addr("192.168.1.1").is_private());
+        assert!(addr("172.16.0.1").is_private());
+        assert!(addr("169.254.1.1").is_private());
+        assert!(addr("fd00::1").is_private());
+        assert!(addr("fe80::1").is_private());
+        assert!(addr("::ffff:10.0.0.5").is_private());
+        assert!(!addr("8.8.8.8").is_private());
+        assert!(!addr("::1").is_private());
+        assert!(!addr("example.com").is_private());
+
+        assert!(addr("::1").is_loopback());
+        assert!(addr("127.0.0.1").is_loopback());
+        assert!(!addr("10.0.0.5").is_loopback());
+        assert!(!addr("localhost").is_loopback());
+
+        let cidr: IpCidr = "10.0.0.0/8".parse().unwrap();
+        assert!(addr("10.0.0.5").in_cidr(&cidr));
+        assert!(!addr("192.168.1.1").in_cidr(&cidr));
+        assert!(!addr("8.8.8.8").in_cidr(&cidr));
+        assert!(!addr("::1").in_cidr(&cidr));
+        assert!(addr("::ffff:10.0.0.5").in_cidr(&cidr));
+        assert!(!addr("::ffff:8.8.8.8").in_cidr(&cidr));
+        assert!(!addr("10.example.com").in_cidr(&cidr));
+    }
+
+    #[test]
+    fn test_address_serde() {
+        for (addr, json) in [
+            ("127.0.0.1:8080", "\"127.0.0.1:8080\""),
+            ("[2001:db8::1]:443", "\"[2001:db8::1]:443\""),
+            ("example.com:80", "\"example.com:80\""),
+        ] {
+            let addr: ServiceAddress = addr.parse().unwrap();
+            assert_eq!(serde_json::to_string(&addr).unwrap(), json);
+            assert_eq!(serde_json::from_str::<ServiceAddress>(json).unwrap(), addr);
+
+            let host = serde_json::to_string(&addr.addr).unwrap();
+            assert_eq!(serde_json::from_str::<Address>(&host).unwrap(), addr.addr);
+        }
+        assert_eq!(
+            serde_json::to_string(&Address::from("2001:db8::1")).unwrap(),
+            "\"2001:db8::1\""
+        );
+
+        assert!(serde_json::from_str::<ServiceAddress>("\"2001:db8::1:443\"").is_err());
+        assert!(serde_json::from_str::<ServiceAddress>("\"example.com\"").is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//! Outbound chained through another outbound
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    address::NetworkType, OutboundPacket, OutboundResult, OutboundService, OutboundServiceTrait,
+    ServiceAddress,
+};
+
+/// Reaches `relay_dest` with the `first` outbound and runs the handshake of
+/// `then` over that stream for the final destination, e.g. vless through a
+/// socks5 proxy.
+#[derive(Debug)]
+pub struct ChainOutbound<F = OutboundService, T = OutboundService> {
+    first: F,
+    then: T,
+    relay_dest: ServiceAddress,
+}
+
+impl<F, T> ChainOutbound<F, T> {
+    pub fn new(first: F, then: T, relay_dest: ServiceAddress) -> Self {
+        Self {
+            first,
+            then,
+            relay_dest,
+        }
+    }
+
+    pub fn first(&self) -> &F {
+        &self.first
+    }
+
+    pub fn then(&self) -> &T {
+        &self.then
+    }
+
+    /// Server of the `then` outbound, the destination of the first hop.
+    pub fn relay_dest(&self) -> &ServiceAddress {
+        &self.relay_dest
+    }
+}
+
+impl<S, F, T> OutboundServiceTrait<S> for ChainOutbound<F, T>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    F: OutboundServiceTrait<S>,
+    T: OutboundServiceTrait<F::Stream>,
+{
+    type Stream = T::Stream;
+
+    /// The first hop is always TCP, `packet` may still ask the second
+    /// outbound for UDP.
+    async fn handshake(&self, stream: S, packet: OutboundPacket) -> OutboundResult<Self::Stream> {
+        let relay = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: self.relay_dest.clone(),
+        };
+        let stream = self.first.handshake(stream, relay).await?;
+
+        self.then.handshake(stream, packet).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        socks::{SocksInbound, SocksInboundOption, SocksOutbound, SocksOutboundOption},
+        vless::{
+            option::VlessUserOption, VlessInbound, VlessInboundOption, VlessOutbound,
+            VlessOutboundOption,
+        },
+        InboundServiceTrait,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chain_socks_vless() {
+        let relay = ServiceAddress::new("10.0.0.1".into(), 443);
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("example.com".into(), 80),
+        };
+
+        let socks_in = SocksInbound::init(SocksInboundOption {
+            udp_enabled: false,
+            allow_v4: false,
+            ..Default::default()
+        })
+        .unwrap();
+        let vless_in = VlessInbound::init(VlessInboundOption {
+            users: vec![VlessUserOption {
+                user: "test".into(),
+                uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+            }],
+            udp_enabled: false,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (client, server) = duplex(4096);
+        let expected = (relay.clone(), packet.dest.clone());
+        let server = tokio::spawn(async move {
+            let (stream, pac) = socks_in.handshake(server).await.unwrap();
+            assert_eq!(pac.dest, expected.0);
+
+            let (mut stream, pac) = vless_in.handshake(stream).await.unwrap();
+            assert_eq!(pac.dest, expected.1);
+            assert_eq!(pac.detail, "test");
+
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let chain = ChainOutbound::new(
+            OutboundService::Socks(SocksOutbound::init(SocksOutboundOption::default()).unwrap()),
+            OutboundService::Vless(
+                VlessOutbound::init(VlessOutboundOption {
+                    uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+                    flow: None,
+                    combine_first_packet: false,
+                    mux: None,
+                })
+                .unwrap(),
+            ),
+            relay,
+        );
+
+        let mut stream = chain.handshake(client, packet).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,395 @@
+//! AEAD chunked stream
+
+use std::{pin::Pin, task::Poll};
+
+use aes_gcm::aead::{
+    generic_array::{typenum::Unsigned, GenericArray},
+    AeadInPlace,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::CryptoError;
+
+/// Maximum payload carried by a single chunk.
+pub const MAX_PAYLOAD_SIZE: usize = 0x3FFF;
+
+/// Length of the encrypted length prefix, before its tag.
+const LENGTH_SIZE: usize = 2;
+
+/// Cipher sealing and opening single chunks with an explicit nonce.
+pub trait AeadCipher {
+    const NONCE_LEN: usize;
+    const TAG_LEN: usize;
+
+    /// Encrypt `data` in place and write the tag to `tag`.
+    fn seal(&self, nonce: &[u8], data: &mut [u8], tag: &mut [u8]) -> Result<(), CryptoError>;
+
+    /// Decrypt `data` in place, verifying it against `tag`.
+    fn open(&self, nonce: &[u8], data: &mut [u8], tag: &[u8]) -> Result<(), CryptoError>;
+}
+
+impl<A: AeadInPlace> AeadCipher for A {
+    const NONCE_LEN: usize = A::NonceSize::USIZE;
+    const TAG_LEN: usize = A::TagSize::USIZE;
+
+    fn seal(&self, nonce: &[u8], data: &mut [u8], tag: &mut [u8]) -> Result<(), CryptoError> {
+        let sealed = self
+            .encrypt_in_place_detached(GenericArray::from_slice(nonce), &[], data)
+            .map_err(|_| CryptoError::Encrypt)?;
+        tag.copy_from_slice(&sealed);
+
+        Ok(())
+    }
+
+    fn open(&self, nonce: &[u8], data: &mut [u8], tag: &[u8]) -> Result<(), CryptoError> {
+        self.decrypt_in_place_detached(
+            GenericArray::from_slice(nonce),
+            &[],
+            data,
+            GenericArray::from_slice(tag),
+        )
+        .map_err(|_| CryptoError::Decrypt)
+    }
+}
+
+/// Little endian nonce counter, incremented once per seal or open.
+#[derive(Debug)]
+struct Nonce(Vec<u8>);
+
+impl Nonce {
+    fn new(len: usize) -> Self {
+        Self(vec![0u8; len])
+    }
+
+    fn increase(&mut self) {
+        for b in self.0.iter_mut() {
+            *b = b.wrapping_add(1);
+            if *b != 0 {
+                break;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ReadState {
+    Length,
+    Payload(usize),
+}
+
+/// Stream with the AEAD chunk framing
+/// `[encrypted length][length tag][encrypted payload][payload tag]...`,
+/// each seal and open using the next nonce of its direction.
+///
+/// Any key exchange or salt comes before the first chunk and is up to the
+/// protocol. A decrypting cipher only known from the peer's first bytes is
+/// given with `set_dec`, after starting with `with_enc`.
+#[derive(Debug)]
+pub struct AeadStream<S, C> {
+    inner: S,
+    enc: C,
+    dec: Option<C>,
+    enc_nonce: Nonce,
+    dec_nonce: Nonce,
+    read_state: ReadState,
+    read_buf: BytesMut,
+    plain: Bytes,
+    write_buf: BytesMut,
+}
+
+impl<S, C> AeadStream<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    C: AeadCipher + Unpin,
+{
+    /// `enc` seals what is written, `dec` opens what is read.
+    pub fn new(inner: S, enc: C, dec: C) -> Self {
+        let mut stream = Self::with_enc(inner, enc);
+        stream.set_dec(dec);
+        stream
+    }
+
+    /// Stream that can only be written until `set_dec` is called.
+    pub fn with_enc(inner: S, enc: C) -> Self {
+        Self {
+            inner,
+            enc,
+            dec: None,
+            enc_nonce: Nonce::new(C::NONCE_LEN),
+            dec_nonce: Nonce::new(C::NONCE_LEN),
+            read_state: ReadState::Length,
+            read_buf: BytesMut::new(),
+            plain: Bytes::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    /// Start opening what is read with `dec`.
+    pub fn set_dec(&mut self, dec: C) {
+        self.dec = Some(dec);
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// The inner stream, for bytes exchanged outside of the chunks. Any
+    /// buffered chunk is neither read nor written by it.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn seal(&mut self, start: usize) -> Result<(), CryptoError> {
+        let end = self.write_buf.len();
+        self.write_buf.put_bytes(0, C::TAG_LEN);
+        let (data, tag) = self.write_buf[start..].split_at_mut(end - start);
+        self.enc.seal(&self.enc_nonce.0, data, tag)?;
+        self.enc_nonce.increase();
+
+        Ok(())
+    }
+
+    fn encrypt_chunk(&mut self, data: &[u8]) -> Result<(), CryptoError> {
+        let start = self.write_buf.len();
+        self.write_buf.put_u16(data.len() as u16);
+        self.seal(start)?;
+
+        let start = self.write_buf.len();
+        self.write_buf.put_slice(data);
+        self.seal(start)
+    }
+
+    fn decrypt_chunk(&mut self, mut chunk: BytesMut) -> Result<(), CryptoError> {
+        let len = chunk.len() - C::TAG_LEN;
+        let (data, tag) = chunk.split_at_mut(len);
+        let dec = self.dec.as_ref().ok_or(CryptoError::Decrypt)?;
+        dec.open(&self.dec_nonce.0, data, tag)?;
+        self.dec_nonce.increase();
+
+        match self.read_state {
+            ReadState::Length => {
+                let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+                if len == 0 || len > MAX_PAYLOAD_SIZE {
+                    return Err(CryptoError::InvalidLength(len));
+                }
+                self.read_state = ReadState::Payload(len);
+            }
+            ReadState::Payload(_) => {
+                chunk.truncate(len);
+                self.plain = chunk.freeze();
+                self.read_state = ReadState::Length;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &self.write_buf) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S, C> AsyncRead for AeadStream<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    C: AeadCipher + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.plain.is_empty() {
+                let n = buf.remaining().min(this.plain.len());
+                buf.put_slice(&this.plain.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+
+            let need = match this.read_state {
+                ReadState::Length => LENGTH_SIZE + C::TAG_LEN,
+                ReadState::Payload(n) => n + C::TAG_LEN,
+            };
+
+            // a partial chunk stays in `read_buf` until the rest arrives
+            while this.read_buf.len() < need {
+                let start = this.read_buf.len();
+                this.read_buf.resize(need, 0);
+                let mut rb = ReadBuf::new(&mut this.read_buf[start..]);
+                match Pin::new(&mut this.inner).poll_read(cx, &mut rb) {
+                    Poll::Ready(Ok(())) => {
+                        let n = rb.filled().len();
+                        this.read_buf.truncate(start + n);
+                        if n == 0 {
+                            if start == 0 && matches!(this.read_state, ReadState::Length) {
+                                // clean EOF on a chunk boundary
+                                return Poll::Ready(Ok(()));
+                            }
+                            return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+                        }
+                    }
+                    Poll::Ready(Err(e)) => {
+                        this.read_buf.truncate(start);
+                        return Poll::Ready(Err(e));
+                    }
+                    Poll::Pending => {
+                        this.read_buf.truncate(start);
+                        return Poll::Pending;
+                    }
+                }
+            }
+
+            let chunk = this.read_buf.split_to(need);
+            this.decrypt_chunk(chunk)?;
+        }
+    }
+}
+
+impl<S, C> AsyncWrite for AeadStream<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    C: AeadCipher + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = self.get_mut();
+
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        if !this.write_buf.is_empty() {
+            return Poll::Pending;
+        }
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(MAX_PAYLOAD_SIZE);
+        this.encrypt_chunk(&buf[..n])?;
+
+        // the chunk is accepted, the rest is written on the next call or flush
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let this = self.get_mut();
+
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let this = self.get_mut();
+
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use aes_gcm::{aead::KeyInit, Aes256Gcm};
+    use chacha20poly1305::ChaCha20Poly1305;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    async fn round_trip<C>(new: impl Fn() -> C)
+    where
+        C: AeadCipher + Unpin + Send + Sync + 'static,
+    {
+        // a pipe smaller than a chunk splits every chunk across reads
+        let (s1, s2) = duplex(1000);
+        let mut client = AeadStream::new(s1, new(), new());
+        let mut server = AeadStream::new(s2, new(), new());
+
+        let data: Vec<u8> = (0..MAX_PAYLOAD_SIZE * 3 + 100).map(|i| i as u8).collect();
+        let expected = data.clone();
+
+        let writer = tokio::spawn(async move {
+            for part in data.chunks(5000) {
+                client.write_all(part).await.unwrap();
+            }
+            client.shutdown().await.unwrap();
+        });
+
+        let mut received = vec![];
+        let mut buf = [0u8; 7];
+        loop {
+            let n = server.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+        writer.await.unwrap();
+
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn test_aead_stream_round_trip() {
+        round_trip(|| Aes256Gcm::new_from_slice(&[1u8; 32]).unwrap()).await;
+        round_trip(|| ChaCha20Poly1305::new_from_slice(&[2u8; 32]).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_aead_stream_tampered() {
+        let cipher = || ChaCha20Poly1305::new_from_slice(&[3u8; 32]).unwrap();
+
+        let mut sealed = AeadStream::new(Cursor::new(vec![]), cipher(), cipher());
+        sealed.write_all(b"hello").await.unwrap();
+        let sealed = sealed.into_inner().into_inner();
+        assert_eq!(sealed.len(), LENGTH_SIZE + 16 + 5 + 16);
+
+        let mut stream = AeadStream::new(Cursor::new(sealed.clone()), cipher(), cipher());
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+
+        let mut tampered = sealed;
+        *tampered.last_mut().unwrap() ^= 1;
+        let mut stream = AeadStream::new(Cursor::new(tampered), cipher(), cipher());
+        let err = stream.read_to_end(&mut vec![]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
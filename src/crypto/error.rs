@@ -0,0 +1,19 @@
+//! Crypto Error
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CryptoError {
+    #[error("invalid payload length: {0}")]
+    InvalidLength(usize),
+    #[error("encryption failed")]
+    Encrypt,
+    #[error("decryption failed")]
+    Decrypt,
+}
+
+impl From<CryptoError> for std::io::Error {
+    fn from(value: CryptoError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, value)
+    }
+}
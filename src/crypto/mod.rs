@@ -0,0 +1,52 @@
+//! Crypto helpers shared by the protocols
+
+pub mod aead;
+pub use aead::{AeadCipher, AeadStream};
+
+pub mod error;
+pub use error::CryptoError;
+
+/// Compare credentials without returning at the first differing byte, so
+/// the time taken does not tell how much of a guess was right. Only the
+/// length may leak.
+///
+/// Constant time with the `constant-time` feature, a plain comparison
+/// otherwise.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    #[cfg(feature = "constant-time")]
+    {
+        subtle::ConstantTimeEq::ct_eq(a, b).into()
+    }
+
+    #[cfg(not(feature = "constant-time"))]
+    {
+        a == b
+    }
+}
+
+/// Whether an entry of `list` satisfies `eq`, which is called for every
+/// entry instead of stopping at the first match.
+pub fn ct_contains<T, F>(list: &[T], eq: F) -> bool
+where
+    F: Fn(&T) -> bool,
+{
+    list.iter().fold(false, |found, entry| eq(entry) | found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ct_eq() {
+        assert!(ct_eq(b"secret", b"secret"));
+        assert!(ct_eq(b"", b""));
+        assert!(!ct_eq(b"secret", b"secreT"));
+        assert!(!ct_eq(b"secret", b"secret!"));
+        assert!(!ct_eq(b"", b"s"));
+
+        let list = [b"a".to_vec(), b"b".to_vec()];
+        assert!(ct_contains(&list, |e| ct_eq(e, b"b")));
+        assert!(!ct_contains(&list, |e| ct_eq(e, b"c")));
+    }
+}
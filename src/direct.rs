@@ -1,22 +1,128 @@
 //! Direct Outbound Service
 
 use std::{
+    io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     pin::Pin,
+    sync::Arc,
+    time::Duration,
 };
 
+use serde::{Deserialize, Serialize};
+use socket2::SockRef;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::{TcpStream, UdpSocket},
+    net::{TcpSocket, TcpStream, UdpSocket},
 };
 
 use crate::{
-    address::NetworkType, Address, OutboundError, OutboundPacket, OutboundResult,
-    OutboundServiceStream, OutboundServiceTrait,
+    address::NetworkType, resolver::Resolver, stream::IdleTimeoutStream, OutboundError,
+    OutboundPacket, OutboundResult, OutboundServiceStream, OutboundServiceTrait, ProxyStream,
 };
 
-#[derive(Debug, Clone, Copy)]
-pub struct DirectOutbound;
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DirectOutboundOption {
+    /// Interface name the outgoing sockets are bound to.
+    ///
+    /// Supported on Linux, Android and Fuchsia (`SO_BINDTODEVICE`) and on
+    /// macOS and iOS (`IP_BOUND_IF`/`IPV6_BOUND_IF`) and on Windows
+    /// (`IP_UNICAST_IF`/`IPV6_UNICAST_IF`), where the interface may also be
+    /// given by its numeric index. Other platforms refuse the option at init.
+    #[serde(default)]
+    pub bind_device: Option<String>,
+}
+
+impl DirectOutboundOption {
+    pub fn validate(&self) -> Vec<OutboundError> {
+        let mut errors = vec![];
+        if self.bind_device.is_some() && !BIND_DEVICE_SUPPORTED {
+            errors.push(OutboundError::Option(
+                "bind_device is not supported on this platform".to_string(),
+            ));
+        }
+        errors
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DirectOutbound {
+    resolver: Option<Arc<dyn Resolver>>,
+    bind_device: Option<String>,
+}
+
+impl DirectOutbound {
+    /// Outbound without a resolver or device, what the former unit struct
+    /// `DirectOutbound` was.
+    pub const fn new() -> Self {
+        Self {
+            resolver: None,
+            bind_device: None,
+        }
+    }
+
+    pub fn init(option: DirectOutboundOption) -> OutboundResult<Self> {
+        if let Some(e) = option.validate().into_iter().next() {
+            return Err(e);
+        }
+
+        Ok(Self {
+            resolver: None,
+            bind_device: option.bind_device,
+        })
+    }
+
+    /// Domains are resolved with `resolver`, without one they are refused
+    /// as `Unresolved`.
+    pub fn with_resolver(resolver: Arc<dyn Resolver>) -> Self {
+        Self {
+            resolver: Some(resolver),
+            bind_device: None,
+        }
+    }
+
+    pub fn set_resolver(&mut self, resolver: Option<Arc<dyn Resolver>>) {
+        self.resolver = resolver;
+    }
+
+    pub(crate) async fn connect_tcp(&self, addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+        let Some(ref device) = self.bind_device else {
+            return TcpStream::connect(addrs).await;
+        };
+
+        let mut last_err = None;
+        for addr in addrs {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            bind_device(SockRef::from(&socket), device, addr.is_ipv6())?;
+
+            match socket.connect(*addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| io::ErrorKind::AddrNotAvailable.into()))
+    }
+
+    async fn resolve(&self, packet: &OutboundPacket) -> OutboundResult<Vec<SocketAddr>> {
+        if let Some(addr) = packet.dest.to_socket_addr() {
+            return Ok(vec![addr]);
+        }
+
+        match self.resolver {
+            Some(ref resolver) => {
+                let addrs = packet.dest.resolve_with(Some(resolver)).await?;
+                if addrs.is_empty() {
+                    return Err(OutboundError::Unresolved);
+                }
+                Ok(addrs)
+            }
+            None => Err(OutboundError::Unresolved),
+        }
+    }
+}
 
 impl<S> OutboundServiceTrait<S> for DirectOutbound
 where
@@ -25,18 +131,16 @@ where
     type Stream = OutboundServiceStream<S>;
 
     async fn handshake(&self, _stream: S, packet: OutboundPacket) -> OutboundResult<Self::Stream> {
-        let addr = match packet.dest.addr {
-            Address::Domain(_) => return Err(OutboundError::Unresolved),
-            Address::Socket(ip) => SocketAddr::new(ip, packet.dest.port),
-        };
+        let addrs = self.resolve(&packet).await?;
 
         match packet.typ {
             NetworkType::Tcp => {
-                let stream = TcpStream::connect(addr).await?;
+                let stream = self.connect_tcp(&addrs).await?;
                 Ok(OutboundServiceStream::Direct(DirectStream::Tcp(stream)))
             }
             NetworkType::Udp => {
-                let stream = UdpStream::connect(addr).await?;
+                let stream =
+                    UdpStream::connect_device(addrs[0], self.bind_device.as_deref()).await?;
                 Ok(OutboundServiceStream::Direct(DirectStream::Udp(stream)))
             }
         }
@@ -49,6 +153,15 @@ pub enum DirectStream {
     Udp(UdpStream),
 }
 
+impl ProxyStream for DirectStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(s) => s.peer_addr().ok(),
+            Self::Udp(s) => s.socket().peer_addr().ok(),
+        }
+    }
+}
+
 impl AsyncRead for DirectStream {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -95,13 +208,52 @@ impl AsyncWrite for DirectStream {
     }
 }
 
+/// Socket settings for `UdpStream::connect_with`.
+#[derive(Debug, Clone, Default)]
+pub struct UdpOptions {
+    /// Kernel receive buffer size in bytes (`SO_RCVBUF`), the system
+    /// default if 0.
+    pub recv_buffer: usize,
+    /// Fail reads with `TimedOut` once no datagram went either way for
+    /// this long, e.g. to drop stale associations.
+    pub idle_timeout: Option<Duration>,
+}
+
 #[derive(Debug)]
 pub struct UdpStream {
-    socket: UdpSocket,
+    inner: UdpInner,
+}
+
+#[derive(Debug)]
+enum UdpInner {
+    Plain(UdpIo),
+    Idle(IdleTimeoutStream<UdpIo>),
 }
 
+/// Connected socket read and written one datagram at a time.
+#[derive(Debug)]
+struct UdpIo(UdpSocket);
+
 impl UdpStream {
     pub async fn connect(addr: SocketAddr) -> std::io::Result<Self> {
+        Self::connect_device(addr, None).await
+    }
+
+    /// Connect with the socket bound to the interface `device`.
+    pub async fn connect_device(addr: SocketAddr, device: Option<&str>) -> std::io::Result<Self> {
+        Self::open(addr, device, &UdpOptions::default()).await
+    }
+
+    /// Connect with the socket set up as `options` asks.
+    pub async fn connect_with(addr: SocketAddr, options: UdpOptions) -> std::io::Result<Self> {
+        Self::open(addr, None, &options).await
+    }
+
+    async fn open(
+        addr: SocketAddr,
+        device: Option<&str>,
+        options: &UdpOptions,
+    ) -> std::io::Result<Self> {
         let local_addr = if addr.is_ipv4() {
             SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
         } else {
@@ -109,9 +261,40 @@ impl UdpStream {
         };
 
         let socket = UdpSocket::bind(local_addr).await?;
+        if let Some(device) = device {
+            bind_device(SockRef::from(&socket), device, addr.is_ipv6())?;
+        }
         socket.connect(addr).await?;
 
-        Ok(Self { socket })
+        let io = UdpIo(socket);
+        let stream = Self {
+            inner: match options.idle_timeout {
+                Some(timeout) => UdpInner::Idle(IdleTimeoutStream::new(io, timeout)),
+                None => UdpInner::Plain(io),
+            },
+        };
+        if options.recv_buffer > 0 {
+            stream.set_recv_buffer_size(options.recv_buffer)?;
+        }
+
+        Ok(stream)
+    }
+
+    fn socket(&self) -> &UdpSocket {
+        match &self.inner {
+            UdpInner::Plain(io) => &io.0,
+            UdpInner::Idle(s) => &s.get_ref().0,
+        }
+    }
+
+    /// Set the kernel receive buffer size (`SO_RCVBUF`), which the system
+    /// may round or cap.
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        SockRef::from(self.socket()).set_recv_buffer_size(size)
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        SockRef::from(self.socket()).recv_buffer_size()
     }
 }
 
@@ -121,7 +304,10 @@ impl AsyncRead for UdpStream {
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        self.get_mut().socket.poll_recv(cx, buf)
+        match &mut self.get_mut().inner {
+            UdpInner::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            UdpInner::Idle(s) => Pin::new(s).poll_read(cx, buf),
+        }
     }
 }
 
@@ -131,7 +317,44 @@ impl AsyncWrite for UdpStream {
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        self.get_mut().socket.poll_send(cx, buf)
+        match &mut self.get_mut().inner {
+            UdpInner::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            UdpInner::Idle(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        Ok(()).into()
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        Ok(()).into()
+    }
+}
+
+impl AsyncRead for UdpIo {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.0.poll_recv(cx, buf)
+    }
+}
+
+impl AsyncWrite for UdpIo {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        self.0.poll_send(cx, buf)
     }
 
     fn poll_flush(
@@ -148,3 +371,291 @@ impl AsyncWrite for UdpStream {
         Ok(()).into()
     }
 }
+
+/// Unconnected UDP socket exchanging datagrams with any peer, the transport
+/// of relays such as SOCKS UDP associate.
+///
+/// Each datagram goes with its source or target address, a datagram is
+/// never split or merged with another.
+#[derive(Debug)]
+pub struct UdpRelaySocket {
+    socket: UdpSocket,
+}
+
+impl UdpRelaySocket {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::bind_device(addr, None).await
+    }
+
+    /// Bind with the socket bound to the interface `device`.
+    pub async fn bind_device(addr: SocketAddr, device: Option<&str>) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        if let Some(device) = device {
+            bind_device(SockRef::from(&socket), device, addr.is_ipv6())?;
+        }
+
+        Ok(Self { socket })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Receive one datagram into `buf`, returning its length and source.
+    /// The part of a datagram that does not fit into `buf` is lost.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf).await
+    }
+
+    /// Send `buf` as one datagram to `target`.
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(buf, target).await
+    }
+
+    /// Poll form of `recv_from`, filling `buf` with one datagram.
+    pub fn poll_recv_from(
+        &self,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<SocketAddr>> {
+        self.socket.poll_recv_from(cx, buf)
+    }
+
+    /// Poll form of `send_to`.
+    pub fn poll_send_to(
+        &self,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> std::task::Poll<io::Result<usize>> {
+        self.socket.poll_send_to(cx, buf, target)
+    }
+}
+
+pub(crate) const BIND_DEVICE_SUPPORTED: bool = cfg!(any(
+    target_os = "android",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "ios",
+    target_os = "macos",
+    windows,
+));
+
+/// Bind `socket` to the interface named `device`.
+#[allow(unused_variables)]
+fn bind_device(socket: SockRef<'_>, device: &str, ipv6: bool) -> io::Result<()> {
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    {
+        socket.bind_device(Some(device.as_bytes()))
+    }
+
+    #[cfg(any(target_os = "ios", target_os = "macos"))]
+    {
+        let name = std::ffi::CString::new(device)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: `name` is a valid NUL terminated string.
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        let index = std::num::NonZeroU32::new(index).ok_or_else(io::Error::last_os_error)?;
+        if ipv6 {
+            socket.bind_device_by_index_v6(Some(index))
+        } else {
+            socket.bind_device_by_index_v4(Some(index))
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsRawSocket;
+
+        use windows_sys::Win32::{
+            NetworkManagement::IpHelper::if_nametoindex,
+            Networking::WinSock::{
+                setsockopt, IPPROTO_IP, IPPROTO_IPV6, IPV6_UNICAST_IF, IP_UNICAST_IF, SOCKET_ERROR,
+            },
+        };
+
+        let index = match device.parse::<u32>() {
+            Ok(index) => index,
+            Err(_) => {
+                let name = std::ffi::CString::new(device)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                // SAFETY: `name` is a valid NUL terminated string.
+                unsafe { if_nametoindex(name.as_ptr().cast()) }
+            }
+        };
+        if index == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no interface {}", device),
+            ));
+        }
+
+        // IPv4 takes the index in network byte order, IPv6 in host order
+        let (level, name, value) = if ipv6 {
+            (IPPROTO_IPV6, IPV6_UNICAST_IF, index)
+        } else {
+            (IPPROTO_IP, IP_UNICAST_IF, index.to_be())
+        };
+        // SAFETY: the socket stays open for the borrow and `value` outlives
+        // the call.
+        let ret = unsafe {
+            setsockopt(
+                socket.as_raw_socket() as _,
+                level,
+                name,
+                (&value as *const u32).cast(),
+                std::mem::size_of::<u32>() as i32,
+            )
+        };
+        if ret == SOCKET_ERROR {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(
+        target_os = "android",
+        target_os = "fuchsia",
+        target_os = "linux",
+        target_os = "ios",
+        target_os = "macos",
+        windows,
+    )))]
+    {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "bind_device is not supported on this platform",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::duplex, net::TcpListener};
+
+    use crate::ServiceAddress;
+
+    use super::*;
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[tokio::test]
+    async fn test_direct_bind_device() {
+        let device = if cfg!(target_os = "linux") {
+            "lo"
+        } else {
+            "lo0"
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::from(listener.local_addr().unwrap()),
+        };
+
+        let direct = DirectOutbound::init(DirectOutboundOption {
+            bind_device: Some(device.to_string()),
+        })
+        .unwrap();
+        let (s1, _s2) = duplex(64);
+        direct.handshake(s1, packet.clone()).await.unwrap();
+        listener.accept().await.unwrap();
+
+        let direct = DirectOutbound::init(DirectOutboundOption {
+            bind_device: Some("no-such-device0".to_string()),
+        })
+        .unwrap();
+        let (s1, _s2) = duplex(64);
+        assert!(direct.handshake(s1, packet).await.is_err());
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_direct_bind_device_index() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::from(listener.local_addr().unwrap()),
+        };
+
+        // 1 is the loopback pseudo-interface
+        let direct = DirectOutbound::init(DirectOutboundOption {
+            bind_device: Some("1".to_string()),
+        })
+        .unwrap();
+        let (s1, _s2) = duplex(64);
+        direct.handshake(s1, packet.clone()).await.unwrap();
+        listener.accept().await.unwrap();
+
+        let direct = DirectOutbound::init(DirectOutboundOption {
+            bind_device: Some("no-such-device0".to_string()),
+        })
+        .unwrap();
+        let (s1, _s2) = duplex(64);
+        assert!(direct.handshake(s1, packet).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay_socket() {
+        let any = "127.0.0.1:0".parse().unwrap();
+        let a = UdpRelaySocket::bind(any).await.unwrap();
+        let b = UdpRelaySocket::bind(any).await.unwrap();
+        let c = UdpRelaySocket::bind(any).await.unwrap();
+        let b_addr = b.local_addr().unwrap();
+
+        // datagrams from two sources keep their boundaries and senders
+        a.send_to(b"from a", b_addr).await.unwrap();
+        c.send_to(b"from c", b_addr).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        for (sender, data) in [(&a, &b"from a"[..]), (&c, &b"from c"[..])] {
+            let (n, from) = b.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], data);
+            assert_eq!(from, sender.local_addr().unwrap());
+
+            let n = std::future::poll_fn(|cx| b.poll_send_to(cx, b"reply", from))
+                .await
+                .unwrap();
+            assert_eq!(n, 5);
+        }
+
+        for socket in [&a, &c] {
+            let mut read = tokio::io::ReadBuf::new(&mut buf);
+            let from = std::future::poll_fn(|cx| socket.poll_recv_from(cx, &mut read))
+                .await
+                .unwrap();
+            assert_eq!(read.filled(), b"reply");
+            assert_eq!(from, b_addr);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_udp_idle_timeout() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = UdpStream::connect_with(
+            peer.local_addr().unwrap(),
+            UdpOptions {
+                recv_buffer: 64 * 1024,
+                idle_timeout: Some(Duration::from_secs(30)),
+            },
+        )
+        .await
+        .unwrap();
+        assert!(stream.recv_buffer_size().unwrap() >= 64 * 1024);
+
+        // a datagram restarts the timer
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 16];
+        let (n, from) = peer.recv_from(&mut buf).await.unwrap();
+        peer.send_to(&buf[..n], from).await.unwrap();
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+
+        let start = tokio::time::Instant::now();
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+}
@@ -2,7 +2,10 @@
 
 use thiserror::Error;
 
-use crate::{address::NetworkType, http::HttpError, socks::SocksError, vless::VlessError};
+use crate::{
+    address::NetworkType, http::HttpError, shadowsocks::ShadowsocksError, socks::SocksError,
+    trojan::TrojanError, vless::VlessError, ServiceAddress,
+};
 
 #[derive(Debug, Error)]
 pub enum InboundError {
@@ -14,6 +17,12 @@ pub enum InboundError {
     Address(#[from] AddressError),
     #[error("handshake error ({0})")]
     Handshake(#[from] ProtocolError),
+    #[error("invalid prefix")]
+    InvalidPrefix,
+    #[error("connection limit reached")]
+    ConnectionLimit,
+    #[error("unsupported protocol")]
+    UnsupportedProtocol,
 }
 
 #[derive(Debug, Error)]
@@ -28,6 +37,14 @@ pub enum OutboundError {
     Unresolved,
     #[error("invalid type {0}")]
     InvalidType(NetworkType),
+    #[error("no outbound for {0}")]
+    NoRoute(ServiceAddress),
+    #[error("destination {0} is the proxy itself")]
+    SelfConnect(ServiceAddress),
+    #[error("connection refused by the proxy")]
+    Refused,
+    #[error("destination unreachable from the proxy")]
+    Unreachable,
 }
 
 #[derive(Debug, Error)]
@@ -50,4 +67,14 @@ pub enum ProtocolError {
     Socks(#[from] SocksError),
     #[error("[http] {0}")]
     Http(#[from] HttpError),
+    #[error("[shadowsocks] {0}")]
+    Shadowsocks(#[from] ShadowsocksError),
+    #[error("[trojan] {0}")]
+    Trojan(#[from] TrojanError),
+    #[cfg(feature = "tls")]
+    #[error("[tls] {0}")]
+    Tls(#[from] crate::tls::TlsError),
+    #[cfg(feature = "ws")]
+    #[error("[ws] {0}")]
+    Ws(#[from] crate::ws::WsError),
 }
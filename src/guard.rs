@@ -0,0 +1,127 @@
+//! Outbound guard against connecting back to the proxy itself
+
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{Address, OutboundError, OutboundPacket, OutboundResult, OutboundServiceTrait};
+
+/// Wraps an outbound and refuses destinations that are one of the proxy's
+/// own listen addresses, so a request cannot loop back into the proxy.
+///
+/// An unspecified listen address (`0.0.0.0`, `::`) matches any loopback or
+/// unspecified destination on its port. Domains other than `localhost` are
+/// not resolved and always pass.
+#[derive(Debug)]
+pub struct LoopGuardOutbound<O> {
+    inner: O,
+    self_addrs: Vec<SocketAddr>,
+}
+
+impl<O> LoopGuardOutbound<O> {
+    pub fn new(inner: O, self_addrs: Vec<SocketAddr>) -> Self {
+        Self { inner, self_addrs }
+    }
+
+    pub fn self_addrs(&self) -> &[SocketAddr] {
+        &self.self_addrs
+    }
+
+    pub fn inner(&self) -> &O {
+        &self.inner
+    }
+
+    /// Whether `ip:port` is one of the self addresses.
+    pub fn is_self(&self, ip: IpAddr, port: u16) -> bool {
+        let ip = ip.to_canonical();
+        self.self_addrs.iter().any(|addr| {
+            if addr.port() != port {
+                return false;
+            }
+            let listen = addr.ip().to_canonical();
+            if listen.is_unspecified() {
+                ip.is_loopback() || ip.is_unspecified()
+            } else {
+                listen == ip
+            }
+        })
+    }
+
+    fn is_self_dest(&self, packet: &OutboundPacket) -> bool {
+        let port = packet.dest.port;
+        match packet.dest.addr {
+            Address::Socket(ip) => self.is_self(ip, port),
+            Address::Domain(ref domain) if domain.eq_ignore_ascii_case("localhost") => {
+                self.is_self(IpAddr::from([127, 0, 0, 1]), port)
+                    || self.is_self(IpAddr::from([0u16, 0, 0, 0, 0, 0, 0, 1]), port)
+            }
+            Address::Domain(_) => false,
+        }
+    }
+}
+
+impl<S, O> OutboundServiceTrait<S> for LoopGuardOutbound<O>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    O: OutboundServiceTrait<S>,
+{
+    type Stream = O::Stream;
+
+    async fn handshake(&self, stream: S, packet: OutboundPacket) -> OutboundResult<Self::Stream> {
+        if self.is_self_dest(&packet) {
+            return Err(OutboundError::SelfConnect(packet.dest));
+        }
+
+        self.inner.handshake(stream, packet).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::net::TcpListener;
+
+    use crate::{address::NetworkType, direct::DirectOutbound, ServiceAddress};
+
+    use super::*;
+
+    fn packet(addr: &str, port: u16) -> OutboundPacket {
+        OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new(addr.into(), port),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_loop_guard() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen = listener.local_addr().unwrap();
+
+        let guard = LoopGuardOutbound::new(DirectOutbound::new(), vec![listen]);
+        for addr in ["127.0.0.1", "::ffff:127.0.0.1", "localhost"] {
+            let err = guard
+                .handshake(Cursor::new(vec![]), packet(addr, listen.port()))
+                .await
+                .unwrap_err();
+            assert!(matches!(err, OutboundError::SelfConnect(_)), "{}", addr);
+        }
+
+        // another port on the same host is not the proxy
+        let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = other.local_addr().unwrap().port();
+        guard
+            .handshake(Cursor::new(vec![]), packet("127.0.0.1", port))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_loop_guard_unspecified() {
+        let guard = LoopGuardOutbound::new((), vec!["0.0.0.0:1080".parse().unwrap()]);
+        assert!(guard.is_self("127.0.0.2".parse().unwrap(), 1080));
+        assert!(guard.is_self("::1".parse().unwrap(), 1080));
+        assert!(!guard.is_self("127.0.0.1".parse().unwrap(), 1081));
+        assert!(!guard.is_self("10.0.0.1".parse().unwrap(), 1080));
+    }
+}
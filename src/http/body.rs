@@ -0,0 +1,263 @@
+//! Http message body framing
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use http::{
+    header::{CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING},
+    Response, StatusCode, Version,
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use super::HttpError;
+
+/// Reads exactly one message body from `inner` and stops there, leaving
+/// anything after it, e.g. the next response on a keep-alive connection,
+/// unread.
+///
+/// A body is either `Content-Length` bytes long, or runs until the peer
+/// closes the connection.
+#[derive(Debug)]
+pub struct BodyReader<S> {
+    inner: S,
+    /// Bytes left for a fixed length body, `None` until EOF.
+    remaining: Option<u64>,
+    done: bool,
+    /// The response asked for the connection to be closed after it.
+    close: bool,
+}
+
+impl<S> BodyReader<S> {
+    /// Body of `len` bytes, or up to EOF when `None`.
+    pub fn new(inner: S, len: Option<u64>) -> Self {
+        Self {
+            inner,
+            remaining: len,
+            done: len == Some(0),
+            close: false,
+        }
+    }
+
+    /// Body framed as the headers of `resp` declare.
+    ///
+    /// `1xx`, `204` and `304` responses have no body. Responses to `HEAD`
+    /// requests neither, the caller knows the method and uses `new` with
+    /// `Some(0)` for them. Chunked bodies are not supported.
+    pub fn from_response<T>(inner: S, resp: &Response<T>) -> Result<Self, HttpError> {
+        let close = closes_connection(resp);
+        let status = resp.status();
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            return Ok(Self {
+                close,
+                ..Self::new(inner, Some(0))
+            });
+        }
+
+        let headers = resp.headers();
+        if headers.contains_key(TRANSFER_ENCODING) {
+            return Err(HttpError::UnsupportedTransferEncoding);
+        }
+
+        // repeated lengths must agree
+        let mut len = None;
+        for value in headers.get_all(CONTENT_LENGTH) {
+            let value = value
+                .to_str()
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .ok_or(HttpError::InvalidResponse)?;
+            if len.is_some_and(|len| len != value) {
+                return Err(HttpError::InvalidResponse);
+            }
+            len = Some(value);
+        }
+
+        Ok(Self {
+            close,
+            ..Self::new(inner, len)
+        })
+    }
+
+    /// Whether the whole body has been read.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Whether the connection can carry another message, which needs the
+    /// body to be read in full, to not be framed by the close and the
+    /// response to not ask for the connection to be closed.
+    pub fn is_reusable(&self) -> bool {
+        self.done && self.remaining.is_some() && !self.close
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+/// Whether `resp` closes the connection after it, as HTTP/1.0 responses do
+/// unless they ask to keep it alive.
+fn closes_connection<T>(resp: &Response<T>) -> bool {
+    let has_token = |token: &str| {
+        resp.headers()
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    };
+
+    has_token("close") || (resp.version() == Version::HTTP_10 && !has_token("keep-alive"))
+}
+
+impl<S> AsyncRead for BodyReader<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.done || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let Some(remaining) = this.remaining else {
+            let filled = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            this.done = buf.filled().len() == filled;
+            return Poll::Ready(Ok(()));
+        };
+
+        // never read past the body
+        let max = usize::try_from(remaining).map_or(buf.remaining(), |r| r.min(buf.remaining()));
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(max));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        if n == 0 {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "body ended early",
+            )));
+        }
+        buf.advance(n);
+
+        let remaining = remaining - n as u64;
+        this.remaining = Some(remaining);
+        this.done = remaining == 0;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    fn response(headers: &[(&str, &str)]) -> Response<()> {
+        let mut builder = Response::builder().status(200);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_body_reader_fixed_length() {
+        let resp = response(&[("Content-Length", "5")]);
+        let inner = Cursor::new(b"helloHTTP/1.1 200 OK\r\n".to_vec());
+
+        let mut body = BodyReader::from_response(inner, &resp).unwrap();
+        let mut data = vec![];
+        body.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"hello");
+        assert!(body.is_done());
+        assert!(body.is_reusable());
+
+        // the next response is left for the connection
+        let mut rest = vec![];
+        body.into_inner().read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"HTTP/1.1 200 OK\r\n");
+
+        // closed before the declared length
+        let mut body = BodyReader::from_response(Cursor::new(b"hel".to_vec()), &resp).unwrap();
+        let err = body.read_to_end(&mut vec![]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(!body.is_done());
+    }
+
+    #[tokio::test]
+    async fn test_body_reader_close_framed() {
+        let resp = response(&[("Connection", "close")]);
+        let mut body = BodyReader::from_response(Cursor::new(b"hello".to_vec()), &resp).unwrap();
+        assert!(!body.is_done());
+
+        let mut data = vec![];
+        body.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"hello");
+        assert!(body.is_done());
+        assert!(!body.is_reusable());
+    }
+
+    #[test]
+    fn test_body_reader_framing() {
+        let body = |headers: &[(&str, &str)]| {
+            BodyReader::from_response(Cursor::new(Vec::<u8>::new()), &response(headers))
+        };
+
+        assert!(body(&[("Content-Length", "0")]).unwrap().is_done());
+        assert!(body(&[("Content-Length", "5"), ("Content-Length", "5")]).is_ok());
+        assert!(matches!(
+            body(&[("Content-Length", "5"), ("Content-Length", "6")]),
+            Err(HttpError::InvalidResponse)
+        ));
+        assert!(matches!(
+            body(&[("Content-Length", "five")]),
+            Err(HttpError::InvalidResponse)
+        ));
+        assert!(matches!(
+            body(&[("Transfer-Encoding", "chunked")]),
+            Err(HttpError::UnsupportedTransferEncoding)
+        ));
+
+        let no_content = Response::builder().status(204).body(()).unwrap();
+        let reader = BodyReader::from_response(Cursor::new(Vec::<u8>::new()), &no_content).unwrap();
+        assert!(reader.is_reusable());
+
+        // asked to close, explicitly or by HTTP/1.0
+        let reusable = |resp: Response<()>| {
+            BodyReader::from_response(Cursor::new(Vec::<u8>::new()), &resp)
+                .unwrap()
+                .is_reusable()
+        };
+        let resp = |version, connection: Option<&str>| {
+            let mut builder = Response::builder()
+                .version(version)
+                .header("Content-Length", "0");
+            if let Some(connection) = connection {
+                builder = builder.header("Connection", connection);
+            }
+            builder.body(()).unwrap()
+        };
+        assert!(!reusable(resp(Version::HTTP_11, Some("Keep-Alive, Close"))));
+        assert!(!reusable(resp(Version::HTTP_10, None)));
+        assert!(reusable(resp(Version::HTTP_10, Some("keep-alive"))));
+        assert!(reusable(resp(Version::HTTP_11, None)));
+    }
+}
@@ -1,36 +1,129 @@
 //! Http Proxy Inbound Service
 
-use std::{borrow::Cow, pin::Pin, task::Poll};
+use std::{borrow::Cow, pin::Pin, task::Poll, time::Duration};
 
-use base64::{prelude::BASE64_URL_SAFE, Engine};
+use base64::{prelude::BASE64_STANDARD, Engine};
 use bytes::Bytes;
-use http::{HeaderMap, Method, Request, Response, StatusCode};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use http::{
+    header::{
+        ALLOW, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, HOST, PROXY_AUTHENTICATE,
+        TRANSFER_ENCODING,
+    },
+    uri::Authority,
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri, Version,
+};
+use tokio::io::{AsyncRead, AsyncWrite, BufStream};
 
 use crate::{
-    address::NetworkType, error::ProtocolError, Address, InboundError, InboundPacket,
-    InboundResult, InboundServiceStream, InboundServiceTrait, ServiceAddress,
+    address::{check_domain, NetworkType},
+    crypto::{ct_contains, ct_eq},
+    error::ProtocolError,
+    inbound::max_auth_attempts,
+    stream::{buf_stream, read_buffer_size},
+    write_all_timeout, Address, InboundError, InboundPacket, InboundResult, InboundServiceStream,
+    InboundServiceTrait, ServiceAddress,
 };
 
 use super::{
-    format_request, option::HttpInboundOption, read_request, write_response, HttpError, MAX_HEADER,
-    MAX_HEADER_SIZE,
+    format_request_with, format_response,
+    option::{HttpAuthScheme, HttpFakeResponse, HttpInboundOption},
+    read_request_with, HttpError, RequestTarget, MAX_HEADER, MAX_HEADER_SIZE,
 };
 
+/// Methods listed in the `Allow` header of a local `OPTIONS` response.
+const LOCAL_ALLOW: &str = "OPTIONS, TRACE, CONNECT, GET, HEAD, POST, PUT, DELETE, PATCH";
+
+/// Request line of an accepted proxy request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequestHead {
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+}
+
 #[derive(Debug)]
 pub struct HttpInbound {
     pub auth: Vec<Vec<u8>>,
+    pub tokens: Vec<Vec<u8>>,
+    scheme: HttpAuthScheme,
+    /// `Proxy-Authenticate` values sent with a 407.
+    challenges: Vec<HeaderValue>,
+    /// Encoded fake response for rejected requests.
+    fake_response: Option<Vec<u8>>,
+    reply_timeout: Option<Duration>,
+    read_buffer: usize,
+    max_auth_attempts: Option<u32>,
+    strict_headers: bool,
+    /// Authorities naming the proxy, see `HttpInboundOption::local_hosts`.
+    local_hosts: Vec<Authority>,
+    strict_domain: bool,
+}
+
+impl HttpInboundOption {
+    pub fn validate(&self) -> Vec<InboundError> {
+        let mut errors = vec![];
+        errors.extend(read_buffer_size(self.read_buffer).err());
+        errors.extend(max_auth_attempts(self.max_auth_attempts).err());
+
+        if !self.auth.is_empty() && !self.scheme.allows_basic() {
+            errors.push(InboundError::Option(
+                "basic auth users given but the basic scheme is disabled".to_string(),
+            ));
+        }
+        if !self.tokens.is_empty() && !self.scheme.allows_bearer() {
+            errors.push(InboundError::Option(
+                "bearer tokens given but the bearer scheme is disabled".to_string(),
+            ));
+        }
+        errors.extend(challenges(self).err());
+        errors.extend(
+            self.fake_response
+                .as_ref()
+                .and_then(|r| fake_response(r).err()),
+        );
+        errors.extend(local_hosts(&self.local_hosts).err());
+
+        errors
+    }
 }
 
 impl HttpInbound {
     pub fn init(in_opt: HttpInboundOption) -> InboundResult<Self> {
+        if let Some(e) = in_opt.validate().into_iter().next() {
+            return Err(e);
+        }
+
+        let challenges = challenges(&in_opt)?;
+        let fake_response = in_opt
+            .fake_response
+            .as_ref()
+            .map(fake_response)
+            .transpose()?;
+        let local_hosts = local_hosts(&in_opt.local_hosts)?;
         let auth: Vec<_> = in_opt
             .auth
             .into_iter()
             .map(|a| [a.user, a.pass].join(":").into_bytes())
             .collect();
+        let tokens: Vec<_> = in_opt.tokens.into_iter().map(String::into_bytes).collect();
 
-        Ok(Self { auth })
+        Ok(Self {
+            auth,
+            tokens,
+            scheme: in_opt.scheme,
+            challenges,
+            fake_response,
+            reply_timeout: in_opt.reply_timeout.map(Duration::from_millis),
+            read_buffer: read_buffer_size(in_opt.read_buffer)?,
+            max_auth_attempts: max_auth_attempts(in_opt.max_auth_attempts)?,
+            strict_headers: in_opt.strict_headers,
+            local_hosts,
+            strict_domain: in_opt.strict_domain,
+        })
+    }
+
+    fn auth_required(&self) -> bool {
+        !self.auth.is_empty() || !self.tokens.is_empty()
     }
 
     fn verify_auth(&self, req: &Request<()>) -> InboundResult<Vec<u8>> {
@@ -39,21 +132,146 @@ impl HttpInbound {
             .get("Proxy-Authorization")
             .ok_or(ProtocolError::Http(HttpError::InvalidAuth))?;
 
-        if auth_val.as_bytes().starts_with(b"Basic ") {
-            let auth = BASE64_URL_SAFE
+        if self.scheme.allows_basic() && auth_val.as_bytes().starts_with(b"Basic ") {
+            let auth = BASE64_STANDARD
                 .decode(&auth_val.as_bytes()[6..])
                 .map_err(|_| {
                     InboundError::Handshake(ProtocolError::Http(HttpError::InvalidAuth))
                 })?;
-            if self.auth.contains(&auth) {
+            if ct_contains(&self.auth, |a| ct_eq(a, &auth)) {
                 return Ok(auth);
             }
         }
 
+        if self.scheme.allows_bearer() {
+            if let Some(token) = auth_val.as_bytes().strip_prefix(b"Bearer ") {
+                if ct_contains(&self.tokens, |t| ct_eq(t, token)) {
+                    return Ok(token.to_vec());
+                }
+            }
+        }
+
         Err(InboundError::Handshake(ProtocolError::Http(
             HttpError::InvalidAuth,
         )))
     }
+
+    /// Whether `req` is addressed to the proxy rather than to be forwarded:
+    /// an `OPTIONS` or `TRACE` in asterisk or origin form, or naming one of
+    /// the `local_hosts`.
+    fn is_local(&self, req: &Request<()>) -> bool {
+        if req.method() != Method::OPTIONS && req.method() != Method::TRACE {
+            return false;
+        }
+
+        let uri = req.uri();
+        let Some(authority) = uri.authority() else {
+            return !is_upgrade(req.headers());
+        };
+        let port = authority.port_u16().unwrap_or_else(|| default_port(uri));
+        self.local_hosts.iter().any(|local| {
+            local.host().eq_ignore_ascii_case(authority.host())
+                && local.port_u16().is_none_or(|p| p == port)
+        })
+    }
+
+    /// Response to a request `is_local`, `TRACE` echoes the request without
+    /// its proxy credentials.
+    fn local_response(&self, req: &mut Request<()>) -> InboundResult<Vec<u8>> {
+        let mut builder = Response::builder()
+            .version(req.version())
+            .status(StatusCode::OK)
+            .header(CONNECTION, "close");
+        let mut body = vec![];
+        if req.method() == Method::TRACE {
+            remove_proxy_headers(req.headers_mut());
+            body = format_request_with(req, RequestTarget::AsIs).map_err(ProtocolError::Http)?;
+            builder = builder.header(CONTENT_TYPE, "message/http");
+        } else {
+            builder = builder.header(ALLOW, LOCAL_ALLOW);
+        }
+        let resp = builder
+            .header(CONTENT_LENGTH, body.len())
+            .body(())
+            .map_err(|e| ProtocolError::Http(e.into()))?;
+
+        let mut buf = format_response(&resp, None).map_err(ProtocolError::Http)?;
+        buf.extend_from_slice(&body);
+
+        Ok(buf)
+    }
+
+    async fn write_response<S>(
+        &self,
+        resp: &Response<()>,
+        stream: &mut S,
+        reason: Option<&str>,
+    ) -> InboundResult<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let buf = format_response(resp, reason).map_err(ProtocolError::Http)?;
+        write_all_timeout(stream, &buf, self.reply_timeout).await?;
+
+        Ok(())
+    }
+
+    /// Write `resp` for a rejected request, or the fake response if one is
+    /// configured.
+    async fn write_reject<S>(&self, resp: &Response<()>, stream: &mut S) -> InboundResult<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        match self.fake_response {
+            Some(ref fake) => Ok(write_all_timeout(stream, fake, self.reply_timeout).await?),
+            None => self.write_response(resp, stream, None).await,
+        }
+    }
+}
+
+/// `Proxy-Authenticate` values for the schemes `opt` allows.
+fn challenges(opt: &HttpInboundOption) -> InboundResult<Vec<HeaderValue>> {
+    let realm = opt.realm.as_deref().unwrap_or("proxy");
+    let realm = realm.replace('\\', "\\\\").replace('"', "\\\"");
+    let mut challenges = vec![];
+    if opt.scheme.allows_basic() {
+        challenges.push(format!("Basic realm=\"{}\"", realm));
+    }
+    if opt.scheme.allows_bearer() {
+        challenges.push(format!("Bearer realm=\"{}\"", realm));
+    }
+
+    challenges
+        .into_iter()
+        .map(HeaderValue::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| InboundError::Option(format!("invalid realm ({})", e)))
+}
+
+fn local_hosts(hosts: &[String]) -> InboundResult<Vec<Authority>> {
+    hosts
+        .iter()
+        .map(|h| h.parse::<Authority>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| InboundError::Option(format!("invalid local host ({})", e)))
+}
+
+fn fake_response(opt: &HttpFakeResponse) -> InboundResult<Vec<u8>> {
+    let status = StatusCode::from_u16(opt.status)
+        .map_err(|e| InboundError::Option(format!("invalid fake response status ({})", e)))?;
+    let resp = Response::builder()
+        .status(status)
+        .header("Content-Type", opt.content_type.as_str())
+        .header("Content-Length", opt.body.len())
+        .header("Connection", "close")
+        .body(())
+        .map_err(|e| InboundError::Option(format!("invalid fake response ({})", e)))?;
+
+    let mut buf = format_response(&resp, None)
+        .map_err(|e| InboundError::Option(format!("invalid fake response ({})", e)))?;
+    buf.extend_from_slice(opt.body.as_bytes());
+
+    Ok(buf)
 }
 
 impl<S> InboundServiceTrait<S> for HttpInbound
@@ -62,42 +280,130 @@ where
 {
     type Stream = HttpInboundStream<S>;
 
-    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket)> {
-        let mut stream = BufStream::new(stream);
-        let mut req = read_request(&mut stream, MAX_HEADER, MAX_HEADER_SIZE)
+    /// Same as `accept`, a request answered by the proxy itself fails with
+    /// `HttpError::LocalRequest` since there is nothing to connect to.
+    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+        match self.accept(stream).await? {
+            (stream, HttpAccept::Request(in_pac), _) => Ok((stream, in_pac)),
+            (_, HttpAccept::Local, _) => Err(ProtocolError::Http(HttpError::LocalRequest).into()),
+        }
+    }
+}
+
+/// Outcome of `HttpInbound::accept`.
+#[derive(Debug)]
+pub enum HttpAccept<'a> {
+    Request(InboundPacket<'a>),
+    /// Request to the proxy itself, already answered. The stream is
+    /// `HttpInboundStream::Local` and there is no destination.
+    Local,
+}
+
+impl HttpInbound {
+    /// Like `handshake`, telling a request to forward apart from one the
+    /// proxy answered itself, and also returning the request line for
+    /// logging or request-aware routing.
+    pub async fn accept<S>(
+        &self,
+        stream: S,
+    ) -> InboundResult<(HttpInboundStream<S>, HttpAccept<'_>, HttpRequestHead)>
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+    {
+        let mut stream = buf_stream(stream, self.read_buffer);
+        let mut attempts = 0;
+        let mut req = loop {
+            let req = read_request_with(
+                &mut stream,
+                MAX_HEADER,
+                MAX_HEADER_SIZE,
+                !self.strict_headers,
+            )
             .await
-            .map_err(|e| ProtocolError::Http(e))?;
-
-        if !self.auth.is_empty() {
-            match self.verify_auth(&req) {
-                Ok(_auth) => {}
-                Err(err) => {
-                    let resp = Response::builder()
-                        .version(req.version())
-                        .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
-                        .body(())
-                        .unwrap();
-                    let _ = write_response(&resp, &mut stream, None).await;
-                    let _ = stream.flush().await?;
-                    return Err(err);
-                }
+            .map_err(ProtocolError::Http)?;
+
+            if !self.auth_required() {
+                break req;
+            }
+            let err = match self.verify_auth(&req) {
+                Ok(_auth) => break req,
+                Err(err) => err,
+            };
+
+            // the client may try again on this connection if the limit
+            // allows it and there is no body to skip
+            attempts += 1;
+            let exhausted = self.max_auth_attempts.is_some_and(|max| attempts >= max);
+            let retry = self.max_auth_attempts.is_some()
+                && !exhausted
+                && self.fake_response.is_none()
+                && !has_body(req.headers());
+
+            let mut resp = error_response(req.version(), StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+            for challenge in &self.challenges {
+                resp.headers_mut()
+                    .append(PROXY_AUTHENTICATE, challenge.clone());
             }
+            if retry {
+                // the connection stays open for the next attempt
+                resp.headers_mut().remove(CONNECTION);
+                self.write_reject(&resp, &mut stream).await?;
+                continue;
+            }
+            let _ = self.write_reject(&resp, &mut stream).await;
+
+            return Err(if exhausted {
+                ProtocolError::Http(HttpError::TooManyAuthAttempts).into()
+            } else {
+                err
+            });
+        };
+
+        let head = HttpRequestHead {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+        };
+
+        // answered here, there is nothing to connect to
+        if self.is_local(&req) {
+            let resp = self.local_response(&mut req)?;
+            write_all_timeout(&mut stream, &resp, self.reply_timeout).await?;
+
+            return Ok((HttpInboundStream::Local(stream), HttpAccept::Local, head));
         }
 
-        let port = req.uri().port_u16().unwrap_or(80);
-        let addr = req
-            .uri()
-            .host()
-            .ok_or(ProtocolError::Http(HttpError::InvalidRequest))?;
+        let upgrade = req.method() != Method::CONNECT && is_upgrade(req.headers());
 
-        let in_pac = InboundPacket {
+        // an upgrade request may be in origin form, its target is then the Host
+        let (addr, port) = match req.uri().host() {
+            Some(host) => (
+                host.to_string(),
+                req.uri()
+                    .port_u16()
+                    .unwrap_or_else(|| default_port(req.uri())),
+            ),
+            None if upgrade => host_header(req.headers())?,
+            None => {
+                let resp = error_response(req.version(), StatusCode::BAD_REQUEST);
+                let _ = self.write_reject(&resp, &mut stream).await;
+
+                return Err(ProtocolError::Http(HttpError::InvalidRequest).into());
+            }
+        };
+
+        let addr = addr.parse::<Address>()?;
+        if self.strict_domain {
+            if let Address::Domain(domain) = &addr {
+                check_domain(domain)?;
+            }
+        }
+
+        let in_pac = HttpAccept::Request(InboundPacket {
             typ: NetworkType::Tcp,
-            dest: ServiceAddress {
-                addr: addr.parse::<Address>()?,
-                port,
-            },
+            dest: ServiceAddress { addr, port },
             detail: Cow::Borrowed(""),
-        };
+        });
 
         if req.method() == Method::CONNECT {
             let resp = Response::builder()
@@ -105,64 +411,181 @@ where
                 .status(StatusCode::OK)
                 .body(())
                 .unwrap();
-            let _ = write_response(&resp, &mut stream, Some("Connection established"))
-                .await
-                .map_err(|e| ProtocolError::Http(e))?;
-            let _ = stream.flush().await?;
+            self.write_response(&resp, &mut stream, Some("Connection established"))
+                .await?;
 
             let stream = HttpInboundStream::Raw(stream);
 
-            return Ok((stream, in_pac));
+            Ok((stream, in_pac, head))
+        } else if upgrade {
+            // the request goes upstream with its Upgrade and Connection
+            // headers, after the 101 the stream is a tunnel
+            remove_proxy_headers(req.headers_mut());
+
+            let req_data = Bytes::from(
+                format_request_with(&req, RequestTarget::Origin).map_err(ProtocolError::Http)?,
+            );
+            let stream = HttpPlainStream {
+                inner: stream,
+                data: Some(req_data),
+                keep_alive: false,
+            };
+
+            Ok((HttpInboundStream::Upgrade(stream), in_pac, head))
         } else {
             if req.uri().scheme().is_none() || req.uri().authority().is_none() {
-                let resp = Response::builder()
-                    .version(req.version())
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(())
-                    .unwrap();
-                let _ = write_response(&resp, &mut stream, None).await;
-                let _ = stream.flush().await?;
+                let resp = error_response(req.version(), StatusCode::BAD_REQUEST);
+                let _ = self.write_reject(&resp, &mut stream).await;
 
                 return Err(ProtocolError::Http(HttpError::InvalidHost).into());
             }
 
+            let keep_alive = is_keep_alive(req.version(), req.headers());
             remove_hop_by_hop_headers(req.headers_mut());
 
-            let req_data = Bytes::from(format_request(&req).map_err(|e| ProtocolError::Http(e))?);
+            // the Connection header was removed with the other hop-by-hop
+            // headers, it is only needed when it differs from the default
+            match (keep_alive, req.version() >= Version::HTTP_11) {
+                (true, false) => {
+                    req.headers_mut()
+                        .insert(CONNECTION, HeaderValue::from_static("keep-alive"));
+                }
+                (false, true) => {
+                    req.headers_mut()
+                        .insert(CONNECTION, HeaderValue::from_static("close"));
+                }
+                _ => {}
+            }
+
+            // the origin only sees the path, so it needs Host for the name
+            if let (false, Some(authority)) =
+                (req.headers().contains_key(HOST), req.uri().authority())
+            {
+                if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                    req.headers_mut().insert(HOST, host);
+                }
+            }
+
+            let req_data = Bytes::from(
+                format_request_with(&req, RequestTarget::Origin).map_err(ProtocolError::Http)?,
+            );
             let stream = HttpPlainStream {
                 inner: stream,
                 data: Some(req_data),
+                keep_alive,
             };
 
             let stream = HttpInboundStream::Plain(stream);
 
-            Ok((stream, in_pac))
+            Ok((stream, in_pac, head))
         }
     }
 }
 
+/// Response without a body to a request that fails, the connection is
+/// closed after it.
+fn error_response(version: Version, status: StatusCode) -> Response<()> {
+    let mut resp = Response::builder()
+        .version(version)
+        .status(status)
+        .body(())
+        .unwrap();
+    let headers = resp.headers_mut();
+    headers.insert(CONTENT_LENGTH, HeaderValue::from_static("0"));
+    headers.insert(CONNECTION, HeaderValue::from_static("close"));
+    resp
+}
+
+/// Whether a request announces a body.
+fn has_body(headers: &HeaderMap) -> bool {
+    headers.contains_key(TRANSFER_ENCODING)
+        || headers
+            .get(CONTENT_LENGTH)
+            .is_some_and(|len| len.as_bytes() != b"0")
+}
+
+/// Default port for the uri scheme, 80 when there is none.
+fn default_port(uri: &Uri) -> u16 {
+    match uri.scheme_str() {
+        Some(s) if s.eq_ignore_ascii_case("https") || s.eq_ignore_ascii_case("wss") => 443,
+        _ => 80,
+    }
+}
+
+/// Whether the request asks for a protocol upgrade, e.g. to websocket.
+fn is_upgrade(header: &HeaderMap) -> bool {
+    let connection_upgrade = header.get_all("Connection").iter().any(|v| {
+        v.as_bytes()
+            .split(|c| *c == b',')
+            .any(|t| t.trim_ascii().eq_ignore_ascii_case(b"upgrade"))
+    });
+
+    connection_upgrade && header.contains_key("Upgrade")
+}
+
+/// Whether the connection stays open after the request, the default of
+/// HTTP/1.1 and later unless `Connection: close` is sent, while HTTP/1.0
+/// needs `Connection: keep-alive`. Old clients send `Proxy-Connection`
+/// instead, it counts when there is no `Connection` header.
+fn is_keep_alive(version: Version, header: &HeaderMap) -> bool {
+    let name = if header.contains_key(CONNECTION) {
+        "Connection"
+    } else {
+        "Proxy-Connection"
+    };
+    let has_token = |token: &[u8]| {
+        header.get_all(name).iter().any(|v| {
+            v.as_bytes()
+                .split(|c| *c == b',')
+                .any(|t| t.trim_ascii().eq_ignore_ascii_case(token))
+        })
+    };
+
+    if has_token(b"close") {
+        false
+    } else {
+        version >= Version::HTTP_11 || has_token(b"keep-alive")
+    }
+}
+
+/// Target from the Host header, port 80 if it has none.
+fn host_header(header: &HeaderMap) -> InboundResult<(String, u16)> {
+    let authority = header
+        .get("Host")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<Authority>().ok())
+        .ok_or(ProtocolError::Http(HttpError::InvalidHost))?;
+
+    Ok((
+        authority.host().to_string(),
+        authority.port_u16().unwrap_or(80),
+    ))
+}
+
+fn remove_proxy_headers(header: &mut HeaderMap) {
+    header.remove("Proxy-Connection");
+    header.remove("Proxy-Authenticate");
+    header.remove("Proxy-Authorization");
+}
+
 fn remove_hop_by_hop_headers(header: &mut HeaderMap) {
     // Strip hop-by-hop header based on RFC:
     // http://www.w3.org/Protocols/rfc2616/rfc2616-sec13.html#sec13.5.1
     // https://www.mnot.net/blog/2011/07/11/what_proxies_must_do
 
-    header.remove("Proxy-Connection");
-    header.remove("Proxy-Authenticate");
-    header.remove("Proxy-Authorization");
+    remove_proxy_headers(header);
     header.remove("TE");
     header.remove("Trailers");
     header.remove("Transfer-Encoding");
     header.remove("Upgrade");
 
-    let connections = header.remove("Connection");
-    if connections.is_none() {
-        return;
-    }
+    // every Connection line counts, the headers may not have been merged
+    let connections: Vec<HeaderValue> = header.get_all("Connection").iter().cloned().collect();
+    header.remove("Connection");
 
     connections
-        .unwrap()
-        .as_bytes()
-        .split(|c| *c == b',')
+        .iter()
+        .flat_map(|value| value.as_bytes().split(|c| *c == b','))
         .for_each(|key| {
             let key_str = String::from_utf8_lossy(key);
             header.remove(key_str.trim());
@@ -176,6 +599,27 @@ where
 {
     Raw(BufStream<S>),
     Plain(HttpPlainStream<BufStream<S>>),
+    /// Upgrade request, forwarded as is and then tunneled.
+    Upgrade(HttpPlainStream<BufStream<S>>),
+    /// Request to the proxy itself, already answered. Reads are at EOF and
+    /// writes are discarded.
+    Local(BufStream<S>),
+}
+
+impl<S> HttpInboundStream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+{
+    /// Whether the request was answered locally and needs no outbound.
+    pub fn is_local(&self) -> bool {
+        matches!(self, Self::Local(_))
+    }
+
+    /// Whether the client keeps a plain forward connection open for more
+    /// requests, always false for the other kinds.
+    pub fn keep_alive(&self) -> bool {
+        matches!(self, Self::Plain(s) if s.keep_alive())
+    }
 }
 
 impl<S> From<HttpInboundStream<S>> for InboundServiceStream<S>
@@ -200,6 +644,8 @@ where
         match self.get_mut() {
             Self::Raw(s) => Pin::new(s).poll_read(cx, buf),
             Self::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Upgrade(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Local(_) => Poll::Ready(Ok(())),
         }
     }
 }
@@ -217,6 +663,8 @@ where
         match self.get_mut() {
             Self::Raw(s) => Pin::new(s).poll_write(cx, buf),
             Self::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Upgrade(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Local(_) => Poll::Ready(Ok(buf.len())),
         }
     }
 
@@ -228,6 +676,8 @@ where
         match self.get_mut() {
             Self::Raw(s) => Pin::new(s).poll_flush(cx),
             Self::Plain(s) => Pin::new(s).poll_flush(cx),
+            Self::Upgrade(s) => Pin::new(s).poll_flush(cx),
+            Self::Local(s) => Pin::new(s).poll_flush(cx),
         }
     }
 
@@ -239,6 +689,8 @@ where
         match self.get_mut() {
             Self::Raw(s) => Pin::new(s).poll_shutdown(cx),
             Self::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Upgrade(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Local(s) => Pin::new(s).poll_shutdown(cx),
         }
     }
 }
@@ -250,6 +702,18 @@ where
 {
     inner: S,
     data: Option<Bytes>,
+    keep_alive: bool,
+}
+
+impl<S> HttpPlainStream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+{
+    /// Effective keep-alive state of the request, from its version and
+    /// `Connection` header.
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
 }
 
 impl<S> AsyncRead for HttpPlainStream<S>
@@ -311,6 +775,8 @@ where
 mod tests {
     use std::io::Cursor;
 
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
     use super::*;
 
     use crate::http::{option::HttpAuthOption, HttpInboundOption};
@@ -322,6 +788,7 @@ mod tests {
                 user: "test".into(),
                 pass: "test".into(),
             }],
+            ..Default::default()
         };
         let inbound = HttpInbound::init(opt).unwrap();
         let mut data =
@@ -331,7 +798,7 @@ mod tests {
         data.extend(
             format!(
                 "Proxy-Authorization: Basic {}\r\n",
-                BASE64_URL_SAFE.encode(b"test:test")
+                BASE64_STANDARD.encode(b"test:test")
             )
             .as_bytes(),
         );
@@ -342,4 +809,537 @@ mod tests {
             println!("{}", err);
         }
     }
+
+    #[tokio::test]
+    async fn test_http_basic_standard_alphabet() {
+        let inbound = HttpInbound::init(HttpInboundOption {
+            auth: vec![HttpAuthOption {
+                user: "user".into(),
+                pass: "?>>".into(),
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+
+        // `/` and `+` of the standard alphabet, not the url safe `_` and `-`
+        let request = |cred: &str| {
+            format!(
+                "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\
+                 Proxy-Authorization: Basic {}\r\n\r\n",
+                cred
+            )
+            .into_bytes()
+        };
+        assert!(inbound
+            .handshake(Cursor::new(request("dXNlcjo/Pj4=")))
+            .await
+            .is_ok());
+        assert!(inbound
+            .handshake(Cursor::new(request("dXNlcjo_Pj4=")))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_scheme_default_port() {
+        let opt = HttpInboundOption::default();
+        let inbound = HttpInbound::init(opt).unwrap();
+
+        for (uri, port) in [
+            ("https://example.com/path", 443),
+            ("http://example.com/path", 80),
+            ("wss://example.com/path", 443),
+            ("https://example.com:8443/path", 8443),
+        ] {
+            let data = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", uri);
+            let (_, pac) = inbound
+                .handshake(Cursor::new(data.into_bytes()))
+                .await
+                .unwrap();
+            assert_eq!(pac.dest.port, port, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_request_head() {
+        let opt = HttpInboundOption::default();
+        let inbound = HttpInbound::init(opt).unwrap();
+
+        let data = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec();
+        let (_, accepted, head) = inbound.accept(Cursor::new(data)).await.unwrap();
+        let HttpAccept::Request(pac) = accepted else {
+            panic!("answered locally");
+        };
+        assert_eq!(head.method, Method::CONNECT);
+        assert_eq!(head.uri, "example.com:443");
+        assert_eq!(pac.dest.port, 443);
+
+        let data =
+            b"GET http://example.com/index.html?q=1 HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec();
+        let (_, _, head) = inbound.accept(Cursor::new(data)).await.unwrap();
+        assert_eq!(head.method, Method::GET);
+        assert_eq!(head.uri, "http://example.com/index.html?q=1");
+        assert_eq!(head.uri.path(), "/index.html");
+        assert_eq!(head.version, Version::HTTP_11);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_http_reply_timeout() {
+        let (mut client, server) = tokio::io::duplex(16);
+
+        let opt = HttpInboundOption {
+            reply_timeout: Some(100),
+            ..Default::default()
+        };
+        let inbound = HttpInbound::init(opt).unwrap();
+
+        tokio::spawn(async move {
+            client
+                .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await
+                .unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let err = inbound.handshake(server).await.unwrap_err();
+        assert!(matches!(err, InboundError::Io(ref e) if e.kind() == std::io::ErrorKind::TimedOut));
+    }
+
+    #[tokio::test]
+    async fn test_http_bearer_auth() {
+        let request = |auth: &str| {
+            Cursor::new(
+                format!(
+                    "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\
+                     Proxy-Authorization: {}\r\n\r\n",
+                    auth
+                )
+                .into_bytes(),
+            )
+        };
+        let basic = format!("Basic {}", BASE64_STANDARD.encode(b"user:pass"));
+
+        let inbound = HttpInbound::init(HttpInboundOption {
+            scheme: HttpAuthScheme::Bearer,
+            tokens: vec!["secret-token".into()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(inbound
+            .handshake(request("Bearer secret-token"))
+            .await
+            .is_ok());
+        assert!(inbound
+            .handshake(request("Bearer other-token"))
+            .await
+            .is_err());
+        assert!(inbound.handshake(request("Bearer secret")).await.is_err());
+        // wrong scheme
+        assert!(inbound.handshake(request(&basic)).await.is_err());
+
+        let inbound = HttpInbound::init(HttpInboundOption {
+            auth: vec![HttpAuthOption {
+                user: "user".into(),
+                pass: "pass".into(),
+            }],
+            scheme: HttpAuthScheme::Both,
+            tokens: vec!["secret-token".into()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(inbound
+            .handshake(request("Bearer secret-token"))
+            .await
+            .is_ok());
+        assert!(inbound.handshake(request(&basic)).await.is_ok());
+        assert!(inbound
+            .handshake(request("Digest secret-token"))
+            .await
+            .is_err());
+
+        // tokens for a disabled scheme are a configuration error
+        assert!(HttpInbound::init(HttpInboundOption {
+            tokens: vec!["secret-token".into()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy_authenticate() {
+        let challenge = |scheme, tokens: Vec<String>| async move {
+            let inbound = HttpInbound::init(HttpInboundOption {
+                auth: vec![HttpAuthOption {
+                    user: "user".into(),
+                    pass: "pass".into(),
+                }],
+                scheme,
+                tokens,
+                realm: Some("corp".into()),
+                ..Default::default()
+            })
+            .unwrap();
+
+            let (mut client, server) = tokio::io::duplex(1024);
+            client
+                .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await
+                .unwrap();
+            assert!(inbound.handshake(server).await.is_err());
+
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            resp
+        };
+
+        let resp = challenge(HttpAuthScheme::Basic, vec![]).await;
+        assert!(resp.starts_with("HTTP/1.1 407"));
+        assert!(resp.contains("Content-Length: 0\r\n"), "{}", resp);
+        assert!(resp.contains("Connection: close\r\n"), "{}", resp);
+        assert!(resp.contains("Proxy-Authenticate: Basic realm=\"corp\"\r\n"));
+        assert!(!resp.contains("Bearer"));
+
+        let resp = challenge(HttpAuthScheme::Both, vec!["token".into()]).await;
+        assert!(resp.contains("Proxy-Authenticate: Basic realm=\"corp\"\r\n"));
+        assert!(resp.contains("Proxy-Authenticate: Bearer realm=\"corp\"\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_http_max_auth_attempts() {
+        let inbound = HttpInbound::init(HttpInboundOption {
+            auth: vec![HttpAuthOption {
+                user: "user".into(),
+                pass: "pass".into(),
+            }],
+            max_auth_attempts: Some(3),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let connect = |auth: &str| {
+            format!(
+                "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\
+                 Proxy-Authorization: Basic {}\r\n\r\n",
+                BASE64_STANDARD.encode(auth)
+            )
+        };
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        for auth in ["user:bad1", "user:bad2", "user:pass"] {
+            client.write_all(connect(auth).as_bytes()).await.unwrap();
+        }
+        let (_, p) = inbound.handshake(server).await.unwrap();
+        assert_eq!(p.dest.port, 443);
+
+        let mut resp = vec![0u8; 4096];
+        let n = client.read(&mut resp).await.unwrap();
+        let resp = String::from_utf8_lossy(&resp[..n]);
+        assert_eq!(resp.matches("HTTP/1.1 407").count(), 2, "{}", resp);
+        assert_eq!(resp.matches("Content-Length: 0\r\n").count(), 2, "{}", resp);
+        assert!(!resp.contains("Connection: close"), "{}", resp);
+
+        // the last allowed failure closes the connection
+        let (mut client, server) = tokio::io::duplex(4096);
+        for _ in 0..3 {
+            client
+                .write_all(connect("user:bad").as_bytes())
+                .await
+                .unwrap();
+        }
+        let err = inbound.handshake(server).await.unwrap_err();
+        assert!(matches!(
+            err,
+            InboundError::Handshake(ProtocolError::Http(HttpError::TooManyAuthAttempts))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_http_upgrade_tunnel() {
+        let inbound = HttpInbound::init(HttpInboundOption::default()).unwrap();
+
+        let data = b"GET /chat HTTP/1.1\r\nHost: example.com:8080\r\nUpgrade: websocket\r\n\
+            Connection: keep-alive, Upgrade\r\nProxy-Connection: keep-alive\r\n\r\nframe"
+            .to_vec();
+        let (mut stream, pac) = inbound.handshake(Cursor::new(data)).await.unwrap();
+        assert!(matches!(stream, HttpInboundStream::Upgrade(_)));
+        assert_eq!(pac.dest, ServiceAddress::new("example.com".into(), 8080));
+
+        let mut forwarded = String::new();
+        stream.read_to_string(&mut forwarded).await.unwrap();
+        assert!(forwarded.starts_with("GET /chat HTTP/1.1\r\n"));
+        assert!(forwarded.contains("Upgrade: websocket\r\n"));
+        assert!(forwarded.contains("Connection: keep-alive, Upgrade\r\n"));
+        assert!(!forwarded.contains("Proxy-Connection"));
+        assert!(forwarded.ends_with("\r\n\r\nframe"));
+
+        // without Upgrade it is still a plain forward
+        let data = b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\
+            Connection: Upgrade\r\n\r\n"
+            .to_vec();
+        let (stream, _) = inbound.handshake(Cursor::new(data)).await.unwrap();
+        assert!(matches!(stream, HttpInboundStream::Plain(_)));
+    }
+
+    #[tokio::test]
+    async fn test_http_plain_origin_form() {
+        let inbound = HttpInbound::init(HttpInboundOption::default()).unwrap();
+
+        let data = b"GET http://example.com:8080/index.html?q=1 HTTP/1.1\r\n\
+            Proxy-Connection: keep-alive\r\n\r\n"
+            .to_vec();
+        let (mut stream, pac) = inbound.handshake(Cursor::new(data)).await.unwrap();
+        assert_eq!(pac.dest, ServiceAddress::new("example.com".into(), 8080));
+
+        let mut forwarded = String::new();
+        stream.read_to_string(&mut forwarded).await.unwrap();
+        assert_eq!(
+            forwarded,
+            "GET /index.html?q=1 HTTP/1.1\r\nHost: example.com:8080\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_bad_request_reply() {
+        let inbound = HttpInbound::init(HttpInboundOption::default()).unwrap();
+
+        // neither an absolute uri nor a Host to forward to
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert!(inbound.handshake(server).await.is_err());
+
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert_eq!(
+            resp,
+            "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_plain_keep_alive() {
+        let inbound = HttpInbound::init(HttpInboundOption::default()).unwrap();
+
+        let cases: [(&[u8], bool, &str); 4] = [
+            (
+                b"GET http://example.com/ HTTP/1.0\r\n\r\n",
+                false,
+                "GET / HTTP/1.0\r\nHost: example.com\r\n\r\n",
+            ),
+            (
+                b"GET http://example.com/ HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+                true,
+                "GET / HTTP/1.0\r\nConnection: keep-alive\r\nHost: example.com\r\n\r\n",
+            ),
+            (
+                b"GET http://example.com/ HTTP/1.1\r\nConnection: close\r\n\r\n",
+                false,
+                "GET / HTTP/1.1\r\nConnection: close\r\nHost: example.com\r\n\r\n",
+            ),
+            (
+                b"GET http://example.com/ HTTP/1.1\r\n\r\n",
+                true,
+                "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            ),
+        ];
+
+        for (data, keep_alive, expected) in cases {
+            let (mut stream, _) = inbound.handshake(Cursor::new(data.to_vec())).await.unwrap();
+            assert!(matches!(stream, HttpInboundStream::Plain(_)));
+            assert_eq!(stream.keep_alive(), keep_alive, "{}", expected);
+
+            let mut forwarded = String::new();
+            stream.read_to_string(&mut forwarded).await.unwrap();
+            assert_eq!(forwarded, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_plain_repeated_connection() {
+        let data = b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\
+            Connection: X-Hop-A\r\nX-Hop-A: 1\r\nConnection: X-Hop-B\r\nX-Hop-B: 2\r\n\
+            Accept: text/html\r\nAccept: */*\r\n\r\n"
+            .to_vec();
+
+        let forward = |strict_headers| {
+            let data = data.clone();
+            async move {
+                let inbound = HttpInbound::init(HttpInboundOption {
+                    strict_headers,
+                    ..Default::default()
+                })
+                .unwrap();
+                let (mut stream, _) = inbound.handshake(Cursor::new(data)).await.unwrap();
+                let mut forwarded = String::new();
+                stream.read_to_string(&mut forwarded).await.unwrap();
+                forwarded
+            }
+        };
+
+        assert_eq!(
+            forward(false).await,
+            "GET / HTTP/1.1\r\nHost: example.com\r\nAccept: text/html, */*\r\n\r\n"
+        );
+        assert_eq!(
+            forward(true).await,
+            "GET / HTTP/1.1\r\nHost: example.com\r\nAccept: text/html\r\nAccept: */*\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_fake_response() {
+        let inbound = HttpInbound::init(HttpInboundOption {
+            auth: vec![HttpAuthOption {
+                user: "user".into(),
+                pass: "pass".into(),
+            }],
+            fake_response: Some(HttpFakeResponse {
+                status: 404,
+                content_type: "text/html".into(),
+                body: "<h1>Not Found</h1>".into(),
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+
+        for request in [
+            &b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"[..],
+        ] {
+            let (mut client, server) = tokio::io::duplex(1024);
+            client.write_all(request).await.unwrap();
+            assert!(inbound.handshake(server).await.is_err());
+
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", resp);
+            assert!(resp.contains("Content-Length: 18\r\n"));
+            assert!(!resp.contains("Proxy-Authenticate"));
+            assert!(resp.ends_with("\r\n\r\n<h1>Not Found</h1>"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_local_options() {
+        let inbound = HttpInbound::init(HttpInboundOption {
+            local_hosts: vec!["proxy.lan:8080".into()],
+            ..Default::default()
+        })
+        .unwrap();
+
+        for request in [
+            &b"OPTIONS * HTTP/1.1\r\nHost: proxy.lan:8080\r\n\r\n"[..],
+            &b"OPTIONS http://PROXY.lan:8080/ HTTP/1.1\r\n\r\n"[..],
+        ] {
+            let (mut client, server) = tokio::io::duplex(1024);
+            client.write_all(request).await.unwrap();
+            let (mut stream, accepted, head) = inbound.accept(server).await.unwrap();
+            assert!(stream.is_local());
+            assert!(matches!(accepted, HttpAccept::Local));
+            assert_eq!(head.method, Method::OPTIONS);
+
+            // nothing goes upstream
+            let mut upstream = vec![];
+            stream.read_to_end(&mut upstream).await.unwrap();
+            assert!(upstream.is_empty());
+            drop(stream);
+
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+            assert!(
+                resp.contains(&format!("Allow: {}\r\n", LOCAL_ALLOW)),
+                "{}",
+                resp
+            );
+            assert!(resp.ends_with("Content-Length: 0\r\n\r\n"), "{}", resp);
+        }
+
+        // the trait handshake has no destination to return
+        let err = inbound
+            .handshake(Cursor::new(
+                b"OPTIONS * HTTP/1.1\r\nHost: proxy.lan:8080\r\n\r\n".to_vec(),
+            ))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            InboundError::Handshake(ProtocolError::Http(HttpError::LocalRequest))
+        ));
+
+        // other hosts and ports are forwarded
+        for request in [
+            &b"OPTIONS http://example.com/ HTTP/1.1\r\n\r\n"[..],
+            &b"OPTIONS http://proxy.lan:8081/ HTTP/1.1\r\n\r\n"[..],
+        ] {
+            let (stream, pac) = inbound
+                .handshake(Cursor::new(request.to_vec()))
+                .await
+                .unwrap();
+            assert!(!stream.is_local());
+            assert_ne!(pac.dest.port, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_local_trace() {
+        let inbound = HttpInbound::init(HttpInboundOption {
+            auth: vec![HttpAuthOption {
+                user: "user".into(),
+                pass: "pass".into(),
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        client
+            .write_all(
+                format!(
+                    "TRACE / HTTP/1.1\r\nHost: proxy.lan\r\nX-Test: 1\r\n\
+                     Proxy-Authorization: Basic {}\r\n\r\n",
+                    BASE64_STANDARD.encode(b"user:pass")
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let (stream, accepted, head) = inbound.accept(server).await.unwrap();
+        assert!(stream.is_local());
+        assert!(matches!(accepted, HttpAccept::Local));
+        assert_eq!(head.method, Method::TRACE);
+        drop(stream);
+
+        let echo = "TRACE / HTTP/1.1\r\nHost: proxy.lan\r\nX-Test: 1\r\n\r\n";
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        assert!(resp.contains("Content-Type: message/http\r\n"), "{}", resp);
+        assert!(
+            resp.contains(&format!("Content-Length: {}\r\n", echo.len())),
+            "{}",
+            resp
+        );
+        assert!(resp.ends_with(&format!("\r\n\r\n{}", echo)), "{}", resp);
+    }
+
+    #[tokio::test]
+    async fn test_http_strict_domain() {
+        let host = format!("{}.com", "a".repeat(300));
+        let request = format!("CONNECT {}:443 HTTP/1.1\r\nHost: {}\r\n\r\n", host, host);
+
+        for strict_domain in [false, true] {
+            let inbound = HttpInbound::init(HttpInboundOption {
+                strict_domain,
+                ..Default::default()
+            })
+            .unwrap();
+            let res = inbound
+                .handshake(Cursor::new(request.clone().into_bytes()))
+                .await;
+            assert_eq!(res.is_err(), strict_domain);
+            if let Ok((_, pac)) = res {
+                assert_eq!(pac.dest.addr, Address::Domain(host.clone()));
+            }
+        }
+    }
 }
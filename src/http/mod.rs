@@ -6,19 +6,27 @@ use thiserror::Error;
 pub mod option;
 pub use option::{HttpInboundOption, HttpOutboundOption};
 
+pub mod body;
+pub use body::BodyReader;
+
 pub mod inbound;
-pub use inbound::{HttpInbound, HttpInboundStream};
+pub use inbound::{HttpAccept, HttpInbound, HttpInboundStream, HttpRequestHead};
 
 pub mod outbound;
 pub use outbound::HttpOutbound;
 
+pub mod pool;
+pub use pool::{HttpPool, PooledStream};
+
 pub mod protocol;
 pub use protocol::{
-    format_request, format_response, read_request, read_response, write_request, write_response,
+    format_request, format_request_with, format_response, merge_list_headers, read_request,
+    read_request_with, read_response, write_request, write_request_with, write_response,
+    RawHeaders, RequestTarget,
 };
 
-const MAX_HEADER: usize = 64;
-const MAX_HEADER_SIZE: usize = 65535;
+pub(crate) const MAX_HEADER: usize = 64;
+pub(crate) const MAX_HEADER_SIZE: usize = 65535;
 
 #[derive(Debug, Error)]
 pub enum HttpError {
@@ -48,6 +56,12 @@ pub enum HttpError {
     InvalidStatusCode(StatusCode),
     #[error("header too large")]
     HeaderTooLarge,
+    #[error("too many authentication attempts")]
+    TooManyAuthAttempts,
+    #[error("unsupported transfer encoding")]
+    UnsupportedTransferEncoding,
+    #[error("request answered by the proxy")]
+    LocalRequest,
 }
 
 #[derive(Debug, Clone)]
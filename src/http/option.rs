@@ -1,11 +1,97 @@
 //! Http Proxy Option
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct HttpInboundOption {
     #[serde(default)]
     pub auth: Vec<HttpAuthOption>,
+    /// Timeout in milliseconds for writing handshake responses.
+    #[serde(default)]
+    pub reply_timeout: Option<u64>,
+    /// Accepted `Proxy-Authorization` schemes.
+    #[serde(default)]
+    pub scheme: HttpAuthScheme,
+    /// Tokens accepted with the `Bearer` scheme.
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    /// Realm sent in the `Proxy-Authenticate` challenge, `"proxy"` if unset.
+    #[serde(default)]
+    pub realm: Option<String>,
+    /// Served instead of the 407/400 to requests without valid credentials
+    /// or that are not proxy requests, so the port looks like a web server.
+    #[serde(default)]
+    pub fake_response: Option<HttpFakeResponse>,
+    /// Read buffer size in bytes for the buffered connection, 8 KiB if unset.
+    #[serde(default)]
+    pub read_buffer: Option<usize>,
+    /// Failed authentications allowed on one connection, each one after the
+    /// first is answered with a new prompt. Unset fails on the first.
+    #[serde(default)]
+    pub max_auth_attempts: Option<u32>,
+    /// Forward repeated headers line by line as received. By default
+    /// repeated list headers such as `Connection` are joined into one.
+    #[serde(default)]
+    pub strict_headers: bool,
+    /// Authorities naming the proxy itself, e.g. `proxy.lan:8080`, or a
+    /// host alone for any port. `OPTIONS` and `TRACE` requests to them are
+    /// answered by the proxy, as are `OPTIONS *` and origin-form ones.
+    #[serde(default)]
+    pub local_hosts: Vec<String>,
+    /// Refuse target hosts that fail `check_domain`, e.g. longer than 253
+    /// bytes.
+    #[serde(default)]
+    pub strict_domain: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpFakeResponse {
+    #[serde(default = "default_fake_status")]
+    pub status: u16,
+    #[serde(default = "default_fake_content_type")]
+    pub content_type: String,
+    #[serde(default)]
+    pub body: String,
+}
+
+impl Default for HttpFakeResponse {
+    fn default() -> Self {
+        Self {
+            status: default_fake_status(),
+            content_type: default_fake_content_type(),
+            body: String::new(),
+        }
+    }
+}
+
+fn default_fake_status() -> u16 {
+    404
+}
+
+fn default_fake_content_type() -> String {
+    "text/html".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpAuthScheme {
+    /// `Basic` with the users in `auth`.
+    #[default]
+    Basic,
+    /// `Bearer` with the tokens in `tokens`.
+    Bearer,
+    /// Either of them.
+    Both,
+}
+
+impl HttpAuthScheme {
+    pub fn allows_basic(&self) -> bool {
+        matches!(self, Self::Basic | Self::Both)
+    }
+
+    pub fn allows_bearer(&self) -> bool {
+        matches!(self, Self::Bearer | Self::Both)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,6 +102,33 @@ pub struct HttpAuthOption {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HttpOutboundOption {
+    /// Credentials for the parent proxy, one or a list used in turn by each
+    /// handshake.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub auth: Vec<HttpAuthOption>,
+    /// Idle connections kept per parent proxy for plain forwarding, 0
+    /// disables the pool. Only `HttpOutbound::forward` pools connections,
+    /// the service handshake tunnels over the stream it is given.
     #[serde(default)]
-    pub auth: Option<HttpAuthOption>,
+    pub max_idle: usize,
+}
+
+/// A single value, as `auth` used to take, or a list of them.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match Option::<OneOrMany<T>>::deserialize(deserializer)? {
+        Some(OneOrMany::One(v)) => vec![v],
+        Some(OneOrMany::Many(v)) => v,
+        None => vec![],
+    })
 }
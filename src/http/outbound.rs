@@ -1,31 +1,107 @@
 //! Http Proxy oubound
 
-use base64::{prelude::BASE64_URL_SAFE, Engine};
-use http::{Method, Request, Uri};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use http::{header::PROXY_AUTHORIZATION, HeaderValue, Method, Request, Uri};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
 
 use crate::{
-    address::NetworkType, error::ProtocolError, OutboundError, OutboundPacket, OutboundResult,
-    OutboundServiceTrait,
+    address::NetworkType, direct::DirectOutbound, error::ProtocolError, OutboundError,
+    OutboundPacket, OutboundResult, OutboundServiceTrait,
 };
 
 use super::{
-    read_response, write_request, HttpError, HttpOutboundOption, MAX_HEADER, MAX_HEADER_SIZE,
+    read_response, write_request, write_request_with, BodyReader, HttpError, HttpOutboundOption,
+    HttpPool, PooledStream, RequestTarget, MAX_HEADER, MAX_HEADER_SIZE,
 };
 
 #[derive(Debug)]
 pub struct HttpOutbound {
-    auth: Option<String>,
+    /// `Proxy-Authorization` values, used round robin.
+    auth: Vec<String>,
+    next_auth: AtomicUsize,
+    pool: Arc<HttpPool>,
 }
 
 impl HttpOutbound {
     pub fn init(option: HttpOutboundOption) -> OutboundResult<Self> {
-        let auth = option.auth.map(|a| {
-            let s = a.user + ":" + &a.pass;
-            format!("Basic {}", BASE64_URL_SAFE.encode(s))
-        });
+        let auth = option
+            .auth
+            .into_iter()
+            .map(|a| {
+                let s = a.user + ":" + &a.pass;
+                format!("Basic {}", BASE64_STANDARD.encode(s))
+            })
+            .collect();
+
+        Ok(Self {
+            auth,
+            next_auth: AtomicUsize::new(0),
+            pool: Arc::new(HttpPool::new(option.max_idle)),
+        })
+    }
+
+    /// The credential for the next request, `None` without auth.
+    fn next_auth(&self) -> Option<&str> {
+        if self.auth.is_empty() {
+            return None;
+        }
+        let i = self.next_auth.fetch_add(1, Ordering::Relaxed) % self.auth.len();
+        Some(&self.auth[i])
+    }
+
+    pub fn pool(&self) -> &Arc<HttpPool> {
+        &self.pool
+    }
+
+    /// Connect to parent proxies with `dialer`, e.g. to use its bound
+    /// device. Idle connections opened before are dropped.
+    pub fn with_dialer(mut self, dialer: DirectOutbound) -> Self {
+        self.pool = Arc::new(HttpPool::new(self.pool.max_idle()).with_dialer(dialer));
+        self
+    }
+
+    /// Send the plain (non-`CONNECT`) request `req` to the parent proxy at
+    /// `proxy` in absolute form, over an idle connection from the pool when
+    /// there is one.
+    ///
+    /// The response is read from the returned stream and its body through a
+    /// `BodyReader`, which `release` hands back to the pool.
+    pub async fn forward(
+        &self,
+        proxy: SocketAddr,
+        mut req: Request<()>,
+    ) -> OutboundResult<PooledStream> {
+        if req.method() == Method::CONNECT {
+            return Err(ProtocolError::Http(HttpError::InvalidRequest).into());
+        }
+
+        if let Some(auth) = self.next_auth() {
+            let auth = HeaderValue::from_str(auth)
+                .map_err(|e| ProtocolError::Http(http::Error::from(e).into()))?;
+            req.headers_mut().insert(PROXY_AUTHORIZATION, auth);
+        }
+
+        let mut stream = self.pool.get(proxy).await?;
+        write_request_with(&req, RequestTarget::Absolute, &mut stream)
+            .await
+            .map_err(ProtocolError::Http)?;
+        stream.flush().await?;
+
+        Ok(stream)
+    }
 
-        Ok(Self { auth })
+    /// Return the stream from `forward` under the response body to the
+    /// pool, see `HttpPool::put`.
+    pub fn release(&self, body: BodyReader<PooledStream>) {
+        self.pool.put(body);
     }
 }
 
@@ -33,6 +109,8 @@ impl<S> OutboundServiceTrait<S> for HttpOutbound
 where
     S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
 {
+    /// Buffered `stream`, its `ProxyStream::peer_addr` is the upstream
+    /// proxy when `stream` is a socket.
     type Stream = BufStream<S>;
 
     async fn handshake(&self, stream: S, packet: OutboundPacket) -> OutboundResult<Self::Stream> {
@@ -53,7 +131,7 @@ where
             .header("Host", host)
             .header("Proxy-Connection", "Keep-Alive");
 
-        if let Some(ref auth) = self.auth {
+        if let Some(auth) = self.next_auth() {
             builder = builder.header("Proxy-Authorization", auth);
         }
 
@@ -61,14 +139,14 @@ where
             .body(())
             .map_err(|e| ProtocolError::Http(e.into()))?;
 
-        let _ = write_request(&req, &mut stream)
+        write_request(&req, &mut stream)
             .await
-            .map_err(|e| ProtocolError::Http(e));
-        let _ = stream.flush().await?;
+            .map_err(ProtocolError::Http)?;
+        stream.flush().await?;
 
         let resp = read_response(&mut stream, MAX_HEADER, MAX_HEADER_SIZE)
             .await
-            .map_err(|e| ProtocolError::Http(e))?;
+            .map_err(ProtocolError::Http)?;
 
         if !resp.status().is_success() {
             return Err(ProtocolError::Http(HttpError::InvalidStatusCode(resp.status())).into());
@@ -77,3 +155,338 @@ where
         Ok(stream)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{address::NetworkType, http::option::HttpAuthOption, ProxyStream, ServiceAddress};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_http_outbound_peer_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufStream::new(stream);
+            let mut line = String::new();
+            while line != "\r\n" {
+                line.clear();
+                stream.read_line(&mut line).await.unwrap();
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            stream.flush().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let out = HttpOutbound::init(HttpOutboundOption {
+            auth: vec![],
+            max_idle: 0,
+        })
+        .unwrap();
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("example.com".into(), 443),
+        };
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        let stream = out.handshake(stream, packet).await.unwrap();
+        assert_eq!(stream.peer_addr(), Some(proxy));
+    }
+
+    #[tokio::test]
+    async fn test_http_outbound_ipv6_host() {
+        let (client, server) = tokio::io::duplex(1024);
+
+        let proxy = tokio::spawn(async move {
+            let mut server = BufStream::new(server);
+            let mut head = String::new();
+            let mut line = String::new();
+            while line != "\r\n" {
+                line.clear();
+                server.read_line(&mut line).await.unwrap();
+                head.push_str(&line);
+            }
+            server
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            server.flush().await.unwrap();
+            head
+        });
+
+        let out = HttpOutbound::init(HttpOutboundOption {
+            auth: vec![],
+            max_idle: 0,
+        })
+        .unwrap();
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("2001:db8::1".into(), 443),
+        };
+        let _stream = out.handshake(client, packet).await.unwrap();
+
+        let head = proxy.await.unwrap();
+        assert!(
+            head.starts_with("CONNECT [2001:db8::1]:443 HTTP/1.1\r\n"),
+            "{}",
+            head
+        );
+        assert!(head.contains("Host: [2001:db8::1]:443\r\n"), "{}", head);
+    }
+
+    /// Parent proxy answering each request with a two byte body, closing
+    /// a connection after `per_conn` requests. Every closed connection is
+    /// reported on `closed`. Requests for `/close` are answered with
+    /// `Connection: close`, those for `/extra` with bytes after the body.
+    async fn keep_alive_proxy(
+        listener: TcpListener,
+        per_conn: usize,
+        closed: tokio::sync::mpsc::UnboundedSender<()>,
+    ) {
+        while let Ok((stream, _)) = listener.accept().await {
+            let closed = closed.clone();
+            tokio::spawn(async move {
+                let mut stream = BufStream::new(stream);
+                'conn: for _ in 0..per_conn {
+                    let mut head = String::new();
+                    let mut line = String::new();
+                    while line != "\r\n" {
+                        line.clear();
+                        if stream.read_line(&mut line).await.unwrap() == 0 {
+                            break 'conn;
+                        }
+                        head.push_str(&line);
+                    }
+                    let resp: &[u8] = if head.contains("/close ") {
+                        b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok"
+                    } else if head.contains("/extra ") {
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nokHTTP/1.1"
+                    } else {
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+                    };
+                    stream.write_all(resp).await.unwrap();
+                    stream.flush().await.unwrap();
+                }
+                drop(stream);
+                let _ = closed.send(());
+            });
+        }
+    }
+
+    /// Response body of a request for `path`, left unread.
+    async fn request(
+        out: &HttpOutbound,
+        proxy: SocketAddr,
+        path: &str,
+    ) -> BodyReader<PooledStream> {
+        let req = Request::get(format!("http://example.com{}", path))
+            .header("Host", "example.com")
+            .body(())
+            .unwrap();
+        let mut stream = out.forward(proxy, req).await.unwrap();
+        let resp = read_response(&mut stream, MAX_HEADER, MAX_HEADER_SIZE)
+            .await
+            .unwrap();
+        assert!(resp.status().is_success());
+        BodyReader::from_response(stream, &resp).unwrap()
+    }
+
+    async fn get_path(
+        out: &HttpOutbound,
+        proxy: SocketAddr,
+        path: &str,
+    ) -> BodyReader<PooledStream> {
+        let mut body = request(out, proxy, path).await;
+        let mut data = vec![];
+        body.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"ok");
+        body
+    }
+
+    async fn get(out: &HttpOutbound, proxy: SocketAddr) -> BodyReader<PooledStream> {
+        get_path(out, proxy, "/").await
+    }
+
+    #[tokio::test]
+    async fn test_http_outbound_pool_reuse() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let (tx, mut closed) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(keep_alive_proxy(listener, usize::MAX, tx));
+
+        let out = HttpOutbound::init(HttpOutboundOption {
+            auth: vec![],
+            max_idle: 1,
+        })
+        .unwrap();
+
+        let stream = get(&out, proxy).await;
+        assert!(!stream.get_ref().is_reused());
+        out.release(stream);
+        assert_eq!(out.pool().idle(proxy), 1);
+
+        for _ in 0..2 {
+            let stream = get(&out, proxy).await;
+            assert!(stream.get_ref().is_reused());
+            assert_eq!(out.pool().idle(proxy), 0);
+            out.release(stream);
+        }
+
+        // a second connection does not fit into the pool
+        let s1 = get(&out, proxy).await;
+        let s2 = get(&out, proxy).await;
+        assert!(!s2.get_ref().is_reused());
+        out.release(s1);
+        out.release(s2);
+        assert_eq!(out.pool().idle(proxy), 1);
+
+        // the connection left out is closed
+        closed.recv().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_outbound_pool_evict() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let (tx, mut closed) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(keep_alive_proxy(listener, 1, tx));
+
+        let out = HttpOutbound::init(HttpOutboundOption {
+            auth: vec![],
+            max_idle: 1,
+        })
+        .unwrap();
+
+        let stream = get(&out, proxy).await;
+        out.release(stream);
+        assert_eq!(out.pool().idle(proxy), 1);
+
+        // the proxy closed the idle connection, it is dropped on reuse
+        closed.recv().await.unwrap();
+        let stream = get(&out, proxy).await;
+        assert!(!stream.get_ref().is_reused());
+        assert_eq!(out.pool().idle(proxy), 0);
+    }
+
+    #[tokio::test]
+    async fn test_http_outbound_pool_not_reusable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let (tx, mut closed) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(keep_alive_proxy(listener, usize::MAX, tx));
+
+        let out = HttpOutbound::init(HttpOutboundOption {
+            auth: vec![],
+            max_idle: 4,
+        })
+        .unwrap();
+
+        // the response asks to close, bytes past the body were read ahead,
+        // the body was not read
+        out.release(get_path(&out, proxy, "/close").await);
+        out.release(get_path(&out, proxy, "/extra").await);
+        out.release(request(&out, proxy, "/").await);
+        assert_eq!(out.pool().idle(proxy), 0);
+
+        // a write was left in the buffer
+        let req = Request::get("http://example.com/")
+            .header("Host", "example.com")
+            .body(())
+            .unwrap();
+        let mut stream = out.forward(proxy, req).await.unwrap();
+        let resp = read_response(&mut stream, MAX_HEADER, MAX_HEADER_SIZE)
+            .await
+            .unwrap();
+        stream.write_all(b"GET").await.unwrap();
+        let mut body = BodyReader::from_response(stream, &resp).unwrap();
+        body.read_to_end(&mut vec![]).await.unwrap();
+        out.release(body);
+        assert_eq!(out.pool().idle(proxy), 0);
+        for _ in 0..4 {
+            closed.recv().await.unwrap();
+        }
+
+        // a disabled pool keeps nothing
+        let out = HttpOutbound::init(HttpOutboundOption {
+            auth: vec![],
+            max_idle: 0,
+        })
+        .unwrap();
+        out.release(get(&out, proxy).await);
+        assert_eq!(out.pool().idle(proxy), 0);
+        closed.recv().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_outbound_auth_rotation() {
+        let out = HttpOutbound::init(HttpOutboundOption {
+            auth: vec![
+                HttpAuthOption {
+                    user: "a".into(),
+                    pass: "1".into(),
+                },
+                HttpAuthOption {
+                    user: "b".into(),
+                    pass: "2".into(),
+                },
+            ],
+            max_idle: 0,
+        })
+        .unwrap();
+
+        let mut seen = vec![];
+        for _ in 0..3 {
+            let (client, server) = tokio::io::duplex(1024);
+            let proxy = tokio::spawn(async move {
+                let mut server = BufStream::new(server);
+                let mut auth = None;
+                let mut line = String::new();
+                while line != "\r\n" {
+                    line.clear();
+                    server.read_line(&mut line).await.unwrap();
+                    if let Some(value) = line.strip_prefix("Proxy-Authorization: ") {
+                        auth = Some(value.trim_end().to_string());
+                    }
+                }
+                server
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await
+                    .unwrap();
+                server.flush().await.unwrap();
+                auth
+            });
+
+            let packet = OutboundPacket {
+                typ: NetworkType::Tcp,
+                dest: ServiceAddress::new("example.com".into(), 443),
+            };
+            let _stream = out.handshake(client, packet).await.unwrap();
+            seen.push(proxy.await.unwrap().unwrap());
+        }
+
+        let basic = |cred: &str| format!("Basic {}", BASE64_STANDARD.encode(cred));
+        assert_eq!(seen, [basic("a:1"), basic("b:2"), basic("a:1")]);
+    }
+
+    #[test]
+    fn test_http_outbound_basic_standard_alphabet() {
+        let out = HttpOutbound::init(HttpOutboundOption {
+            auth: vec![HttpAuthOption {
+                user: "user".into(),
+                pass: "?>>".into(),
+            }],
+            max_idle: 0,
+        })
+        .unwrap();
+        assert_eq!(out.next_auth(), Some("Basic dXNlcjo/Pj4="));
+    }
+}
@@ -0,0 +1,185 @@
+//! Http Proxy upstream connection pool
+
+use std::{
+    collections::HashMap,
+    io,
+    mem::MaybeUninit,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use socket2::SockRef;
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader, BufWriter, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::{direct::DirectOutbound, ProxyStream};
+
+use super::BodyReader;
+
+/// Buffered connection, the read buffer is reachable to see whether
+/// anything is left unread.
+type Conn = BufReader<BufWriter<TcpStream>>;
+
+/// Idle keep-alive connections to parent proxies, keyed by the proxy
+/// address.
+///
+/// Only plain (non-`CONNECT`) forwarding can share a connection, a tunnel
+/// belongs to its client until closed.
+#[derive(Debug, Default)]
+pub struct HttpPool {
+    idle: Mutex<HashMap<SocketAddr, Vec<Conn>>>,
+    max_idle: usize,
+    /// Opens new connections, with its bound device.
+    dialer: DirectOutbound,
+}
+
+impl HttpPool {
+    /// Keep at most `max_idle` idle connections per proxy, 0 disables the
+    /// pool.
+    pub fn new(max_idle: usize) -> Self {
+        Self {
+            idle: Mutex::new(HashMap::new()),
+            max_idle,
+            dialer: DirectOutbound::new(),
+        }
+    }
+
+    /// Open new connections with `dialer` instead of a plain connect.
+    pub fn with_dialer(mut self, dialer: DirectOutbound) -> Self {
+        self.dialer = dialer;
+        self
+    }
+
+    pub fn max_idle(&self) -> usize {
+        self.max_idle
+    }
+
+    /// Number of idle connections to `proxy`.
+    pub fn idle(&self, proxy: SocketAddr) -> usize {
+        let idle = self.idle.lock().unwrap();
+        idle.get(&proxy).map_or(0, |conns| conns.len())
+    }
+
+    /// An idle connection to `proxy` that is still open, or a new one.
+    /// Closed connections found on the way are dropped.
+    pub async fn get(&self, proxy: SocketAddr) -> io::Result<PooledStream> {
+        while let Some(stream) = self.take(proxy) {
+            if is_alive(stream.get_ref().get_ref()) {
+                return Ok(PooledStream {
+                    stream,
+                    proxy,
+                    reused: true,
+                });
+            }
+        }
+
+        let stream = self.dialer.connect_tcp(&[proxy]).await?;
+        Ok(PooledStream {
+            stream: BufReader::new(BufWriter::new(stream)),
+            proxy,
+            reused: false,
+        })
+    }
+
+    /// Return the stream under `body` for reuse. It is closed instead
+    /// unless the body was read in full, the response let the connection
+    /// stay open, nothing past the body was read ahead and nothing written
+    /// is still waiting to be flushed.
+    pub fn put(&self, body: BodyReader<PooledStream>) {
+        if self.max_idle == 0 || !body.is_reusable() {
+            return;
+        }
+        let stream = body.into_inner();
+        if !stream.stream.buffer().is_empty() || !stream.stream.get_ref().buffer().is_empty() {
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.entry(stream.proxy).or_default();
+        if conns.len() < self.max_idle {
+            conns.push(stream.stream);
+        }
+    }
+
+    fn take(&self, proxy: SocketAddr) -> Option<Conn> {
+        self.idle.lock().unwrap().get_mut(&proxy)?.pop()
+    }
+}
+
+/// An idle connection is alive when reading would block: end of stream
+/// means the proxy closed it, and data before a request means the
+/// connection is out of step.
+fn is_alive(stream: &TcpStream) -> bool {
+    let mut buf = [MaybeUninit::uninit()];
+    matches!(
+        SockRef::from(stream).peek(&mut buf),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+    )
+}
+
+/// Connection to a parent proxy taken from `HttpPool`.
+#[derive(Debug)]
+pub struct PooledStream {
+    stream: Conn,
+    proxy: SocketAddr,
+    reused: bool,
+}
+
+impl PooledStream {
+    pub fn proxy(&self) -> SocketAddr {
+        self.proxy
+    }
+
+    /// Whether the connection came from the pool rather than a new connect.
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+}
+
+impl ProxyStream for PooledStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.proxy)
+    }
+}
+
+impl AsyncRead for PooledStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for PooledStream {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().stream).poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().stream).consume(amt)
+    }
+}
+
+impl AsyncWrite for PooledStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
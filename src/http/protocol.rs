@@ -2,23 +2,134 @@
 
 use std::str::FromStr;
 
-use http::{Method, Request, Response, StatusCode, Uri, Version};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use http::{
+    header, HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode, Uri, Version,
+};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, Lines};
 
 use super::HttpError;
 
+/// Header lines of a parsed request as they appeared on the wire, stored in
+/// the request extensions so `format_request` can keep their order and casing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RawHeaders(pub Vec<(String, String)>);
+
+/// Form of the request-target written on the request line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RequestTarget {
+    /// The uri exactly as it is in the request.
+    #[default]
+    AsIs,
+    /// `/path?query`, for requests sent to the origin server.
+    Origin,
+    /// `http://host/path?query`, for requests sent to a parent proxy.
+    Absolute,
+    /// `host:port`, for `CONNECT`.
+    Authority,
+}
+
+impl RequestTarget {
+    /// The request-target of `req` in this form. Absolute and authority
+    /// form fall back to the `Host` header for origin-form uris.
+    pub fn format(&self, req: &Request<()>) -> Result<String, HttpError> {
+        let uri = req.uri();
+        let authority = || -> Result<String, HttpError> {
+            match uri.authority() {
+                Some(authority) => Ok(authority.to_string()),
+                None => req
+                    .headers()
+                    .get(http::header::HOST)
+                    .and_then(|h| h.to_str().ok())
+                    .filter(|h| !h.is_empty())
+                    .map(|h| h.to_string())
+                    .ok_or(HttpError::InvalidHost),
+            }
+        };
+        let path = || {
+            uri.path_and_query()
+                .map(|p| p.as_str())
+                .filter(|p| !p.is_empty())
+                .unwrap_or("/")
+        };
+
+        match self {
+            Self::AsIs => Ok(uri.to_string()),
+            Self::Origin => Ok(path().to_string()),
+            Self::Absolute => {
+                let scheme = uri.scheme_str().unwrap_or("http");
+                Ok(format!("{}://{}{}", scheme, authority()?, path()))
+            }
+            Self::Authority => authority(),
+        }
+    }
+}
+
+/// Headers defined as comma-separated lists, repeated lines of these can be
+/// joined into one without changing their meaning.
+const LIST_HEADERS: &[HeaderName] = &[
+    header::ACCEPT,
+    header::ACCEPT_CHARSET,
+    header::ACCEPT_ENCODING,
+    header::ACCEPT_LANGUAGE,
+    header::CACHE_CONTROL,
+    header::CONNECTION,
+    header::PRAGMA,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+    header::VIA,
+];
+
+/// Most empty lines skipped before a request or status line.
+const MAX_LEADING_EMPTY_LINES: usize = 4;
+
+/// The first line that is not empty, some clients send stray CRLFs before
+/// the request line which RFC 9112 says to ignore.
+async fn start_line<R>(reader: &mut Lines<R>) -> Result<Option<String>, HttpError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    for _ in 0..=MAX_LEADING_EMPTY_LINES {
+        let Some(line) = reader.next_line().await? else {
+            return Ok(None);
+        };
+        if !line.trim().is_empty() {
+            return Ok(Some(line));
+        }
+    }
+
+    Err(HttpError::InvalidLine(String::new()))
+}
+
 pub async fn read_request<S>(
+    stream: &mut S,
+    max_headers: usize,
+    max_headers_size: usize,
+) -> Result<Request<()>, HttpError>
+where
+    S: AsyncReadExt + AsyncBufReadExt + Unpin,
+{
+    read_request_with(stream, max_headers, max_headers_size, false).await
+}
+
+/// Like `read_request`, with repeated list headers such as `Connection`
+/// joined into one comma-separated value when `merge_lists` is set.
+pub async fn read_request_with<S>(
     stream: &mut S,
     mut max_headers: usize,
     mut max_headers_size: usize,
+    merge_lists: bool,
 ) -> Result<Request<()>, HttpError>
 where
     S: AsyncReadExt + AsyncBufReadExt + Unpin,
 {
     let mut reader = stream.lines();
 
-    let header_str = reader.next_line().await?.ok_or(HttpError::InvalidRequest)?;
-    let method_uri_version: Vec<&str> = header_str.split(|c| c == ' ').collect();
+    let header_str = start_line(&mut reader)
+        .await?
+        .ok_or(HttpError::InvalidRequest)?;
+    let method_uri_version: Vec<&str> = header_str.split(' ').collect();
 
     if method_uri_version.len() != 3 {
         return Err(HttpError::InvalidLine(header_str));
@@ -35,6 +146,7 @@ where
     let version = parse_version(method_uri_version[2])?;
 
     let mut builder = Request::builder().method(method).uri(uri).version(version);
+    let mut raw = RawHeaders::default();
 
     loop {
         let line = reader.next_line().await?.ok_or(HttpError::InvalidRequest)?;
@@ -64,13 +176,64 @@ where
         max_headers_size -= hdr_size;
 
         builder = builder.header(key.trim(), value.trim());
+        raw.0
+            .push((key.trim().to_string(), value.trim().to_string()));
     }
 
-    let request = builder.body(())?;
+    let mut request = builder.extension(raw).body(())?;
+    if merge_lists {
+        merge_list_headers(&mut request);
+    }
 
     Ok(request)
 }
 
+/// Join repeated list headers of `req` into one comma-separated value,
+/// written where the first of them was.
+pub fn merge_list_headers(req: &mut Request<()>) {
+    for name in LIST_HEADERS {
+        let Some(merged) = merged_value(req.headers(), name) else {
+            continue;
+        };
+        req.headers_mut().insert(name, merged.clone());
+
+        if let (Some(raw), Ok(merged)) = (
+            req.extensions_mut().get_mut::<RawHeaders>(),
+            merged.to_str(),
+        ) {
+            let mut first = true;
+            raw.0.retain_mut(|(key, value)| {
+                if !key.eq_ignore_ascii_case(name.as_str()) {
+                    return true;
+                }
+                if first {
+                    first = false;
+                    *value = merged.to_string();
+                    return true;
+                }
+                false
+            });
+        }
+    }
+}
+
+fn merged_value(headers: &HeaderMap, name: &HeaderName) -> Option<HeaderValue> {
+    let mut values = headers.get_all(name).iter();
+    let first = values.next()?;
+    let mut merged = first.as_bytes().to_vec();
+    let mut repeated = false;
+    for value in values {
+        repeated = true;
+        merged.extend_from_slice(b", ");
+        merged.extend_from_slice(value.as_bytes());
+    }
+
+    if !repeated {
+        return None;
+    }
+    HeaderValue::from_bytes(&merged).ok()
+}
+
 pub async fn read_response<S>(
     stream: &mut S,
     mut max_headers: usize,
@@ -81,11 +244,10 @@ where
 {
     let mut reader = stream.lines();
 
-    let header_str = reader
-        .next_line()
+    let header_str = start_line(&mut reader)
         .await?
         .ok_or(HttpError::InvalidResponse)?;
-    let version_status: Vec<&str> = header_str.split(|c| c == ' ').collect();
+    let version_status: Vec<&str> = header_str.split(' ').collect();
 
     if version_status.len() < 3 {
         return Err(HttpError::InvalidLine(header_str));
@@ -138,7 +300,18 @@ pub async fn write_request<S>(req: &Request<()>, stream: &mut S) -> Result<(), H
 where
     S: AsyncWriteExt + Unpin,
 {
-    let buf = format_request(req)?;
+    write_request_with(req, RequestTarget::AsIs, stream).await
+}
+
+pub async fn write_request_with<S>(
+    req: &Request<()>,
+    target: RequestTarget,
+    stream: &mut S,
+) -> Result<(), HttpError>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    let buf = format_request_with(req, target)?;
     stream.write_all(&buf).await?;
 
     Ok(())
@@ -159,8 +332,13 @@ where
 }
 
 pub fn format_request(req: &Request<()>) -> Result<Vec<u8>, HttpError> {
+    format_request_with(req, RequestTarget::AsIs)
+}
+
+/// Like `format_request`, with the request-target written in `target` form.
+pub fn format_request_with(req: &Request<()>, target: RequestTarget) -> Result<Vec<u8>, HttpError> {
     let method = req.method().as_str();
-    let uri = req.uri().to_string();
+    let uri = target.format(req)?;
     let version = format_version(req.version())?;
     let estimated_len = method.len()
         + uri.len()
@@ -182,7 +360,37 @@ pub fn format_request(req: &Request<()>) -> Result<Vec<u8>, HttpError> {
     buf.extend_from_slice(version.as_bytes());
     buf.extend_from_slice(b"\r\n");
 
+    // raw lines still in the header map go out verbatim and in order, then
+    // whatever was added after parsing
+    let raw = req.extensions().get::<RawHeaders>();
+    let in_raw = |key: &str, value: &[u8]| {
+        raw.is_some_and(|raw| {
+            raw.0
+                .iter()
+                .any(|(k, v)| k.eq_ignore_ascii_case(key) && v.as_bytes() == value)
+        })
+    };
+
+    if let Some(raw) = raw {
+        for (key, value) in raw.0.iter() {
+            let present = req
+                .headers()
+                .get_all(key.as_str())
+                .iter()
+                .any(|v| v.as_bytes() == value.as_bytes());
+            if present {
+                buf.extend_from_slice(key.as_bytes());
+                buf.extend_from_slice(b": ");
+                buf.extend_from_slice(value.as_bytes());
+                buf.extend_from_slice(b"\r\n");
+            }
+        }
+    }
+
     for (key, value) in req.headers().iter() {
+        if in_raw(key.as_str(), value.as_bytes()) {
+            continue;
+        }
         buf.extend_from_slice(canonical_header_key(key.as_str()).as_bytes());
         buf.extend_from_slice(b": ");
         buf.extend_from_slice(value.as_bytes());
@@ -307,4 +515,119 @@ mod tests {
             .unwrap();
         assert_eq!(resp_data.into_inner(), data.into_inner());
     }
+
+    #[tokio::test]
+    async fn test_request_leading_empty_lines() {
+        let data = b"\r\nCONNECT bing.com:443 HTTP/1.1\r\nHost: bing.com:443\r\n\r\n".to_vec();
+        let req = read_request(&mut Cursor::new(data), 64, 65535)
+            .await
+            .unwrap();
+        assert_eq!(req.method(), Method::CONNECT);
+        assert_eq!(req.uri().authority().unwrap(), "bing.com:443");
+
+        let data = b"\r\n \r\nHTTP/1.1 200 OK\r\n\r\n".to_vec();
+        let resp = read_response(&mut Cursor::new(data), 64, 65535)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // only a few are skipped
+        let mut data = b"\r\n".repeat(MAX_LEADING_EMPTY_LINES + 1);
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        assert!(matches!(
+            read_request(&mut Cursor::new(data), 64, 65535).await,
+            Err(HttpError::InvalidLine(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_request_raw_headers() {
+        let data = b"GET http://example.com/ HTTP/1.1\r\nuser-agent: test\r\nHost: example.com\r\n\
+            accept: */*\r\nX-Custom-HEADER: 1\r\nproxy-connection: keep-alive\r\n\r\n"
+            .to_vec();
+        let mut req = read_request(&mut Cursor::new(data.clone()), 64, 65535)
+            .await
+            .unwrap();
+        assert_eq!(format_request(&req).unwrap(), data);
+
+        req.headers_mut().remove("Proxy-Connection");
+        req.headers_mut()
+            .insert("via", http::HeaderValue::from_static("1.1 proxy"));
+        assert_eq!(
+            format_request(&req).unwrap(),
+            b"GET http://example.com/ HTTP/1.1\r\nuser-agent: test\r\nHost: example.com\r\n\
+            accept: */*\r\nX-Custom-HEADER: 1\r\nVia: 1.1 proxy\r\n\r\n"
+        );
+
+        // without the raw record names are canonicalized
+        req.extensions_mut().clear();
+        assert!(format_request(&req)
+            .unwrap()
+            .starts_with(b"GET http://example.com/ HTTP/1.1\r\nUser-Agent: test\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_request_merge_list_headers() {
+        let data = b"GET / HTTP/1.1\r\nconnection: keep-alive\r\nHost: example.com\r\n\
+            Connection: Upgrade\r\nX-Custom: a\r\nX-Custom: b\r\n\r\n"
+            .to_vec();
+
+        let req = read_request_with(&mut Cursor::new(data.clone()), 64, 65535, true)
+            .await
+            .unwrap();
+        assert_eq!(req.headers().get_all("connection").iter().count(), 1);
+        assert_eq!(req.headers()["connection"], "keep-alive, Upgrade");
+        // only list headers are merged
+        assert_eq!(req.headers().get_all("x-custom").iter().count(), 2);
+        assert_eq!(
+            format_request(&req).unwrap(),
+            b"GET / HTTP/1.1\r\nconnection: keep-alive, Upgrade\r\nHost: example.com\r\n\
+            X-Custom: a\r\nX-Custom: b\r\n\r\n"
+        );
+
+        let req = read_request(&mut Cursor::new(data.clone()), 64, 65535)
+            .await
+            .unwrap();
+        assert_eq!(format_request(&req).unwrap(), data);
+    }
+
+    #[test]
+    fn test_request_target() {
+        let req = Request::get("http://example.com:8080/a/b?q=1")
+            .header("Host", "example.com:8080")
+            .body(())
+            .unwrap();
+
+        // to the origin server
+        assert_eq!(
+            format_request_with(&req, RequestTarget::Origin).unwrap(),
+            b"GET /a/b?q=1 HTTP/1.1\r\nHost: example.com:8080\r\n\r\n"
+        );
+        // to a parent proxy
+        assert_eq!(
+            format_request_with(&req, RequestTarget::Absolute).unwrap(),
+            b"GET http://example.com:8080/a/b?q=1 HTTP/1.1\r\nHost: example.com:8080\r\n\r\n"
+        );
+        assert_eq!(
+            RequestTarget::Authority.format(&req).unwrap(),
+            "example.com:8080"
+        );
+
+        // an origin-form request takes the proxy target from Host
+        let req = Request::get("/")
+            .header("Host", "example.com")
+            .body(())
+            .unwrap();
+        assert_eq!(RequestTarget::Origin.format(&req).unwrap(), "/");
+        assert_eq!(
+            RequestTarget::Absolute.format(&req).unwrap(),
+            "http://example.com/"
+        );
+
+        let req = Request::get("/").body(()).unwrap();
+        assert!(matches!(
+            RequestTarget::Absolute.format(&req),
+            Err(HttpError::InvalidHost)
+        ));
+    }
 }
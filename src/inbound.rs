@@ -8,10 +8,13 @@ use crate::{
     address::NetworkType,
     http::{HttpInbound, HttpInboundStream},
     mixed::{MixedInbound, MixedInboundStream},
+    multi::{MultiInbound, MultiInboundStream},
     option::InboundServiceOption,
+    shadowsocks::{ShadowsocksInbound, ShadowsocksStream},
     socks::SocksInbound,
+    trojan::TrojanInbound,
     vless::VlessInbound,
-    CachedStream, InboundResult, InboundServiceTrait, ServiceAddress,
+    CachedStream, InboundError, InboundResult, InboundServiceTrait, ServiceAddress,
 };
 
 #[derive(Debug, Clone)]
@@ -21,6 +24,16 @@ pub struct InboundPacket<'a> {
     pub detail: Cow<'a, str>,
 }
 
+/// Auth attempt limit from an inbound option.
+pub(crate) fn max_auth_attempts(max: Option<u32>) -> InboundResult<Option<u32>> {
+    match max {
+        Some(0) => Err(InboundError::Option(
+            "max_auth_attempts must be at least 1".to_string(),
+        )),
+        max => Ok(max),
+    }
+}
+
 macro_rules! inbound_service_enum {
     {
         $(#[$meta:meta])*
@@ -56,7 +69,7 @@ macro_rules! inbound_service_enum {
         {
             type Stream = InboundServiceStream<S>;
 
-            async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket)> {
+            async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
                 match self {
                     $(
                         $name::$id(svc) => {
@@ -170,7 +183,10 @@ inbound_service_enum! {
         Http(HttpInbound),
         Socks(SocksInbound),
         Miexd(MixedInbound),
+        Multi(MultiInbound),
         Vless(VlessInbound),
+        Shadowsocks(ShadowsocksInbound),
+        Trojan(TrojanInbound),
     }
 }
 
@@ -183,8 +199,11 @@ in_stream_traits_enum! {
         Raw(S),
         Buf(BufStream<S>),
         Cached(CachedStream<S>),
+        CachedBuf(CachedStream<BufStream<S>>),
         Http(HttpInboundStream<S>),
         Mixed(MixedInboundStream<S>),
+        Multi(MultiInboundStream<S>),
+        Shadowsocks(ShadowsocksStream<S>),
     }
 }
 
@@ -215,6 +234,15 @@ where
     }
 }
 
+impl<S> From<CachedStream<BufStream<S>>> for InboundServiceStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    fn from(value: CachedStream<BufStream<S>>) -> Self {
+        Self::CachedBuf(value)
+    }
+}
+
 impl InboundService {
     pub fn init(opt: InboundServiceOption) -> InboundResult<InboundService> {
         match opt {
@@ -222,6 +250,8 @@ impl InboundService {
             InboundServiceOption::Socks(o) => Ok(SocksInbound::init(o)?.into()),
             InboundServiceOption::Mixed(o) => Ok(MixedInbound::init(o)?.into()),
             InboundServiceOption::Vless(o) => Ok(VlessInbound::init(o)?.into()),
+            InboundServiceOption::Shadowsocks(o) => Ok(ShadowsocksInbound::init(o)?.into()),
+            InboundServiceOption::Trojan(o) => Ok(TrojanInbound::init(o)?.into()),
         }
     }
 }
@@ -248,6 +278,7 @@ mod tests {
                 user: "test".into(),
                 uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
             }],
+            ..Default::default()
         });
 
         let svc = InboundService::init(opt).unwrap();
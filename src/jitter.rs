@@ -0,0 +1,237 @@
+//! Outbound connection-establishment jitter
+
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    OutboundError, OutboundPacket, OutboundResult, OutboundService, OutboundServiceOption,
+    OutboundServiceStream, OutboundServiceTrait,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JitterOutboundOption {
+    /// Longest random delay in milliseconds before each handshake, none if
+    /// unset.
+    #[serde(default)]
+    pub connect_jitter: Option<u64>,
+    /// Outbound the handshake is run with after the delay.
+    pub outbound: Box<OutboundServiceOption>,
+}
+
+impl JitterOutboundOption {
+    /// Includes the problems of the wrapped outbound.
+    pub fn validate(&self) -> Vec<OutboundError> {
+        match *self.outbound {
+            OutboundServiceOption::Jitter(_) => vec![OutboundError::Option(
+                "jitter outbound cannot wrap another jitter outbound".to_string(),
+            )],
+            ref outbound => outbound.validate(),
+        }
+    }
+}
+
+/// Wraps an outbound and sleeps a random duration in `[0, connect_jitter]`
+/// before each handshake, spreading simultaneous reconnects to the same upstream.
+#[derive(Debug)]
+pub struct JitterOutbound<O> {
+    inner: O,
+    connect_jitter: Option<Duration>,
+}
+
+impl<O> JitterOutbound<O> {
+    pub fn new(inner: O, connect_jitter: Option<Duration>) -> Self {
+        Self {
+            inner,
+            connect_jitter,
+        }
+    }
+
+    pub fn connect_jitter(&self) -> Option<Duration> {
+        self.connect_jitter
+    }
+
+    pub fn inner(&self) -> &O {
+        &self.inner
+    }
+
+    fn delay(&self) -> Duration {
+        match self.connect_jitter {
+            Some(max) if !max.is_zero() => rand::thread_rng().gen_range(Duration::ZERO..=max),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+impl JitterOutbound<Box<OutboundService>> {
+    /// Jitter around the outbound of `option`, which may not be a jitter
+    /// outbound itself.
+    pub fn init(option: JitterOutboundOption) -> OutboundResult<Self> {
+        Self::with_outbound(
+            OutboundService::init(*option.outbound)?,
+            option.connect_jitter.map(Duration::from_millis),
+        )
+    }
+
+    pub(crate) fn with_outbound(
+        outbound: OutboundService,
+        connect_jitter: Option<Duration>,
+    ) -> OutboundResult<Self> {
+        if matches!(outbound, OutboundService::Jitter(_)) {
+            return Err(OutboundError::Option(
+                "jitter outbound cannot wrap another jitter outbound".to_string(),
+            ));
+        }
+
+        Ok(Self::new(Box::new(outbound), connect_jitter))
+    }
+
+    /// Handshake with the wrapped service itself, going through
+    /// `OutboundService::handshake` would make its future contain itself.
+    pub async fn handshake<S>(
+        &self,
+        stream: S,
+        packet: OutboundPacket,
+    ) -> OutboundResult<OutboundServiceStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        let delay = self.delay();
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+
+        match self.inner.as_ref() {
+            OutboundService::Direct(svc) => svc.handshake(stream, packet).await,
+            OutboundService::Vless(svc) => Ok(svc.handshake(stream, packet).await?.into()),
+            OutboundService::Socks(svc) => Ok(svc.handshake(stream, packet).await?.into()),
+            OutboundService::Http(svc) => Ok(svc.handshake(stream, packet).await?.into()),
+            OutboundService::Shadowsocks(svc) => Ok(svc.handshake(stream, packet).await?.into()),
+            OutboundService::Trojan(svc) => Ok(svc.handshake(stream, packet).await?.into()),
+            OutboundService::Jitter(_) => Err(OutboundError::Option(
+                "jitter outbound cannot wrap another jitter outbound".to_string(),
+            )),
+        }
+    }
+}
+
+impl<S, O> OutboundServiceTrait<S> for JitterOutbound<O>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    O: OutboundServiceTrait<S>,
+{
+    type Stream = O::Stream;
+
+    async fn handshake(&self, stream: S, packet: OutboundPacket) -> OutboundResult<Self::Stream> {
+        let delay = self.delay();
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+
+        self.inner.handshake(stream, packet).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    use tokio::time::Instant;
+
+    use crate::{address::NetworkType, ServiceAddress};
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct RecordOutbound {
+        started: Arc<Mutex<Vec<Instant>>>,
+    }
+
+    impl<S> OutboundServiceTrait<S> for RecordOutbound
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        type Stream = S;
+
+        async fn handshake(&self, stream: S, _packet: OutboundPacket) -> OutboundResult<S> {
+            self.started.lock().unwrap().push(Instant::now());
+            Ok(stream)
+        }
+    }
+
+    fn packet() -> OutboundPacket {
+        OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("127.0.0.1".into(), 80),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_jitter() {
+        let record = RecordOutbound::default();
+        let started = record.started.clone();
+        let out = Arc::new(JitterOutbound::new(
+            record,
+            Some(Duration::from_millis(100)),
+        ));
+
+        let begin = Instant::now();
+        let mut set = tokio::task::JoinSet::new();
+        for _ in 0..20 {
+            let out = out.clone();
+            set.spawn(async move { out.handshake(Cursor::new(vec![]), packet()).await });
+        }
+        while let Some(res) = set.join_next().await {
+            res.unwrap().unwrap();
+        }
+
+        // sleeps end on millisecond ticks, so two handshakes may start
+        // together, twenty all in the same tick would mean no jitter
+        let started = started.lock().unwrap();
+        assert_eq!(started.len(), 20);
+        assert!(started.iter().any(|t| *t != started[0]));
+        for t in started.iter() {
+            assert!(*t - begin <= Duration::from_millis(100));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_connect_jitter() {
+        let record = RecordOutbound::default();
+        let started = record.started.clone();
+        let out = JitterOutbound::new(record, None);
+
+        let begin = Instant::now();
+        out.handshake(Cursor::new(vec![]), packet()).await.unwrap();
+
+        assert_eq!(started.lock().unwrap()[0], begin);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_jitter_service() {
+        let option: OutboundServiceOption = serde_json::from_str(
+            r#"{"jitter": {"connect_jitter": 100, "outbound": {"vless": {
+                "uuid": "fc42fe34-e267-4c69-8861-2bc419057519", "flow": null}}}}"#,
+        )
+        .unwrap();
+        let svc = OutboundService::init(option.clone()).unwrap();
+        assert_eq!(svc.name(), "Jitter");
+
+        let begin = Instant::now();
+        let stream = svc.handshake(Cursor::new(vec![]), packet()).await.unwrap();
+        assert!(matches!(stream, OutboundServiceStream::Vless(_)));
+        assert!(Instant::now() - begin <= Duration::from_millis(100));
+
+        // a jitter of a jitter would only add the delays up
+        let nested = OutboundServiceOption::Jitter(JitterOutboundOption {
+            connect_jitter: Some(100),
+            outbound: Box::new(option),
+        });
+        assert!(OutboundService::init(nested).is_err());
+    }
+}
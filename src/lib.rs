@@ -6,7 +6,7 @@ pub mod error;
 pub use error::{InboundError, OutboundError};
 
 pub mod option;
-pub use option::{InboundServiceOption, OutboundServiceOption};
+pub use option::{InboundServiceOption, OutboundServiceOption, ServiceConfig};
 
 pub mod inbound;
 pub use inbound::{InboundPacket, InboundService, InboundServiceStream};
@@ -15,19 +15,39 @@ pub mod outbound;
 pub use outbound::{OutboundPacket, OutboundService, OutboundServiceStream};
 
 pub mod address;
-pub use address::{AddrType, AddrTypeConvert, Address, ServiceAddress};
+pub use address::{AddrType, AddrTypeConvert, Address, IpCidr, ServiceAddress};
 
 pub mod varint;
 pub use varint::{read_varint, variant_len, write_varint};
 
+pub mod resolver;
+pub use resolver::{LimitedResolver, Resolver, ResolverOption, SystemResolver};
+
 pub mod stream;
-pub use stream::CachedStream;
+pub use stream::{
+    peek, write_all_timeout, CachedStream, CloseNotifyStream, CountingStream, IdleTimeoutStream,
+    LengthDelimitedStream, ProxyStream,
+};
 
+pub mod chain;
+pub mod crypto;
 pub mod direct;
+pub mod guard;
 pub mod http;
+pub mod jitter;
+pub mod limit;
 pub mod mixed;
+pub mod multi;
+pub mod prefix;
+pub mod relay;
+pub mod shadowsocks;
 pub mod socks;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trojan;
 pub mod vless;
+#[cfg(feature = "ws")]
+pub mod ws;
 
 pub type InboundResult<T> = std::result::Result<T, InboundError>;
 pub type OutboundResult<T> = std::result::Result<T, OutboundError>;
@@ -39,7 +59,7 @@ where
 {
     type Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync;
 
-    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket)>;
+    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)>;
 }
 
 #[trait_variant::make(OutboundServiceTrait: Send + Sync)]
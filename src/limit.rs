@@ -0,0 +1,195 @@
+//! Global limit on concurrent inbound connections
+
+use std::{pin::Pin, sync::Arc, task::Poll};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+
+use crate::{InboundError, InboundPacket, InboundResult, InboundServiceTrait};
+
+/// What happens to a connection arriving while the limit is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LimitMode {
+    /// Fail the handshake with `ConnectionLimit`.
+    #[default]
+    Reject,
+    /// Wait until another connection is dropped.
+    Queue,
+}
+
+/// Wraps an inbound and allows at most `max_connections` streams at once.
+///
+/// The semaphore can be shared, so several inbounds count against one cap.
+/// A slot is held from the start of the handshake until the returned stream
+/// is dropped.
+#[derive(Debug)]
+pub struct LimitInbound<I> {
+    inner: I,
+    permits: Arc<Semaphore>,
+    mode: LimitMode,
+}
+
+impl<I> LimitInbound<I> {
+    pub fn new(inner: I, max_connections: usize, mode: LimitMode) -> Self {
+        Self::with_semaphore(inner, Arc::new(Semaphore::new(max_connections)), mode)
+    }
+
+    pub fn with_semaphore(inner: I, permits: Arc<Semaphore>, mode: LimitMode) -> Self {
+        Self {
+            inner,
+            permits,
+            mode,
+        }
+    }
+
+    /// Slots currently free.
+    pub fn available(&self) -> usize {
+        self.permits.available_permits()
+    }
+
+    pub fn inner(&self) -> &I {
+        &self.inner
+    }
+}
+
+impl<S, I> InboundServiceTrait<S> for LimitInbound<I>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    I: InboundServiceTrait<S>,
+{
+    type Stream = LimitStream<I::Stream>;
+
+    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+        let permit = match self.mode {
+            LimitMode::Reject => self.permits.clone().try_acquire_owned().ok(),
+            LimitMode::Queue => self.permits.clone().acquire_owned().await.ok(),
+        }
+        .ok_or(InboundError::ConnectionLimit)?;
+
+        let (stream, pac) = self.inner.handshake(stream).await?;
+
+        Ok((
+            LimitStream {
+                inner: stream,
+                _permit: permit,
+            },
+            pac,
+        ))
+    }
+}
+
+/// Stream holding a connection slot until dropped.
+#[derive(Debug)]
+pub struct LimitStream<S> {
+    inner: S,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<S> LimitStream<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> AsyncRead for LimitStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for LimitStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, io::Cursor, time::Duration};
+
+    use crate::{address::NetworkType, ServiceAddress};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct AcceptAll;
+
+    impl<S> InboundServiceTrait<S> for AcceptAll
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        type Stream = S;
+
+        async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+            let pac = InboundPacket {
+                typ: NetworkType::Tcp,
+                dest: ServiceAddress::new("example.com".into(), 443),
+                detail: Cow::Borrowed(""),
+            };
+            Ok((stream, pac))
+        }
+    }
+
+    fn conn() -> Cursor<Vec<u8>> {
+        Cursor::new(vec![])
+    }
+
+    #[tokio::test]
+    async fn test_limit_reject() {
+        let inbound = LimitInbound::new(AcceptAll, 2, LimitMode::Reject);
+
+        let (first, _) = inbound.handshake(conn()).await.unwrap();
+        let (_second, _) = inbound.handshake(conn()).await.unwrap();
+        let err = inbound.handshake(conn()).await.unwrap_err();
+        assert!(matches!(err, InboundError::ConnectionLimit));
+
+        drop(first);
+        assert_eq!(inbound.available(), 1);
+        assert!(inbound.handshake(conn()).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_limit_queue() {
+        let inbound = LimitInbound::new(AcceptAll, 1, LimitMode::Queue);
+
+        let (first, _) = inbound.handshake(conn()).await.unwrap();
+        let queued = tokio::time::timeout(Duration::from_secs(1), inbound.handshake(conn())).await;
+        assert!(queued.is_err());
+
+        let (res, _) = tokio::join!(inbound.handshake(conn()), async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(first);
+        });
+        assert!(res.is_ok());
+    }
+}
@@ -1,21 +1,101 @@
-//! Mixed for socks5 or http proxy
+//! Mixed for socks5 or http proxy, and rule based outbound selection
 
-use std::pin::Pin;
+use std::{io, pin::Pin};
 
-use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufStream};
+use tokio::io::{AsyncRead, AsyncWrite, BufStream};
 
 use crate::{
-    http::{option::HttpAuthOption, HttpInbound, HttpInboundOption, HttpInboundStream},
-    socks::{option::SocksAuthOption, SocksInbound, SocksInboundOption},
-    CachedStream, InboundPacket, InboundResult, InboundServiceStream, InboundServiceTrait,
+    http::{
+        option::{HttpAuthOption, HttpAuthScheme},
+        HttpInbound, HttpInboundOption, HttpInboundStream,
+    },
+    inbound::max_auth_attempts,
+    option::default_udp_enabled,
+    socks::{
+        option::{default_allow_v4, default_eager_reply, SocksAuthOption},
+        protocol::SocksStatus,
+        SocksInbound, SocksInboundOption, SocksPendingReply, SocksReplyAddr,
+    },
+    stream::read_buffer_size,
+    Address, CachedStream, InboundError, InboundPacket, InboundResult, InboundServiceStream,
+    InboundServiceTrait, IpCidr, OutboundError, OutboundPacket, OutboundResult, OutboundService,
+    OutboundServiceTrait,
 };
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MixedInboundOption {
     #[serde(default)]
     auth: Vec<MixedAuthOption>,
+    #[serde(default = "default_udp_enabled")]
+    udp_enabled: bool,
+    #[serde(default = "default_allow_v4")]
+    allow_v4: bool,
+    #[serde(default)]
+    socks4_strict: bool,
+    #[serde(default)]
+    reply_timeout: Option<u64>,
+    #[serde(default)]
+    reply_addr: SocksReplyAddr,
+    /// Most bytes read before the protocol is picked, streams not identified
+    /// within it are handled as http.
+    #[serde(default = "default_max_peek")]
+    max_peek: usize,
+    /// Read buffer size in bytes for the buffered connection, 8 KiB if unset.
+    #[serde(default)]
+    read_buffer: Option<usize>,
+    /// Failed authentications allowed on one connection.
+    #[serde(default)]
+    max_auth_attempts: Option<u32>,
+    /// Send the socks success reply as soon as the request is read. When
+    /// false, `MixedInbound::accept` leaves it to the caller.
+    #[serde(default = "default_eager_reply")]
+    eager_reply: bool,
+    /// See `SocksInboundOption::unmap_v4`, only applies to socks requests.
+    #[serde(default)]
+    unmap_v4: bool,
+    /// Refuse implausible socks and http domains, see
+    /// `SocksInboundOption::strict_domain`.
+    #[serde(default)]
+    strict_domain: bool,
+}
+
+impl MixedInboundOption {
+    pub fn validate(&self) -> Vec<InboundError> {
+        let mut errors = vec![];
+        errors.extend(read_buffer_size(self.read_buffer).err());
+        errors.extend(max_auth_attempts(self.max_auth_attempts).err());
+        if self.max_peek == 0 {
+            errors.push(InboundError::Option(
+                "max_peek must be at least 1".to_string(),
+            ));
+        }
+        errors
+    }
+}
+
+impl Default for MixedInboundOption {
+    fn default() -> Self {
+        Self {
+            auth: vec![],
+            udp_enabled: default_udp_enabled(),
+            allow_v4: default_allow_v4(),
+            socks4_strict: false,
+            reply_timeout: None,
+            reply_addr: SocksReplyAddr::default(),
+            max_peek: default_max_peek(),
+            read_buffer: None,
+            max_auth_attempts: None,
+            eager_reply: default_eager_reply(),
+            unmap_v4: false,
+            strict_domain: false,
+        }
+    }
+}
+
+/// Long enough for the longest http method token, `"CONNECT "`.
+fn default_max_peek() -> usize {
+    8
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,10 +108,16 @@ pub struct MixedAuthOption {
 pub struct MixedInbound {
     http_in: HttpInbound,
     socks_in: SocksInbound,
+    max_peek: usize,
 }
 
 impl MixedInbound {
     pub fn init(opt: MixedInboundOption) -> InboundResult<Self> {
+        if let Some(e) = opt.validate().into_iter().next() {
+            return Err(e);
+        }
+        let max_peek = opt.max_peek;
+
         let socks_opt = SocksInboundOption {
             auth: opt
                 .auth
@@ -41,6 +127,16 @@ impl MixedInbound {
                     pass: auth.pass.clone(),
                 })
                 .collect(),
+            udp_enabled: opt.udp_enabled,
+            allow_v4: opt.allow_v4,
+            socks4_strict: opt.socks4_strict,
+            reply_timeout: opt.reply_timeout,
+            reply_addr: opt.reply_addr,
+            read_buffer: opt.read_buffer,
+            max_auth_attempts: opt.max_auth_attempts,
+            eager_reply: opt.eager_reply,
+            unmap_v4: opt.unmap_v4,
+            strict_domain: opt.strict_domain,
         };
         let socks_in = SocksInbound::init(socks_opt)?;
 
@@ -53,38 +149,169 @@ impl MixedInbound {
                     pass: auth.pass,
                 })
                 .collect(),
+            reply_timeout: opt.reply_timeout,
+            scheme: HttpAuthScheme::Basic,
+            tokens: vec![],
+            realm: None,
+            fake_response: None,
+            read_buffer: opt.read_buffer,
+            max_auth_attempts: opt.max_auth_attempts,
+            strict_headers: false,
+            local_hosts: vec![],
+            strict_domain: opt.strict_domain,
         };
         let http_in = HttpInbound::init(http_opt)?;
 
-        Ok(Self { http_in, socks_in })
+        Ok(Self {
+            http_in,
+            socks_in,
+            max_peek,
+        })
     }
 }
 
-impl<S> InboundServiceTrait<S> for MixedInbound
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MixedProtocol {
+    Socks,
+    Http,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detection<P = MixedProtocol> {
+    Found(P),
+    /// The bytes so far are a prefix of a known protocol, which needs at
+    /// least this many bytes in total to be told apart.
+    NeedMore(usize),
+    Unknown,
+}
+
+const HTTP_METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"PUT ",
+    b"POST ",
+    b"HEAD ",
+    b"PATCH ",
+    b"TRACE ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"CONNECT ",
+];
+
+/// Identify the protocol from the first bytes of a stream.
+pub fn detect(buf: &[u8]) -> Detection {
+    match buf.first() {
+        None => return Detection::NeedMore(1),
+        Some(4 | 5) => return Detection::Found(MixedProtocol::Socks),
+        Some(_) => {}
+    }
+
+    let mut need = None;
+    for method in HTTP_METHODS {
+        if buf.starts_with(method) {
+            return Detection::Found(MixedProtocol::Http);
+        }
+        if method.starts_with(buf) {
+            need = Some(need.map_or(method.len(), |n: usize| n.min(method.len())));
+        }
+    }
+
+    match need {
+        Some(n) => Detection::NeedMore(n),
+        None => Detection::Unknown,
+    }
+}
+
+/// Peek into `stream` until `detect` is sure or `max_peek` bytes are cached.
+/// The peeked bytes stay in the stream for the inner handshake.
+pub async fn peek_protocol<S>(
+    stream: &mut CachedStream<S>,
+    max_peek: usize,
+) -> io::Result<Option<MixedProtocol>>
 where
-    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
 {
-    type Stream = MixedInboundStream<S>;
+    peek_with(stream, max_peek, detect).await
+}
 
-    async fn handshake(&self, mut stream: S) -> InboundResult<(Self::Stream, InboundPacket)> {
-        let byte = stream.read_u8().await?;
+/// `peek_protocol` with another detector.
+pub async fn peek_with<S, P>(
+    stream: &mut CachedStream<S>,
+    max_peek: usize,
+    detect: impl Fn(&[u8]) -> Detection<P>,
+) -> io::Result<Option<P>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    let mut want = 1;
 
-        let stream = CachedStream::new(stream, Some(Bytes::from(vec![byte].into_boxed_slice())));
-        match byte {
-            4 | 5 => {
-                let (stream, pac) = self.socks_in.handshake(stream).await?;
-                let stream = MixedInboundStream::Socks(stream);
-                Ok((stream, pac))
+    loop {
+        let buf = stream.peek_n(want).await?;
+        if buf.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        match detect(buf) {
+            Detection::Found(proto) => return Ok(Some(proto)),
+            Detection::Unknown => return Ok(None),
+            Detection::NeedMore(n) => {
+                // EOF or the limit before the protocol was clear
+                if buf.len() < want || buf.len() >= max_peek {
+                    return Ok(None);
+                }
+                want = n.min(max_peek);
             }
-            _ => {
+        }
+    }
+}
+
+impl MixedInbound {
+    /// Run the handshake of the detected protocol. Without `eager_reply`
+    /// a socks client is not answered yet and its pending reply is given
+    /// back, as by `SocksInbound::accept`. Http never has one.
+    pub async fn accept<S>(
+        &self,
+        stream: S,
+    ) -> InboundResult<(
+        MixedInboundStream<S>,
+        InboundPacket<'_>,
+        Option<SocksPendingReply>,
+    )>
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+    {
+        let mut stream = CachedStream::new(stream, None);
+        let proto = peek_protocol(&mut stream, self.max_peek).await?;
+
+        match proto {
+            Some(MixedProtocol::Socks) => {
+                let (stream, pac, pending) = self.socks_in.accept(stream).await?;
+                Ok((MixedInboundStream::Socks(stream), pac, pending))
+            }
+            Some(MixedProtocol::Http) | None => {
                 let (stream, pac) = self.http_in.handshake(stream).await?;
-                let stream = MixedInboundStream::Http(stream);
-                Ok((stream, pac))
+                Ok((MixedInboundStream::Http(stream), pac, None))
             }
         }
     }
 }
 
+impl<S> InboundServiceTrait<S> for MixedInbound
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+{
+    type Stream = MixedInboundStream<S>;
+
+    /// Same as `accept`, a pending socks reply is sent right away.
+    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+        let (mut stream, pac, pending) = self.accept(stream).await?;
+        if let (Some(pending), MixedInboundStream::Socks(s)) = (pending, &mut stream) {
+            pending.reply(s, SocksStatus::SUCCEEDED, None).await?;
+        }
+
+        Ok((stream, pac))
+    }
+}
+
 #[derive(Debug)]
 pub enum MixedInboundStream<S>
 where
@@ -158,3 +385,321 @@ where
         }
     }
 }
+
+/// Rule matched against the destination of an outbound packet.
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    /// Ip destination inside the network.
+    Cidr(IpCidr),
+    /// Domain destination equal to, or a subdomain of, the suffix.
+    DomainSuffix(String),
+    /// Any destination.
+    Any,
+}
+
+impl Matcher {
+    pub fn matches(&self, addr: &Address) -> bool {
+        match (self, addr) {
+            (Matcher::Any, _) => true,
+            (Matcher::Cidr(cidr), Address::Socket(ip)) => cidr.contains(ip),
+            (Matcher::DomainSuffix(suffix), Address::Domain(domain)) => {
+                // bytes, the offset may fall inside a multibyte character
+                let suffix = suffix.trim_start_matches('.').as_bytes();
+                let domain = domain.trim_end_matches('.').as_bytes();
+                if domain.len() < suffix.len() {
+                    return false;
+                }
+                let (head, tail) = domain.split_at(domain.len() - suffix.len());
+                tail.eq_ignore_ascii_case(suffix) && (head.is_empty() || head.ends_with(b"."))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Outbound delegating to the first rule matching the packet destination.
+#[derive(Debug)]
+pub struct MixedOutbound<O = OutboundService> {
+    rules: Vec<(Matcher, O)>,
+}
+
+impl<O> MixedOutbound<O> {
+    pub fn new(rules: Vec<(Matcher, O)>) -> Self {
+        Self { rules }
+    }
+
+    pub fn push(&mut self, matcher: Matcher, outbound: O) {
+        self.rules.push((matcher, outbound));
+    }
+
+    pub fn select(&self, addr: &Address) -> Option<&O> {
+        self.rules
+            .iter()
+            .find(|(matcher, _)| matcher.matches(addr))
+            .map(|(_, outbound)| outbound)
+    }
+}
+
+impl<S, O> OutboundServiceTrait<S> for MixedOutbound<O>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+    O: OutboundServiceTrait<S>,
+{
+    type Stream = O::Stream;
+
+    async fn handshake(&self, stream: S, packet: OutboundPacket) -> OutboundResult<Self::Stream> {
+        match self.select(&packet.dest.addr) {
+            Some(outbound) => outbound.handshake(stream, packet).await,
+            None => Err(OutboundError::NoRoute(packet.dest)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use crate::{address::NetworkType, ServiceAddress};
+
+    use super::*;
+
+    fn mixed_option(max_peek: usize) -> MixedInboundOption {
+        MixedInboundOption {
+            max_peek,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_mixed_detect() {
+        assert_eq!(detect(b""), Detection::NeedMore(1));
+        assert_eq!(detect(&[5, 1, 0]), Detection::Found(MixedProtocol::Socks));
+        assert_eq!(detect(&[4]), Detection::Found(MixedProtocol::Socks));
+        assert_eq!(detect(b"CONN"), Detection::NeedMore(8));
+        assert_eq!(detect(b"CONNECT"), Detection::NeedMore(8));
+        assert_eq!(detect(b"CONNECT "), Detection::Found(MixedProtocol::Http));
+        assert_eq!(detect(b"P"), Detection::NeedMore(4));
+        assert_eq!(detect(b"PO"), Detection::NeedMore(5));
+        assert_eq!(detect(b"GET /"), Detection::Found(MixedProtocol::Http));
+        assert_eq!(detect(&[0x16, 3, 1, 0, 5]), Detection::Unknown);
+        assert_eq!(detect(b"GETX"), Detection::Unknown);
+    }
+
+    #[tokio::test]
+    async fn test_mixed_peek_split_method() {
+        let inbound = MixedInbound::init(mixed_option(8)).unwrap();
+
+        let (mut client, server) = duplex(1024);
+        let write = async move {
+            client.write_all(b"CONN").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            client
+                .write_all(b"ECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+                .await
+                .unwrap();
+            client
+        };
+        let (_client, res) = tokio::join!(write, inbound.handshake(server));
+        let (stream, pac) = res.unwrap();
+        assert!(matches!(stream, MixedInboundStream::Http(_)));
+        assert_eq!(pac.dest, ServiceAddress::new("example.com".into(), 443));
+    }
+
+    #[tokio::test]
+    async fn test_mixed_peek_limit() {
+        let mut stream = CachedStream::new(Cursor::new(b"CONNECT x".to_vec()), None);
+        assert_eq!(peek_protocol(&mut stream, 4).await.unwrap(), None);
+        assert_eq!(stream.peek_n(0).await.unwrap(), b"CONN");
+
+        // nothing is read past what detection needs
+        let mut stream = CachedStream::new(Cursor::new(vec![5, 1, 0]), None);
+        assert_eq!(
+            peek_protocol(&mut stream, 8).await.unwrap(),
+            Some(MixedProtocol::Socks)
+        );
+        assert_eq!(stream.peek_n(0).await.unwrap(), &[5]);
+
+        assert!(MixedInbound::init(mixed_option(0)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mixed_routing() {
+        let inbound = MixedInbound::init(mixed_option(8)).unwrap();
+        let dest = ServiceAddress::new("127.0.0.1".into(), 80);
+
+        let requests: [(&[u8], bool); 3] = [
+            (&[4, 1, 0, 80, 127, 0, 0, 1, 0], true),
+            (&[5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80], true),
+            (
+                b"CONNECT 127.0.0.1:80 HTTP/1.1\r\nHost: 127.0.0.1:80\r\n\r\n",
+                false,
+            ),
+        ];
+        for (request, socks) in requests {
+            let (mut client, server) = duplex(1024);
+            client.write_all(request).await.unwrap();
+
+            let (stream, pac) = inbound.handshake(server).await.unwrap();
+            assert_eq!(pac.dest, dest);
+            match stream {
+                MixedInboundStream::Socks(_) => assert!(socks),
+                MixedInboundStream::Http(_) => assert!(!socks),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mixed_eager_reply() {
+        let inbound = MixedInbound::init(MixedInboundOption {
+            eager_reply: false,
+            ..mixed_option(8)
+        })
+        .unwrap();
+
+        let (mut client, server) = duplex(1024);
+        client
+            .write_all(&[5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        let (mut stream, _, pending) = inbound.accept(server).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        let mut reply = [0u8; 10];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), client.read(&mut reply))
+                .await
+                .is_err()
+        );
+        let MixedInboundStream::Socks(s) = &mut stream else {
+            panic!("not socks");
+        };
+        pending
+            .unwrap()
+            .reply(s, SocksStatus::CONNECTION_REFUSED, None)
+            .await
+            .unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [5, 5]);
+
+        // the trait handshake still answers right away
+        let (mut client, server) = duplex(1024);
+        client
+            .write_all(&[5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        inbound.handshake(server).await.unwrap();
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[2..4], [5, 0]);
+    }
+
+    #[derive(Debug)]
+    struct NamedOutbound(&'static str);
+
+    impl<S> OutboundServiceTrait<S> for NamedOutbound
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+    {
+        type Stream = CachedStream<S>;
+
+        async fn handshake(
+            &self,
+            stream: S,
+            _packet: OutboundPacket,
+        ) -> OutboundResult<Self::Stream> {
+            Ok(CachedStream::new(
+                stream,
+                Some(Bytes::from_static(self.0.as_bytes())),
+            ))
+        }
+    }
+
+    async fn route(out: &MixedOutbound<NamedOutbound>, addr: &str) -> OutboundResult<String> {
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new(addr.into(), 443),
+        };
+        let mut stream = out.handshake(Cursor::new(vec![]), packet).await?;
+        let mut name = String::new();
+        stream.read_to_string(&mut name).await?;
+        Ok(name)
+    }
+
+    #[tokio::test]
+    async fn test_mixed_outbound() {
+        let out = MixedOutbound::new(vec![
+            (
+                Matcher::Cidr("10.0.0.0/8".parse().unwrap()),
+                NamedOutbound("direct"),
+            ),
+            (Matcher::DomainSuffix("lan".into()), NamedOutbound("direct")),
+            (Matcher::Any, NamedOutbound("proxy")),
+        ]);
+
+        assert_eq!(route(&out, "10.0.0.5").await.unwrap(), "direct");
+        assert_eq!(route(&out, "10.255.0.1").await.unwrap(), "direct");
+        assert_eq!(route(&out, "nas.lan").await.unwrap(), "direct");
+        assert_eq!(route(&out, "8.8.8.8").await.unwrap(), "proxy");
+        assert_eq!(route(&out, "11.0.0.1").await.unwrap(), "proxy");
+        assert_eq!(route(&out, "example.com").await.unwrap(), "proxy");
+        assert_eq!(route(&out, "plan").await.unwrap(), "proxy");
+    }
+
+    #[tokio::test]
+    async fn test_mixed_outbound_no_route() {
+        let out = MixedOutbound::new(vec![(
+            Matcher::DomainSuffix("example.com".into()),
+            NamedOutbound("proxy"),
+        )]);
+
+        assert_eq!(route(&out, "www.example.com").await.unwrap(), "proxy");
+        assert!(matches!(
+            route(&out, "example.org").await,
+            Err(OutboundError::NoRoute(_))
+        ));
+    }
+
+    #[test]
+    fn test_domain_suffix_multibyte() {
+        let matcher = Matcher::DomainSuffix("example.com".into());
+        let domain = |d: &str| Address::Domain(d.into());
+
+        // "é" is two bytes, the suffix offset lands inside it
+        assert!(!matcher.matches(&domain("éexample.com")));
+        assert!(!matcher.matches(&domain("aéxample.com")));
+        assert!(matcher.matches(&domain("bücher.EXAMPLE.com")));
+
+        let matcher = Matcher::DomainSuffix("bücher.de".into());
+        assert!(matcher.matches(&domain("www.bücher.de")));
+        assert!(!matcher.matches(&domain("xbücher.de")));
+    }
+
+    #[tokio::test]
+    async fn test_mixed_strict_domain() {
+        let inbound = MixedInbound::init(MixedInboundOption {
+            strict_domain: true,
+            ..mixed_option(8)
+        })
+        .unwrap();
+
+        let (mut client, server) = duplex(1024);
+        client
+            .write_all(&[5, 1, 0, 5, 1, 0, 3, 3, b'a', b' ', b'b', 0, 80])
+            .await
+            .unwrap();
+        assert!(inbound.handshake(server).await.is_err());
+
+        let host = "a".repeat(300);
+        let (mut client, server) = duplex(1024);
+        client
+            .write_all(format!("CONNECT {}:443 HTTP/1.1\r\n\r\n", host).as_bytes())
+            .await
+            .unwrap();
+        assert!(inbound.handshake(server).await.is_err());
+    }
+}
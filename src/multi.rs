@@ -0,0 +1,329 @@
+//! Multi protocol inbound, any of vless, socks and http on one port
+
+use std::pin::Pin;
+
+use tokio::io::{AsyncRead, AsyncWrite, BufStream};
+
+use crate::{
+    http::{HttpInbound, HttpInboundStream},
+    mixed::{peek_with, Detection},
+    socks::SocksInbound,
+    vless::{protocol::VERSION, VlessInbound},
+    CachedStream, InboundError, InboundPacket, InboundResult, InboundServiceStream,
+    InboundServiceTrait,
+};
+
+/// Vless version byte, 16 bytes of uuid and the addons length.
+const VLESS_PEEK: usize = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultiProtocol {
+    Vless,
+    Socks,
+    Http,
+}
+
+/// Picks the protocol from the bytes peeked so far.
+pub type Detector = fn(&[u8]) -> Detection<MultiProtocol>;
+
+/// Identify vless, socks or http from the first bytes of a stream.
+///
+/// Vless starts with its version byte and is taken once a uuid and the
+/// addons length could follow, socks with its version 4 or 5, and http with
+/// a method token of printable characters ending in a space.
+pub fn detect_multi(buf: &[u8]) -> Detection<MultiProtocol> {
+    match buf.first() {
+        None => return Detection::NeedMore(1),
+        Some(&VERSION) if buf.len() >= VLESS_PEEK => return Detection::Found(MultiProtocol::Vless),
+        Some(&VERSION) => return Detection::NeedMore(VLESS_PEEK),
+        Some(4 | 5) => return Detection::Found(MultiProtocol::Socks),
+        Some(_) => {}
+    }
+
+    for (i, c) in buf.iter().enumerate() {
+        match c {
+            b' ' if i > 0 => return Detection::Found(MultiProtocol::Http),
+            c if is_tchar(*c) => {}
+            _ => return Detection::Unknown,
+        }
+    }
+
+    Detection::NeedMore(buf.len() + 1)
+}
+
+/// Token character of RFC 9110.
+fn is_tchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+/// Inbound dispatching each stream to the vless, socks or http inbound its
+/// first bytes belong to, a generalized `MixedInbound`.
+///
+/// Streams the detector does not recognize within `max_peek` bytes are
+/// handled as http. A protocol without an inbound fails the handshake with
+/// `InboundError::UnsupportedProtocol`.
+#[derive(Debug)]
+pub struct MultiInbound {
+    vless_in: Option<VlessInbound>,
+    socks_in: Option<SocksInbound>,
+    http_in: Option<HttpInbound>,
+    detector: Detector,
+    max_peek: usize,
+}
+
+impl Default for MultiInbound {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MultiInbound {
+    /// Inbound without protocols, detecting with `detect_multi`.
+    pub fn new() -> Self {
+        Self {
+            vless_in: None,
+            socks_in: None,
+            http_in: None,
+            detector: detect_multi,
+            max_peek: VLESS_PEEK,
+        }
+    }
+
+    pub fn with_vless(mut self, inbound: VlessInbound) -> Self {
+        self.vless_in = Some(inbound);
+        self
+    }
+
+    pub fn with_socks(mut self, inbound: SocksInbound) -> Self {
+        self.socks_in = Some(inbound);
+        self
+    }
+
+    pub fn with_http(mut self, inbound: HttpInbound) -> Self {
+        self.http_in = Some(inbound);
+        self
+    }
+
+    pub fn with_detector(mut self, detector: Detector) -> Self {
+        self.detector = detector;
+        self
+    }
+
+    /// Most bytes read before the protocol is picked, 18 by default so a
+    /// vless request can be told apart.
+    pub fn with_max_peek(mut self, max_peek: usize) -> Self {
+        self.max_peek = max_peek;
+        self
+    }
+}
+
+impl<S> InboundServiceTrait<S> for MultiInbound
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+{
+    type Stream = MultiInboundStream<S>;
+
+    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+        let mut stream = CachedStream::new(stream, None);
+        let proto = peek_with(&mut stream, self.max_peek, self.detector).await?;
+
+        match proto.unwrap_or(MultiProtocol::Http) {
+            MultiProtocol::Vless => {
+                let inbound = self
+                    .vless_in
+                    .as_ref()
+                    .ok_or(InboundError::UnsupportedProtocol)?;
+                let (stream, pac) = inbound.handshake(stream).await?;
+                Ok((MultiInboundStream::Vless(stream), pac))
+            }
+            MultiProtocol::Socks => {
+                let inbound = self
+                    .socks_in
+                    .as_ref()
+                    .ok_or(InboundError::UnsupportedProtocol)?;
+                let (stream, pac) = inbound.handshake(stream).await?;
+                Ok((MultiInboundStream::Socks(stream), pac))
+            }
+            MultiProtocol::Http => {
+                let inbound = self
+                    .http_in
+                    .as_ref()
+                    .ok_or(InboundError::UnsupportedProtocol)?;
+                let (stream, pac) = inbound.handshake(stream).await?;
+                Ok((MultiInboundStream::Http(stream), pac))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MultiInboundStream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+{
+    Vless(CachedStream<BufStream<CachedStream<S>>>),
+    Socks(BufStream<CachedStream<S>>),
+    Http(HttpInboundStream<CachedStream<S>>),
+}
+
+impl<S> From<MultiInboundStream<S>> for InboundServiceStream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+{
+    fn from(value: MultiInboundStream<S>) -> Self {
+        Self::Multi(value)
+    }
+}
+
+impl<S> AsyncRead for MultiInboundStream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+{
+    #[inline]
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Vless(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Socks(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Http(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for MultiInboundStream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+{
+    #[inline]
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        match self.get_mut() {
+            Self::Vless(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Socks(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Http(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    #[inline]
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        match self.get_mut() {
+            Self::Vless(s) => Pin::new(s).poll_flush(cx),
+            Self::Socks(s) => Pin::new(s).poll_flush(cx),
+            Self::Http(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    #[inline]
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        match self.get_mut() {
+            Self::Vless(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Socks(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Http(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        http::HttpInboundOption,
+        socks::SocksInboundOption,
+        vless::{option::VlessUserOption, VlessInboundOption},
+        ServiceAddress,
+    };
+
+    use super::*;
+
+    fn multi_inbound() -> MultiInbound {
+        let vless_in = VlessInbound::init(VlessInboundOption {
+            users: vec![VlessUserOption {
+                user: "test".into(),
+                uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+        let socks_in = SocksInbound::init(SocksInboundOption::default()).unwrap();
+        let http_in = HttpInbound::init(HttpInboundOption::default()).unwrap();
+
+        MultiInbound::new()
+            .with_vless(vless_in)
+            .with_socks(socks_in)
+            .with_http(http_in)
+    }
+
+    #[test]
+    fn test_multi_detect() {
+        assert_eq!(detect_multi(b""), Detection::NeedMore(1));
+        assert_eq!(detect_multi(&[0, 252, 66]), Detection::NeedMore(18));
+        assert_eq!(
+            detect_multi(&[0; 18]),
+            Detection::Found(MultiProtocol::Vless)
+        );
+        assert_eq!(
+            detect_multi(&[5, 1, 0]),
+            Detection::Found(MultiProtocol::Socks)
+        );
+        assert_eq!(detect_multi(b"PROPFIND"), Detection::NeedMore(9));
+        assert_eq!(
+            detect_multi(b"PROPFIND /"),
+            Detection::Found(MultiProtocol::Http)
+        );
+        assert_eq!(detect_multi(b" GET"), Detection::Unknown);
+        assert_eq!(detect_multi(&[0x16, 3, 1, 0, 5]), Detection::Unknown);
+    }
+
+    #[tokio::test]
+    async fn test_multi_inbound() {
+        let inbound = multi_inbound();
+
+        let vless = [
+            0, 252, 66, 254, 52, 226, 103, 76, 105, 136, 97, 43, 196, 25, 5, 117, 25, 0, 1, 34,
+            184, 1, 127, 0, 0, 1,
+        ];
+        let socks = [5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0x22, 0xb8];
+        let http = b"CONNECT 127.0.0.1:8888 HTTP/1.1\r\nHost: 127.0.0.1:8888\r\n\r\n";
+
+        for greeting in [&vless[..], &socks[..], &http[..]] {
+            let (mut client, server) = duplex(1024);
+            client.write_all(greeting).await.unwrap();
+            client.write_all(b"data").await.unwrap();
+
+            let (mut stream, pac) = inbound.handshake(server).await.unwrap();
+            match greeting[0] {
+                0 => assert!(matches!(stream, MultiInboundStream::Vless(_))),
+                5 => assert!(matches!(stream, MultiInboundStream::Socks(_))),
+                _ => assert!(matches!(stream, MultiInboundStream::Http(_))),
+            }
+            assert_eq!(pac.dest, ServiceAddress::new("127.0.0.1".into(), 8888));
+
+            let mut data = [0u8; 4];
+            stream.read_exact(&mut data).await.unwrap();
+            assert_eq!(&data, b"data");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multi_inbound_unsupported() {
+        let inbound = MultiInbound::new();
+
+        let (mut client, server) = duplex(1024);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let err = inbound.handshake(server).await.unwrap_err();
+        assert!(matches!(err, InboundError::UnsupportedProtocol));
+    }
+}
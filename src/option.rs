@@ -1,14 +1,55 @@
 //! Service Option
 
-use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use serde::{
+    de::{
+        self,
+        value::{EnumAccessDeserializer, MapAccessDeserializer, StrDeserializer},
+        EnumAccess, MapAccess, Visitor,
+    },
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 
 use crate::{
+    direct::DirectOutboundOption,
     http::{HttpInboundOption, HttpOutboundOption},
+    jitter::JitterOutboundOption,
     mixed::MixedInboundOption,
+    shadowsocks::{ShadowsocksInboundOption, ShadowsocksOutboundOption},
     socks::{SocksInboundOption, SocksOutboundOption},
+    trojan::{TrojanInboundOption, TrojanOutboundOption},
     vless::{VlessInboundOption, VlessOutboundOption},
+    InboundError, OutboundError,
 };
 
+/// Inbounds and outbounds of one config document.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceConfig {
+    #[serde(default)]
+    pub inbounds: Vec<InboundServiceOption>,
+    #[serde(default)]
+    pub outbounds: Vec<OutboundServiceOption>,
+}
+
+impl ServiceConfig {
+    /// Every problem in the document, prefixed with where it was found.
+    pub fn validate(&self) -> Vec<String> {
+        let inbounds = self.inbounds.iter().enumerate().flat_map(|(i, opt)| {
+            opt.validate()
+                .into_iter()
+                .map(move |e| format!("inbounds[{}]: {}", i, e))
+        });
+        let outbounds = self.outbounds.iter().enumerate().flat_map(|(i, opt)| {
+            opt.validate()
+                .into_iter()
+                .map(move |e| format!("outbounds[{}]: {}", i, e))
+        });
+
+        inbounds.chain(outbounds).collect()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum InboundServiceOption {
@@ -16,13 +57,201 @@ pub enum InboundServiceOption {
     Socks(SocksInboundOption),
     Mixed(MixedInboundOption),
     Vless(VlessInboundOption),
+    Shadowsocks(ShadowsocksInboundOption),
+    Trojan(TrojanInboundOption),
+}
+
+impl InboundServiceOption {
+    /// Check the option without building the service, returning every
+    /// problem instead of stopping at the first like `init` does. The
+    /// protocol options' own `validate` reports the same problems their
+    /// `init` would fail on.
+    pub fn validate(&self) -> Vec<InboundError> {
+        match self {
+            Self::Http(o) => o.validate(),
+            Self::Socks(o) => o.validate(),
+            Self::Mixed(o) => o.validate(),
+            Self::Vless(o) => o.validate(),
+            Self::Shadowsocks(o) => o.validate(),
+            Self::Trojan(o) => o.validate(),
+        }
+    }
 }
 
+/// Besides the tagged form, a bare `"direct"` is read as a direct outbound
+/// with default options, the form from before it took any.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case", remote = "Self")]
 pub enum OutboundServiceOption {
-    Direct,
+    Direct(DirectOutboundOption),
     Http(HttpOutboundOption),
     Socks(SocksOutboundOption),
     Vless(VlessOutboundOption),
+    Shadowsocks(ShadowsocksOutboundOption),
+    Trojan(TrojanOutboundOption),
+    Jitter(JitterOutboundOption),
+}
+
+impl Serialize for OutboundServiceOption {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        OutboundServiceOption::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for OutboundServiceOption {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OptionVisitor;
+
+        impl<'de> Visitor<'de> for OptionVisitor {
+            type Value = OutboundServiceOption;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an outbound option")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                match v {
+                    "direct" => Ok(OutboundServiceOption::Direct(Default::default())),
+                    _ => OutboundServiceOption::deserialize(StrDeserializer::new(v)),
+                }
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                OutboundServiceOption::deserialize(MapAccessDeserializer::new(map))
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+                OutboundServiceOption::deserialize(EnumAccessDeserializer::new(data))
+            }
+        }
+
+        // compact formats cannot peek at the input, and only ever hold the
+        // tagged form `serialize` writes
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(OptionVisitor)
+        } else {
+            OutboundServiceOption::deserialize(deserializer)
+        }
+    }
+}
+
+impl OutboundServiceOption {
+    /// Check the option without building the service, returning every
+    /// problem instead of stopping at the first like `init` does. The
+    /// protocol options' own `validate` reports the same problems their
+    /// `init` would fail on.
+    pub fn validate(&self) -> Vec<OutboundError> {
+        match self {
+            Self::Direct(o) => o.validate(),
+            Self::Http(_) => vec![],
+            Self::Socks(o) => o.validate(),
+            Self::Vless(o) => o.validate_service(),
+            Self::Shadowsocks(o) => o.validate(),
+            Self::Trojan(o) => o.validate(),
+            Self::Jitter(o) => o.validate(),
+        }
+    }
+}
+
+/// UDP commands are accepted unless an inbound explicitly disables them.
+pub(crate) fn default_udp_enabled() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{trojan::option::TrojanUserOption, vless::option::VlessUserOption};
+
+    use super::*;
+
+    #[test]
+    fn test_config_validate() {
+        let config = ServiceConfig {
+            inbounds: vec![
+                InboundServiceOption::Vless(VlessInboundOption {
+                    users: vec![VlessUserOption {
+                        user: "a".into(),
+                        uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+                    }],
+                    ..Default::default()
+                }),
+                InboundServiceOption::Trojan(TrojanInboundOption {
+                    users: vec![TrojanUserOption {
+                        user: "b".into(),
+                        password: "".into(),
+                    }],
+                    unmap_v4: false,
+                }),
+            ],
+            outbounds: vec![
+                OutboundServiceOption::Vless(VlessOutboundOption {
+                    uuid: "not-a-uuid".into(),
+                    flow: None,
+                    combine_first_packet: false,
+                    mux: None,
+                }),
+                OutboundServiceOption::Socks(SocksOutboundOption {
+                    version: 6,
+                    ..Default::default()
+                }),
+                OutboundServiceOption::Direct(DirectOutboundOption::default()),
+            ],
+        };
+
+        let errors = config.validate();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].starts_with("inbounds[1]: option error (empty trojan password"));
+        assert!(errors[1].starts_with("outbounds[0]: option error (invalid uuid"));
+        assert_eq!(
+            errors[2],
+            "outbounds[1]: option error (unsupport service socks version: 6)"
+        );
+    }
+
+    #[test]
+    fn test_direct_bare_option() {
+        let config: ServiceConfig =
+            serde_json::from_str(r#"{"outbounds": ["direct", {"direct": {"bind_device": "lo"}}]}"#)
+                .unwrap();
+        assert!(matches!(
+            &config.outbounds[0],
+            OutboundServiceOption::Direct(o) if o.bind_device.is_none()
+        ));
+        assert!(matches!(
+            &config.outbounds[1],
+            OutboundServiceOption::Direct(o) if o.bind_device.as_deref() == Some("lo")
+        ));
+
+        let json = serde_json::to_string(&config.outbounds[1]).unwrap();
+        assert_eq!(json, r#"{"direct":{"bind_device":"lo"}}"#);
+
+        let err = serde_json::from_str::<OutboundServiceOption>(r#""socks""#).unwrap_err();
+        assert!(err.to_string().contains("newtype variant"), "{}", err);
+        let err = serde_json::from_str::<OutboundServiceOption>(r#"{"ftp": {}}"#).unwrap_err();
+        assert!(err.to_string().contains("unknown variant"), "{}", err);
+
+        let bytes = bincode::serialize(&config.outbounds[1]).unwrap();
+        let option: OutboundServiceOption = bincode::deserialize(&bytes).unwrap();
+        assert!(matches!(
+            option,
+            OutboundServiceOption::Direct(o) if o.bind_device.as_deref() == Some("lo")
+        ));
+    }
+
+    #[test]
+    fn test_option_defaults() {
+        fn same<T: Default + Serialize + serde::de::DeserializeOwned>(json: &str) {
+            let parsed: T = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                serde_json::to_value(parsed).unwrap(),
+                serde_json::to_value(T::default()).unwrap()
+            );
+        }
+
+        same::<SocksInboundOption>("{}");
+        same::<SocksOutboundOption>("{}");
+        same::<HttpInboundOption>("{}");
+        same::<MixedInboundOption>("{}");
+        same::<VlessInboundOption>(r#"{"users": []}"#);
+    }
 }
@@ -1,18 +1,24 @@
 //! Outbound Service
 
+use std::sync::Arc;
+
 use tokio::io::{AsyncRead, AsyncWrite, BufStream};
 
 use crate::{
     address::NetworkType,
     direct::{DirectOutbound, DirectStream},
     http::HttpOutbound,
+    jitter::JitterOutbound,
     option::OutboundServiceOption,
-    socks::SocksOutbound,
+    resolver::Resolver,
+    shadowsocks::{ShadowsocksOutbound, ShadowsocksStream},
+    socks::{SocksOutbound, SocksOutboundStream},
+    trojan::TrojanOutbound,
     vless::{VlessOutbound, VlessOutboundStream},
     OutboundResult, OutboundServiceTrait, ServiceAddress,
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OutboundPacket {
     pub typ: NetworkType,
     pub dest: ServiceAddress,
@@ -165,6 +171,9 @@ outbound_service_enum! {
         Vless(VlessOutbound),
         Socks(SocksOutbound),
         Http(HttpOutbound),
+        Shadowsocks(ShadowsocksOutbound),
+        Trojan(TrojanOutbound),
+        Jitter(JitterOutbound<Box<OutboundService>>),
     }
 }
 
@@ -178,6 +187,8 @@ out_stream_traits_enum! {
         Buf(BufStream<S>),
         Direct(DirectStream),
         Vless(VlessOutboundStream<S>),
+        Socks(SocksOutboundStream<S>),
+        Shadowsocks(ShadowsocksStream<S>),
     }
 }
 
@@ -201,11 +212,40 @@ where
 
 impl OutboundService {
     pub fn init(opt: OutboundServiceOption) -> OutboundResult<OutboundService> {
+        Self::init_with_resolver(opt, None)
+    }
+
+    /// Like `init`, handing `resolver` to the services resolving names
+    /// locally so a process shares one resolver.
+    pub fn init_with_resolver(
+        opt: OutboundServiceOption,
+        resolver: Option<Arc<dyn Resolver>>,
+    ) -> OutboundResult<OutboundService> {
         match opt {
-            OutboundServiceOption::Direct => Ok(DirectOutbound.into()),
-            OutboundServiceOption::Vless(o) => Ok(VlessOutbound::init(o)?.into()),
-            OutboundServiceOption::Socks(o) => Ok(SocksOutbound::init(o)?.into()),
+            OutboundServiceOption::Direct(o) => {
+                let mut direct = DirectOutbound::init(o)?;
+                direct.set_resolver(resolver);
+                Ok(direct.into())
+            }
+            OutboundServiceOption::Vless(o) => {
+                if let Some(e) = o.validate_service().into_iter().next() {
+                    return Err(e);
+                }
+                Ok(VlessOutbound::init(o)?.into())
+            }
+            OutboundServiceOption::Socks(o) => {
+                let mut socks = SocksOutbound::init(o)?;
+                socks.set_resolver(resolver);
+                Ok(socks.into())
+            }
             OutboundServiceOption::Http(o) => Ok(HttpOutbound::init(o)?.into()),
+            OutboundServiceOption::Shadowsocks(o) => Ok(ShadowsocksOutbound::init(o)?.into()),
+            OutboundServiceOption::Trojan(o) => Ok(TrojanOutbound::init(o)?.into()),
+            OutboundServiceOption::Jitter(o) => {
+                let outbound = Self::init_with_resolver(*o.outbound, resolver)?;
+                let jitter = o.connect_jitter.map(std::time::Duration::from_millis);
+                Ok(JitterOutbound::with_outbound(outbound, jitter)?.into())
+            }
         }
     }
 }
@@ -226,6 +266,8 @@ mod tests {
         let opt = OutboundServiceOption::Vless(VlessOutboundOption {
             uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
             flow: None,
+            combine_first_packet: false,
+            mux: None,
         });
 
         let svc = OutboundService::init(opt).unwrap();
@@ -242,4 +284,21 @@ mod tests {
 
         println!("{} {:?}", svc.name(), result);
     }
+
+    #[test]
+    fn test_service_outbound_vless_mux() {
+        let opt = VlessOutboundOption {
+            uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+            flow: None,
+            combine_first_packet: false,
+            mux: Some(Default::default()),
+        };
+        assert!(VlessOutbound::init(opt.clone()).is_ok());
+
+        let opt = OutboundServiceOption::Vless(opt);
+        let errors = opt.validate();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        let err = OutboundService::init(opt).unwrap_err();
+        assert!(err.to_string().contains("connect_mux"), "{}", err);
+    }
 }
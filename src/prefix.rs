@@ -0,0 +1,206 @@
+//! Fixed byte prefix before the inner protocol
+
+use std::{fmt, sync::Arc};
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    InboundError, InboundPacket, InboundResult, InboundServiceTrait, OutboundPacket,
+    OutboundResult, OutboundServiceTrait,
+};
+
+/// Bytes written before the inner handshake, either fixed or computed per connection.
+#[derive(Clone)]
+pub enum Prefix {
+    Fixed(Bytes),
+    Computed(Arc<dyn Fn() -> Bytes + Send + Sync>),
+}
+
+impl Prefix {
+    pub fn bytes(&self) -> Bytes {
+        match self {
+            Prefix::Fixed(b) => b.clone(),
+            Prefix::Computed(f) => f(),
+        }
+    }
+}
+
+impl fmt::Debug for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Prefix::Fixed(b) => f.debug_tuple("Fixed").field(b).finish(),
+            Prefix::Computed(_) => f.debug_tuple("Computed").finish(),
+        }
+    }
+}
+
+impl From<Bytes> for Prefix {
+    fn from(value: Bytes) -> Self {
+        Prefix::Fixed(value)
+    }
+}
+
+/// Wraps an outbound and writes a prefix right after connecting, before the
+/// inner handshake.
+#[derive(Debug)]
+pub struct PrefixOutbound<O> {
+    inner: O,
+    prefix: Prefix,
+}
+
+impl<O> PrefixOutbound<O> {
+    pub fn new(inner: O, prefix: impl Into<Prefix>) -> Self {
+        Self {
+            inner,
+            prefix: prefix.into(),
+        }
+    }
+
+    pub fn prefix(&self) -> &Prefix {
+        &self.prefix
+    }
+
+    pub fn inner(&self) -> &O {
+        &self.inner
+    }
+}
+
+impl<S, O> OutboundServiceTrait<S> for PrefixOutbound<O>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    O: OutboundServiceTrait<S>,
+{
+    type Stream = O::Stream;
+
+    async fn handshake(
+        &self,
+        mut stream: S,
+        packet: OutboundPacket,
+    ) -> OutboundResult<Self::Stream> {
+        let prefix = self.prefix.bytes();
+        if !prefix.is_empty() {
+            stream.write_all(&prefix).await?;
+        }
+
+        self.inner.handshake(stream, packet).await
+    }
+}
+
+/// Wraps an inbound and consumes a fixed prefix before the inner handshake,
+/// rejecting connections that do not start with it.
+#[derive(Debug)]
+pub struct PrefixInbound<I> {
+    inner: I,
+    prefix: Bytes,
+}
+
+impl<I> PrefixInbound<I> {
+    pub fn new(inner: I, prefix: Bytes) -> Self {
+        Self { inner, prefix }
+    }
+
+    pub fn prefix(&self) -> &Bytes {
+        &self.prefix
+    }
+
+    pub fn inner(&self) -> &I {
+        &self.inner
+    }
+}
+
+impl<S, I> InboundServiceTrait<S> for PrefixInbound<I>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    I: InboundServiceTrait<S>,
+{
+    type Stream = I::Stream;
+
+    async fn handshake(&self, mut stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+        let mut buf = vec![0u8; self.prefix.len()];
+        stream.read_exact(&mut buf).await?;
+        if buf != self.prefix {
+            return Err(InboundError::InvalidPrefix);
+        }
+
+        self.inner.handshake(stream).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::duplex;
+
+    use crate::{
+        address::NetworkType,
+        trojan::{
+            option::TrojanUserOption, TrojanInbound, TrojanInboundOption, TrojanOutbound,
+            TrojanOutboundOption,
+        },
+        ServiceAddress,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_prefix_round_trip() {
+        let (client, server) = duplex(4096);
+
+        let outbound = PrefixOutbound::new(
+            TrojanOutbound::init(TrojanOutboundOption {
+                password: "test".into(),
+            })
+            .unwrap(),
+            Bytes::from_static(b"GET / HTTP/1.1\r\n"),
+        );
+        let inbound = PrefixInbound::new(
+            TrojanInbound::init(TrojanInboundOption {
+                users: vec![TrojanUserOption {
+                    user: "test".into(),
+                    password: "test".into(),
+                }],
+                unmap_v4: false,
+            })
+            .unwrap(),
+            Bytes::from_static(b"GET / HTTP/1.1\r\n"),
+        );
+
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("example.com".into(), 443),
+        };
+
+        let (out, inb) = tokio::join!(
+            outbound.handshake(client, packet.clone()),
+            inbound.handshake(server)
+        );
+        out.unwrap();
+        let (_, pac) = inb.unwrap();
+
+        assert_eq!(pac.dest, packet.dest);
+        assert_eq!(pac.detail, "test");
+    }
+
+    #[tokio::test]
+    async fn test_prefix_mismatch() {
+        let inbound = PrefixInbound::new(
+            TrojanInbound::init(TrojanInboundOption {
+                users: vec![TrojanUserOption {
+                    user: "test".into(),
+                    password: "test".into(),
+                }],
+                unmap_v4: false,
+            })
+            .unwrap(),
+            Bytes::from_static(b"abcd"),
+        );
+
+        let err = inbound
+            .handshake(Cursor::new(b"abce".to_vec()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, InboundError::InvalidPrefix));
+    }
+}
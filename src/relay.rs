@@ -0,0 +1,162 @@
+//! Relay between an inbound and an outbound stream
+
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Buffer of `tokio::io::copy_bidirectional`.
+pub const DEFAULT_RELAY_BUFFER: usize = 8 * 1024;
+
+fn default_buffer_size() -> usize {
+    DEFAULT_RELAY_BUFFER
+}
+
+/// Relay settings, the defaults match `tokio::io::copy_bidirectional`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayConfig {
+    /// Bytes buffered per direction, the most moved by a single write.
+    /// Larger buffers mean fewer syscalls on high throughput tunnels. 0
+    /// uses the default.
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: DEFAULT_RELAY_BUFFER,
+        }
+    }
+}
+
+/// Copy data both ways between `a` and `b` until both sides are done,
+/// returning the bytes moved from `a` to `b` and from `b` to `a`.
+///
+/// A side reaching EOF has the other side's write half shut down while the
+/// opposite direction keeps running.
+pub async fn relay<A, B>(a: &mut A, b: &mut B, config: &RelayConfig) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    // an empty buffer reads as EOF right away
+    let size = match config.buffer_size {
+        0 => DEFAULT_RELAY_BUFFER,
+        n => n,
+    };
+    tokio::io::copy_bidirectional_with_sizes(a, b, size, size).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Stream recording the largest single write, to check the relay buffer.
+    struct MaxWriteStream<S> {
+        inner: S,
+        max_write: usize,
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for MaxWriteStream<S> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for MaxWriteStream<S> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            this.max_write = this.max_write.max(buf.len());
+            Pin::new(&mut this.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Push `len` bytes each way through a relay with `config` and return the
+    /// largest write the relay made towards `b`.
+    async fn transfer(config: RelayConfig, len: usize) -> usize {
+        let (mut client, mut a) = duplex(1 << 20);
+        let (b, mut server) = duplex(1 << 20);
+        let mut b = MaxWriteStream {
+            inner: b,
+            max_write: 0,
+        };
+
+        let upload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let download: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+
+        let relay = async {
+            let res = relay(&mut a, &mut b, &config).await.unwrap();
+            assert_eq!(res, (len as u64, len as u64));
+        };
+        let client = async {
+            let (mut r, mut w) = tokio::io::split(&mut client);
+            let write = async {
+                w.write_all(&upload).await.unwrap();
+                w.shutdown().await.unwrap();
+            };
+            let mut buf = vec![];
+            tokio::join!(write, r.read_to_end(&mut buf)).1.unwrap();
+            assert!(buf == download);
+        };
+        let server = async {
+            let (mut r, mut w) = tokio::io::split(&mut server);
+            let write = async {
+                w.write_all(&download).await.unwrap();
+                w.shutdown().await.unwrap();
+            };
+            let mut buf = vec![];
+            tokio::join!(write, r.read_to_end(&mut buf)).1.unwrap();
+            assert!(buf == upload);
+        };
+        tokio::join!(relay, client, server);
+
+        b.max_write
+    }
+
+    #[tokio::test]
+    async fn test_relay_large_transfer() {
+        let max_write = transfer(RelayConfig::default(), 8 << 20).await;
+        assert!(max_write <= DEFAULT_RELAY_BUFFER, "{}", max_write);
+    }
+
+    #[tokio::test]
+    async fn test_relay_buffer_size() {
+        let max_write = transfer(
+            RelayConfig {
+                buffer_size: 64 * 1024,
+            },
+            4 << 20,
+        )
+        .await;
+        assert!(max_write > DEFAULT_RELAY_BUFFER, "{}", max_write);
+        assert!(max_write <= 64 * 1024, "{}", max_write);
+
+        let max_write = transfer(RelayConfig { buffer_size: 0 }, 4 << 20).await;
+        assert!(max_write > 1, "{}", max_write);
+        assert!(max_write <= DEFAULT_RELAY_BUFFER, "{}", max_write);
+    }
+}
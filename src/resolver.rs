@@ -0,0 +1,255 @@
+//! Name resolution shared by outbounds
+
+use std::{
+    fmt::Debug,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+use crate::{Address, ServiceAddress};
+
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// Resolves domains for outbounds, one instance can be shared by all
+/// services of a process through `Arc<dyn Resolver>`.
+pub trait Resolver: Debug + Send + Sync {
+    fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> ResolveFuture<'a>;
+}
+
+/// Resolver backed by the system `getaddrinfo`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move { Ok(tokio::net::lookup_host((domain, port)).await?.collect()) })
+    }
+}
+
+/// Resolver settings shared by the outbounds of a process.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResolverOption {
+    /// Most resolutions running at once, the rest wait for a free slot.
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+}
+
+impl ResolverOption {
+    /// Build the system resolver with these settings.
+    pub fn build(&self) -> Arc<dyn Resolver> {
+        match self.max_concurrent {
+            Some(limit) => Arc::new(LimitedResolver::new(SystemResolver, limit)),
+            None => Arc::new(SystemResolver),
+        }
+    }
+}
+
+/// Runs at most `limit` resolutions of the inner resolver at once.
+#[derive(Debug)]
+pub struct LimitedResolver<R> {
+    inner: R,
+    permits: Semaphore,
+}
+
+impl<R: Resolver> LimitedResolver<R> {
+    pub fn new(inner: R, limit: usize) -> Self {
+        Self {
+            inner,
+            permits: Semaphore::new(limit.max(1)),
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R: Resolver> Resolver for LimitedResolver<R> {
+    fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let _permit = self
+                .permits
+                .acquire()
+                .await
+                .map_err(|_| io::Error::other("resolver closed"))?;
+            self.inner.resolve(domain, port).await
+        })
+    }
+}
+
+/// Lets the `Sync` service futures await a resolver future that is only
+/// `Send`.
+struct SyncFuture<F>(F);
+
+// SAFETY: the future is only reachable through `Pin<&mut Self>` in `poll`,
+// a shared reference gives no access to it.
+unsafe impl<F: Send> Sync for SyncFuture<F> {}
+
+impl<F: Future + Unpin> Future for SyncFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.get_mut().0).poll(cx)
+    }
+}
+
+impl ServiceAddress {
+    /// Resolve into socket addresses with `resolver`, or the system resolver
+    /// if none is given.
+    pub async fn resolve_with(
+        &self,
+        resolver: Option<&Arc<dyn Resolver>>,
+    ) -> io::Result<Vec<SocketAddr>> {
+        match (&self.addr, resolver) {
+            (Address::Domain(domain), Some(resolver)) => {
+                SyncFuture(resolver.resolve(domain, self.port)).await
+            }
+            _ => self.resolve().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use tokio::{io::duplex, net::TcpListener};
+
+    use crate::{
+        address::NetworkType,
+        option::OutboundServiceOption,
+        socks::{SocksInbound, SocksInboundOption, SocksOutboundOption},
+        InboundServiceTrait, OutboundPacket, OutboundService, OutboundServiceTrait,
+    };
+
+    use super::*;
+
+    /// Resolves everything to localhost and counts the lookups.
+    #[derive(Debug, Default)]
+    struct CountingResolver(AtomicUsize);
+
+    impl Resolver for CountingResolver {
+        fn resolve<'a>(&'a self, _domain: &'a str, port: u16) -> ResolveFuture<'a> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_resolver() {
+        let counter = Arc::new(CountingResolver::default());
+        let resolver: Arc<dyn Resolver> = counter.clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("counted.test".into(), port),
+        };
+
+        let direct = OutboundService::init_with_resolver(
+            OutboundServiceOption::Direct(Default::default()),
+            Some(resolver.clone()),
+        )
+        .unwrap();
+        let (s1, _s2) = duplex(64);
+        direct.handshake(s1, packet.clone()).await.unwrap();
+        listener.accept().await.unwrap();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+        let socks = OutboundService::init_with_resolver(
+            OutboundServiceOption::Socks(SocksOutboundOption {
+                resolve_locally: true,
+                ..Default::default()
+            }),
+            Some(resolver),
+        )
+        .unwrap();
+
+        let socks_in = SocksInbound::init(SocksInboundOption::default()).unwrap();
+        let (s1, s2) = duplex(4096);
+        let (out, inb) = tokio::join!(socks.handshake(s1, packet), socks_in.handshake(s2));
+        out.unwrap();
+        let (_, pac) = inb.unwrap();
+        assert_eq!(pac.dest, ServiceAddress::new("127.0.0.1".into(), port));
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+    }
+
+    /// Tracks how many resolutions run at once.
+    #[derive(Debug, Default)]
+    struct GatedResolver {
+        running: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    impl Resolver for GatedResolver {
+        fn resolve<'a>(&'a self, _domain: &'a str, port: u16) -> ResolveFuture<'a> {
+            Box::pin(async move {
+                let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+                self.peak.fetch_max(running, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                self.running.fetch_sub(1, Ordering::SeqCst);
+                Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))])
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_limited_resolver() {
+        let resolver = Arc::new(LimitedResolver::new(GatedResolver::default(), 3));
+
+        let mut set = tokio::task::JoinSet::new();
+        for i in 0..20 {
+            let resolver = resolver.clone();
+            set.spawn(async move {
+                let domain = format!("host{}.test", i);
+                resolver.resolve(&domain, 80).await.unwrap()
+            });
+        }
+        while let Some(res) = set.join_next().await {
+            assert_eq!(res.unwrap().len(), 1);
+        }
+
+        assert_eq!(resolver.inner().peak.load(Ordering::SeqCst), 3);
+        assert_eq!(resolver.inner().running.load(Ordering::SeqCst), 0);
+    }
+
+    /// Holds a `Send` but not `Sync` value across an await.
+    #[derive(Debug)]
+    struct CellResolver;
+
+    impl Resolver for CellResolver {
+        fn resolve<'a>(&'a self, _domain: &'a str, port: u16) -> ResolveFuture<'a> {
+            Box::pin(async move {
+                let port = std::cell::Cell::new(port);
+                tokio::task::yield_now().await;
+                Ok(vec![SocketAddr::from(([127, 0, 0, 1], port.get()))])
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_only_resolver() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("cell.test".into(), port),
+        };
+
+        let direct = crate::direct::DirectOutbound::with_resolver(Arc::new(CellResolver));
+        let (s1, _s2) = duplex(64);
+        direct.handshake(s1, packet).await.unwrap();
+        listener.accept().await.unwrap();
+    }
+}
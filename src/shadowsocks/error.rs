@@ -0,0 +1,26 @@
+//! Shadowsocks Error
+
+use thiserror::Error;
+
+use crate::{crypto::CryptoError, error::AddressError};
+
+#[derive(Debug, Error)]
+pub enum ShadowsocksError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    InvalidAddress(#[from] AddressError),
+    #[error("invalid salt length: {0}")]
+    InvalidSalt(usize),
+    #[error("{0}")]
+    Crypto(#[from] CryptoError),
+}
+
+impl From<ShadowsocksError> for std::io::Error {
+    fn from(value: ShadowsocksError) -> Self {
+        match value {
+            ShadowsocksError::Io(e) => e,
+            other => std::io::Error::new(std::io::ErrorKind::InvalidData, other),
+        }
+    }
+}
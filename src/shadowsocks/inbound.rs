@@ -0,0 +1,91 @@
+//! Shadowsocks service for inbound
+
+use std::borrow::Cow;
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+use crate::{
+    address::NetworkType, Address, InboundError, InboundPacket, InboundResult,
+    InboundServiceStream, InboundServiceTrait, ServiceAddress,
+};
+
+use super::{
+    protocol::{evp_bytes_to_key, CipherKind, ShadowsocksAddrType},
+    ShadowsocksError, ShadowsocksInboundOption, ShadowsocksStream,
+};
+
+#[derive(Debug)]
+pub struct ShadowsocksInbound {
+    method: CipherKind,
+    key: Bytes,
+    unmap_v4: bool,
+}
+
+impl ShadowsocksInboundOption {
+    pub fn validate(&self) -> Vec<InboundError> {
+        let mut errors = vec![];
+        if self.password.is_empty() {
+            errors.push(InboundError::Option(
+                "empty shadowsocks password".to_string(),
+            ));
+        }
+        errors
+    }
+}
+
+impl ShadowsocksInbound {
+    pub fn init(option: ShadowsocksInboundOption) -> InboundResult<Self> {
+        if let Some(e) = option.validate().into_iter().next() {
+            return Err(e);
+        }
+
+        let key = evp_bytes_to_key(option.password.as_bytes(), option.method.key_len());
+
+        Ok(Self {
+            method: option.method,
+            key,
+            unmap_v4: option.unmap_v4,
+        })
+    }
+}
+
+impl<S> InboundServiceTrait<S> for ShadowsocksInbound
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    type Stream = ShadowsocksStream<S>;
+
+    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+        let mut stream = ShadowsocksStream::new(stream, self.method, self.key.clone());
+
+        let mut addr = Address::read::<_, ShadowsocksAddrType>(&mut stream)
+            .await
+            .map_err(|e| InboundError::Handshake(ShadowsocksError::from(e).into()))?;
+        if self.unmap_v4 {
+            addr = addr.unmap_v4();
+        }
+        let port = stream
+            .read_u16()
+            .await
+            .map_err(|e| InboundError::Handshake(ShadowsocksError::from(e).into()))?;
+
+        Ok((
+            stream,
+            InboundPacket {
+                typ: NetworkType::Tcp,
+                dest: ServiceAddress { addr, port },
+                detail: Cow::Borrowed(""),
+            },
+        ))
+    }
+}
+
+impl<S> From<ShadowsocksStream<S>> for InboundServiceStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    fn from(value: ShadowsocksStream<S>) -> Self {
+        Self::Shadowsocks(value)
+    }
+}
@@ -0,0 +1,124 @@
+//! Shadowsocks AEAD service
+
+pub mod option;
+pub use option::{ShadowsocksInboundOption, ShadowsocksOutboundOption};
+
+pub mod inbound;
+pub use inbound::ShadowsocksInbound;
+
+pub mod outbound;
+pub use outbound::ShadowsocksOutbound;
+
+pub mod protocol;
+pub use protocol::{CipherKind, ShadowsocksStream};
+
+pub mod error;
+pub use error::ShadowsocksError;
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        address::NetworkType, InboundServiceTrait, OutboundPacket, OutboundServiceTrait,
+        ServiceAddress,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shadowsocks_svc() {
+        for method in [CipherKind::Aes256Gcm, CipherKind::Chacha20Poly1305] {
+            let (s1, s2) = duplex(4096);
+
+            let ss_in = ShadowsocksInbound::init(ShadowsocksInboundOption {
+                method,
+                password: "test".into(),
+                unmap_v4: false,
+            })
+            .unwrap();
+            let ss_out = ShadowsocksOutbound::init(ShadowsocksOutboundOption {
+                method,
+                password: "test".into(),
+            })
+            .unwrap();
+
+            let server = tokio::spawn(async move {
+                let (mut s, p) = ss_in.handshake(s2).await.unwrap();
+                assert_eq!(p.typ, NetworkType::Tcp);
+                assert_eq!(p.dest.to_string(), "example.com:443");
+                let mut buf = [0u8; 5];
+                s.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+                s.write_all(b"byebye").await.unwrap();
+                s.flush().await.unwrap();
+            });
+
+            let packet = OutboundPacket {
+                typ: NetworkType::Tcp,
+                dest: ServiceAddress::new("example.com".into(), 443),
+            };
+            let mut s = ss_out.handshake(s1, packet).await.unwrap();
+            s.write_all(b"hello").await.unwrap();
+            s.flush().await.unwrap();
+            let mut buf = [0u8; 6];
+            s.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"byebye");
+
+            server.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shadowsocks_wrong_password() {
+        let (s1, s2) = duplex(4096);
+
+        let ss_in = ShadowsocksInbound::init(ShadowsocksInboundOption {
+            method: CipherKind::Aes256Gcm,
+            password: "test".into(),
+            unmap_v4: false,
+        })
+        .unwrap();
+        let ss_out = ShadowsocksOutbound::init(ShadowsocksOutboundOption {
+            method: CipherKind::Aes256Gcm,
+            password: "wrong".into(),
+        })
+        .unwrap();
+
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("127.0.0.1".into(), 80),
+        };
+        let _s = ss_out.handshake(s1, packet).await.unwrap();
+
+        assert!(ss_in.handshake(s2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shadowsocks_unmap_v4() {
+        for (unmap_v4, dest) in [(false, "[::ffff:1.2.3.4]:80"), (true, "1.2.3.4:80")] {
+            let (s1, s2) = duplex(4096);
+
+            let ss_in = ShadowsocksInbound::init(ShadowsocksInboundOption {
+                method: CipherKind::Aes256Gcm,
+                password: "test".into(),
+                unmap_v4,
+            })
+            .unwrap();
+            let ss_out = ShadowsocksOutbound::init(ShadowsocksOutboundOption {
+                method: CipherKind::Aes256Gcm,
+                password: "test".into(),
+            })
+            .unwrap();
+
+            let packet = OutboundPacket {
+                typ: NetworkType::Tcp,
+                dest: ServiceAddress::new("::ffff:1.2.3.4".parse().unwrap(), 80),
+            };
+            let (out, inb) = tokio::join!(ss_out.handshake(s1, packet), ss_in.handshake(s2));
+            out.unwrap();
+            let (_, pac) = inb.unwrap();
+            assert_eq!(pac.dest.to_string(), dest);
+        }
+    }
+}
@@ -0,0 +1,20 @@
+//! Shadowsocks service option
+
+use serde::{Deserialize, Serialize};
+
+use super::protocol::CipherKind;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShadowsocksInboundOption {
+    pub method: CipherKind,
+    pub password: String,
+    /// See `SocksInboundOption::unmap_v4`.
+    #[serde(default)]
+    pub unmap_v4: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShadowsocksOutboundOption {
+    pub method: CipherKind,
+    pub password: String,
+}
@@ -0,0 +1,83 @@
+//! Shadowsocks service for outbound
+
+use bytes::{BufMut, Bytes};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    address::NetworkType, OutboundError, OutboundPacket, OutboundResult, OutboundServiceStream,
+    OutboundServiceTrait,
+};
+
+use super::{
+    protocol::{evp_bytes_to_key, CipherKind, ShadowsocksAddrType},
+    ShadowsocksError, ShadowsocksOutboundOption, ShadowsocksStream,
+};
+
+#[derive(Debug)]
+pub struct ShadowsocksOutbound {
+    method: CipherKind,
+    key: Bytes,
+}
+
+impl ShadowsocksOutboundOption {
+    pub fn validate(&self) -> Vec<OutboundError> {
+        let mut errors = vec![];
+        if self.password.is_empty() {
+            errors.push(OutboundError::Option(
+                "empty shadowsocks password".to_string(),
+            ));
+        }
+        errors
+    }
+}
+
+impl ShadowsocksOutbound {
+    pub fn init(option: ShadowsocksOutboundOption) -> OutboundResult<Self> {
+        if let Some(e) = option.validate().into_iter().next() {
+            return Err(e);
+        }
+
+        let key = evp_bytes_to_key(option.password.as_bytes(), option.method.key_len());
+
+        Ok(Self {
+            method: option.method,
+            key,
+        })
+    }
+}
+
+impl<S> OutboundServiceTrait<S> for ShadowsocksOutbound
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    type Stream = ShadowsocksStream<S>;
+
+    async fn handshake(&self, stream: S, packet: OutboundPacket) -> OutboundResult<Self::Stream> {
+        if packet.typ != NetworkType::Tcp {
+            return Err(OutboundError::InvalidType(packet.typ));
+        }
+
+        let mut header = vec![];
+        packet
+            .dest
+            .addr
+            .put_to_buf::<_, ShadowsocksAddrType>(&mut header)
+            .map_err(|e| OutboundError::Handshake(ShadowsocksError::from(e).into()))?;
+        header.put_u16(packet.dest.port);
+
+        let mut stream = ShadowsocksStream::new(stream, self.method, self.key.clone());
+        stream.write_all(&header).await?;
+        stream.flush().await?;
+
+        Ok(stream)
+    }
+}
+
+impl<S> From<ShadowsocksStream<S>> for OutboundServiceStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    fn from(value: ShadowsocksStream<S>) -> Self {
+        Self::Shadowsocks(value)
+    }
+}
@@ -0,0 +1,391 @@
+//! shadowsocks protocol - AEAD cipher and salted chunked stream
+
+use std::{pin::Pin, task::Poll};
+
+use aes_gcm::{aead::KeyInit, Aes256Gcm};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use md5::{Digest, Md5};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    crypto::{AeadCipher, AeadStream, CryptoError},
+    impl_addr_type, AddrType, AddrTypeConvert,
+};
+
+use super::ShadowsocksError;
+
+pub use crate::crypto::aead::MAX_PAYLOAD_SIZE;
+
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const SUBKEY_INFO: &[u8] = b"ss-subkey";
+
+impl_addr_type! {
+    pub enum ShadowsocksAddrType {
+        Ipv4 = 1,
+        Ipv6 = 4,
+        Fqdn = 3,
+        Unknown = 255,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CipherKind {
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-ietf-poly1305")]
+    Chacha20Poly1305,
+}
+
+impl CipherKind {
+    pub fn key_len(&self) -> usize {
+        match self {
+            CipherKind::Aes256Gcm => 32,
+            CipherKind::Chacha20Poly1305 => 32,
+        }
+    }
+
+    pub fn salt_len(&self) -> usize {
+        self.key_len()
+    }
+}
+
+/// Derive the master key from a password (OpenSSL `EVP_BytesToKey` with MD5).
+pub fn evp_bytes_to_key(password: &[u8], key_len: usize) -> Bytes {
+    let mut key = BytesMut::with_capacity(key_len + 16);
+    let mut prev: Vec<u8> = vec![];
+
+    while key.len() < key_len {
+        let mut hasher = Md5::new();
+        hasher.update(&prev);
+        hasher.update(password);
+        prev = hasher.finalize().to_vec();
+        key.put_slice(&prev);
+    }
+
+    key.truncate(key_len);
+    key.freeze()
+}
+
+#[derive(Clone)]
+enum Aead {
+    Aes256Gcm(Box<Aes256Gcm>),
+    Chacha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+/// A session cipher for one direction of a connection. Its nonces are
+/// counted by the `AeadStream` sealing or opening with it.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: Aead,
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cipher").finish_non_exhaustive()
+    }
+}
+
+impl Cipher {
+    /// Create a session cipher with the subkey derived from `key` and `salt` (HKDF-SHA1).
+    pub fn new(kind: CipherKind, key: &[u8], salt: &[u8]) -> Result<Self, ShadowsocksError> {
+        if salt.len() != kind.salt_len() {
+            return Err(ShadowsocksError::InvalidSalt(salt.len()));
+        }
+
+        let mut subkey = vec![0u8; kind.key_len()];
+        Hkdf::<Sha1>::new(Some(salt), key)
+            .expand(SUBKEY_INFO, &mut subkey)
+            .map_err(|_| ShadowsocksError::InvalidSalt(salt.len()))?;
+
+        let aead = match kind {
+            CipherKind::Aes256Gcm => Aead::Aes256Gcm(Box::new(
+                Aes256Gcm::new_from_slice(&subkey).map_err(|_| CryptoError::Encrypt)?,
+            )),
+            CipherKind::Chacha20Poly1305 => Aead::Chacha20Poly1305(Box::new(
+                ChaCha20Poly1305::new_from_slice(&subkey).map_err(|_| CryptoError::Encrypt)?,
+            )),
+        };
+
+        Ok(Self { aead })
+    }
+}
+
+impl AeadCipher for Cipher {
+    const NONCE_LEN: usize = NONCE_SIZE;
+    const TAG_LEN: usize = TAG_SIZE;
+
+    fn seal(&self, nonce: &[u8], data: &mut [u8], tag: &mut [u8]) -> Result<(), CryptoError> {
+        match self.aead {
+            Aead::Aes256Gcm(ref c) => AeadCipher::seal(c.as_ref(), nonce, data, tag),
+            Aead::Chacha20Poly1305(ref c) => AeadCipher::seal(c.as_ref(), nonce, data, tag),
+        }
+    }
+
+    fn open(&self, nonce: &[u8], data: &mut [u8], tag: &[u8]) -> Result<(), CryptoError> {
+        match self.aead {
+            Aead::Aes256Gcm(ref c) => AeadCipher::open(c.as_ref(), nonce, data, tag),
+            Aead::Chacha20Poly1305(ref c) => AeadCipher::open(c.as_ref(), nonce, data, tag),
+        }
+    }
+}
+
+/// Stream speaking the shadowsocks AEAD framing:
+/// `[salt][encrypted length][length tag][encrypted payload][payload tag]...`,
+/// an `AeadStream` keyed by the salt sent ahead of each direction.
+#[derive(Debug)]
+pub struct ShadowsocksStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    inner: AeadStream<S, Cipher>,
+    kind: CipherKind,
+    key: Bytes,
+    /// Own salt, until it is written ahead of the first chunk.
+    salt: Bytes,
+    /// Peer's salt, until all of it is read.
+    peer_salt: Option<BytesMut>,
+}
+
+impl<S> ShadowsocksStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    pub fn new(inner: S, kind: CipherKind, key: Bytes) -> Self {
+        let mut salt = vec![0u8; kind.salt_len()];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let enc = Cipher::new(kind, &key, &salt).expect("salt of the cipher's length");
+
+        Self {
+            inner: AeadStream::with_enc(inner, enc),
+            kind,
+            key,
+            salt: salt.into(),
+            peer_salt: Some(BytesMut::with_capacity(kind.salt_len())),
+        }
+    }
+
+    /// Read the peer's salt and key the read side with it. `false` on EOF
+    /// before the salt.
+    fn poll_read_salt(&mut self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<bool>> {
+        let Some(salt) = self.peer_salt.as_mut() else {
+            return Poll::Ready(Ok(true));
+        };
+
+        let need = self.kind.salt_len();
+        while salt.len() < need {
+            let start = salt.len();
+            salt.resize(need, 0);
+            let mut rb = ReadBuf::new(&mut salt[start..]);
+            let res = Pin::new(self.inner.get_mut()).poll_read(cx, &mut rb);
+            let n = rb.filled().len();
+            salt.truncate(start + n);
+            match res {
+                Poll::Ready(Ok(())) if n == 0 => {
+                    if start == 0 {
+                        return Poll::Ready(Ok(false));
+                    }
+                    return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+                }
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let dec = Cipher::new(self.kind, &self.key, salt)?;
+        self.inner.set_dec(dec);
+        self.peer_salt = None;
+
+        Poll::Ready(Ok(true))
+    }
+
+    fn poll_write_salt(&mut self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.salt.is_empty() {
+            let n = match Pin::new(self.inner.get_mut()).poll_write(cx, &self.salt) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.salt.advance(n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Finish a salt that is partly written. One not started yet waits
+    /// for the first chunk.
+    fn poll_finish_salt(&mut self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.salt.len() == self.kind.salt_len() {
+            return Poll::Ready(Ok(()));
+        }
+        self.poll_write_salt(cx)
+    }
+}
+
+impl<S> AsyncRead for ShadowsocksStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        match this.poll_read_salt(cx) {
+            Poll::Ready(Ok(true)) => Pin::new(&mut this.inner).poll_read(cx, buf),
+            // clean EOF before the salt
+            Poll::Ready(Ok(false)) => Poll::Ready(Ok(())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S> AsyncWrite for ShadowsocksStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = self.get_mut();
+
+        if !buf.is_empty() {
+            match this.poll_write_salt(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let this = self.get_mut();
+
+        match this.poll_finish_salt(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let this = self.get_mut();
+
+        match this.poll_finish_salt(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn test_cipher_round_trip() {
+        for kind in [CipherKind::Aes256Gcm, CipherKind::Chacha20Poly1305] {
+            let key = evp_bytes_to_key(b"password", kind.key_len());
+            let salt = vec![7u8; kind.salt_len()];
+
+            let enc = Cipher::new(kind, &key, &salt).unwrap();
+            let dec = Cipher::new(kind, &key, &salt).unwrap();
+
+            for (i, msg) in [&b"hello"[..], &b"world"[..]].into_iter().enumerate() {
+                let nonce = [i as u8; NONCE_SIZE];
+                let mut data = msg.to_vec();
+                let mut tag = [0u8; TAG_SIZE];
+                enc.seal(&nonce, &mut data, &mut tag).unwrap();
+                assert_ne!(data, msg);
+                dec.open(&nonce, &mut data, &tag).unwrap();
+                assert_eq!(data, msg);
+            }
+
+            let nonce = [0u8; NONCE_SIZE];
+            let mut data = b"tampered".to_vec();
+            let mut tag = [0u8; TAG_SIZE];
+            enc.seal(&nonce, &mut data, &mut tag).unwrap();
+            tag[0] ^= 1;
+            assert!(dec.open(&nonce, &mut data, &tag).is_err());
+
+            assert!(Cipher::new(kind, &key, &salt[1..]).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shadowsocks_stream_salt() {
+        let kind = CipherKind::Chacha20Poly1305;
+        let key = evp_bytes_to_key(b"password", kind.key_len());
+
+        // nothing is sent before the first chunk
+        let mut stream = ShadowsocksStream::new(Cursor::new(vec![]), kind, key.clone());
+        stream.flush().await.unwrap();
+        assert!(stream.inner.get_ref().get_ref().is_empty());
+
+        stream.write_all(b"hello").await.unwrap();
+        let sealed = stream.inner.into_inner().into_inner();
+        assert_eq!(sealed.len(), kind.salt_len() + 2 + TAG_SIZE + 5 + TAG_SIZE);
+
+        let mut stream = ShadowsocksStream::new(Cursor::new(sealed.clone()), kind, key.clone());
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+
+        // EOF before the salt is clean, inside it is not
+        let mut stream = ShadowsocksStream::new(Cursor::new(vec![]), kind, key.clone());
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        let mut stream = ShadowsocksStream::new(Cursor::new(sealed[..4].to_vec()), kind, key);
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_shadowsocks_stream() {
+        for kind in [CipherKind::Aes256Gcm, CipherKind::Chacha20Poly1305] {
+            let key = evp_bytes_to_key(b"password", kind.key_len());
+            let (s1, s2) = duplex(1024);
+
+            let mut client = ShadowsocksStream::new(s1, kind, key.clone());
+            let mut server = ShadowsocksStream::new(s2, kind, key);
+
+            let data: Vec<u8> = (0..MAX_PAYLOAD_SIZE * 3 + 100).map(|i| i as u8).collect();
+            let expected = data.clone();
+
+            let writer = tokio::spawn(async move {
+                client.write_all(&data).await.unwrap();
+                client.shutdown().await.unwrap();
+            });
+
+            let mut received = vec![];
+            server.read_to_end(&mut received).await.unwrap();
+            writer.await.unwrap();
+
+            assert_eq!(received, expected);
+        }
+    }
+}
@@ -1,82 +1,173 @@
 //! Socks service for inbound
 
-use std::borrow::Cow;
+use std::{borrow::Cow, fmt, net::SocketAddr, sync::Arc, time::Duration};
 
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::io::{AsyncRead, AsyncWrite, BufStream};
 
 use crate::{
-    address::NetworkType, Address, InboundError, InboundPacket, InboundResult, InboundServiceTrait,
+    address::NetworkType,
+    crypto::ct_contains,
+    inbound::max_auth_attempts,
+    stream::{buf_stream, read_buffer_size},
+    write_all_timeout, Address, InboundError, InboundPacket, InboundResult, InboundServiceTrait,
     ServiceAddress,
 };
 
 use super::{
     option::SocksAuthOption,
-    protocol::{SocksAddr, SocksAuth, SocksCommand, SocksError, SocksServerHandshake, SocksStatus},
-    SocksInboundOption,
+    protocol::{
+        SocksAddr, SocksAuth, SocksCommand, SocksError, SocksReply, SocksRequest,
+        SocksServerHandshake, SocksStatus, SocksVersion,
+    },
+    SocksInboundOption, SocksReplyAddr,
 };
 
-#[derive(Debug)]
+/// Checks the user id of a socks4 request, empty when the client sent none.
+pub type Socks4Verifier = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
 pub struct SocksInbound {
     users: Vec<SocksAuth>,
+    socks4_verifier: Option<Socks4Verifier>,
+    udp_enabled: bool,
+    allow_v4: bool,
+    socks4_strict: bool,
+    reply_timeout: Option<Duration>,
+    reply_addr: SocksReplyAddr,
+    read_buffer: usize,
+    max_auth_attempts: Option<u32>,
+    eager_reply: bool,
+    unmap_v4: bool,
+    strict_domain: bool,
+}
+
+impl SocksInboundOption {
+    pub fn validate(&self) -> Vec<InboundError> {
+        let mut errors = vec![];
+        errors.extend(read_buffer_size(self.read_buffer).err());
+        errors.extend(max_auth_attempts(self.max_auth_attempts).err());
+        errors
+    }
 }
 
 impl SocksInbound {
     pub fn init(option: SocksInboundOption) -> InboundResult<Self> {
+        if let Some(e) = option.validate().into_iter().next() {
+            return Err(e);
+        }
+
         let mut users = vec![];
         if !option.auth.is_empty() {
-            for user in option.auth.into_iter() {
+            for user in option.auth {
                 if user != SocksAuthOption::NoAuth {
                     users.push(user.into())
                 }
             }
         }
 
-        Ok(Self { users })
+        Ok(Self {
+            users,
+            socks4_verifier: None,
+            udp_enabled: option.udp_enabled,
+            allow_v4: option.allow_v4,
+            socks4_strict: option.socks4_strict,
+            reply_timeout: option.reply_timeout.map(Duration::from_millis),
+            reply_addr: option.reply_addr,
+            read_buffer: read_buffer_size(option.read_buffer)?,
+            max_auth_attempts: max_auth_attempts(option.max_auth_attempts)?,
+            eager_reply: option.eager_reply,
+            unmap_v4: option.unmap_v4,
+            strict_domain: option.strict_domain,
+        })
+    }
+
+    /// Set the address reported to clients on success, e.g. the bound
+    /// address of a UDP relay.
+    pub fn set_reply_addr(&mut self, addr: impl Into<SocksReplyAddr>) {
+        self.reply_addr = addr.into();
+    }
+
+    pub fn reply_addr(&self) -> SocksReplyAddr {
+        self.reply_addr
+    }
+
+    /// Check socks4 user ids with `verifier` instead of the configured
+    /// `Socks4` entries, e.g. against an ident service.
+    pub fn set_socks4_verifier(&mut self, verifier: Socks4Verifier) {
+        self.socks4_verifier = Some(verifier);
     }
 
     pub fn auth(&self, other: &SocksAuth) -> bool {
-        if self.users.is_empty() && other == &SocksAuth::NoAuth {
+        // an empty socks4 user id is what a client without credentials sends
+        let no_auth = match other {
+            SocksAuth::NoAuth => true,
+            SocksAuth::Socks4(user) => user.is_empty(),
+            SocksAuth::Username(..) => false,
+        };
+        if self.users.is_empty() && no_auth {
             return true;
         }
 
-        self.users.contains(other)
+        ct_contains(&self.users, |user| user.ct_eq(other))
     }
-}
-
-impl<S> InboundServiceTrait<S> for SocksInbound
-where
-    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
-{
-    type Stream = BufStream<S>;
 
-    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, crate::InboundPacket)> {
-        let mut stream = BufStream::new(stream);
+    /// Run the handshake on `stream` and return the requested destination.
+    ///
+    /// With `eager_reply` the success reply is sent before returning and no
+    /// pending reply is given back. Otherwise the client has not been
+    /// answered yet, the caller connects to the destination first and then
+    /// reports the outcome with `SocksPendingReply::reply`.
+    pub async fn accept<S>(
+        &self,
+        stream: S,
+    ) -> InboundResult<(BufStream<S>, InboundPacket<'_>, Option<SocksPendingReply>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = buf_stream(stream, self.read_buffer);
 
-        let mut srv_hand = SocksServerHandshake::new();
+        let mut srv_hand = SocksServerHandshake::new()
+            .with_allow_v4(self.allow_v4)
+            .with_socks4_strict(self.socks4_strict)
+            .with_strict_domain(self.strict_domain)
+            .with_max_auth_attempts(self.max_auth_attempts)
+            .with_require_auth(!self.users.is_empty());
 
         let request = srv_hand
-            .accept(&mut stream)
+            .accept_with(&mut stream, |auth| self.auth(auth))
             .await
             .map_err(|e| InboundError::Handshake(e.into()))?;
 
-        if !self.auth(request.auth()) {
+        let authorized = match (&self.socks4_verifier, request.version()) {
+            (Some(verify), SocksVersion::V4) => match request.auth() {
+                SocksAuth::Socks4(user) => verify(user),
+                _ => verify(b""),
+            },
+            _ => self.auth(request.auth()),
+        };
+
+        // rejections are best effort, the error returned below is the result
+        if !authorized {
             if let Ok(msg) = request.reply(SocksStatus::NOT_ALLOWED, None) {
-                let _ = stream.write_all(&msg).await;
-                let _ = stream.flush().await;
+                let _ = write_all_timeout(&mut stream, &msg, self.reply_timeout).await;
             }
 
-            return Err(InboundError::Handshake(
-                SocksError::InvalidAuth(request.auth().to_string()).into(),
-            ));
+            // no credentials at all is a client without auth, not a bad guess
+            let err = match request.auth() {
+                SocksAuth::NoAuth => SocksError::AuthRequired,
+                SocksAuth::Socks4(user) if user.is_empty() => SocksError::AuthRequired,
+                SocksAuth::Socks4(user) | SocksAuth::Username(user, _) => {
+                    SocksError::AuthRejected(String::from_utf8_lossy(user).into_owned())
+                }
+            };
+            return Err(InboundError::Handshake(err.into()));
         }
 
         let typ = match request.command() {
             SocksCommand::CONNECT => NetworkType::Tcp,
-            SocksCommand::UDP_ASSOCIATE => NetworkType::Udp,
+            SocksCommand::UDP_ASSOCIATE if self.udp_enabled => NetworkType::Udp,
             other => {
                 if let Ok(msg) = request.reply(SocksStatus::COMMAND_NOT_SUPPORTED, None) {
-                    let _ = stream.write_all(&msg).await;
-                    let _ = stream.flush().await;
+                    let _ = write_all_timeout(&mut stream, &msg, self.reply_timeout).await;
                 }
 
                 return Err(InboundError::Handshake(
@@ -85,17 +176,31 @@ where
             }
         };
 
-        if let Ok(msg) = request.reply(SocksStatus::SUCCEEDED, None) {
-            let _ = stream.write_all(&msg).await?;
-            let _ = stream.flush().await;
-        }
-
         let port = request.port();
-        let addr = match request.get_addr() {
+        let addr = if self.unmap_v4 {
+            request.addr().clone().unmap_v4()
+        } else {
+            request.addr().clone()
+        };
+        let addr = match addr {
             SocksAddr::Domain(d) => Address::Domain(d),
             SocksAddr::Socket(ip) => Address::Socket(ip),
         };
 
+        let pending = SocksPendingReply {
+            request,
+            reply_addr: self.reply_addr,
+            reply_timeout: self.reply_timeout,
+        };
+        let pending = if self.eager_reply {
+            pending
+                .reply(&mut stream, SocksStatus::SUCCEEDED, None)
+                .await?;
+            None
+        } else {
+            Some(pending)
+        };
+
         Ok((
             stream,
             InboundPacket {
@@ -103,6 +208,89 @@ where
                 dest: ServiceAddress { addr, port },
                 detail: Cow::Borrowed(""),
             },
+            pending,
         ))
     }
 }
+
+impl fmt::Debug for SocksInbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SocksInbound")
+            .field("users", &self.users)
+            .field("socks4_verifier", &self.socks4_verifier.is_some())
+            .field("udp_enabled", &self.udp_enabled)
+            .field("allow_v4", &self.allow_v4)
+            .field("socks4_strict", &self.socks4_strict)
+            .field("reply_timeout", &self.reply_timeout)
+            .field("reply_addr", &self.reply_addr)
+            .field("read_buffer", &self.read_buffer)
+            .field("max_auth_attempts", &self.max_auth_attempts)
+            .field("eager_reply", &self.eager_reply)
+            .field("unmap_v4", &self.unmap_v4)
+            .field("strict_domain", &self.strict_domain)
+            .finish()
+    }
+}
+
+/// Reply still owed to a client accepted by `SocksInbound::accept` without
+/// `eager_reply`.
+#[derive(Debug)]
+pub struct SocksPendingReply {
+    request: SocksRequest,
+    reply_addr: SocksReplyAddr,
+    reply_timeout: Option<Duration>,
+}
+
+impl SocksPendingReply {
+    /// Answer the request with `status`. The bound address `addr` falls back
+    /// to the inbound's `reply_addr` strategy.
+    pub async fn reply<W>(
+        self,
+        stream: &mut W,
+        status: SocksStatus,
+        addr: Option<SocketAddr>,
+    ) -> InboundResult<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let msg = match addr.map_or(self.reply_addr, SocksReplyAddr::Bound) {
+            SocksReplyAddr::Unspecified => self.request.reply(status, None),
+            SocksReplyAddr::Requested => self.request.reply(status, Some(self.request.addr())),
+            SocksReplyAddr::Bound(addr) => {
+                let reply = SocksReply::new(status, SocksAddr::Socket(addr.ip()), addr.port());
+                let mut msg = vec![];
+                reply
+                    .put_to_buf(self.request.version(), &mut msg)
+                    .map(|_| msg)
+            }
+        };
+        if let Ok(msg) = msg {
+            write_all_timeout(stream, &msg, self.reply_timeout).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S> InboundServiceTrait<S> for SocksInbound
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+{
+    type Stream = BufStream<S>;
+
+    /// Same as `accept`, a reply left pending without `eager_reply` is sent
+    /// right away since the caller has no way to report the outcome.
+    async fn handshake(
+        &self,
+        stream: S,
+    ) -> InboundResult<(Self::Stream, crate::InboundPacket<'_>)> {
+        let (mut stream, packet, pending) = self.accept(stream).await?;
+        if let Some(pending) = pending {
+            pending
+                .reply(&mut stream, SocksStatus::SUCCEEDED, None)
+                .await?;
+        }
+
+        Ok((stream, packet))
+    }
+}
@@ -1,13 +1,16 @@
 //! Socks service
 
 pub mod option;
-pub use option::{SocksInboundOption, SocksOutboundOption};
+pub use option::{SocksInboundOption, SocksOutboundOption, SocksReplyAddr};
 
 pub mod inbound;
-pub use inbound::SocksInbound;
+pub use inbound::{Socks4Verifier, SocksInbound, SocksPendingReply};
 
 pub mod outbound;
-pub use outbound::SocksOutbound;
+pub use outbound::{SocksOutbound, SocksOutboundStream};
+
+pub mod udp;
+pub use udp::SocksUdpStream;
 
 pub mod protocol;
 pub use protocol::SocksError;
@@ -38,6 +41,7 @@ mod tests {
                     },
                     SocksAuthOption::Socks4("test".into()),
                 ],
+                ..Default::default()
             };
 
             let socks_in = SocksInbound::init(svc_opt).unwrap();
@@ -50,21 +54,22 @@ mod tests {
                 assert_eq!(n, 5);
                 assert_eq!(&buf, "hello".as_bytes());
                 let _ = s.write("byebye".as_bytes()).await.unwrap();
-                let _ = s.flush().await.unwrap();
+                s.flush().await.unwrap();
             }
         });
 
         let socks_opt_v5 = SocksOutboundOption {
-            version: 5,
             auth: SocksAuthOption::Username {
                 user: "test".into(),
                 pass: "test".into(),
             },
+            ..Default::default()
         };
 
         let socks_opt_v4 = SocksOutboundOption {
             version: 4,
             auth: SocksAuthOption::Socks4("test".into()),
+            ..Default::default()
         };
 
         let in_pac = OutboundPacket {
@@ -78,21 +83,625 @@ mod tests {
         tokio::time::sleep(Duration::from_millis(100)).await;
 
         let out_v4 = SocksOutbound::init(socks_opt_v4).unwrap();
-        let s = out_v4.handshake(&mut s1, in_pac.clone()).await.unwrap();
+        let mut s = out_v4.handshake(&mut s1, in_pac.clone()).await.unwrap();
         let _ = s.write("hello".as_bytes()).await.unwrap();
-        let _ = s.flush().await.unwrap();
+        s.flush().await.unwrap();
         let mut buf = [0u8; 6];
         let n = s.read(&mut buf).await.unwrap();
         assert_eq!(n, 6);
         assert_eq!(&buf, "byebye".as_bytes());
 
         let out_v5 = SocksOutbound::init(socks_opt_v5).unwrap();
-        let s = out_v5.handshake(&mut s1, in_pac.clone()).await.unwrap();
+        let mut s = out_v5.handshake(&mut s1, in_pac.clone()).await.unwrap();
         let _ = s.write("hello".as_bytes()).await.unwrap();
-        let _ = s.flush().await.unwrap();
+        s.flush().await.unwrap();
         let mut buf = [0u8; 6];
         let n = s.read(&mut buf).await.unwrap();
         assert_eq!(n, 6);
         assert_eq!(&buf, "byebye".as_bytes());
     }
+
+    #[tokio::test]
+    async fn test_socks_udp_disabled() {
+        for udp_enabled in [false, true] {
+            let (mut client, server) = duplex(4096);
+
+            let socks_in = SocksInbound::init(SocksInboundOption {
+                udp_enabled,
+                ..Default::default()
+            })
+            .unwrap();
+
+            client
+                .write_all(&[5, 1, 0, 5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+
+            let result = socks_in.handshake(server).await;
+
+            let mut reply = [0u8; 12];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply[..2], &[5, 0]);
+
+            if udp_enabled {
+                let (_, p) = result.unwrap();
+                assert_eq!(p.typ, NetworkType::Udp);
+                assert_eq!(reply[3], 0);
+            } else {
+                assert!(result.is_err());
+                assert_eq!(
+                    reply[3],
+                    u8::from(protocol::SocksStatus::COMMAND_NOT_SUPPORTED)
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks_v4_disabled() {
+        let socks_in = SocksInbound::init(SocksInboundOption {
+            allow_v4: false,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (mut client, server) = duplex(4096);
+        client
+            .write_all(&[4, 1, 0, 80, 127, 0, 0, 1, 0])
+            .await
+            .unwrap();
+        let err = socks_in.handshake(server).await.unwrap_err();
+        assert!(err.to_string().contains("SOCKS4 disabled"));
+        drop(client);
+
+        let (mut client, server) = duplex(4096);
+        client
+            .write_all(&[5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        let (_, p) = socks_in.handshake(server).await.unwrap();
+        assert_eq!(p.dest, ServiceAddress::new("127.0.0.1".into(), 80));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_socks_reply_timeout() {
+        // the client never reads, so the reply cannot fit into the pipe
+        let (mut client, server) = duplex(4);
+
+        let socks_in = SocksInbound::init(SocksInboundOption {
+            reply_timeout: Some(100),
+            ..Default::default()
+        })
+        .unwrap();
+
+        tokio::spawn(async move {
+            client
+                .write_all(&[5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let err = socks_in.handshake(server).await.unwrap_err();
+        assert!(
+            matches!(err, crate::InboundError::Io(ref e) if e.kind() == std::io::ErrorKind::TimedOut)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_socks_reply_addr() {
+        let (mut client, server) = duplex(4096);
+
+        let socks_in = SocksInbound::init(SocksInboundOption {
+            reply_addr: SocksReplyAddr::Bound("10.0.0.1:5353".parse().unwrap()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        client
+            .write_all(&[5, 1, 0, 5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        socks_in.handshake(server).await.unwrap();
+
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0, 5, 0, 0, 1, 10, 0, 0, 1, 0x14, 0xe9]);
+    }
+
+    #[tokio::test]
+    async fn test_socks_reply_addr_strategies() {
+        // CONNECT 1.2.3.4:80
+        let request = [5, 1, 0, 5, 1, 0, 1, 1, 2, 3, 4, 0, 80];
+        let cases = [
+            (
+                SocksReplyAddr::Unspecified,
+                [5, 0, 5, 0, 0, 1, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                SocksReplyAddr::Requested,
+                [5, 0, 5, 0, 0, 1, 1, 2, 3, 4, 0, 80],
+            ),
+            (
+                SocksReplyAddr::Bound("10.0.0.1:5353".parse().unwrap()),
+                [5, 0, 5, 0, 0, 1, 10, 0, 0, 1, 0x14, 0xe9],
+            ),
+        ];
+
+        for (reply_addr, expected) in cases {
+            let (mut client, server) = duplex(4096);
+            let socks_in = SocksInbound::init(SocksInboundOption {
+                reply_addr,
+                ..Default::default()
+            })
+            .unwrap();
+
+            client.write_all(&request).await.unwrap();
+            socks_in.handshake(server).await.unwrap();
+
+            let mut reply = [0u8; 12];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, expected, "{}", reply_addr);
+        }
+
+        for (s, reply_addr) in [
+            ("unspecified", SocksReplyAddr::Unspecified),
+            ("requested", SocksReplyAddr::Requested),
+            (
+                "10.0.0.1:5353",
+                SocksReplyAddr::Bound("10.0.0.1:5353".parse().unwrap()),
+            ),
+        ] {
+            assert_eq!(s.parse::<SocksReplyAddr>().unwrap(), reply_addr);
+            assert_eq!(reply_addr.to_string(), s);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks_eager_reply() {
+        let option = |eager_reply| SocksInboundOption {
+            eager_reply,
+            ..Default::default()
+        };
+        let request = [5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80];
+
+        // the success reply is already sent when accept returns
+        let socks_in = SocksInbound::init(option(true)).unwrap();
+        let (mut client, server) = duplex(4096);
+        client.write_all(&request).await.unwrap();
+        let (_, _, pending) = socks_in.accept(server).await.unwrap();
+        assert!(pending.is_none());
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[2..4], [5, 0]);
+
+        // nothing is sent until the caller reports the outcome
+        let socks_in = SocksInbound::init(option(false)).unwrap();
+        let (mut client, server) = duplex(4096);
+        client.write_all(&request).await.unwrap();
+        let (mut stream, p, pending) = socks_in.accept(server).await.unwrap();
+        assert_eq!(p.dest, ServiceAddress::new("127.0.0.1".into(), 80));
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        let mut reply = [0u8; 10];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), client.read(&mut reply))
+                .await
+                .is_err()
+        );
+        pending
+            .unwrap()
+            .reply(&mut stream, protocol::SocksStatus::HOST_UNREACHABLE, None)
+            .await
+            .unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [5, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_socks_unmap_v4() {
+        let mapped: std::net::Ipv6Addr = "::ffff:1.2.3.4".parse().unwrap();
+        let mut request = vec![5, 1, 0, 5, 1, 0, 4];
+        request.extend_from_slice(&mapped.octets());
+        request.extend_from_slice(&[0, 80]);
+
+        for (unmap_v4, ip) in [
+            (false, std::net::IpAddr::V6(mapped)),
+            (true, "1.2.3.4".parse().unwrap()),
+        ] {
+            let socks_in = SocksInbound::init(SocksInboundOption {
+                unmap_v4,
+                ..Default::default()
+            })
+            .unwrap();
+            let (mut client, server) = duplex(4096);
+            client.write_all(&request).await.unwrap();
+            let (_, pac) = socks_in.handshake(server).await.unwrap();
+            assert_eq!(pac.dest.addr, crate::Address::Socket(ip));
+        }
+    }
+
+    /// SOCKS4 only server, answers anything else with a v4 rejection.
+    async fn socks4_stub<S>(mut s: S)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let ver = s.read_u8().await.unwrap();
+        if ver != 4 {
+            s.write_all(&[0, 91, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            return;
+        }
+        let mut head = [0u8; 7];
+        s.read_exact(&mut head).await.unwrap();
+        while s.read_u8().await.unwrap() != 0 {}
+        s.write_all(&[0, 90, 0, 0, 0, 0, 0, 0]).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_socks_version_auto() {
+        let out = SocksOutbound::init(SocksOutboundOption {
+            version: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(out.version(), protocol::SocksVersion::V5);
+
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("127.0.0.1".into(), 80),
+        };
+
+        // the greeting is rejected, the caller has to reconnect
+        let (s1, s2) = duplex(4096);
+        let (res, _) = tokio::join!(out.handshake(s1, packet.clone()), socks4_stub(s2));
+        assert!(matches!(
+            res,
+            Err(crate::OutboundError::Handshake(
+                crate::error::ProtocolError::Socks(SocksError::Socks5Rejected)
+            ))
+        ));
+        assert_eq!(out.version(), protocol::SocksVersion::V4);
+
+        let (s1, s2) = duplex(4096);
+        let (res, _) = tokio::join!(out.handshake(s1, packet), socks4_stub(s2));
+        assert!(res.is_ok());
+
+        // v5 is tried again later
+        tokio::time::sleep(Duration::from_secs(300)).await;
+        assert_eq!(out.version(), protocol::SocksVersion::V5);
+
+        // auto cannot use credentials of only one version
+        assert!(SocksOutbound::init(SocksOutboundOption {
+            version: 0,
+            auth: SocksAuthOption::Socks4("test".into()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socks_status_error() {
+        let out = SocksOutbound::init(SocksOutboundOption::default()).unwrap();
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("127.0.0.1".into(), 80),
+        };
+
+        let (out, packet) = (&out, &packet);
+        let reply = |status: protocol::SocksStatus| async move {
+            let (s1, mut s2) = duplex(4096);
+            let server = async move {
+                let mut greeting = [0u8; 3];
+                s2.read_exact(&mut greeting).await.unwrap();
+                s2.write_all(&[5, 0]).await.unwrap();
+                let mut req = [0u8; 10];
+                s2.read_exact(&mut req).await.unwrap();
+                s2.write_all(&[5, u8::from(status), 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+            };
+            tokio::join!(out.handshake(s1, packet.clone()), server).0
+        };
+
+        let res = reply(protocol::SocksStatus::CONNECTION_REFUSED).await;
+        assert!(matches!(res, Err(crate::OutboundError::Refused)));
+
+        let res = reply(protocol::SocksStatus::HOST_UNREACHABLE).await;
+        assert!(matches!(res, Err(crate::OutboundError::Unreachable)));
+
+        let res = reply(protocol::SocksStatus::GENERAL_FAILURE).await;
+        assert!(matches!(
+            res,
+            Err(crate::OutboundError::Handshake(
+                crate::error::ProtocolError::Socks(SocksError::InvalidStatus(1))
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_socks_read_buffer() {
+        let option = |read_buffer| SocksInboundOption {
+            read_buffer,
+            ..Default::default()
+        };
+        assert!(SocksInbound::init(option(Some(0))).is_err());
+
+        // a buffer smaller than any message still gets through the handshake
+        let socks_in = SocksInbound::init(option(Some(1))).unwrap();
+        let (mut client, server) = duplex(4096);
+        client.write_all(&[5, 1, 0, 5, 1, 0, 3, 11]).await.unwrap();
+        client.write_all(b"example.com\x01\xbb").await.unwrap();
+        let (_, p) = socks_in.handshake(server).await.unwrap();
+        assert_eq!(p.dest, ServiceAddress::new("example.com".into(), 443));
+    }
+
+    #[tokio::test]
+    async fn test_socks_max_auth_attempts() {
+        let socks_in = SocksInbound::init(SocksInboundOption {
+            auth: vec![SocksAuthOption::Username {
+                user: "test".into(),
+                pass: "test".into(),
+            }],
+            max_auth_attempts: Some(3),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (mut client, server) = duplex(4096);
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        for pass in [b"bad1", b"bad2"] {
+            client.write_all(&[1, 4]).await.unwrap();
+            client.write_all(b"test\x04").await.unwrap();
+            client.write_all(pass).await.unwrap();
+        }
+        client.write_all(&[1, 4]).await.unwrap();
+        client.write_all(b"test\x04test").await.unwrap();
+        client
+            .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+
+        let (_, p) = socks_in.handshake(server).await.unwrap();
+        assert_eq!(p.dest, ServiceAddress::new("127.0.0.1".into(), 80));
+
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 2, 1, 1, 1, 1, 1, 0]);
+
+        // the last allowed failure closes the connection
+        let (mut client, server) = duplex(4096);
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        for _ in 0..3 {
+            client.write_all(&[1, 4]).await.unwrap();
+            client.write_all(b"test\x03bad").await.unwrap();
+        }
+        let err = socks_in.handshake(server).await.unwrap_err();
+        assert!(matches!(
+            err,
+            crate::InboundError::Handshake(crate::error::ProtocolError::Socks(
+                SocksError::TooManyAuthAttempts
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_socks_require_auth() {
+        let socks_in = SocksInbound::init(SocksInboundOption {
+            auth: vec![SocksAuthOption::Username {
+                user: "test".into(),
+                pass: "test".into(),
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+
+        // no authentication only, refused in the greeting
+        let (mut client, server) = duplex(4096);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let err = socks_in.handshake(server).await.unwrap_err();
+        assert!(matches!(
+            err,
+            crate::InboundError::Handshake(crate::error::ProtocolError::Socks(
+                SocksError::AuthRequired
+            ))
+        ));
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0xff]);
+    }
+
+    #[tokio::test]
+    async fn test_socks_auth_rejected() {
+        let socks_in = SocksInbound::init(SocksInboundOption {
+            auth: vec![SocksAuthOption::Username {
+                user: "test".into(),
+                pass: "test".into(),
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+        let socks_err = |err| match err {
+            crate::InboundError::Handshake(crate::error::ProtocolError::Socks(e)) => e,
+            other => panic!("{}", other),
+        };
+
+        let (mut client, server) = duplex(4096);
+        client
+            .write_all(&[5, 1, 2, 1, 4, b't', b'e', b's', b't', 3, b'b', b'a', b'd'])
+            .await
+            .unwrap();
+        client
+            .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        let err = socks_err(socks_in.handshake(server).await.unwrap_err());
+        assert!(matches!(err, SocksError::AuthRejected(ref user) if user == "test"));
+        // the password stays out of the message
+        assert!(!err.to_string().contains("bad"), "{}", err);
+
+        // method, username/password status and the rejection all arrive
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 2, 1, 0, 5, 2, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        // socks4 without a user id
+        let (mut client, server) = duplex(4096);
+        client
+            .write_all(&[4, 1, 0, 80, 127, 0, 0, 1, 0])
+            .await
+            .unwrap();
+        let err = socks_err(socks_in.handshake(server).await.unwrap_err());
+        assert!(matches!(err, SocksError::AuthRequired));
+
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, [0, 0x5b, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_socks4_verifier() {
+        let mut socks_in = SocksInbound::init(SocksInboundOption {
+            auth: vec![SocksAuthOption::Socks4("bob".into())],
+            ..Default::default()
+        })
+        .unwrap();
+        socks_in.set_socks4_verifier(std::sync::Arc::new(|user: &[u8]| user == b"alice"));
+
+        for (user, ok) in [(&b"alice"[..], true), (b"bob", false), (b"", false)] {
+            let (mut client, server) = duplex(4096);
+            client
+                .write_all(&[4, 1, 0, 80, 127, 0, 0, 1])
+                .await
+                .unwrap();
+            client.write_all(user).await.unwrap();
+            client.write_all(&[0]).await.unwrap();
+
+            let result = socks_in.handshake(server).await;
+            assert_eq!(result.is_ok(), ok, "{}", String::from_utf8_lossy(user));
+
+            let mut reply = [0u8; 8];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[1], if ok { 90 } else { 91 });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks4_empty_userid() {
+        let request = [4, 1, 0, 80, 127, 0, 0, 1, 0];
+
+        let socks_in = SocksInbound::init(SocksInboundOption::default()).unwrap();
+        let (mut client, server) = duplex(4096);
+        client.write_all(&request).await.unwrap();
+        socks_in.handshake(server).await.unwrap();
+
+        let socks_in = SocksInbound::init(SocksInboundOption {
+            auth: vec![SocksAuthOption::Socks4("bob".into())],
+            ..Default::default()
+        })
+        .unwrap();
+        let (mut client, server) = duplex(4096);
+        client.write_all(&request).await.unwrap();
+        let err = socks_in.handshake(server).await.unwrap_err();
+        assert!(err.to_string().contains("no credentials"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_socks_resolve_locally() {
+        #[derive(Debug)]
+        struct StaticResolver;
+
+        impl crate::Resolver for StaticResolver {
+            fn resolve<'a>(
+                &'a self,
+                _domain: &'a str,
+                port: u16,
+            ) -> crate::resolver::ResolveFuture<'a> {
+                Box::pin(async move { Ok(vec![std::net::SocketAddr::from(([10, 0, 0, 1], port))]) })
+            }
+        }
+
+        /// Accepts a SOCKS5 connect and returns the request after the version,
+        /// command and reserved bytes.
+        async fn server(mut s: tokio::io::DuplexStream) -> Vec<u8> {
+            let mut greeting = [0u8; 3];
+            s.read_exact(&mut greeting).await.unwrap();
+            s.write_all(&[5, 0]).await.unwrap();
+
+            let mut head = [0u8; 4];
+            s.read_exact(&mut head).await.unwrap();
+            let mut req = vec![head[3]];
+            let len = match head[3] {
+                1 => 4,
+                3 => {
+                    let len = s.read_u8().await.unwrap();
+                    req.push(len);
+                    len as usize
+                }
+                _ => 16,
+            };
+            let mut rest = vec![0u8; len + 2];
+            s.read_exact(&mut rest).await.unwrap();
+            s.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+
+            req.extend(rest);
+            req
+        }
+
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("example.com".into(), 80),
+        };
+        for (resolve_locally, expected) in [
+            (false, b"\x03\x0bexample.com\x00\x50".to_vec()),
+            (true, vec![1, 10, 0, 0, 1, 0, 80]),
+        ] {
+            let mut out = SocksOutbound::init(SocksOutboundOption {
+                resolve_locally,
+                ..Default::default()
+            })
+            .unwrap();
+            out.set_resolver(Some(std::sync::Arc::new(StaticResolver)));
+
+            let (s1, s2) = duplex(4096);
+            let (res, req) = tokio::join!(out.handshake(s1, packet.clone()), server(s2));
+            res.unwrap();
+            assert_eq!(req, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks_strict_domain() {
+        let mut v5 = vec![5, 1, 0, 5, 1, 0, 3, 3];
+        v5.extend_from_slice(b"a b\x00\x50");
+        let mut v4a = vec![4, 1, 0, 80, 0, 0, 0, 1, 0];
+        v4a.extend_from_slice(b"a b\x00");
+
+        for request in [v5, v4a] {
+            for strict_domain in [false, true] {
+                let socks_in = SocksInbound::init(SocksInboundOption {
+                    strict_domain,
+                    ..Default::default()
+                })
+                .unwrap();
+                let (mut client, server) = duplex(4096);
+                client.write_all(&request).await.unwrap();
+                let res = socks_in.handshake(server).await;
+                assert_eq!(res.is_err(), strict_domain, "{:?}", request);
+                if let Ok((_, pac)) = res {
+                    assert_eq!(pac.dest.addr, crate::Address::Domain("a b".into()));
+                }
+            }
+        }
+
+        // empty domains are refused even when permissive
+        let v5 = vec![5, 1, 0, 5, 1, 0, 3, 0, 0, 80];
+        let v4a = vec![4, 1, 0, 80, 0, 0, 0, 1, 0, 0];
+        for request in [v5, v4a] {
+            let socks_in = SocksInbound::init(SocksInboundOption::default()).unwrap();
+            let (mut client, server) = duplex(4096);
+            client.write_all(&request).await.unwrap();
+            assert!(socks_in.handshake(server).await.is_err(), "{:?}", request);
+        }
+    }
 }
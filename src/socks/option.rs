@@ -1,6 +1,10 @@
 //! Socks service option
 
-use serde::{Deserialize, Serialize};
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::option::default_udp_enabled;
 
 use super::protocol::SocksAuth;
 
@@ -8,32 +12,183 @@ use super::protocol::SocksAuth;
 pub struct SocksInboundOption {
     #[serde(default)]
     pub auth: Vec<SocksAuthOption>,
+    #[serde(default = "default_udp_enabled")]
+    pub udp_enabled: bool,
+    /// Accept SOCKS4/4a clients, set to false for SOCKS5 only.
+    #[serde(default = "default_allow_v4")]
+    pub allow_v4: bool,
+    /// Refuse SOCKS4a hostname requests, for setups where the server must
+    /// not resolve names on behalf of SOCKS4 clients.
+    #[serde(default)]
+    pub socks4_strict: bool,
+    /// Timeout in milliseconds for writing handshake replies.
+    #[serde(default)]
+    pub reply_timeout: Option<u64>,
+    /// Bound address reported in successful replies: `unspecified` for
+    /// `0.0.0.0:0` (the default), `requested` to echo the destination, or
+    /// an address.
+    #[serde(default)]
+    pub reply_addr: SocksReplyAddr,
+    /// Read buffer size in bytes for the buffered connection, 8 KiB if unset.
+    #[serde(default)]
+    pub read_buffer: Option<usize>,
+    /// Failed authentications allowed on one connection, each one after the
+    /// first is answered with a new prompt. Unset fails on the first.
+    #[serde(default)]
+    pub max_auth_attempts: Option<u32>,
+    /// Send the success reply as soon as the request is read. When false,
+    /// `SocksInbound::accept` leaves it to the caller, which can report
+    /// the outcome of connecting to the destination instead.
+    #[serde(default = "default_eager_reply")]
+    pub eager_reply: bool,
+    /// Hand on IPv4-mapped IPv6 destinations such as `::ffff:1.2.3.4` in
+    /// their IPv4 form, so they are connected to the same way on every
+    /// platform.
+    #[serde(default)]
+    pub unmap_v4: bool,
+    /// Refuse requested domains that are empty, longer than 253 bytes or
+    /// not printable ASCII. By default any domain is handed on.
+    #[serde(default)]
+    pub strict_domain: bool,
+}
+
+impl Default for SocksInboundOption {
+    fn default() -> Self {
+        Self {
+            auth: vec![],
+            udp_enabled: default_udp_enabled(),
+            allow_v4: default_allow_v4(),
+            socks4_strict: false,
+            reply_timeout: None,
+            reply_addr: SocksReplyAddr::default(),
+            read_buffer: None,
+            max_auth_attempts: None,
+            eager_reply: default_eager_reply(),
+            unmap_v4: false,
+            strict_domain: false,
+        }
+    }
+}
+
+/// Bound address a `SocksInbound` reports to its clients.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SocksReplyAddr {
+    /// `0.0.0.0:0`, or `[::]:0` for IPv6 requests.
+    #[default]
+    Unspecified,
+    /// The destination of the request, for clients that check the reply
+    /// against it.
+    Requested,
+    /// A fixed address, e.g. the bound address of a UDP relay.
+    Bound(SocketAddr),
+}
+
+impl From<Option<SocketAddr>> for SocksReplyAddr {
+    fn from(value: Option<SocketAddr>) -> Self {
+        value.map_or(Self::Unspecified, Self::Bound)
+    }
+}
+
+impl fmt::Display for SocksReplyAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unspecified => write!(f, "unspecified"),
+            Self::Requested => write!(f, "requested"),
+            Self::Bound(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+impl FromStr for SocksReplyAddr {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unspecified" => Ok(Self::Unspecified),
+            "requested" => Ok(Self::Requested),
+            addr => addr.parse().map(Self::Bound),
+        }
+    }
+}
+
+impl Serialize for SocksReplyAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// An address alone, as `reply_addr` used to take, still means `Bound`.
+impl<'de> Deserialize<'de> for SocksReplyAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(s) => s.parse().map_err(D::Error::custom),
+            None => Ok(Self::Unspecified),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SocksOutboundOption {
+    /// 4 or 5, or 0 to try SOCKS5 and fall back to SOCKS4 if the server
+    /// rejects the greeting. Falling back needs a new connection, the
+    /// handshake that detected the rejection fails and later ones use v4.
     #[serde(default = "default_version")]
     pub version: u8,
     #[serde(default)]
     pub auth: SocksAuthOption,
+    /// Largest UDP payload accepted from the relay, bigger datagrams are
+    /// reported as errors instead of being truncated.
+    #[serde(default = "default_udp_max_datagram")]
+    pub udp_max_datagram: usize,
+    /// Resolve domains here and send the server an IP address, for servers
+    /// that refuse domain requests. By default the server resolves them.
+    #[serde(default)]
+    pub resolve_locally: bool,
+    /// IP address of the server, where a udp relay replied as `0.0.0.0` or
+    /// `::` is taken to be when the stream handed to the handshake cannot
+    /// tell its peer.
+    #[serde(default)]
+    pub server_addr: Option<SocketAddr>,
+}
+
+impl Default for SocksOutboundOption {
+    fn default() -> Self {
+        Self {
+            version: default_version(),
+            auth: SocksAuthOption::default(),
+            udp_max_datagram: default_udp_max_datagram(),
+            resolve_locally: false,
+            server_addr: None,
+        }
+    }
 }
 
 fn default_version() -> u8 {
     5
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) fn default_udp_max_datagram() -> usize {
+    super::udp::MAX_DATAGRAM
+}
+
+pub(crate) fn default_allow_v4() -> bool {
+    true
+}
+
+pub(crate) fn default_eager_reply() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum SocksAuthOption {
+    #[default]
     NoAuth,
     Socks4(String),
-    Username { user: String, pass: String },
-}
-
-impl Default for SocksAuthOption {
-    fn default() -> Self {
-        Self::NoAuth
-    }
+    Username {
+        user: String,
+        pass: String,
+    },
 }
 
 impl From<SocksAuthOption> for SocksAuth {
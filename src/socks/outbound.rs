@@ -1,84 +1,305 @@
 //! Socks service for outbound
 
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::Poll,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::Instant,
+};
 
 use crate::{
-    address::NetworkType, Address, OutboundError, OutboundPacket, OutboundResult,
-    OutboundServiceTrait,
+    address::NetworkType, resolver::Resolver, Address, OutboundError, OutboundPacket,
+    OutboundResult, OutboundServiceStream, OutboundServiceTrait, ProxyStream, ServiceAddress,
 };
 
 use super::{
     protocol::{
-        SocksAddr, SocksAuth, SocksClientHandshake, SocksCommand, SocksRequest, SocksStatus,
-        SocksVersion,
+        SocksAddr, SocksAuth, SocksClientHandshake, SocksCommand, SocksReply, SocksRequest,
+        SocksStatus, SocksTarget, SocksVersion,
     },
+    udp::SocksUdpStream,
     SocksError, SocksOutboundOption,
 };
 
+/// How long auto keeps using v4 after a rejected v5 greeting. A hang up
+/// looks the same as a v4 only server, so v5 is tried again afterwards.
+const FALLBACK_V4_TTL: Duration = Duration::from_secs(300);
+
 #[derive(Debug)]
 pub struct SocksOutbound {
     version: SocksVersion,
+    /// Version 0, fall back to v4 for a while once the server rejected v5.
+    auto: bool,
+    /// When v5 was last rejected.
+    fallback_v4: Mutex<Option<Instant>>,
     auth: SocksAuth,
+    resolve_locally: bool,
+    resolver: Option<Arc<dyn Resolver>>,
+    udp_max_datagram: usize,
+    /// Peer assumed for streams that cannot report one.
+    server_addr: Option<SocketAddr>,
+}
+
+impl SocksOutboundOption {
+    pub fn validate(&self) -> Vec<OutboundError> {
+        let mut errors = vec![];
+
+        // auto must be able to speak both versions
+        let versions: &[SocksVersion] = match self.version {
+            0 => &[SocksVersion::V4, SocksVersion::V5],
+            4 => &[SocksVersion::V4],
+            5 => &[SocksVersion::V5],
+            n => {
+                errors.push(OutboundError::Option(format!(
+                    "unsupport service socks version: {0:x}",
+                    n
+                )));
+                &[]
+            }
+        };
+        let auth = SocksAuth::from(self.auth.clone());
+        if !versions.iter().all(|v| auth.validate(*v)) {
+            errors.push(OutboundError::Option(
+                "authentication method dismatch socks version".to_string(),
+            ));
+        }
+
+        errors
+    }
 }
 
 impl SocksOutbound {
     pub fn init(option: SocksOutboundOption) -> OutboundResult<Self> {
-        let version = option.version.try_into().map_err(|n| {
-            OutboundError::Option(format!("unsupport service socks version: {0:x}", n))
-        })?;
+        if let Some(e) = option.validate().into_iter().next() {
+            return Err(e);
+        }
 
+        let auto = option.version == 0;
+        let version = if auto {
+            SocksVersion::V5
+        } else {
+            option.version.try_into().map_err(|n| {
+                OutboundError::Option(format!("unsupport service socks version: {0:x}", n))
+            })?
+        };
         let auth: SocksAuth = option.auth.into();
 
-        if !auth.validate(version) {
-            return Err(OutboundError::Option(
-                "authentication method dismatch socks version".to_string(),
-            )
-            .into());
+        Ok(Self {
+            auth,
+            version,
+            auto,
+            fallback_v4: Mutex::new(None),
+            resolve_locally: option.resolve_locally,
+            resolver: None,
+            udp_max_datagram: option.udp_max_datagram,
+            server_addr: option.server_addr,
+        })
+    }
+
+    /// Version the next handshake uses.
+    pub fn version(&self) -> SocksVersion {
+        let fallback = *self.fallback_v4.lock().unwrap();
+        match fallback {
+            Some(since) if self.auto && since.elapsed() < FALLBACK_V4_TTL => SocksVersion::V4,
+            _ => self.version,
         }
+    }
 
-        Ok(Self { auth, version })
+    /// Resolve domains here and send the server an IP address.
+    pub fn set_resolve_locally(&mut self, resolve_locally: bool) {
+        self.resolve_locally = resolve_locally;
     }
-}
 
-impl<S> OutboundServiceTrait<S> for SocksOutbound
-where
-    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
-{
-    type Stream = S;
+    /// Resolver for local resolution, the system resolver if `None`.
+    pub fn set_resolver(&mut self, resolver: Option<Arc<dyn Resolver>>) {
+        self.resolver = resolver;
+    }
+
+    /// Run the handshake on `stream` and return the server's reply along
+    /// with the stream, e.g. the relay endpoint of a udp associate in its
+    /// bound address.
+    ///
+    /// A udp relay replied as an unspecified address is taken to be on the
+    /// host at the other end of `stream`, or at `server_addr` if the stream
+    /// cannot tell.
+    pub async fn connect<S>(
+        &self,
+        stream: S,
+        packet: OutboundPacket,
+    ) -> OutboundResult<(SocksOutboundStream<S>, SocksReply)>
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + Unpin + ProxyStream,
+    {
+        let peer = stream.peer_addr().or(self.server_addr);
+        self.connect_with_peer(stream, packet, peer).await
+    }
 
-    async fn handshake(
+    /// Like `connect` for streams that cannot report their peer, `peer` is
+    /// the server's address, if known.
+    pub async fn connect_with_peer<S>(
         &self,
         mut stream: S,
         packet: OutboundPacket,
-    ) -> OutboundResult<Self::Stream> {
+        peer: Option<SocketAddr>,
+    ) -> OutboundResult<(SocksOutboundStream<S>, SocksReply)>
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+    {
         let addr = match packet.dest.addr {
+            Address::Domain(_) if self.resolve_locally => {
+                let ip = packet
+                    .dest
+                    .resolve_with(self.resolver.as_ref())
+                    .await?
+                    .first()
+                    .ok_or(OutboundError::Unresolved)?
+                    .ip();
+                SocksAddr::Socket(ip)
+            }
             Address::Domain(domain) => SocksAddr::Domain(domain),
             Address::Socket(ip) => SocksAddr::Socket(ip),
         };
 
         let port = packet.dest.port;
 
-        let command = match packet.typ {
-            NetworkType::Tcp => SocksCommand::CONNECT,
-            NetworkType::Udp => SocksCommand::UDP_ASSOCIATE,
+        // an associate request names the address datagrams come from, which
+        // is not known before the local socket is bound
+        let (command, req_addr, req_port) = match packet.typ {
+            NetworkType::Tcp => (SocksCommand::CONNECT, addr.clone(), port),
+            NetworkType::Udp => (
+                SocksCommand::UDP_ASSOCIATE,
+                SocksAddr::Socket(Ipv4Addr::UNSPECIFIED.into()),
+                0,
+            ),
         };
 
-        let req = SocksRequest::new(self.version, command, addr, port, self.auth.clone())
+        let version = self.version();
+        let req = SocksRequest::new(version, command, req_addr, req_port, self.auth.clone())
             .map_err(|e| OutboundError::Handshake(e.into()))?;
 
-        let mut cli = SocksClientHandshake::new(req);
+        let mut cli = SocksClientHandshake::new(req)
+            .with_v4_detection(self.auto && matches!(version, SocksVersion::V5));
 
-        let reply = cli
-            .connect(&mut stream)
-            .await
-            .map_err(|e| OutboundError::Handshake(e.into()))?;
+        let reply = cli.connect(&mut stream).await.map_err(|e| {
+            if matches!(e, SocksError::Socks5Rejected) {
+                *self.fallback_v4.lock().unwrap() = Some(Instant::now());
+            }
+            OutboundError::Handshake(e.into())
+        })?;
 
         if reply.status() != SocksStatus::SUCCEEDED {
-            return Err(OutboundError::Handshake(
-                SocksError::InvalidStatus(reply.status().into()).into(),
-            ));
+            return Err(reply.status().to_outbound_error());
+        }
+
+        if !matches!(command, SocksCommand::UDP_ASSOCIATE) {
+            return Ok((SocksOutboundStream::Tcp(stream), reply));
         }
 
+        let relay = match reply.addr() {
+            SocksAddr::Socket(ip) => SocketAddr::new(*ip, reply.port()),
+            SocksAddr::Domain(domain) => *ServiceAddress::new(domain.into(), reply.port())
+                .resolve_with(self.resolver.as_ref())
+                .await?
+                .first()
+                .ok_or(OutboundError::Unresolved)?,
+        };
+        // servers reply an unspecified relay meaning the host they run on
+        let relay = match peer {
+            Some(peer) if relay.ip().is_unspecified() => SocketAddr::new(peer.ip(), relay.port()),
+            _ => relay,
+        };
+        let target = SocksTarget::new(addr, port);
+        let stream = SocksUdpStream::connect(stream, relay, target, self.udp_max_datagram).await?;
+
+        Ok((SocksOutboundStream::Udp(stream), reply))
+    }
+}
+
+impl<S> OutboundServiceTrait<S> for SocksOutbound
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+{
+    type Stream = SocksOutboundStream<S>;
+
+    /// Same as `connect_with_peer` with `server_addr` as the peer, without
+    /// the reply.
+    async fn handshake(&self, stream: S, packet: OutboundPacket) -> OutboundResult<Self::Stream> {
+        let (stream, _) = self
+            .connect_with_peer(stream, packet, self.server_addr)
+            .await?;
         Ok(stream)
     }
 }
+
+#[derive(Debug)]
+pub enum SocksOutboundStream<S> {
+    Tcp(S),
+    Udp(SocksUdpStream<S>),
+}
+
+impl<S> From<SocksOutboundStream<S>> for OutboundServiceStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    fn from(value: SocksOutboundStream<S>) -> Self {
+        OutboundServiceStream::Socks(value)
+    }
+}
+
+impl<S> AsyncRead for SocksOutboundStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Udp(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for SocksOutboundStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Udp(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Self::Udp(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Udp(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
@@ -1,12 +1,12 @@
 //! Socks protocol client handshake
 
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::IpAddr;
 
 use bytes::BufMut;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use super::{
-    SocksAddr, SocksAuth, SocksError, SocksReply, SocksRequest, SocksStatus, SocksVersion,
+    SocksAddr, SocksAuth, SocksError, SocksReply, SocksRequest, SocksTarget, SocksVersion,
     NO_AUTHENTICATION, USERNAME_PASSWORD,
 };
 
@@ -14,6 +14,7 @@ use super::{
 pub struct SocksClientHandshake {
     request: SocksRequest,
     state: State,
+    detect_v4: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -32,9 +33,17 @@ impl SocksClientHandshake {
         SocksClientHandshake {
             request,
             state: State::Initial,
+            detect_v4: false,
         }
     }
 
+    /// Report a SOCKS5 greeting answered by a hang up or a v4 reply as
+    /// `Socks5Rejected`, for callers able to retry with SOCKS4.
+    pub fn with_v4_detection(mut self, detect_v4: bool) -> Self {
+        self.detect_v4 = detect_v4;
+        self
+    }
+
     pub async fn connect<S>(&mut self, stream: &mut S) -> Result<SocksReply, SocksError>
     where
         S: AsyncReadExt + AsyncWriteExt + Unpin,
@@ -76,7 +85,16 @@ impl SocksClientHandshake {
     where
         S: AsyncReadExt + AsyncWriteExt + Unpin,
     {
-        let mut msg = vec![];
+        let msg = self.generate_v4_request()?;
+
+        stream.write_all(&msg).await?;
+        stream.flush().await?;
+        self.state = State::Socks4Wait;
+        Ok(None)
+    }
+
+    fn generate_v4_request(&self) -> Result<Vec<u8>, SocksError> {
+        let mut msg = Vec::with_capacity(self.request.wire_len());
 
         msg.put_u8(SocksVersion::V4.into());
         msg.put_u8(self.request.command().into());
@@ -109,33 +127,18 @@ impl SocksClientHandshake {
             msg.put_u8(0);
         }
 
-        let _ = stream.write_all(&msg).await?;
-        let _ = stream.flush().await?;
-        self.state = State::Socks4Wait;
-        Ok(None)
+        Ok(msg)
     }
 
     async fn handle_v4<S>(&mut self, stream: &mut S) -> Result<Option<SocksReply>, SocksError>
     where
         S: AsyncReadExt + AsyncWriteExt + Unpin,
     {
-        let ver = stream.read_u8().await?;
-        if ver != 0 {
-            return Err(SocksError::InvalidVersion(ver));
-        }
-
-        let status = stream.read_u8().await?;
-        let port = stream.read_u16().await?;
-        let ip: Ipv4Addr = stream.read_u32().await?.into();
+        let reply = SocksReply::read_from(stream, SocksVersion::V4).await?;
 
         self.state = State::Done;
 
-        Ok(SocksReply {
-            port,
-            addr: SocksAddr::Socket(ip.into()),
-            status: SocksStatus::from_socks4_status(status),
-        }
-        .into())
+        Ok(Some(reply))
     }
 
     async fn send_v5_initial<S>(&mut self, stream: &mut S) -> Result<Option<SocksReply>, SocksError>
@@ -160,8 +163,8 @@ impl SocksClientHandshake {
             }
         }
 
-        let _ = stream.write_all(&msg).await;
-        let _ = stream.flush().await?;
+        stream.write_all(&msg).await?;
+        stream.flush().await?;
         self.state = State::Socks5AuthWait;
 
         Ok(None)
@@ -171,7 +174,16 @@ impl SocksClientHandshake {
     where
         S: AsyncReadExt + AsyncWriteExt + Unpin,
     {
-        let ver = stream.read_u8().await?;
+        // a SOCKS4 only server hangs up or answers with a v4 reply, whose
+        // first byte is 0
+        let ver = match stream.read_u8().await {
+            Ok(0) if self.detect_v4 => return Err(SocksError::Socks5Rejected),
+            Ok(ver) => ver,
+            Err(e) if self.detect_v4 && e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(SocksError::Socks5Rejected)
+            }
+            Err(e) => return Err(e.into()),
+        };
         if ver != 5 {
             return Err(SocksError::InvalidVersion(ver));
         }
@@ -182,8 +194,8 @@ impl SocksClientHandshake {
             other => return Err(SocksError::InvalidAuthMethod(other)),
         };
 
-        let _ = stream.write_all(&msg).await?;
-        let _ = stream.flush().await?;
+        stream.write_all(&msg).await?;
+        stream.flush().await?;
         self.state = next_state;
 
         Ok(None)
@@ -208,8 +220,8 @@ impl SocksClientHandshake {
 
         let msg = self.generate_v5_command()?;
 
-        let _ = stream.write_all(&msg).await?;
-        let _ = stream.flush().await?;
+        stream.write_all(&msg).await?;
+        stream.flush().await?;
         self.state = State::Socks5Wait;
 
         Ok(None)
@@ -234,13 +246,12 @@ impl SocksClientHandshake {
     }
 
     fn generate_v5_command(&self) -> Result<Vec<u8>, SocksError> {
-        let mut msg = vec![];
+        let mut msg = Vec::with_capacity(self.request.wire_len());
 
         msg.put_u8(5); // version
         msg.put_u8(self.request.command().into());
         msg.put_u8(0); // reserved
-        self.request.addr().put_to_buf(&mut msg)?;
-        msg.put_u16(self.request.port());
+        SocksTarget::new(self.request.addr().clone(), self.request.port()).put_to_buf(&mut msg)?;
 
         Ok(msg)
     }
@@ -249,22 +260,177 @@ impl SocksClientHandshake {
     where
         S: AsyncReadExt + AsyncWriteExt + Unpin,
     {
-        let ver = stream.read_u8().await?;
-        if ver != 5 {
-            return Err(SocksError::InvalidVersion(ver));
+        let reply = SocksReply::read_from(stream, SocksVersion::V5).await?;
+
+        self.state = State::Done;
+
+        Ok(Some(reply))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Cursor},
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    use crate::socks::protocol::SocksCommand;
+
+    use super::*;
+
+    /// Replays `reads` and fails every write after the first `writes_ok`.
+    struct FailingStream {
+        reads: Cursor<Vec<u8>>,
+        writes_ok: usize,
+    }
+
+    impl AsyncRead for FailingStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().reads).poll_read(cx, buf)
         }
+    }
 
-        let status: SocksStatus = stream
-            .read_u8()
-            .await?
-            .try_into()
-            .map_err(|n| SocksError::InvalidStatus(n))?;
-        let _reserved = stream.read_u8().await?;
-        let addr = SocksAddr::read_from(stream).await?;
-        let port = stream.read_u16().await?;
+    impl AsyncWrite for FailingStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            if this.writes_ok == 0 {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            this.writes_ok -= 1;
+            Poll::Ready(Ok(buf.len()))
+        }
 
-        self.state = State::Done;
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks_client_write_error() {
+        let request = SocksRequest::new(
+            SocksVersion::V5,
+            SocksCommand::CONNECT,
+            SocksAddr::Domain("example.com".into()),
+            443,
+            SocksAuth::Username(b"user".to_vec(), b"pass".to_vec()),
+        )
+        .unwrap();
+
+        // the greeting, the credentials and the command fail in turn
+        for writes_ok in 0..3 {
+            let mut stream = FailingStream {
+                reads: Cursor::new(vec![5, 2, 1, 0]),
+                writes_ok,
+            };
+            let err = SocksClientHandshake::new(request.clone())
+                .connect(&mut stream)
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err, SocksError::Io(ref e) if e.kind() == io::ErrorKind::BrokenPipe),
+                "{}: {:?}",
+                writes_ok,
+                err
+            );
+            // nothing is read after the failed write
+            assert_eq!(stream.reads.position(), [0, 2, 4][writes_ok]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks_client_v4_detection() {
+        let request = SocksRequest::new(
+            SocksVersion::V5,
+            SocksCommand::CONNECT,
+            SocksAddr::Domain("example.com".into()),
+            443,
+            SocksAuth::NoAuth,
+        )
+        .unwrap();
+
+        for detect_v4 in [false, true] {
+            // a hang up and a v4 reply to the greeting
+            for reads in [vec![], vec![0, 91, 0, 0, 0, 0, 0, 0]] {
+                let mut stream = FailingStream {
+                    reads: Cursor::new(reads.clone()),
+                    writes_ok: 1,
+                };
+                let err = SocksClientHandshake::new(request.clone())
+                    .with_v4_detection(detect_v4)
+                    .connect(&mut stream)
+                    .await
+                    .unwrap_err();
+                match (detect_v4, reads.is_empty()) {
+                    (true, _) => assert!(matches!(err, SocksError::Socks5Rejected), "{:?}", err),
+                    (false, true) => assert!(
+                        matches!(err, SocksError::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof),
+                        "{:?}",
+                        err
+                    ),
+                    (false, false) => {
+                        assert!(matches!(err, SocksError::InvalidVersion(0)), "{:?}", err)
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_socks_request_wire_len() -> Result<(), SocksError> {
+        let cases = [
+            (SocksVersion::V4, "1.2.3.4", SocksAuth::NoAuth),
+            (
+                SocksVersion::V4,
+                "1.2.3.4",
+                SocksAuth::Socks4(b"user".to_vec()),
+            ),
+            (SocksVersion::V4, "example.com", SocksAuth::NoAuth),
+            (
+                SocksVersion::V4,
+                "example.com",
+                SocksAuth::Socks4(b"user".to_vec()),
+            ),
+            (SocksVersion::V5, "1.2.3.4", SocksAuth::NoAuth),
+            (SocksVersion::V5, "::1", SocksAuth::NoAuth),
+            (
+                SocksVersion::V5,
+                "example.com",
+                SocksAuth::Username(b"user".to_vec(), b"pass".to_vec()),
+            ),
+        ];
+
+        for (version, addr, auth) in cases {
+            let addr = match addr.parse() {
+                Ok(ip) => SocksAddr::Socket(ip),
+                Err(_) => SocksAddr::Domain(addr.to_string()),
+            };
+            let request = SocksRequest::new(version, SocksCommand::CONNECT, addr, 80, auth)?;
+            let cli = SocksClientHandshake::new(request.clone());
+
+            let msg = match version {
+                SocksVersion::V4 => cli.generate_v4_request()?,
+                SocksVersion::V5 => cli.generate_v5_command()?,
+            };
+
+            assert_eq!(request.wire_len(), msg.len());
+        }
 
-        Ok(Some(SocksReply::new(status, addr, port)))
+        Ok(())
     }
 }
@@ -2,6 +2,8 @@
 
 use std::{str::Utf8Error, string::FromUtf8Error};
 
+use crate::error::AddressError;
+
 #[derive(thiserror::Error, Debug)]
 pub enum SocksError {
     #[error("Io error: {0}")]
@@ -16,12 +18,16 @@ pub enum SocksError {
     InvalidCommand(u8),
     #[error("Invalid address")]
     InvalidAddress,
+    #[error("{0}")]
+    Address(#[from] AddressError),
     #[error("Invalid address type: {0:x}")]
     InvalidAddrType(u8),
     #[error("Invalid authentication method: {0:x}")]
     InvalidAuthMethod(u8),
-    #[error("Invalid authentication: {0}")]
-    InvalidAuth(String),
+    #[error("Authentication required but no credentials given")]
+    AuthRequired,
+    #[error("Authentication rejected for user `{0}`")]
+    AuthRejected(String),
     #[error("Unknown authentication")]
     UnknonwAuth,
     #[error("Invalid status {0:x}")]
@@ -36,6 +42,12 @@ pub enum SocksError {
     UnsupportAuthType,
     #[error("Unsupport authentication method")]
     UnsupportAuthMethod,
+    #[error("SOCKS4 disabled")]
+    Socks4Disabled,
+    #[error("SOCKS5 greeting rejected")]
+    Socks5Rejected,
+    #[error("Too many authentication attempts")]
+    TooManyAuthAttempts,
     #[error("Handshake finished status: {0}")]
     HandshakeFinished(String),
 }
@@ -9,11 +9,16 @@ pub use server::SocksServerHandshake;
 pub mod error;
 pub use error::SocksError;
 
-use std::{fmt, net::IpAddr};
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr},
+};
 
 use bytes::BufMut;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+use crate::{address::check_domain, crypto::ct_eq, OutboundError};
+
 macro_rules! enum_int {
     {
         $(#[$meta:meta])*
@@ -67,6 +72,20 @@ macro_rules! enum_int {
                 }
             }
         }
+
+        impl std::str::FromStr for $name {
+            type Err = String;
+
+            /// Parse the `Display` name, ignoring case.
+            fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+                $(
+                    if s.eq_ignore_ascii_case(stringify!($id)) {
+                        return Ok($name::$id);
+                    }
+                )+
+                Err(s.to_string())
+            }
+        }
     };
 }
 
@@ -75,8 +94,11 @@ macro_rules! enum_int {
 const USERNAME_PASSWORD: u8 = 0x02;
 /// Constant for "no authentication".
 const NO_AUTHENTICATION: u8 = 0x00;
+/// Constant for "no acceptable methods", the server's answer when none of
+/// the offered methods is supported.
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SocksReply {
     status: SocksStatus,
     addr: SocksAddr,
@@ -99,6 +121,80 @@ impl SocksReply {
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    /// Exact number of bytes `put_to_buf` writes for the given version.
+    pub fn wire_len(&self, version: SocksVersion) -> usize {
+        match version {
+            SocksVersion::V4 => 8,
+            SocksVersion::V5 => 3 + self.addr.wire_len() + 2,
+        }
+    }
+
+    /// Read a complete reply, including the leading version byte, in the
+    /// wire format of the given version.
+    pub async fn read_from<S>(r: &mut S, version: SocksVersion) -> Result<SocksReply, SocksError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let ver = r.read_u8().await?;
+
+        match version {
+            SocksVersion::V4 => {
+                if ver != 0 {
+                    return Err(SocksError::InvalidVersion(ver));
+                }
+
+                let status = SocksStatus::from_socks4_status(r.read_u8().await?);
+                let SocksTarget { addr, port } = SocksTarget::read_v4_from(r).await?;
+
+                Ok(SocksReply::new(status, addr, port))
+            }
+            SocksVersion::V5 => {
+                if ver != 5 {
+                    return Err(SocksError::InvalidVersion(ver));
+                }
+
+                let status: SocksStatus = r
+                    .read_u8()
+                    .await?
+                    .try_into()
+                    .map_err(SocksError::InvalidStatus)?;
+                let _reserved = r.read_u8().await?;
+                let SocksTarget { addr, port } = SocksTarget::read_from(r).await?;
+
+                Ok(SocksReply::new(status, addr, port))
+            }
+        }
+    }
+
+    /// Write the reply in the wire format of the given version.
+    ///
+    /// SOCKS4 can only carry an IPv4 address, any other address is sent
+    /// as `0.0.0.0:0`.
+    pub fn put_to_buf<B>(&self, version: SocksVersion, buf: &mut B) -> Result<(), SocksError>
+    where
+        B: BufMut,
+    {
+        match version {
+            SocksVersion::V4 => {
+                buf.put_u8(0);
+                buf.put_u8(self.status.into_socks4_status());
+                let target = SocksTarget::new(self.addr.clone(), self.port);
+                if target.put_v4_to_buf(buf).is_err() {
+                    buf.put_u16(0);
+                    buf.put_u32(0);
+                }
+            }
+            SocksVersion::V5 => {
+                buf.put_u8(5);
+                buf.put_u8(self.status.into());
+                buf.put_u8(0); // reserved.
+                SocksTarget::new(self.addr.clone(), self.port).put_to_buf(buf)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -158,6 +254,27 @@ impl SocksRequest {
     pub fn get_addr(self) -> SocksAddr {
         self.addr
     }
+
+    /// Exact number of bytes of the request message sent by the client.
+    ///
+    /// For SOCKS4 this includes the user id and the SOCKS4a hostname; for
+    /// SOCKS5 only the request itself, the method negotiation and username
+    /// authentication are separate messages.
+    pub fn wire_len(&self) -> usize {
+        match self.version {
+            SocksVersion::V4 => {
+                // ver + cmd + port + ipv4 + userid + nul
+                let mut len = 1 + 1 + 2 + 4 + self.auth.size() + 1;
+                if !matches!(self.addr, SocksAddr::Socket(IpAddr::V4(_))) {
+                    // socks4a hostname + nul
+                    len += self.addr.to_string().len() + 1;
+                }
+                len
+            }
+            // ver + cmd + rsv + addr + port
+            SocksVersion::V5 => 3 + self.addr.wire_len() + 2,
+        }
+    }
 }
 
 enum_int! {
@@ -199,6 +316,91 @@ impl fmt::Display for SocksAddr {
     }
 }
 
+/// Destination address and port of a SOCKS message, kept together so both
+/// are always read and written in the order of the given protocol version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SocksTarget {
+    pub addr: SocksAddr,
+    pub port: u16,
+}
+
+impl SocksTarget {
+    pub fn new(addr: SocksAddr, port: u16) -> Self {
+        Self { addr, port }
+    }
+
+    /// Read a SOCKS5 target: `ATYP | ADDR | PORT`.
+    pub async fn read_from<S>(r: &mut S) -> Result<SocksTarget, SocksError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        Self::read_from_with(r, true).await
+    }
+
+    /// Read a SOCKS5 target, `strict` turns the domain check on.
+    pub async fn read_from_with<S>(r: &mut S, strict: bool) -> Result<SocksTarget, SocksError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let addr = SocksAddr::read_from_with(r, strict).await?;
+        let port = r.read_u16().await?;
+
+        Ok(SocksTarget { addr, port })
+    }
+
+    /// Write a SOCKS5 target: `ATYP | ADDR | PORT`.
+    pub fn put_to_buf<B>(&self, buf: &mut B) -> Result<(), SocksError>
+    where
+        B: BufMut,
+    {
+        self.addr.put_to_buf(buf)?;
+        buf.put_u16(self.port);
+
+        Ok(())
+    }
+
+    /// Read a SOCKS4 target: `PORT | IPV4`.
+    ///
+    /// For SOCKS4a the returned address is the `0.0.0.x` marker, the
+    /// hostname follows the user id and must be read by the caller.
+    pub async fn read_v4_from<S>(r: &mut S) -> Result<SocksTarget, SocksError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let port = r.read_u16().await?;
+        let ip = Ipv4Addr::from(r.read_u32().await?);
+
+        Ok(SocksTarget {
+            addr: SocksAddr::Socket(ip.into()),
+            port,
+        })
+    }
+
+    /// Write a SOCKS4 target: `PORT | IPV4`.
+    pub fn put_v4_to_buf<B>(&self, buf: &mut B) -> Result<(), SocksError>
+    where
+        B: BufMut,
+    {
+        match &self.addr {
+            SocksAddr::Socket(IpAddr::V4(ip)) => {
+                buf.put_u16(self.port);
+                buf.put_slice(ip.octets().as_slice());
+                Ok(())
+            }
+            _ => Err(SocksError::UnsupportAddrtype),
+        }
+    }
+}
+
+impl fmt::Display for SocksTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.addr {
+            SocksAddr::Socket(IpAddr::V6(ip)) => write!(f, "[{}]:{}", ip, self.port),
+            addr => write!(f, "{}:{}", addr, self.port),
+        }
+    }
+}
+
 /// Provided authentication from a SOCKS handshake
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SocksAuth {
@@ -223,6 +425,20 @@ impl SocksAuth {
         }
     }
 
+    /// Equality that compares credentials with `crypto::ct_eq`, for
+    /// checking what a client sent against the configured users.
+    pub fn ct_eq(&self, other: &SocksAuth) -> bool {
+        match (self, other) {
+            (SocksAuth::NoAuth, SocksAuth::NoAuth) => true,
+            (SocksAuth::Socks4(a), SocksAuth::Socks4(b)) => ct_eq(a, b),
+            (SocksAuth::Username(u1, p1), SocksAuth::Username(u2, p2)) => {
+                // both halves are compared whatever the first gives
+                ct_eq(u1, u2) & ct_eq(p1, p2)
+            }
+            _ => false,
+        }
+    }
+
     pub fn size(&self) -> usize {
         match self {
             SocksAuth::NoAuth => 0,
@@ -236,12 +452,12 @@ impl std::fmt::Display for SocksAuth {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::NoAuth => write!(f, "noauth"),
-            Self::Socks4(d) => write!(f, "socks4 auth {}", String::from_utf8_lossy(&d)),
+            Self::Socks4(d) => write!(f, "socks4 auth {}", String::from_utf8_lossy(d)),
             Self::Username(user, pass) => write!(
                 f,
                 "username: {} password: {}",
-                String::from_utf8_lossy(&user),
-                String::from_utf8_lossy(&pass)
+                String::from_utf8_lossy(user),
+                String::from_utf8_lossy(pass)
             ),
         }
     }
@@ -294,10 +510,65 @@ impl SocksStatus {
             _ => SocksStatus::GENERAL_FAILURE,
         }
     }
+
+    /// Reply status for a failed connect to the destination. A timeout is
+    /// reported as `HOST_UNREACHABLE`, anything unmapped as `GENERAL_FAILURE`.
+    /// `TTL_EXPIRED` would claim a hop limit ran out, which a socket never reports.
+    pub fn from_io_error(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => SocksStatus::CONNECTION_REFUSED,
+            io::ErrorKind::HostUnreachable => SocksStatus::HOST_UNREACHABLE,
+            io::ErrorKind::NetworkUnreachable => SocksStatus::NETWORK_UNREACHABLE,
+            io::ErrorKind::TimedOut => SocksStatus::HOST_UNREACHABLE,
+            _ => SocksStatus::GENERAL_FAILURE,
+        }
+    }
+
+    /// Reply status for an outbound that failed to reach the destination.
+    pub fn from_outbound_error(err: &OutboundError) -> Self {
+        match err {
+            OutboundError::Io(e) => Self::from_io_error(e),
+            OutboundError::Unresolved | OutboundError::Unreachable => SocksStatus::HOST_UNREACHABLE,
+            OutboundError::Refused => SocksStatus::CONNECTION_REFUSED,
+            OutboundError::NoRoute(_) | OutboundError::SelfConnect(_) => SocksStatus::NOT_ALLOWED,
+            _ => SocksStatus::GENERAL_FAILURE,
+        }
+    }
+
+    /// Error for a failed request answered with this status, refused and
+    /// unreachable destinations are typed so callers can route around them.
+    pub fn to_outbound_error(self) -> OutboundError {
+        match self {
+            SocksStatus::CONNECTION_REFUSED => OutboundError::Refused,
+            SocksStatus::HOST_UNREACHABLE | SocksStatus::NETWORK_UNREACHABLE => {
+                OutboundError::Unreachable
+            }
+            status => OutboundError::Handshake(SocksError::InvalidStatus(status.into()).into()),
+        }
+    }
 }
 
 impl SocksAddr {
+    /// Length of the SOCKS5 encoding, including the address type byte.
+    pub fn wire_len(&self) -> usize {
+        match self {
+            SocksAddr::Socket(IpAddr::V4(_)) => 1 + 4,
+            SocksAddr::Socket(IpAddr::V6(_)) => 1 + 16,
+            SocksAddr::Domain(domain) => 1 + 1 + domain.len(),
+        }
+    }
+
+    /// Read an address, rejecting implausible domains with `check_domain`.
     pub async fn read_from<S>(r: &mut S) -> Result<SocksAddr, SocksError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        Self::read_from_with(r, true).await
+    }
+
+    /// Read an address, `strict` turns the domain check on. Empty domains
+    /// are always rejected.
+    pub async fn read_from_with<S>(r: &mut S, strict: bool) -> Result<SocksAddr, SocksError>
     where
         S: AsyncRead + Unpin,
     {
@@ -307,25 +578,43 @@ impl SocksAddr {
                 let mut addr = [0u8; 4];
                 let _ = r.read_exact(&mut addr).await?;
                 let ip4 = IpAddr::from(addr);
-                Ok(SocksAddr::Socket(ip4.into()))
+                Ok(SocksAddr::Socket(ip4))
             }
             3 => {
                 let str_len = r.read_u8().await?;
                 let mut addr = vec![0u8; str_len as usize];
                 let _ = r.read_exact(&mut addr).await?;
                 let addr = String::from_utf8(addr)?;
+                // an empty domain can never be connected to, strict or not
+                if strict {
+                    check_domain(&addr)?;
+                } else if addr.is_empty() {
+                    return Err(SocksError::InvalidAddress);
+                }
                 Ok(SocksAddr::Domain(addr))
             }
             4 => {
                 let mut addr = [0u8; 16];
                 let _ = r.read_exact(&mut addr).await?;
                 let ip6 = IpAddr::from(addr);
-                Ok(SocksAddr::Socket(ip6.into()))
+                Ok(SocksAddr::Socket(ip6))
             }
             other => Err(SocksError::InvalidAddrType(other)),
         }
     }
 
+    /// The IPv4 form of an IPv4-mapped IPv6 address, any other address as
+    /// it is.
+    pub fn unmap_v4(self) -> SocksAddr {
+        match self {
+            SocksAddr::Socket(IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => SocksAddr::Socket(IpAddr::V4(ip)),
+                None => self,
+            },
+            addr => addr,
+        }
+    }
+
     pub fn put_to_buf<B>(&self, buf: &mut B) -> Result<(), SocksError>
     where
         B: BufMut,
@@ -353,3 +642,286 @@ impl SocksAddr {
         Ok(())
     }
 }
+
+/// Write a SOCKS5 UDP datagram header:
+/// `RSV(2) | FRAG(1) | ATYP | ADDR | PORT`.
+pub fn encode_udp_header(
+    addr: &SocksAddr,
+    port: u16,
+    frag: u8,
+    buf: &mut impl BufMut,
+) -> Result<(), SocksError> {
+    buf.put_u16(0);
+    buf.put_u8(frag);
+    addr.put_to_buf(buf)?;
+    buf.put_u16(port);
+
+    Ok(())
+}
+
+/// Parse a SOCKS5 UDP datagram header, returning the address, port,
+/// fragment number and header length. The data follows in `buf`.
+pub fn decode_udp_header(buf: &[u8]) -> Result<(SocksAddr, u16, u8, usize), SocksError> {
+    if buf.len() < 4 {
+        return Err(SocksError::UnsupportFrame);
+    }
+    let frag = buf[2];
+
+    let (addr, addr_len) = match buf[3] {
+        1 if buf.len() >= 4 + 4 + 2 => {
+            let ip: [u8; 4] = buf[4..8].try_into().unwrap();
+            (SocksAddr::Socket(IpAddr::from(ip)), 4)
+        }
+        4 if buf.len() >= 4 + 16 + 2 => {
+            let ip: [u8; 16] = buf[4..20].try_into().unwrap();
+            (SocksAddr::Socket(IpAddr::from(ip)), 16)
+        }
+        3 if buf.len() >= 5 && buf.len() >= 5 + buf[4] as usize + 2 => {
+            let len = buf[4] as usize;
+            let domain = std::str::from_utf8(&buf[5..5 + len])?;
+            (SocksAddr::Domain(domain.to_owned()), 1 + len)
+        }
+        1 | 3 | 4 => return Err(SocksError::UnsupportFrame),
+        other => return Err(SocksError::InvalidAddrType(other)),
+    };
+
+    let at = 4 + addr_len;
+    let port = u16::from_be_bytes([buf[at], buf[at + 1]]);
+
+    Ok((addr, port, frag, at + 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_socks_reply_put_to_buf() -> Result<(), SocksError> {
+        for addr in [
+            SocksAddr::Socket("1.2.3.4".parse().unwrap()),
+            SocksAddr::Socket("::1".parse().unwrap()),
+            SocksAddr::Domain("example.com".to_string()),
+        ] {
+            for version in [SocksVersion::V4, SocksVersion::V5] {
+                let request = SocksRequest::new(
+                    version,
+                    SocksCommand::CONNECT,
+                    addr.clone(),
+                    8080,
+                    SocksAuth::NoAuth,
+                )?;
+                let reply = SocksReply::new(SocksStatus::SUCCEEDED, addr.clone(), 8080);
+
+                let mut buf = vec![];
+                reply.put_to_buf(version, &mut buf)?;
+
+                assert_eq!(buf, request.reply(SocksStatus::SUCCEEDED, Some(&addr))?);
+                assert_eq!(buf.len(), reply.wire_len(version));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_socks_reply_read_from() -> Result<(), SocksError> {
+        let reply = SocksReply::new(
+            SocksStatus::SUCCEEDED,
+            SocksAddr::Socket("1.2.3.4".parse().unwrap()),
+            1080,
+        );
+
+        let buf = [0, 0x5A, 0x04, 0x38, 1, 2, 3, 4];
+        let read = SocksReply::read_from(&mut Cursor::new(buf), SocksVersion::V4).await?;
+        assert_eq!(read, reply);
+
+        let buf = [5, 0, 0, 1, 1, 2, 3, 4, 0x04, 0x38];
+        let read = SocksReply::read_from(&mut Cursor::new(buf), SocksVersion::V5).await?;
+        assert_eq!(read, reply);
+
+        let reply = SocksReply::new(
+            SocksStatus::HOST_UNREACHABLE,
+            SocksAddr::Domain("example.com".to_string()),
+            443,
+        );
+        let mut buf = vec![];
+        reply.put_to_buf(SocksVersion::V5, &mut buf)?;
+        let read = SocksReply::read_from(&mut Cursor::new(buf), SocksVersion::V5).await?;
+        assert_eq!(read, reply);
+
+        let buf = [4, 0, 0, 1, 1, 2, 3, 4, 0, 80];
+        assert!(matches!(
+            SocksReply::read_from(&mut Cursor::new(buf), SocksVersion::V5).await,
+            Err(SocksError::InvalidVersion(4))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_socks_target_round_trip() -> Result<(), SocksError> {
+        for addr in [
+            SocksAddr::Socket("127.0.0.1".parse().unwrap()),
+            SocksAddr::Socket("::1".parse().unwrap()),
+            SocksAddr::Domain("example.com".to_string()),
+        ] {
+            let target = SocksTarget::new(addr, 1080);
+
+            let mut buf = vec![];
+            target.put_to_buf(&mut buf)?;
+            let read = SocksTarget::read_from(&mut Cursor::new(buf)).await?;
+
+            assert_eq!(target, read);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_socks_target_order() -> Result<(), SocksError> {
+        let target = SocksTarget::new(SocksAddr::Socket("1.2.3.4".parse().unwrap()), 0x5678);
+
+        // SOCKS5: address before port
+        let mut buf = vec![];
+        target.put_to_buf(&mut buf)?;
+        assert_eq!(buf, [1, 1, 2, 3, 4, 0x56, 0x78]);
+        assert_eq!(SocksTarget::read_from(&mut Cursor::new(buf)).await?, target);
+
+        // SOCKS4: port before address
+        let mut buf = vec![];
+        target.put_v4_to_buf(&mut buf)?;
+        assert_eq!(buf, [0x56, 0x78, 1, 2, 3, 4]);
+        assert_eq!(
+            SocksTarget::read_v4_from(&mut Cursor::new(buf)).await?,
+            target
+        );
+
+        let domain = SocksTarget::new(SocksAddr::Domain("example.com".to_string()), 80);
+        assert!(domain.put_v4_to_buf(&mut vec![]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_socks_addr_domain_check() {
+        let long = vec![b'a'; 254];
+        for domain in [&b""[..], &long[..], b"bad\x00name"] {
+            let mut buf = vec![3, domain.len() as u8];
+            buf.extend_from_slice(domain);
+
+            assert!(matches!(
+                SocksAddr::read_from(&mut Cursor::new(buf.clone())).await,
+                Err(SocksError::Address(_))
+            ));
+            assert_eq!(
+                SocksAddr::read_from_with(&mut Cursor::new(buf), false)
+                    .await
+                    .is_ok(),
+                !domain.is_empty()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks_addr_unmapped() {
+        let mapped: std::net::Ipv6Addr = "::ffff:1.2.3.4".parse().unwrap();
+        let mut buf = vec![4];
+        buf.extend_from_slice(&mapped.octets());
+
+        let addr = SocksAddr::read_from(&mut Cursor::new(buf)).await.unwrap();
+        assert_eq!(addr, SocksAddr::Socket(IpAddr::V6(mapped)));
+        assert_eq!(
+            addr.unmap_v4(),
+            SocksAddr::Socket("1.2.3.4".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_socks_enum_from_str() {
+        assert_eq!("v5".parse(), Ok(SocksVersion::V5));
+        assert_eq!("V4".parse(), Ok(SocksVersion::V4));
+        assert!(matches!("connect".parse(), Ok(SocksCommand::CONNECT)));
+        assert!(matches!(
+            "Udp_Associate".parse(),
+            Ok(SocksCommand::UDP_ASSOCIATE)
+        ));
+        assert_eq!(SocksVersion::V5.to_string().parse(), Ok(SocksVersion::V5));
+
+        for s in ["", "5", "v6", "socks5"] {
+            assert_eq!(s.parse::<SocksVersion>(), Err(s.to_string()));
+        }
+        for s in ["", "1", "conn", "connect "] {
+            assert!(s.parse::<SocksCommand>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_socks_status_from_io_error() {
+        for (kind, status) in [
+            (
+                io::ErrorKind::ConnectionRefused,
+                SocksStatus::CONNECTION_REFUSED,
+            ),
+            (
+                io::ErrorKind::HostUnreachable,
+                SocksStatus::HOST_UNREACHABLE,
+            ),
+            (
+                io::ErrorKind::NetworkUnreachable,
+                SocksStatus::NETWORK_UNREACHABLE,
+            ),
+            (io::ErrorKind::TimedOut, SocksStatus::HOST_UNREACHABLE),
+            (io::ErrorKind::BrokenPipe, SocksStatus::GENERAL_FAILURE),
+        ] {
+            let err = io::Error::from(kind);
+            assert_eq!(SocksStatus::from_io_error(&err), status, "{:?}", kind);
+            assert_eq!(
+                SocksStatus::from_outbound_error(&OutboundError::Io(err)),
+                status
+            );
+        }
+
+        assert_eq!(
+            SocksStatus::from_outbound_error(&OutboundError::Unresolved),
+            SocksStatus::HOST_UNREACHABLE
+        );
+    }
+
+    #[test]
+    fn test_udp_header_round_trip() -> Result<(), SocksError> {
+        for addr in [
+            SocksAddr::Socket("1.2.3.4".parse().unwrap()),
+            SocksAddr::Socket("2001:db8::1".parse().unwrap()),
+            SocksAddr::Domain("example.com".to_string()),
+        ] {
+            let mut buf = vec![];
+            encode_udp_header(&addr, 53, 2, &mut buf)?;
+            assert_eq!(&buf[..3], &[0, 0, 2]);
+            assert_eq!(buf.len(), 3 + addr.wire_len() + 2);
+            buf.extend_from_slice(b"data");
+
+            let (read, port, frag, len) = decode_udp_header(&buf)?;
+            assert_eq!((read, port, frag), (addr, 53, 2));
+            assert_eq!(&buf[len..], b"data");
+
+            assert!(decode_udp_header(&buf[..len - 1]).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_socks_auth_ct_eq() {
+        let user = |u: &str, p: &str| SocksAuth::Username(u.into(), p.into());
+
+        assert!(user("user", "pass").ct_eq(&user("user", "pass")));
+        assert!(!user("user", "pass").ct_eq(&user("user", "pasS")));
+        assert!(!user("user", "pass").ct_eq(&user("usr", "pass")));
+        assert!(SocksAuth::Socks4(b"id".to_vec()).ct_eq(&SocksAuth::Socks4(b"id".to_vec())));
+        assert!(!SocksAuth::Socks4(b"id".to_vec()).ct_eq(&SocksAuth::Socks4(b"di".to_vec())));
+        assert!(SocksAuth::NoAuth.ct_eq(&SocksAuth::NoAuth));
+        assert!(!SocksAuth::NoAuth.ct_eq(&user("", "")));
+    }
+}
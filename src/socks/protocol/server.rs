@@ -1,22 +1,32 @@
 //! Socks protocol server handshake
 
 use core::str;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use bytes::BufMut;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
 
+use crate::address::check_domain;
+
 use super::{
-    SocksAddr, SocksAuth, SocksCommand, SocksError, SocksRequest, SocksStatus, SocksVersion,
-    NO_AUTHENTICATION, USERNAME_PASSWORD,
+    SocksAddr, SocksAuth, SocksCommand, SocksError, SocksReply, SocksRequest, SocksStatus,
+    SocksTarget, SocksVersion, NO_ACCEPTABLE_METHODS, NO_AUTHENTICATION, USERNAME_PASSWORD,
 };
 
 const UNSPECIFIED_ADDR: SocksAddr = SocksAddr::Socket(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+const UNSPECIFIED_ADDR_V6: SocksAddr = SocksAddr::Socket(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
 
 #[derive(Debug, Clone)]
 pub struct SocksServerHandshake {
     state: State,
     auth: Option<SocksAuth>,
+    allow_v4: bool,
+    socks4_strict: bool,
+    strict_domain: bool,
+    max_auth_attempts: Option<u32>,
+    auth_attempts: u32,
+    offered_methods: Vec<u8>,
+    require_auth: bool,
 }
 
 #[derive(Clone, Debug, Copy, PartialEq, Eq)]
@@ -37,20 +47,91 @@ enum State {
     Failed,
 }
 
+impl Default for SocksServerHandshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SocksServerHandshake {
     pub fn new() -> Self {
         Self {
             auth: None,
             state: State::Initial,
+            allow_v4: true,
+            socks4_strict: false,
+            strict_domain: false,
+            max_auth_attempts: None,
+            auth_attempts: 0,
+            offered_methods: Vec::new(),
+            require_auth: false,
         }
     }
 
+    /// Refuse SOCKS4 requests right after the version byte.
+    pub fn with_allow_v4(mut self, allow_v4: bool) -> Self {
+        self.allow_v4 = allow_v4;
+        self
+    }
+
+    /// Plain SOCKS4 only, hostname (SOCKS4a) requests are refused with
+    /// `UnsupportAddrtype` instead of being resolved by the server.
+    pub fn with_socks4_strict(mut self, socks4_strict: bool) -> Self {
+        self.socks4_strict = socks4_strict;
+        self
+    }
+
+    /// Reject requested domains, SOCKS4a hostnames included, that fail
+    /// `check_domain`.
+    pub fn with_strict_domain(mut self, strict_domain: bool) -> Self {
+        self.strict_domain = strict_domain;
+        self
+    }
+
+    /// Check SOCKS5 username/password during the sub-negotiation, failing
+    /// it up to `max - 1` times with a prompt for another try before the
+    /// handshake ends with `TooManyAuthAttempts`.
+    ///
+    /// Without a limit every username/password is acknowledged and left to
+    /// the caller to check in the request.
+    pub fn with_max_auth_attempts(mut self, max: Option<u32>) -> Self {
+        self.max_auth_attempts = max;
+        self
+    }
+
+    /// Answer a SOCKS5 greeting without username/password with "no
+    /// acceptable methods" instead of selecting no authentication.
+    pub fn with_require_auth(mut self, require_auth: bool) -> Self {
+        self.require_auth = require_auth;
+        self
+    }
+
+    /// Auth methods the client offered in its SOCKS5 greeting, empty
+    /// before the greeting and for SOCKS4.
+    pub fn offered_methods(&self) -> &[u8] {
+        &self.offered_methods
+    }
+
     pub async fn accept<S>(&mut self, stream: &mut S) -> Result<SocksRequest, SocksError>
     where
         S: AsyncReadExt + AsyncBufReadExt + AsyncWriteExt + Unpin,
+    {
+        self.accept_with(stream, |_| true).await
+    }
+
+    /// Like `accept`, checking credentials with `verify` when
+    /// `max_auth_attempts` is set.
+    pub async fn accept_with<S, F>(
+        &mut self,
+        stream: &mut S,
+        verify: F,
+    ) -> Result<SocksRequest, SocksError>
+    where
+        S: AsyncReadExt + AsyncBufReadExt + AsyncWriteExt + Unpin,
+        F: Fn(&SocksAuth) -> bool,
     {
         loop {
-            if let Some(request) = self.handshake(stream).await? {
+            if let Some(request) = self.handshake_with(stream, &verify).await? {
                 return Ok(request);
             }
         }
@@ -59,22 +140,53 @@ impl SocksServerHandshake {
     pub async fn handshake<S>(&mut self, stream: &mut S) -> Result<Option<SocksRequest>, SocksError>
     where
         S: AsyncReadExt + AsyncBufReadExt + AsyncWriteExt + Unpin,
+    {
+        self.handshake_with(stream, &|_| true).await
+    }
+
+    async fn handshake_with<S, F>(
+        &mut self,
+        stream: &mut S,
+        verify: &F,
+    ) -> Result<Option<SocksRequest>, SocksError>
+    where
+        S: AsyncReadExt + AsyncBufReadExt + AsyncWriteExt + Unpin,
+        F: Fn(&SocksAuth) -> bool,
     {
         let ver = stream.read_u8().await?;
+        let request_version = match (self.state, ver) {
+            (State::Initial, 4) => Some(SocksVersion::V4),
+            (State::Socks5Wait, 5) => Some(SocksVersion::V5),
+            _ => None,
+        };
 
         let result = match (self.state, ver) {
+            (State::Initial, 4) if !self.allow_v4 => Err(SocksError::Socks4Disabled),
             (State::Initial, 4) => self.s4(stream).await,
             (State::Initial, 5) => self.s5_initial(stream).await,
             (State::Initial, v) => Err(SocksError::InvalidVersion(v)),
-            (State::Socks5Username, 1) => self.s5_uname(stream).await,
+            (State::Socks5Username, 1) => self.s5_uname(stream, verify).await,
             (State::Socks5Wait, 5) => self.s5(stream).await,
             (State::Done, _) => Err(SocksError::HandshakeFinished("done".to_string())),
             (State::Failed, _) => Err(SocksError::HandshakeFinished("failed".to_string())),
             _ => Err(SocksError::UnsupportFrame),
         };
 
-        if result.is_err() {
+        if let Err(ref e) = result {
             self.state = State::Failed;
+
+            // the client waits for an answer to its request, so it gets one
+            // before the error is returned
+            if let (Some(version), Some(status)) = (request_version, failure_status(e)) {
+                let mut reply = vec![];
+                if SocksReply::new(status, UNSPECIFIED_ADDR, 0)
+                    .put_to_buf(version, &mut reply)
+                    .is_ok()
+                {
+                    let _ = stream.write_all(&reply).await;
+                    let _ = stream.flush().await;
+                }
+            }
         }
 
         result
@@ -88,9 +200,8 @@ impl SocksServerHandshake {
             .read_u8()
             .await?
             .try_into()
-            .map_err(|n| SocksError::InvalidCommand(n))?;
-        let port = stream.read_u16().await?;
-        let ip = stream.read_u32().await?;
+            .map_err(SocksError::InvalidCommand)?;
+        let SocksTarget { addr, port } = SocksTarget::read_v4_from(stream).await?;
 
         let mut buf = Vec::with_capacity(255);
         buf.clear();
@@ -101,20 +212,26 @@ impl SocksServerHandshake {
             SocksAuth::Socks4(buf[..n - 1].to_vec())
         };
 
-        let addr = if ip != 0 && (ip >> 8) == 0 {
-            // Socks4a; a hostname is given.
-            buf.clear();
-            let n = stream.read_until(0, &mut buf).await?;
-            if n == 0 {
-                return Err(SocksError::InvalidAddress);
+        let addr = match addr {
+            SocksAddr::Socket(IpAddr::V4(ip)) if is_socks4a(ip) && self.socks4_strict => {
+                return Err(SocksError::UnsupportAddrtype);
             }
-
-            let hostname = str::from_utf8(&buf[..n - 1])?;
-
-            SocksAddr::Domain(hostname.to_owned())
-        } else {
-            let ip4: std::net::Ipv4Addr = ip.into();
-            SocksAddr::Socket(ip4.into())
+            SocksAddr::Socket(IpAddr::V4(ip)) if is_socks4a(ip) => {
+                // Socks4a; a hostname is given.
+                buf.clear();
+                let n = stream.read_until(0, &mut buf).await?;
+                if n <= 1 {
+                    return Err(SocksError::InvalidAddress);
+                }
+
+                let hostname = str::from_utf8(&buf[..n - 1])?;
+                if self.strict_domain {
+                    check_domain(hostname)?;
+                }
+
+                SocksAddr::Domain(hostname.to_owned())
+            }
+            addr => addr,
         };
 
         let request = SocksRequest::new(SocksVersion::V4, command, addr, port, auth)?;
@@ -134,26 +251,41 @@ impl SocksServerHandshake {
         let nmethods = stream.read_u8().await?;
         let mut methods = vec![0u8; nmethods as usize];
         let _ = stream.read_exact(&mut methods).await?;
+        self.offered_methods = methods;
+
+        let methods = &self.offered_methods;
         let (next, reply) = if methods.contains(&USERNAME_PASSWORD) {
             (State::Socks5Username, [5, USERNAME_PASSWORD])
-        } else if methods.contains(&NO_AUTHENTICATION) {
+        } else if !self.require_auth && methods.contains(&NO_AUTHENTICATION) {
             self.auth = Some(SocksAuth::NoAuth);
             (State::Socks5Wait, [5, NO_AUTHENTICATION])
         } else {
+            // RFC 1928 asks for a reply before closing, so the client does
+            // not wait for one
+            stream.write_all(&[5, NO_ACCEPTABLE_METHODS]).await?;
+            stream.flush().await?;
+            if methods.contains(&NO_AUTHENTICATION) {
+                return Err(SocksError::AuthRequired);
+            }
             return Err(SocksError::UnsupportAuthMethod);
         };
 
-        let _ = stream.write_all(&reply).await?;
-        let _ = stream.flush().await?;
+        stream.write_all(&reply).await?;
+        stream.flush().await?;
 
         self.state = next;
 
         Ok(None)
     }
 
-    pub async fn s5_uname<S>(&mut self, stream: &mut S) -> Result<Option<SocksRequest>, SocksError>
+    pub async fn s5_uname<S, F>(
+        &mut self,
+        stream: &mut S,
+        verify: &F,
+    ) -> Result<Option<SocksRequest>, SocksError>
     where
         S: AsyncReadExt + AsyncBufReadExt + AsyncWriteExt + Unpin,
+        F: Fn(&SocksAuth) -> bool,
     {
         let ulen = stream.read_u8().await?;
         let mut username = vec![0u8; ulen as usize];
@@ -163,10 +295,26 @@ impl SocksServerHandshake {
         let mut password = vec![0u8; plen as usize];
         let _ = stream.read_exact(&mut password).await?;
 
-        let _ = stream.write_all(&[1, 0]).await?;
-        let _ = stream.flush().await?;
+        let auth = SocksAuth::Username(username, password);
+        if let Some(max) = self.max_auth_attempts {
+            if !verify(&auth) {
+                // a non-zero status, the client may send another
+                // username/password while attempts are left
+                stream.write_all(&[1, 1]).await?;
+                stream.flush().await?;
+
+                self.auth_attempts += 1;
+                if self.auth_attempts >= max {
+                    return Err(SocksError::TooManyAuthAttempts);
+                }
+                return Ok(None);
+            }
+        }
 
-        self.auth = Some(SocksAuth::Username(username, password));
+        stream.write_all(&[1, 0]).await?;
+        stream.flush().await?;
+
+        self.auth = Some(auth);
         self.state = State::Socks5Wait;
 
         Ok(None)
@@ -180,15 +328,12 @@ impl SocksServerHandshake {
             .read_u8()
             .await?
             .try_into()
-            .map_err(|n| SocksError::InvalidCommand(n))?;
+            .map_err(SocksError::InvalidCommand)?;
         let _ignore = stream.read_u8().await?;
-        let addr = SocksAddr::read_from(stream).await?;
-        let port = stream.read_u16().await?;
+        let SocksTarget { addr, port } =
+            SocksTarget::read_from_with(stream, self.strict_domain).await?;
 
-        let auth = self
-            .auth
-            .take()
-            .ok_or_else(|| SocksError::UnsupportAuthType)?;
+        let auth = self.auth.take().ok_or(SocksError::UnsupportAuthType)?;
 
         let request = SocksRequest::new(SocksVersion::V5, command, addr, port, auth)?;
 
@@ -198,6 +343,25 @@ impl SocksServerHandshake {
     }
 }
 
+/// Reply status for a request the handshake failed on, none when the
+/// connection itself failed.
+fn failure_status(err: &SocksError) -> Option<SocksStatus> {
+    match err {
+        SocksError::Io(_) => None,
+        SocksError::InvalidCommand(_) => Some(SocksStatus::COMMAND_NOT_SUPPORTED),
+        SocksError::InvalidAddrType(_) | SocksError::UnsupportAddrtype => {
+            Some(SocksStatus::ADDRTYPE_NOT_SUPPORTED)
+        }
+        _ => Some(SocksStatus::GENERAL_FAILURE),
+    }
+}
+
+/// SOCKS4a marks a hostname request with the address `0.0.0.x`, `x != 0`.
+fn is_socks4a(ip: Ipv4Addr) -> bool {
+    let ip = u32::from(ip);
+    ip != 0 && (ip >> 8) == 0
+}
+
 impl SocksRequest {
     pub fn reply(
         &self,
@@ -232,14 +396,187 @@ impl SocksRequest {
         w.put_u8(5);
         w.put_u8(status.into());
         w.put_u8(0); // reserved.
-        if let Some(a) = addr {
-            a.put_to_buf(&mut w)?;
-            w.put_u16(self.port());
-        } else {
-            // TODO: sometimes I think we want to answer with ::, not 0.0.0.0
-            UNSPECIFIED_ADDR.put_to_buf(&mut w)?;
-            w.put_u16(0);
+
+        let header_len = w.len();
+        let encoded = addr.map(|a| SocksTarget::new(a.clone(), self.port()).put_to_buf(&mut w));
+        if !matches!(encoded, Some(Ok(()))) {
+            // no address, or one that cannot be encoded such as an over-long
+            // domain, the client still gets a valid reply
+            w.truncate(header_len);
+
+            // answer in the address family the client asked for
+            let unspecified = match self.addr() {
+                SocksAddr::Socket(IpAddr::V6(_)) => UNSPECIFIED_ADDR_V6,
+                _ => UNSPECIFIED_ADDR,
+            };
+            SocksTarget::new(unspecified, 0).put_to_buf(&mut w)?;
         }
         Ok(w)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_s5_unspecified_reply() -> Result<(), SocksError> {
+        let request = |addr: SocksAddr| {
+            SocksRequest::new(
+                SocksVersion::V5,
+                SocksCommand::CONNECT,
+                addr,
+                443,
+                SocksAuth::NoAuth,
+            )
+        };
+
+        let reply = request(SocksAddr::Socket("::1".parse().unwrap()))?
+            .reply(SocksStatus::SUCCEEDED, None)?;
+        assert_eq!(reply[3], 4);
+        assert_eq!(&reply[4..], &[0u8; 18]);
+
+        let reply = request(SocksAddr::Socket("127.0.0.1".parse().unwrap()))?
+            .reply(SocksStatus::SUCCEEDED, None)?;
+        assert_eq!(&reply[3..], &[1, 0, 0, 0, 0, 0, 0]);
+
+        let reply = request(SocksAddr::Domain("example.com".to_string()))?
+            .reply(SocksStatus::SUCCEEDED, None)?;
+        assert_eq!(&reply[3..], &[1, 0, 0, 0, 0, 0, 0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_s5_reply_encode_fallback() -> Result<(), SocksError> {
+        let request = SocksRequest::new(
+            SocksVersion::V5,
+            SocksCommand::CONNECT,
+            SocksAddr::Socket("127.0.0.1".parse().unwrap()),
+            443,
+            SocksAuth::NoAuth,
+        )?;
+
+        let bound = SocksAddr::Domain("a".repeat(300));
+        let reply = request.reply(SocksStatus::SUCCEEDED, Some(&bound))?;
+        assert_eq!(reply, [5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_s4_strict() {
+        let mut data = vec![4, 1, 0, 80, 0, 0, 0, 1, 0];
+        data.extend_from_slice(b"example.com\0");
+
+        let mut stream = tokio::io::BufStream::new(std::io::Cursor::new(data.clone()));
+        let request = SocksServerHandshake::new()
+            .accept(&mut stream)
+            .await
+            .unwrap();
+        assert_eq!(
+            request.addr(),
+            &SocksAddr::Domain("example.com".to_string())
+        );
+        assert_eq!(request.port(), 80);
+
+        let mut stream = tokio::io::BufStream::new(std::io::Cursor::new(data));
+        let err = SocksServerHandshake::new()
+            .with_socks4_strict(true)
+            .accept(&mut stream)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SocksError::UnsupportAddrtype));
+
+        // plain SOCKS4 is still accepted
+        let mut stream =
+            tokio::io::BufStream::new(std::io::Cursor::new(vec![4, 1, 0, 80, 127, 0, 0, 1, 0]));
+        let request = SocksServerHandshake::new()
+            .with_socks4_strict(true)
+            .accept(&mut stream)
+            .await
+            .unwrap();
+        assert_eq!(
+            request.addr(),
+            &SocksAddr::Socket("127.0.0.1".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_s5_no_acceptable_methods() {
+        // GSSAPI only
+        let (mut client, server) = tokio::io::duplex(64);
+        client.write_all(&[5, 1, 1]).await.unwrap();
+
+        let mut stream = tokio::io::BufStream::new(server);
+        let err = SocksServerHandshake::new()
+            .accept(&mut stream)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SocksError::UnsupportAuthMethod));
+
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0xff]);
+    }
+
+    #[tokio::test]
+    async fn test_s5_offered_methods() {
+        let mut stream = tokio::io::BufStream::new(std::io::Cursor::new(vec![
+            5, 3, 0, 1, 2, 1, 4, b'u', b's', b'e', b'r', 4, b'p', b'a', b's', b's', 5, 1, 0, 1,
+            127, 0, 0, 1, 0, 80,
+        ]));
+
+        let mut hand = SocksServerHandshake::new();
+        assert!(hand.offered_methods().is_empty());
+        let request = hand.accept(&mut stream).await.unwrap();
+        assert_eq!(hand.offered_methods(), &[0, 1, 2]);
+        assert_eq!(
+            request.auth(),
+            &SocksAuth::Username(b"user".to_vec(), b"pass".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_s5_request_failure_reply() {
+        let cases: [(&[u8], u8); 3] = [
+            // 9 is not a known command
+            (&[5, 9, 0, 1, 127, 0, 0, 1, 0, 80], 7),
+            // unknown address type
+            (&[5, 1, 0, 9, 127, 0, 0, 1, 0, 80], 8),
+            // empty domain
+            (&[5, 1, 0, 3, 0, 0, 80], 1),
+        ];
+
+        for (request, status) in cases {
+            let (mut client, server) = tokio::io::duplex(64);
+            client.write_all(&[5, 1, 0]).await.unwrap();
+            client.write_all(request).await.unwrap();
+
+            let mut stream = tokio::io::BufStream::new(server);
+            assert!(SocksServerHandshake::new()
+                .accept(&mut stream)
+                .await
+                .is_err());
+
+            let mut reply = [0u8; 12];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [5, 0, 5, status, 0, 1, 0, 0, 0, 0, 0, 0]);
+        }
+
+        // socks4 with an unsupported command
+        let (mut client, server) = tokio::io::duplex(64);
+        client
+            .write_all(&[4, 9, 0, 80, 127, 0, 0, 1, 0])
+            .await
+            .unwrap();
+        let mut stream = tokio::io::BufStream::new(server);
+        assert!(SocksServerHandshake::new()
+            .accept(&mut stream)
+            .await
+            .is_err());
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0, 0x5b, 0, 0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,463 @@
+//! Socks5 UDP associate client
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    pin::Pin,
+    task::{ready, Poll},
+};
+
+use bytes::BufMut;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+};
+
+use super::protocol::{decode_udp_header, encode_udp_header, SocksError, SocksTarget};
+
+/// Largest datagram a UDP relay can carry.
+pub const MAX_DATAGRAM: usize = 65535;
+
+/// Longest SOCKS UDP header, with a 255 byte domain.
+const MAX_HEADER: usize = 3 + 1 + 1 + 255 + 2;
+
+/// Datagrams to one target through a SOCKS5 UDP relay.
+///
+/// Writes are sent as single datagrams wrapped in the SOCKS UDP header.
+/// Reads return the payload of one datagram, a payload larger than the read
+/// buffer is returned over the following reads. The TCP control connection
+/// is kept for the lifetime of the stream, the association ends when the
+/// server closes it. Reads also poll it for that end, anything the server
+/// sends on it is discarded, SOCKS5 gives it no meaning.
+#[derive(Debug)]
+pub struct SocksUdpStream<S> {
+    control: S,
+    socket: UdpSocket,
+    target: SocksTarget,
+    recv_buf: Vec<u8>,
+    pending: Range<usize>,
+    max_datagram: usize,
+}
+
+impl<S> SocksUdpStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    /// Bind a local socket and connect it to the relay the server replied with.
+    /// Payloads larger than `max_datagram` are refused as `InvalidData`, both
+    /// received and written ones.
+    pub async fn connect(
+        control: S,
+        relay: SocketAddr,
+        target: SocksTarget,
+        max_datagram: usize,
+    ) -> io::Result<Self> {
+        if relay.ip().is_unspecified() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "unspecified udp relay address",
+            ));
+        }
+
+        let local = match relay {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(relay).await?;
+
+        Ok(Self {
+            control,
+            socket,
+            target,
+            // one spare byte tells a full buffer from a truncated datagram
+            recv_buf: vec![0u8; max_datagram + MAX_HEADER + 1],
+            pending: 0..0,
+            max_datagram,
+        })
+    }
+
+    pub fn target(&self) -> &SocksTarget {
+        &self.target
+    }
+
+    pub fn relay_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn control(&self) -> &S {
+        &self.control
+    }
+}
+
+/// `RSV(2) | FRAG(1) | ATYP | ADDR | PORT`
+fn put_udp_header<B: BufMut>(target: &SocksTarget, buf: &mut B) -> Result<(), SocksError> {
+    encode_udp_header(&target.addr, target.port, 0, buf)
+}
+
+/// Parse a datagram header, returning the source and the header length.
+/// Fragmented datagrams are not supported.
+fn parse_udp_header(buf: &[u8]) -> Result<(SocksTarget, usize), SocksError> {
+    let (addr, port, frag, len) = decode_udp_header(buf)?;
+    if frag != 0 {
+        return Err(SocksError::UnsupportFrame);
+    }
+
+    Ok((SocksTarget::new(addr, port), len))
+}
+
+impl<S> AsyncRead for SocksUdpStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.pending.is_empty() {
+            // the server ends the association by closing the control
+            // connection, any byte it sends there instead is dropped
+            let mut probe = [0u8; 1];
+            let mut probe = ReadBuf::new(&mut probe);
+            if let Poll::Ready(res) = Pin::new(&mut this.control).poll_read(cx, &mut probe) {
+                res?;
+                if probe.filled().is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            loop {
+                let mut recv = ReadBuf::new(&mut this.recv_buf);
+                ready!(this.socket.poll_recv(cx, &mut recv))?;
+                let len = recv.filled().len();
+
+                // drop datagrams that are not valid relay frames
+                let Ok((_, header_len)) = parse_udp_header(&this.recv_buf[..len]) else {
+                    continue;
+                };
+
+                if len == this.recv_buf.len() || len - header_len > this.max_datagram {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "udp datagram exceeds the max datagram size",
+                    )));
+                }
+
+                this.pending = header_len..len;
+                break;
+            }
+        }
+
+        let n = this.pending.len().min(buf.remaining());
+        buf.put_slice(&this.recv_buf[this.pending.start..this.pending.start + n]);
+        this.pending.start += n;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for SocksUdpStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.len() > this.max_datagram {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "udp payload exceeds the max datagram size",
+            )));
+        }
+
+        let mut datagram = Vec::with_capacity(3 + this.target.addr.wire_len() + 2 + buf.len());
+        put_udp_header(&this.target, &mut datagram).map_err(io::Error::other)?;
+        datagram.extend_from_slice(buf);
+
+        ready!(this.socket.poll_send(cx, &datagram))?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().control).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().control).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        address::NetworkType,
+        socks::{
+            protocol::SocksAddr, SocksInbound, SocksInboundOption, SocksOutbound,
+            SocksOutboundStream, SocksReplyAddr,
+        },
+        InboundServiceTrait, OutboundPacket, OutboundServiceTrait, ServiceAddress,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_udp_header() {
+        let target = SocksTarget::new(SocksAddr::Domain("example.com".into()), 53);
+        let mut buf = vec![];
+        put_udp_header(&target, &mut buf).unwrap();
+        buf.extend_from_slice(b"data");
+
+        let (read, len) = parse_udp_header(&buf).unwrap();
+        assert_eq!(read, target);
+        assert_eq!(&buf[len..], b"data");
+
+        // fragments are refused
+        buf[2] = 1;
+        assert!(parse_udp_header(&buf).is_err());
+        // truncated
+        assert!(parse_udp_header(&[0, 0, 0, 1, 127, 0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate() {
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+
+        let socks_in = SocksInbound::init(SocksInboundOption {
+            reply_addr: SocksReplyAddr::Bound(relay_addr),
+            ..Default::default()
+        })
+        .unwrap();
+        let socks_out = SocksOutbound::init(crate::socks::SocksOutboundOption::default()).unwrap();
+
+        let packet = OutboundPacket {
+            typ: NetworkType::Udp,
+            dest: ServiceAddress::new("8.8.8.8".into(), 53),
+        };
+        let (s1, s2) = duplex(4096);
+        let (out, inb) = tokio::join!(socks_out.handshake(s1, packet), socks_in.handshake(s2));
+        let mut out = out.unwrap();
+        let (control, pac) = inb.unwrap();
+        assert_eq!(pac.typ, NetworkType::Udp);
+
+        out.write_all(b"query").await.unwrap();
+
+        // the relay sees the wrapped datagram and answers from the target
+        let mut buf = [0u8; 1024];
+        let (n, client) = relay.recv_from(&mut buf).await.unwrap();
+        let (target, len) = parse_udp_header(&buf[..n]).unwrap();
+        assert_eq!(
+            target,
+            SocksTarget::new(SocksAddr::Socket("8.8.8.8".parse().unwrap()), 53)
+        );
+        assert_eq!(&buf[len..n], b"query");
+        relay.send_to(&buf[..n], client).await.unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = out.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"query");
+
+        // closing the control connection ends the association
+        drop(control);
+        assert_eq!(out.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate_reply() {
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+
+        let socks_in = SocksInbound::init(SocksInboundOption {
+            allow_v4: false,
+            reply_addr: SocksReplyAddr::Bound(relay_addr),
+            ..Default::default()
+        })
+        .unwrap();
+        let socks_out = SocksOutbound::init(crate::socks::SocksOutboundOption::default()).unwrap();
+
+        let packet = OutboundPacket {
+            typ: NetworkType::Udp,
+            dest: ServiceAddress::new("8.8.8.8".into(), 53),
+        };
+        let (s1, s2) = duplex(4096);
+        let (out, inb) = tokio::join!(socks_out.connect(s1, packet), socks_in.handshake(s2));
+        let (stream, reply) = out.unwrap();
+        let _control = inb.unwrap();
+
+        assert!(matches!(stream, SocksOutboundStream::Udp(_)));
+        assert_eq!(reply.addr(), &SocksAddr::Socket(relay_addr.ip()));
+        assert_eq!(reply.port(), relay_addr.port());
+    }
+
+    async fn relay_pair(
+        max_datagram: usize,
+    ) -> (
+        SocksUdpStream<tokio::io::DuplexStream>,
+        tokio::io::DuplexStream,
+        UdpSocket,
+    ) {
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (control, peer) = duplex(64);
+        let target = SocksTarget::new(SocksAddr::Socket("8.8.8.8".parse().unwrap()), 53);
+        let stream =
+            SocksUdpStream::connect(control, relay.local_addr().unwrap(), target, max_datagram)
+                .await
+                .unwrap();
+        (stream, peer, relay)
+    }
+
+    #[tokio::test]
+    async fn test_udp_large_datagram() {
+        let (mut out, _control, relay) = relay_pair(MAX_DATAGRAM).await;
+
+        let payload: Vec<u8> = (0..60000u32).map(|i| i as u8).collect();
+        out.write_all(&payload).await.unwrap();
+
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let (n, client) = relay.recv_from(&mut buf).await.unwrap();
+        let (_, len) = parse_udp_header(&buf[..n]).unwrap();
+        assert_eq!(&buf[len..n], &payload[..]);
+        relay.send_to(&buf[..n], client).await.unwrap();
+
+        // read in chunks smaller than the datagram
+        let mut read = vec![0u8; payload.len()];
+        out.read_exact(&mut read).await.unwrap();
+        assert_eq!(read, payload);
+    }
+
+    #[tokio::test]
+    async fn test_udp_oversized_datagram() {
+        let (mut out, _control, relay) = relay_pair(1000).await;
+
+        out.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 64];
+        let (n, client) = relay.recv_from(&mut buf).await.unwrap();
+        let (_, len) = parse_udp_header(&buf[..n]).unwrap();
+
+        let mut datagram = buf[..len].to_vec();
+        datagram.extend_from_slice(&[1u8; 2000]);
+        relay.send_to(&datagram, client).await.unwrap();
+
+        let mut buf = vec![0u8; 4096];
+        let err = out.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // nor are larger payloads sent
+        let err = out.write(&[1u8; 1001]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(out.write(&[1u8; 1000]).await.unwrap(), 1000);
+        let mut buf = vec![0u8; 4096];
+        let (n, _) = relay.recv_from(&mut buf).await.unwrap();
+        let (_, len) = parse_udp_header(&buf[..n]).unwrap();
+        assert_eq!(n - len, 1000);
+    }
+
+    /// Socks5 server replying a udp associate with the relay as 0.0.0.0
+    /// and only the port set.
+    async fn unspecified_relay_server<S>(mut s: S, relay_port: u16) -> S
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let mut buf = [0u8; 10];
+        s.read_exact(&mut buf[..3]).await.unwrap();
+        s.write_all(&[5, 0]).await.unwrap();
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[1], 3);
+        let [hi, lo] = relay_port.to_be_bytes();
+        s.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, hi, lo])
+            .await
+            .unwrap();
+        s
+    }
+
+    #[tokio::test]
+    async fn test_udp_unspecified_relay() {
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_port = relay.local_addr().unwrap().port();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            unspecified_relay_server(s, relay_port).await
+        });
+
+        let socks_out = SocksOutbound::init(crate::socks::SocksOutboundOption::default()).unwrap();
+        let packet = OutboundPacket {
+            typ: NetworkType::Udp,
+            dest: ServiceAddress::new("8.8.8.8".into(), 53),
+        };
+        let stream = tokio::net::TcpStream::connect(server_addr).await.unwrap();
+        let (out, _) = socks_out.connect(stream, packet).await.unwrap();
+        let _control = server.await.unwrap();
+        let SocksOutboundStream::Udp(mut out) = out else {
+            panic!("not a udp stream");
+        };
+        assert_eq!(out.relay_addr().unwrap().ip(), server_addr.ip());
+
+        out.write_all(b"query").await.unwrap();
+        let mut buf = [0u8; 1024];
+        let (n, _) = relay.recv_from(&mut buf).await.unwrap();
+        let (_, len) = parse_udp_header(&buf[..n]).unwrap();
+        assert_eq!(&buf[len..n], b"query");
+    }
+
+    #[tokio::test]
+    async fn test_udp_unspecified_relay_service() {
+        use crate::{option::OutboundServiceOption, OutboundService, OutboundServiceStream};
+
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_port = relay.local_addr().unwrap().port();
+        let packet = OutboundPacket {
+            typ: NetworkType::Udp,
+            dest: ServiceAddress::new("8.8.8.8".into(), 53),
+        };
+
+        // a duplex stream has no peer, the configured server address is used
+        let service = OutboundService::init(OutboundServiceOption::Socks(
+            crate::socks::SocksOutboundOption {
+                server_addr: Some("127.0.0.1:1080".parse().unwrap()),
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+        let (s1, s2) = duplex(4096);
+        let (out, _control) = tokio::join!(
+            service.handshake(s1, packet.clone()),
+            unspecified_relay_server(s2, relay_port)
+        );
+        let OutboundServiceStream::Socks(SocksOutboundStream::Udp(mut out)) = out.unwrap() else {
+            panic!("not a udp stream");
+        };
+        assert_eq!(out.relay_addr().unwrap(), relay.local_addr().unwrap());
+
+        out.write_all(b"query").await.unwrap();
+        let mut buf = [0u8; 1024];
+        let (n, _) = relay.recv_from(&mut buf).await.unwrap();
+        let (_, len) = parse_udp_header(&buf[..n]).unwrap();
+        assert_eq!(&buf[len..n], b"query");
+
+        // without one it cannot be reached
+        let service =
+            OutboundService::init(OutboundServiceOption::Socks(Default::default())).unwrap();
+        let (s1, s2) = duplex(4096);
+        let (out, _control) = tokio::join!(
+            service.handshake(s1, packet),
+            unspecified_relay_server(s2, relay_port)
+        );
+        assert!(out.is_err());
+    }
+}
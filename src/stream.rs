@@ -1,17 +1,195 @@
 //! Stream utils
 
-use std::{pin::Pin, task::Poll};
+use std::{
+    collections::VecDeque,
+    future::{poll_fn, Future},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Poll},
+    time::Duration,
+};
 
-use bytes::Bytes;
-use tokio::io::{AsyncRead, AsyncWrite};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    time::{Instant, Sleep},
+};
+
+use crate::{InboundError, InboundResult};
+
+/// Write all of `buf` and flush, failing with `TimedOut` if the peer does not
+/// take the data within `timeout`.
+pub async fn write_all_timeout<W>(
+    stream: &mut W,
+    buf: &[u8],
+    timeout: Option<Duration>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let write = async {
+        stream.write_all(buf).await?;
+        stream.flush().await
+    };
+
+    match timeout {
+        Some(t) => tokio::time::timeout(t, write)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "write timeout"))?,
+        None => write.await,
+    }
+}
+
+/// Read the first `n` bytes of `stream`, fewer only if it ends before, to
+/// pick a protocol from. The returned stream still yields them, so the
+/// protocol reads them again.
+pub async fn peek<S>(stream: S, n: usize) -> io::Result<(CachedStream<S>, Bytes)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    let mut stream = CachedStream::new(stream, None);
+    let head = Bytes::copy_from_slice(stream.peek_n(n).await?);
+
+    Ok((stream, head))
+}
+
+/// Buffer size of `BufStream::new`.
+pub(crate) const DEFAULT_BUF_SIZE: usize = 8 * 1024;
+
+/// Read buffer size from an inbound option, an empty buffer would make
+/// every read look like EOF.
+pub(crate) fn read_buffer_size(read_buffer: Option<usize>) -> InboundResult<usize> {
+    match read_buffer {
+        Some(0) => Err(InboundError::Option(
+            "read_buffer must be at least 1".to_string(),
+        )),
+        Some(n) => Ok(n),
+        None => Ok(DEFAULT_BUF_SIZE),
+    }
+}
+
+/// `BufStream` with a read buffer of `read_buffer` bytes.
+pub(crate) fn buf_stream<S>(stream: S, read_buffer: usize) -> BufStream<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    BufStream::with_capacity(read_buffer, DEFAULT_BUF_SIZE, stream)
+}
+
+/// Reader keeping a copy of every byte read through it, so what a failed
+/// handshake consumed can be replayed.
+#[derive(Debug)]
+pub(crate) struct RecordReader<'a, R> {
+    inner: &'a mut R,
+    record: BytesMut,
+}
+
+impl<'a, R> RecordReader<'a, R> {
+    pub(crate) fn new(inner: &'a mut R) -> Self {
+        Self {
+            inner,
+            record: BytesMut::new(),
+        }
+    }
+
+    pub(crate) fn into_record(self) -> Bytes {
+        self.record.freeze()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RecordReader<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut *this.inner).poll_read(cx, buf))?;
+        this.record.extend_from_slice(&buf.filled()[filled..]);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Streams that may know the address of their remote end.
+///
+/// Wrappers report the address of the stream they wrap, so a handshake
+/// result over a socket still tells which peer it talks to.
+pub trait ProxyStream {
+    /// Address of the peer, `None` when the stream is not a socket.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl ProxyStream for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+impl<S: ProxyStream> ProxyStream for &mut S {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+}
+
+impl<S> ProxyStream for BufStream<S>
+where
+    S: AsyncRead + AsyncWrite + ProxyStream,
+{
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+}
+
+impl<S> ProxyStream for CachedStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + ProxyStream,
+{
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+impl<S: ProxyStream> ProxyStream for CountingStream<S> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+impl<S: ProxyStream> ProxyStream for IdleTimeoutStream<S> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+impl<S: ProxyStream> ProxyStream for CloseNotifyStream<S> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+impl ProxyStream for tokio::io::DuplexStream {}
 
 #[derive(Debug)]
 pub struct CachedStream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
 {
-    cache: Option<Bytes>,
+    /// Segments returned by reads before the inner stream, in order.
+    cache: VecDeque<Bytes>,
     inner: S,
+    read_closed: bool,
+    write_cache: BytesMut,
+    write_threshold: usize,
 }
 
 impl<S> CachedStream<S>
@@ -19,7 +197,85 @@ where
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
 {
     pub fn new(inner: S, cache: Option<Bytes>) -> Self {
-        Self { cache, inner }
+        Self::with_write_cache(inner, cache, 0)
+    }
+
+    /// Like `new` with several cached segments, read in order without
+    /// joining them first.
+    pub fn new_multi(inner: S, segments: impl IntoIterator<Item = Bytes>) -> Self {
+        let mut stream = Self::new(inner, None);
+        stream.cache = segments.into_iter().filter(|b| !b.is_empty()).collect();
+        stream
+    }
+
+    /// Like `new`, but writes are held back while they add up to at most
+    /// `write_threshold` bytes. The held bytes go out together with the
+    /// first write past the threshold, or on flush/shutdown.
+    pub fn with_write_cache(inner: S, cache: Option<Bytes>, write_threshold: usize) -> Self {
+        Self {
+            cache: cache.into_iter().collect(),
+            inner,
+            read_closed: false,
+            write_cache: BytesMut::new(),
+            write_threshold,
+        }
+    }
+
+    fn poll_write_cache(&mut self, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_cache.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.write_cache) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => self.write_cache.advance(n),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Whether the read side has reached EOF.
+    pub fn is_read_closed(&self) -> bool {
+        self.read_closed
+    }
+
+    /// Read until `n` bytes are cached, or the inner stream reaches EOF, and
+    /// return the cached bytes without consuming them. No more than `n` bytes
+    /// are read ahead.
+    pub async fn peek_n(&mut self, n: usize) -> io::Result<&[u8]> {
+        let cached = self.cache.iter().map(Bytes::len).sum::<usize>();
+        if self.cache.len() == 1 && cached >= n {
+            return Ok(&self.cache[0]);
+        }
+
+        // peeking needs the cache in one piece
+        let mut cache = BytesMut::with_capacity(cached.max(n));
+        for segment in self.cache.drain(..) {
+            cache.extend_from_slice(&segment);
+        }
+
+        let mut result = Ok(());
+        while cache.len() < n {
+            let limit = (n - cache.len()) as u64;
+            match (&mut self.inner).take(limit).read_buf(&mut cache).await {
+                Ok(0) => {
+                    self.read_closed = true;
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        if !cache.is_empty() {
+            self.cache.push_back(cache.freeze());
+        }
+        result?;
+
+        Ok(self.cache.front().map(|b| &b[..]).unwrap_or_default())
     }
 }
 
@@ -34,10 +290,10 @@ where
     ) -> Poll<std::io::Result<()>> {
         let this = self.get_mut();
 
-        if let Some(mut cache) = this.cache.take() {
+        if let Some(mut cache) = this.cache.pop_front() {
             if buf.remaining() < cache.len() {
                 buf.put_slice(&cache.split_to(buf.remaining())[..]);
-                this.cache = Some(cache);
+                this.cache.push_front(cache);
             } else {
                 buf.put_slice(&cache[..]);
             }
@@ -45,7 +301,15 @@ where
             return Ok(()).into();
         }
 
-        Pin::new(&mut this.inner).poll_read(cx, buf)
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            if buf.filled().len() == filled && buf.remaining() > 0 {
+                this.read_closed = true;
+            }
+        }
+
+        result
     }
 }
 
@@ -58,20 +322,849 @@ where
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, std::io::Error>> {
-        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+        let this = self.get_mut();
+
+        if this.write_threshold > 0 {
+            this.write_cache.extend_from_slice(buf);
+            if this.write_cache.len() > this.write_threshold {
+                this.write_threshold = 0;
+                if let Poll::Ready(Err(e)) = this.poll_write_cache(cx) {
+                    return Poll::Ready(Err(e));
+                }
+            }
+            return Poll::Ready(Ok(buf.len()));
+        }
+
+        ready!(this.poll_write_cache(cx))?;
+        Pin::new(&mut this.inner).poll_write(cx, buf)
     }
 
     fn poll_flush(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        let this = self.get_mut();
+        this.write_threshold = 0;
+        ready!(this.poll_write_cache(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
     }
 
     fn poll_shutdown(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
+        let this = self.get_mut();
+        this.write_threshold = 0;
+        ready!(this.poll_write_cache(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Counts the bytes read from and written to the inner stream.
+///
+/// The counters are shared, so one pair can sum up every connection of a
+/// service.
+#[derive(Debug)]
+pub struct CountingStream<S> {
+    inner: S,
+    read: Arc<AtomicU64>,
+    write: Arc<AtomicU64>,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self::with_counters(inner, Arc::default(), Arc::default())
+    }
+
+    pub fn with_counters(inner: S, read: Arc<AtomicU64>, write: Arc<AtomicU64>) -> Self {
+        Self { inner, read, write }
+    }
+
+    pub fn read_bytes(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub fn write_bytes(&self) -> u64 {
+        self.write.load(Ordering::Relaxed)
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> AsyncRead for CountingStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.read
+            .fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for CountingStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.write.fetch_add(n as u64, Ordering::Relaxed);
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Fails reads and writes with `TimedOut` once the stream has made no
+/// progress in either direction for `timeout`.
+///
+/// Every completed read or write restarts the timer, so a tunnel only times
+/// out when both peers go silent.
+#[derive(Debug)]
+pub struct IdleTimeoutStream<S> {
+    inner: S,
+    timeout: Duration,
+    last_active: Instant,
+    // one timer per direction, a split stream has a task waiting on each
+    read_sleep: Pin<Box<Sleep>>,
+    write_sleep: Pin<Box<Sleep>>,
+}
+
+impl<S> IdleTimeoutStream<S> {
+    pub fn new(inner: S, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            last_active: Instant::now(),
+            read_sleep: Box::pin(tokio::time::sleep(timeout)),
+            write_sleep: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn reset(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    /// Turn a pending operation into `TimedOut` once `sleep` fired, moving
+    /// it first to the deadline set by the latest activity.
+    fn poll_idle<T>(
+        sleep: &mut Pin<Box<Sleep>>,
+        deadline: Instant,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<T>> {
+        if sleep.deadline() != deadline {
+            sleep.as_mut().reset(deadline);
+        }
+        ready!(sleep.as_mut().poll(cx));
+        Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout")))
+    }
+}
+
+impl<S> AsyncRead for IdleTimeoutStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(res) => {
+                this.reset();
+                Poll::Ready(res)
+            }
+            Poll::Pending => {
+                let deadline = this.last_active + this.timeout;
+                Self::poll_idle(&mut this.read_sleep, deadline, cx)
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for IdleTimeoutStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(res) => {
+                this.reset();
+                Poll::Ready(res)
+            }
+            Poll::Pending => {
+                let deadline = this.last_active + this.timeout;
+                Self::poll_idle(&mut this.write_sleep, deadline, cx)
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
         Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
     }
 }
+
+/// Calls `on_close` once when the stream is dropped, e.g. to update a
+/// connection gauge or log how long a connection lasted.
+///
+/// Shutting the stream down does not count as closing it, the callback
+/// still runs exactly once on drop.
+pub struct CloseNotifyStream<S> {
+    inner: S,
+    // the mutex makes the stream `Sync` for a callback that is only `Send`
+    on_close: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+}
+
+impl<S> CloseNotifyStream<S> {
+    pub fn new<F>(inner: S, on_close: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        Self {
+            inner,
+            on_close: Mutex::new(Some(Box::new(on_close))),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S> Drop for CloseNotifyStream<S> {
+    fn drop(&mut self) {
+        let on_close = self
+            .on_close
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(on_close) = on_close {
+            on_close();
+        }
+    }
+}
+
+impl<S: std::fmt::Debug> std::fmt::Debug for CloseNotifyStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CloseNotifyStream")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> AsyncRead for CloseNotifyStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for CloseNotifyStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Largest frame a `LengthDelimitedStream` reads, the most a u16 length
+/// prefix can announce.
+pub const MAX_FRAME_SIZE: usize = u16::MAX as usize;
+
+/// Bytes read from the inner stream at a time while a frame is incomplete.
+const FRAME_READ_CHUNK: usize = 4096;
+
+/// Splits the inner stream into messages, each prefixed with its length as a
+/// big endian u16.
+///
+/// `send_frame` and `recv_frame` exchange whole messages. Through
+/// `AsyncWrite` every write becomes one frame, and `AsyncRead` yields the
+/// payloads back to back without their boundaries.
+#[derive(Debug)]
+pub struct LengthDelimitedStream<S> {
+    inner: S,
+    max_frame: usize,
+    /// Received bytes not yet returned as a frame.
+    read_buf: BytesMut,
+    /// Rest of the frame being returned through `AsyncRead`.
+    read_frame: Bytes,
+    /// Encoded frames not yet written to the inner stream.
+    write_buf: BytesMut,
+}
+
+impl<S> LengthDelimitedStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self::with_max_frame(inner, MAX_FRAME_SIZE)
+    }
+
+    /// Frames longer than `max_frame` bytes are refused in both directions,
+    /// it is capped at `MAX_FRAME_SIZE`.
+    pub fn with_max_frame(inner: S, max_frame: usize) -> Self {
+        Self {
+            inner,
+            max_frame: max_frame.min(MAX_FRAME_SIZE),
+            read_buf: BytesMut::new(),
+            read_frame: Bytes::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    pub fn max_frame(&self) -> usize {
+        self.max_frame
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// The inner stream, dropping buffered data of both directions.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn put_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > self.max_frame {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame of {} bytes is too large", frame.len()),
+            ));
+        }
+
+        self.write_buf.reserve(2 + frame.len());
+        self.write_buf.put_u16(frame.len() as u16);
+        self.write_buf.put_slice(frame);
+
+        Ok(())
+    }
+}
+
+impl<S> LengthDelimitedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    /// Read the next frame, `None` at EOF between frames.
+    pub async fn recv_frame(&mut self) -> io::Result<Option<Bytes>> {
+        poll_fn(|cx| self.poll_recv_frame(cx)).await
+    }
+
+    fn poll_recv_frame(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<Option<Bytes>>> {
+        loop {
+            let mut need = 2;
+            if self.read_buf.len() >= 2 {
+                let len = u16::from_be_bytes([self.read_buf[0], self.read_buf[1]]) as usize;
+                if len > self.max_frame {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("frame of {} bytes is too large", len),
+                    )));
+                }
+                if self.read_buf.len() >= 2 + len {
+                    self.read_buf.advance(2);
+                    return Poll::Ready(Ok(Some(self.read_buf.split_to(len).freeze())));
+                }
+                need = 2 + len;
+            }
+
+            let filled = self.read_buf.len();
+            let want = (need - filled).max(FRAME_READ_CHUNK);
+            self.read_buf.resize(filled + want, 0);
+            let mut buf = tokio::io::ReadBuf::new(&mut self.read_buf[filled..]);
+            let res = Pin::new(&mut self.inner).poll_read(cx, &mut buf);
+            let n = buf.filled().len();
+            self.read_buf.truncate(filled + n);
+            ready!(res)?;
+
+            if n == 0 {
+                if self.read_buf.is_empty() {
+                    return Poll::Ready(Ok(None));
+                }
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "stream ended inside a frame",
+                )));
+            }
+        }
+    }
+}
+
+impl<S> LengthDelimitedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    /// Write `frame` as one message and flush it.
+    pub async fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        poll_fn(|cx| self.poll_write_buf(cx)).await?;
+        self.put_frame(frame)?;
+        self.flush().await
+    }
+
+    /// Write out the encoded frames.
+    fn poll_write_buf(&mut self, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncRead for LengthDelimitedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // empty frames carry nothing to return
+        while this.read_frame.is_empty() {
+            match ready!(this.poll_recv_frame(cx))? {
+                Some(frame) => this.read_frame = frame,
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let n = this.read_frame.len().min(buf.remaining());
+        buf.put_slice(&this.read_frame.split_to(n));
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for LengthDelimitedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    /// Takes up to `max_frame` bytes of `buf` as one frame.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(this.max_frame);
+        this.put_frame(&buf[..n])?;
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::{duplex, AsyncReadExt};
+
+    use super::*;
+
+    /// Records every write it receives, shared with other modules' tests.
+    #[derive(Default)]
+    pub(crate) struct WriteLog(pub(crate) Vec<Vec<u8>>);
+
+    impl AsyncRead for WriteLog {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for WriteLog {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().0.push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_stream_write_cache() {
+        let mut stream = CachedStream::with_write_cache(WriteLog::default(), None, 16);
+        stream.write_all(b"head").await.unwrap();
+        stream.write_all(b"er").await.unwrap();
+        assert!(stream.inner.0.is_empty());
+        stream.flush().await.unwrap();
+        stream.write_all(b"after").await.unwrap();
+        assert_eq!(stream.inner.0, vec![b"header".to_vec(), b"after".to_vec()]);
+
+        // a write past the threshold carries the cached bytes along
+        let mut stream = CachedStream::with_write_cache(WriteLog::default(), None, 8);
+        stream.write_all(b"head").await.unwrap();
+        stream.write_all(b"payload").await.unwrap();
+        assert_eq!(stream.inner.0, vec![b"headpayload".to_vec()]);
+
+        // shutdown sends what is left
+        let mut stream = CachedStream::with_write_cache(WriteLog::default(), None, 8);
+        stream.write_all(b"head").await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert_eq!(stream.inner.0, vec![b"head".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_cached_stream_read_closed() {
+        let (s1, mut s2) = duplex(64);
+        let mut stream = CachedStream::new(s1, Some(Bytes::from_static(b"ca")));
+
+        s2.write_all(b"che").await.unwrap();
+        s2.shutdown().await.unwrap();
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"cache");
+        assert!(stream.is_read_closed());
+    }
+
+    #[tokio::test]
+    async fn test_counting_stream() {
+        let (s1, mut s2) = duplex(4096);
+        let total = Arc::new(AtomicU64::new(0));
+        let mut stream = CountingStream::with_counters(s1, total.clone(), Arc::default());
+
+        stream.write_all(&[0u8; 1000]).await.unwrap();
+        stream.write_all(&[0u8; 24]).await.unwrap();
+        let mut buf = [0u8; 1024];
+        s2.read_exact(&mut buf).await.unwrap();
+
+        s2.write_all(&[1u8; 300]).await.unwrap();
+        drop(s2);
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+
+        assert_eq!(stream.write_bytes(), 1024);
+        assert_eq!(stream.read_bytes(), 300);
+        assert_eq!(total.load(Ordering::Relaxed), 300);
+    }
+
+    #[tokio::test]
+    async fn test_cached_peek_n() {
+        let (mut client, server) = duplex(64);
+        let mut stream = CachedStream::new(server, Some(Bytes::from_static(b"ab")));
+
+        client.write_all(b"cdefgh").await.unwrap();
+        assert_eq!(stream.peek_n(1).await.unwrap(), b"ab");
+        assert_eq!(stream.peek_n(4).await.unwrap(), b"abcd");
+        assert_eq!(stream.peek_n(3).await.unwrap(), b"abcd");
+
+        drop(client);
+        assert_eq!(stream.peek_n(16).await.unwrap(), b"abcdefgh");
+        assert!(stream.is_read_closed());
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"abcdefgh");
+    }
+
+    #[tokio::test]
+    async fn test_peek_replay() {
+        let (mut client, server) = duplex(64);
+        client.write_all(b"GET").await.unwrap();
+        client.write_all(b" / HTTP/1.1\r\n").await.unwrap();
+        drop(client);
+
+        let (mut stream, head) = peek(server, 5).await.unwrap();
+        assert_eq!(head, &b"GET /"[..]);
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"GET / HTTP/1.1\r\n");
+
+        // a short stream gives what there is
+        let (mut client, server) = duplex(64);
+        client.write_all(b"\x16\x03").await.unwrap();
+        drop(client);
+        let (_, head) = peek(server, 5).await.unwrap();
+        assert_eq!(head, &b"\x16\x03"[..]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout() {
+        let (client, mut server) = duplex(64);
+        let mut client = IdleTimeoutStream::new(client, Duration::from_secs(10));
+
+        // activity keeps the stream alive past the timeout
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_secs(6)).await;
+            server.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        }
+
+        // then the server stalls
+        let start = Instant::now();
+        let mut buf = [0u8; 4];
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout_split() {
+        let (client, _server) = duplex(8);
+        let client = IdleTimeoutStream::new(client, Duration::from_secs(10));
+        let (mut r, mut w) = tokio::io::split(client);
+
+        // a read and a write both stuck in their own tasks time out
+        let read = tokio::spawn(async move { r.read(&mut [0u8; 4]).await });
+        let write = tokio::spawn(async move { w.write_all(&[0u8; 16]).await });
+        let (read, write) = tokio::time::timeout(Duration::from_secs(30), async {
+            (read.await.unwrap(), write.await.unwrap())
+        })
+        .await
+        .unwrap();
+        assert_eq!(read.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(write.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_close_notify() {
+        let closed = Arc::new(AtomicU64::new(0));
+        let (client, mut server) = duplex(64);
+
+        let counter = closed.clone();
+        let mut client = CloseNotifyStream::new(client, move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(closed.load(Ordering::Relaxed), 0);
+
+        let mut buf = Vec::new();
+        server.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"ping");
+
+        drop(client);
+        assert_eq!(closed.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_length_delimited_split() {
+        let (client, mut server) = duplex(1024);
+        let mut client = LengthDelimitedStream::new(client);
+
+        // two frames arriving byte by byte, then an empty one
+        let write = async move {
+            for b in [0, 5, b'h', b'e', b'l', b'l', b'o', 0, 2, b'o', b'k', 0, 0] {
+                server.write_all(&[b]).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            server
+        };
+        let read = async {
+            let mut frames = vec![];
+            for _ in 0..3 {
+                frames.push(client.recv_frame().await.unwrap().unwrap());
+            }
+            frames
+        };
+        let (mut server, frames) = tokio::join!(write, read);
+        assert_eq!(frames, [&b"hello"[..], b"ok", b""]);
+
+        // the plain io side
+        client.send_frame(b"frame").await.unwrap();
+        client.write_all(b"data").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0u8; 13];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"\x00\x05frame\x00\x04data");
+
+        server
+            .write_all(b"\x00\x03abc\x00\x00\x00\x02de")
+            .await
+            .unwrap();
+        drop(server);
+        let mut data = String::new();
+        client.read_to_string(&mut data).await.unwrap();
+        assert_eq!(data, "abcde");
+        assert!(client.recv_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_length_delimited_max_frame() {
+        let (client, mut server) = duplex(1024);
+        let mut client = LengthDelimitedStream::with_max_frame(client, 4);
+
+        let err = client.send_frame(b"hello").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // writes are cut at the limit
+        assert_eq!(client.write(b"hello").await.unwrap(), 4);
+
+        server.write_all(b"\x00\x05hello").await.unwrap();
+        let err = client.recv_frame().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // the stream ends inside a frame
+        let (client, mut server) = duplex(1024);
+        let mut client = LengthDelimitedStream::new(client);
+        server.write_all(b"\x00\x05hel").await.unwrap();
+        drop(server);
+        let err = client.recv_frame().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_cached_stream_segments() {
+        let segments = [
+            Bytes::from_static(b"abc"),
+            Bytes::new(),
+            Bytes::from_static(b"de"),
+            Bytes::from_static(b"fghi"),
+        ];
+        let mut stream = CachedStream::new_multi(Cursor::new(b"jk".to_vec()), segments);
+
+        let mut reads = vec![];
+        let mut buf = [0u8; 2];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            reads.push(buf[..n].to_vec());
+        }
+        // a read never spans two segments
+        assert_eq!(
+            reads,
+            [&b"ab"[..], b"c", b"de", b"fg", b"hi", b"jk"].map(<[u8]>::to_vec)
+        );
+
+        // peeking joins the segments
+        let segments = [Bytes::from_static(b"ab"), Bytes::from_static(b"cd")];
+        let mut stream = CachedStream::new_multi(Cursor::new(b"ef".to_vec()), segments);
+        assert_eq!(stream.peek_n(5).await.unwrap(), b"abcde");
+        let mut data = vec![];
+        stream.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"abcdef");
+    }
+}
@@ -0,0 +1,650 @@
+//! TLS transport
+
+pub mod option;
+pub use option::{
+    SniPolicy, TlsInboundOption, TlsMultiInboundOption, TlsOutboundOption, TlsRouteOption,
+};
+
+use std::sync::Arc;
+
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    client, rustls,
+    rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server, LazyConfigAcceptor, TlsAcceptor, TlsConnector,
+};
+
+use crate::{
+    error::ProtocolError, Address, InboundError, InboundPacket, InboundResult, InboundService,
+    InboundServiceTrait, OutboundError, OutboundPacket, OutboundResult, OutboundServiceTrait,
+};
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("invalid certificate: {0}")]
+    InvalidCertificate(String),
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+    #[error("invalid server name {0}")]
+    InvalidServerName(String),
+    #[error("no inbound for alpn {0}")]
+    UnknownAlpn(String),
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn parse_certificates(pem: &str) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    CertificateDer::pem_slice_iter(pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::InvalidCertificate(e.to_string()))
+}
+
+/// Runs an outbound over a TLS connection.
+#[derive(Debug)]
+pub struct TlsOutbound<O> {
+    inner: O,
+    config: Arc<rustls::ClientConfig>,
+    sni: SniPolicy,
+}
+
+impl<O> TlsOutbound<O> {
+    /// Fails without `certificate`, no roots are trusted by default so an
+    /// empty store would reject every server.
+    pub fn init(inner: O, option: TlsOutboundOption) -> OutboundResult<Self> {
+        if option.certificate.is_empty() {
+            return Err(OutboundError::Option(
+                "no trusted root certificates".to_string(),
+            ));
+        }
+
+        let mut roots = rustls::RootCertStore::empty();
+        for pem in option.certificate.iter() {
+            for cert in parse_certificates(pem).map_err(|e| OutboundError::Option(e.to_string()))? {
+                roots
+                    .add(cert)
+                    .map_err(|e| OutboundError::Option(e.to_string()))?;
+            }
+        }
+
+        let mut config = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| OutboundError::Option(e.to_string()))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = option.alpn.into_iter().map(String::into_bytes).collect();
+        config.enable_early_data = option.early_data;
+
+        Ok(Self::with_config(inner, Arc::new(config), option.sni))
+    }
+
+    /// Use a prepared client config, `SniPolicy::None` turns its SNI off.
+    /// Early data is written when the config enables it.
+    pub fn with_config(inner: O, mut config: Arc<rustls::ClientConfig>, sni: SniPolicy) -> Self {
+        if sni == SniPolicy::None && config.enable_sni {
+            Arc::make_mut(&mut config).enable_sni = false;
+        }
+
+        Self { inner, config, sni }
+    }
+
+    pub fn sni(&self) -> &SniPolicy {
+        &self.sni
+    }
+
+    pub fn inner(&self) -> &O {
+        &self.inner
+    }
+
+    /// Name used for SNI and certificate verification.
+    ///
+    /// rustls never sends an IP address as SNI, so IP destinations go
+    /// without the extension under `FromDestination`.
+    pub fn server_name(&self, dest: &Address) -> Result<ServerName<'static>, TlsError> {
+        let name = match (&self.sni, dest) {
+            (SniPolicy::Fixed(name), _) => name.as_str(),
+            (_, Address::Domain(domain)) => domain.as_str(),
+            (_, Address::Socket(ip)) => return Ok(ServerName::IpAddress((*ip).into())),
+        };
+
+        ServerName::try_from(name.to_string())
+            .map_err(|_| TlsError::InvalidServerName(name.to_string()))
+    }
+}
+
+impl<S, O> OutboundServiceTrait<S> for TlsOutbound<O>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    O: OutboundServiceTrait<client::TlsStream<S>>,
+{
+    type Stream = O::Stream;
+
+    async fn handshake(&self, stream: S, packet: OutboundPacket) -> OutboundResult<Self::Stream> {
+        let name = self
+            .server_name(&packet.dest.addr)
+            .map_err(|e| OutboundError::Handshake(e.into()))?;
+        // with early data the connector returns before the handshake is
+        // done and the inner handshake's writes ride in the ClientHello
+        let stream = TlsConnector::from(self.config.clone())
+            .early_data(self.config.enable_early_data)
+            .connect(name, stream)
+            .await
+            .map_err(|e| OutboundError::Handshake(TlsError::from(e).into()))?;
+
+        self.inner.handshake(stream, packet).await
+    }
+}
+
+/// Runs an inbound over an accepted TLS connection.
+#[derive(Debug)]
+pub struct TlsInbound<I> {
+    inner: I,
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl<I> TlsInbound<I> {
+    pub fn init(inner: I, option: TlsInboundOption) -> InboundResult<Self> {
+        let certs = parse_certificates(&option.certificate)
+            .map_err(|e| InboundError::Option(e.to_string()))?;
+        let key = PrivateKeyDer::from_pem_slice(option.key.as_bytes())
+            .map_err(|e| InboundError::Option(TlsError::InvalidKey(e.to_string()).to_string()))?;
+
+        let mut config = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| InboundError::Option(e.to_string()))?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| InboundError::Option(e.to_string()))?;
+        config.alpn_protocols = option.alpn.into_iter().map(String::into_bytes).collect();
+
+        Ok(Self::with_config(inner, Arc::new(config)))
+    }
+
+    pub fn with_config(inner: I, config: Arc<rustls::ServerConfig>) -> Self {
+        Self { inner, config }
+    }
+
+    pub fn inner(&self) -> &I {
+        &self.inner
+    }
+}
+
+impl<S, I> InboundServiceTrait<S> for TlsInbound<I>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    I: InboundServiceTrait<server::TlsStream<S>>,
+{
+    type Stream = I::Stream;
+
+    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+        let stream = TlsAcceptor::from(self.config.clone())
+            .accept(stream)
+            .await
+            .map_err(|e| InboundError::Handshake(ProtocolError::from(TlsError::from(e))))?;
+
+        self.inner.handshake(stream).await
+    }
+}
+
+/// TLS inbound picking the inner inbound by the negotiated ALPN, e.g. http
+/// for `http/1.1` and vless for `h2` on the same port.
+///
+/// Clients offering no ALPN, or none of the routed ones, complete the
+/// handshake without ALPN. They go to the default inbound if there is one
+/// and are rejected with `TlsError::UnknownAlpn` otherwise.
+#[derive(Debug)]
+pub struct TlsMultiInbound<I = InboundService> {
+    routes: Vec<(Vec<u8>, I)>,
+    default: Option<I>,
+    config: Arc<rustls::ServerConfig>,
+    /// `config` without ALPN, for clients offering none of its protocols.
+    no_alpn: Arc<rustls::ServerConfig>,
+}
+
+impl TlsMultiInbound {
+    pub fn init(option: TlsMultiInboundOption) -> InboundResult<Self> {
+        let mut inbound = Self::new(option.tls)?;
+        for route in option.routes {
+            inbound = inbound.with_route(route.alpn, InboundService::init(route.inbound)?);
+        }
+        if let Some(default) = option.default {
+            inbound = inbound.with_default(InboundService::init(default)?);
+        }
+
+        Ok(inbound)
+    }
+}
+
+impl<I> TlsMultiInbound<I> {
+    /// Inbound without routes, protocols in `option.alpn` are offered ahead
+    /// of the routed ones.
+    pub fn new(option: TlsInboundOption) -> InboundResult<Self> {
+        let config = TlsInbound::init((), option)?.config;
+        Ok(Self::with_config(config))
+    }
+
+    pub fn with_config(config: Arc<rustls::ServerConfig>) -> Self {
+        let mut no_alpn = config.clone();
+        Arc::make_mut(&mut no_alpn).alpn_protocols.clear();
+
+        Self {
+            routes: vec![],
+            default: None,
+            config,
+            no_alpn,
+        }
+    }
+
+    /// Hand connections that negotiated `alpn` to `inbound`, the protocol
+    /// is added to the ones the server offers.
+    pub fn with_route(mut self, alpn: impl Into<String>, inbound: I) -> Self {
+        let alpn = alpn.into().into_bytes();
+        let config = Arc::make_mut(&mut self.config);
+        if !config.alpn_protocols.contains(&alpn) {
+            config.alpn_protocols.push(alpn.clone());
+        }
+        self.routes.push((alpn, inbound));
+        self
+    }
+
+    /// Inbound for connections without a routed ALPN.
+    pub fn with_default(mut self, inbound: I) -> Self {
+        self.default = Some(inbound);
+        self
+    }
+
+    /// Inbound for a negotiated ALPN, `None` when nothing was negotiated.
+    pub fn route(&self, alpn: Option<&[u8]>) -> Option<&I> {
+        alpn.and_then(|alpn| {
+            self.routes
+                .iter()
+                .find(|(route, _)| route == alpn)
+                .map(|(_, inbound)| inbound)
+        })
+        .or(self.default.as_ref())
+    }
+}
+
+impl<S, I> InboundServiceTrait<S> for TlsMultiInbound<I>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    I: InboundServiceTrait<server::TlsStream<S>>,
+{
+    type Stream = I::Stream;
+
+    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+        let tls_err = |e| InboundError::Handshake(ProtocolError::from(TlsError::from(e)));
+        let start = LazyConfigAcceptor::new(rustls::server::Acceptor::default(), stream)
+            .await
+            .map_err(tls_err)?;
+
+        // rustls ends the handshake with an alert when no offered protocol
+        // is supported, so those clients are served without ALPN
+        let offered: Vec<String> = start
+            .client_hello()
+            .alpn()
+            .into_iter()
+            .flatten()
+            .map(|alpn| String::from_utf8_lossy(alpn).into_owned())
+            .collect();
+        let config = if offered.iter().any(|alpn| {
+            self.config
+                .alpn_protocols
+                .contains(&alpn.as_bytes().to_vec())
+        }) {
+            self.config.clone()
+        } else {
+            self.no_alpn.clone()
+        };
+        let stream = start.into_stream(config).await.map_err(tls_err)?;
+
+        let alpn = stream.get_ref().1.alpn_protocol();
+        let inbound = self.route(alpn).ok_or_else(|| {
+            let alpn = match alpn {
+                Some(alpn) => String::from_utf8_lossy(alpn).into_owned(),
+                None => offered.join(","),
+            };
+            InboundError::Handshake(TlsError::UnknownAlpn(alpn).into())
+        })?;
+
+        inbound.handshake(stream).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use tokio::io::duplex;
+
+    use crate::{address::NetworkType, ServiceAddress};
+
+    use super::*;
+
+    /// Inbound reporting the received SNI as packet detail.
+    struct SniInbound;
+
+    impl<S> InboundServiceTrait<server::TlsStream<S>> for SniInbound
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        type Stream = server::TlsStream<S>;
+
+        async fn handshake(
+            &self,
+            stream: server::TlsStream<S>,
+        ) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+            let sni = stream.get_ref().1.server_name().unwrap_or("").to_string();
+            Ok((
+                stream,
+                InboundPacket {
+                    typ: NetworkType::Tcp,
+                    dest: ServiceAddress::new("127.0.0.1".into(), 0),
+                    detail: Cow::Owned(sni),
+                },
+            ))
+        }
+    }
+
+    #[derive(Debug)]
+    struct PassOutbound;
+
+    impl<S> OutboundServiceTrait<S> for PassOutbound
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        type Stream = S;
+
+        async fn handshake(&self, stream: S, _packet: OutboundPacket) -> OutboundResult<S> {
+            Ok(stream)
+        }
+    }
+
+    async fn received_sni(sni: SniPolicy, dest: &str) -> String {
+        let cert = rcgen::generate_simple_self_signed(vec![
+            "example.com".to_string(),
+            "fixed.test".to_string(),
+            "127.0.0.1".to_string(),
+        ])
+        .unwrap();
+
+        let inbound = TlsInbound::init(
+            SniInbound,
+            TlsInboundOption {
+                certificate: cert.cert.pem(),
+                key: cert.key_pair.serialize_pem(),
+                alpn: vec![],
+            },
+        )
+        .unwrap();
+        let outbound = TlsOutbound::init(
+            PassOutbound,
+            TlsOutboundOption {
+                sni,
+                alpn: vec![],
+                certificate: vec![cert.cert.pem()],
+                early_data: false,
+            },
+        )
+        .unwrap();
+
+        let (s1, s2) = duplex(16384);
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new(dest.into(), 443),
+        };
+        let (out, inb) = tokio::join!(outbound.handshake(s1, packet), inbound.handshake(s2));
+        out.unwrap();
+
+        inb.unwrap().1.detail.into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_sni_policy() {
+        assert_eq!(
+            received_sni(SniPolicy::Fixed("fixed.test".into()), "example.com").await,
+            "fixed.test"
+        );
+        assert_eq!(
+            received_sni(SniPolicy::FromDestination, "example.com").await,
+            "example.com"
+        );
+        assert_eq!(
+            received_sni(SniPolicy::FromDestination, "127.0.0.1").await,
+            ""
+        );
+        assert_eq!(received_sni(SniPolicy::None, "example.com").await, "");
+    }
+
+    /// Inbound reporting its name as packet detail.
+    struct NamedInbound(&'static str);
+
+    impl<S> InboundServiceTrait<server::TlsStream<S>> for NamedInbound
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        type Stream = server::TlsStream<S>;
+
+        async fn handshake(
+            &self,
+            stream: server::TlsStream<S>,
+        ) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+            Ok((
+                stream,
+                InboundPacket {
+                    typ: NetworkType::Tcp,
+                    dest: ServiceAddress::new("127.0.0.1".into(), 0),
+                    detail: Cow::Borrowed(self.0),
+                },
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_multi_alpn() {
+        let cert = rcgen::generate_simple_self_signed(vec!["example.com".to_string()]).unwrap();
+        let option = TlsInboundOption {
+            certificate: cert.cert.pem(),
+            key: cert.key_pair.serialize_pem(),
+            alpn: vec![],
+        };
+        let with_default = |default| {
+            let inbound = TlsMultiInbound::new(option.clone())
+                .unwrap()
+                .with_route("http/1.1", NamedInbound("http"))
+                .with_route("h2", NamedInbound("vless"));
+            match default {
+                Some(name) => inbound.with_default(NamedInbound(name)),
+                None => inbound,
+            }
+        };
+
+        let connect = |inbound: TlsMultiInbound<NamedInbound>, alpn: &[&str]| {
+            let outbound = TlsOutbound::init(
+                PassOutbound,
+                TlsOutboundOption {
+                    sni: SniPolicy::FromDestination,
+                    alpn: alpn.iter().map(|a| a.to_string()).collect(),
+                    certificate: vec![cert.cert.pem()],
+                    early_data: false,
+                },
+            )
+            .unwrap();
+            async move {
+                let (s1, s2) = duplex(16384);
+                let packet = OutboundPacket {
+                    typ: NetworkType::Tcp,
+                    dest: ServiceAddress::new("example.com".into(), 443),
+                };
+                let (_, inb) = tokio::join!(outbound.handshake(s1, packet), inbound.handshake(s2));
+                inb.map(|(_, pac)| pac.detail.into_owned())
+            }
+        };
+
+        assert_eq!(
+            connect(with_default(None), &["http/1.1"]).await.unwrap(),
+            "http"
+        );
+        assert_eq!(connect(with_default(None), &["h2"]).await.unwrap(), "vless");
+        assert_eq!(
+            connect(with_default(Some("fallback")), &[]).await.unwrap(),
+            "fallback"
+        );
+
+        let err = connect(with_default(None), &[]).await.unwrap_err();
+        assert!(err.to_string().contains("no inbound for alpn"), "{}", err);
+
+        // unrouted offers complete the handshake without ALPN
+        assert_eq!(
+            connect(with_default(Some("fallback")), &["h3"])
+                .await
+                .unwrap(),
+            "fallback"
+        );
+        assert_eq!(
+            connect(with_default(None), &["h3", "h2"]).await.unwrap(),
+            "vless"
+        );
+        let err = connect(with_default(None), &["h3"]).await.unwrap_err();
+        assert!(
+            err.to_string().contains("no inbound for alpn h3"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tls_multi_option() {
+        let cert = rcgen::generate_simple_self_signed(vec!["example.com".to_string()]).unwrap();
+        let json = serde_json::json!({
+            "certificate": cert.cert.pem(),
+            "key": cert.key_pair.serialize_pem(),
+            "routes": [
+                {
+                    "alpn": "h2",
+                    "inbound": {"vless": {"users": [
+                        {"user": "test", "uuid": "fc42fe34-e267-4c69-8861-2bc419057519"}
+                    ]}}
+                },
+                {"alpn": "http/1.1", "inbound": {"http": {}}}
+            ],
+            "default": {"socks": {"version": 5}}
+        });
+        let option: TlsMultiInboundOption = serde_json::from_value(json).unwrap();
+        let inbound = TlsMultiInbound::init(option).unwrap();
+
+        assert_eq!(inbound.config.alpn_protocols, [&b"h2"[..], b"http/1.1"]);
+        assert!(matches!(
+            inbound.route(Some(b"h2")),
+            Some(InboundService::Vless(_))
+        ));
+        assert!(matches!(
+            inbound.route(Some(b"http/1.1")),
+            Some(InboundService::Http(_))
+        ));
+        assert!(matches!(
+            inbound.route(None),
+            Some(InboundService::Socks(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_vless_early_data() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::vless::{Request, VlessOutbound, VlessOutboundOption};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["example.com".to_string()]).unwrap();
+        let certs = parse_certificates(&cert.cert.pem()).unwrap();
+        let key = PrivateKeyDer::from_pem_slice(cert.key_pair.serialize_pem().as_bytes()).unwrap();
+        let mut server_config = rustls::ServerConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+        server_config.max_early_data_size = 4096;
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let vless = VlessOutbound::init(VlessOutboundOption {
+            uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+            flow: None,
+            combine_first_packet: false,
+            mux: None,
+        })
+        .unwrap();
+        let outbound = TlsOutbound::init(
+            vless,
+            TlsOutboundOption {
+                sni: SniPolicy::FromDestination,
+                alpn: vec![],
+                certificate: vec![cert.cert.pem()],
+                early_data: true,
+            },
+        )
+        .unwrap();
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("example.com".into(), 443),
+        };
+
+        // returns the request if it came as early data
+        let serve = |s| {
+            let acceptor = acceptor.clone();
+            async move {
+                let mut stream = acceptor.accept(s).await.unwrap();
+                let mut early = vec![];
+                if let Some(mut reader) = stream.get_mut().1.early_data() {
+                    std::io::Read::read_to_end(&mut reader, &mut early).unwrap();
+                }
+                let req = if early.is_empty() {
+                    Request::read(&mut stream).await.unwrap();
+                    None
+                } else {
+                    Some(
+                        Request::read(&mut std::io::Cursor::new(early))
+                            .await
+                            .unwrap(),
+                    )
+                };
+                stream.write_all(&[0, 0]).await.unwrap();
+                stream.write_all(b"ok").await.unwrap();
+                stream.flush().await.unwrap();
+                req
+            }
+        };
+
+        let connect = |s| async {
+            let mut stream = outbound.handshake(s, packet.clone()).await.unwrap();
+            stream.flush().await.unwrap();
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ok");
+        };
+
+        // the first connection has no session to resume
+        let (s1, s2) = duplex(16384);
+        let (req, _) = tokio::join!(serve(s2), connect(s1));
+        assert!(req.is_none());
+
+        let (s1, s2) = duplex(16384);
+        let (req, _) = tokio::join!(serve(s2), connect(s1));
+        assert_eq!(req.unwrap().destination, Some(packet.dest.clone()));
+    }
+
+    #[test]
+    fn test_tls_outbound_default_roots() {
+        let err = TlsOutbound::init(PassOutbound, TlsOutboundOption::default()).unwrap_err();
+        assert!(
+            err.to_string().contains("no trusted root certificates"),
+            "{}",
+            err
+        );
+    }
+}
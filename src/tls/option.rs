@@ -0,0 +1,68 @@
+//! TLS transport option
+
+use serde::{Deserialize, Serialize};
+
+use crate::option::InboundServiceOption;
+
+/// How the outbound picks the server name indication.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SniPolicy {
+    /// Always send this name.
+    Fixed(String),
+    /// Send the destination domain, IP destinations send no SNI.
+    #[default]
+    FromDestination,
+    /// Never send the extension.
+    None,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TlsOutboundOption {
+    #[serde(default)]
+    pub sni: SniPolicy,
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    /// PEM encoded certificates trusted as roots, at least one is required.
+    #[serde(default)]
+    pub certificate: Vec<String>,
+    /// Send the first writes, such as the inner protocol's request, as TLS
+    /// 1.3 early data when resuming a session that allows it. Without such
+    /// a session, or if the server rejects it, they go after the handshake.
+    ///
+    /// Early data has no replay protection: an attacker who captured it can
+    /// send it to the server again. Here it carries the inner request, e.g.
+    /// the VLESS request with the user id and destination, so only enable it
+    /// when the inner protocol tolerates replays.
+    #[serde(default)]
+    pub early_data: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsInboundOption {
+    /// PEM encoded certificate chain.
+    pub certificate: String,
+    /// PEM encoded private key.
+    pub key: String,
+    #[serde(default)]
+    pub alpn: Vec<String>,
+}
+
+/// Option of `TlsMultiInbound`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsMultiInboundOption {
+    #[serde(flatten)]
+    pub tls: TlsInboundOption,
+    /// Inner inbound for each ALPN, offered in this order after `alpn`.
+    #[serde(default)]
+    pub routes: Vec<TlsRouteOption>,
+    /// Inner inbound for clients without a routed ALPN.
+    #[serde(default)]
+    pub default: Option<InboundServiceOption>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsRouteOption {
+    pub alpn: String,
+    pub inbound: InboundServiceOption,
+}
@@ -0,0 +1,19 @@
+//! Trojan Error
+
+use thiserror::Error;
+
+use crate::error::AddressError;
+
+#[derive(Debug, Error)]
+pub enum TrojanError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    InvalidAddress(#[from] AddressError),
+    #[error("invalid command: {0}")]
+    InvalidCommand(u8),
+    #[error("invalid password hash")]
+    InvalidPassword,
+    #[error("missing crlf")]
+    InvalidCrlf,
+}
@@ -0,0 +1,180 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use tokio::io::{AsyncRead, AsyncWrite, BufStream};
+
+use crate::{
+    address::NetworkType, InboundError, InboundPacket, InboundResult, InboundServiceTrait,
+};
+
+use super::{
+    option::TrojanInboundOption,
+    protocol::{password_hash, Request, COMMAND_UDP, HASH_LEN},
+    TrojanError,
+};
+
+#[derive(Debug)]
+pub struct TrojanInbound {
+    users: HashMap<[u8; HASH_LEN], String>,
+    unmap_v4: bool,
+}
+
+impl TrojanInboundOption {
+    pub fn validate(&self) -> Vec<InboundError> {
+        let mut errors = vec![];
+        if self.users.is_empty() {
+            errors.push(InboundError::Option("no trojan users".to_string()));
+        }
+        for user in self.users.iter().filter(|u| u.password.is_empty()) {
+            errors.push(InboundError::Option(format!(
+                "empty trojan password for user {}",
+                user.user
+            )));
+        }
+        errors
+    }
+}
+
+impl TrojanInbound {
+    pub fn add_user(&mut self, password: &str, user: String) {
+        self.users.insert(password_hash(password), user);
+    }
+
+    pub fn init(option: TrojanInboundOption) -> InboundResult<Self> {
+        if let Some(e) = option.validate().into_iter().next() {
+            return Err(e);
+        }
+
+        let users = option
+            .users
+            .into_iter()
+            .map(|user| (password_hash(&user.password), user.user))
+            .collect();
+
+        Ok(Self {
+            users,
+            unmap_v4: option.unmap_v4,
+        })
+    }
+}
+
+impl<S> InboundServiceTrait<S> for TrojanInbound
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    type Stream = BufStream<S>;
+
+    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+        let mut stream = BufStream::new(stream);
+        let request = Request::read(&mut stream)
+            .await
+            .map_err(|e| InboundError::Handshake(e.into()))?;
+
+        let user = self
+            .users
+            .get(&request.hash)
+            .ok_or(InboundError::Handshake(TrojanError::InvalidPassword.into()))?;
+
+        let mut dest = request.destination;
+        if self.unmap_v4 {
+            dest.addr = dest.addr.unmap_v4();
+        }
+
+        let typ = if request.command == COMMAND_UDP {
+            NetworkType::Udp
+        } else {
+            NetworkType::Tcp
+        };
+
+        Ok((
+            stream,
+            InboundPacket {
+                typ,
+                dest,
+                detail: Cow::Borrowed(user),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        trojan::{option::TrojanUserOption, protocol::COMMAND_TCP},
+        ServiceAddress,
+    };
+
+    use super::*;
+
+    fn inbound() -> TrojanInbound {
+        TrojanInbound::init(TrojanInboundOption {
+            users: vec![TrojanUserOption {
+                user: "test".into(),
+                password: "test".into(),
+            }],
+            unmap_v4: false,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_trojan_inbound() {
+        let req = Request {
+            hash: password_hash("test"),
+            command: COMMAND_TCP,
+            destination: ServiceAddress::new("127.0.0.1".into(), 1234),
+        };
+        let s = Cursor::new(req.into_buf(Some(b"test")).unwrap());
+
+        let ti = inbound();
+        let (_, pac) = ti.handshake(s).await.unwrap();
+
+        assert_eq!(pac.typ, NetworkType::Tcp);
+        assert_eq!(pac.dest, req.destination);
+        assert_eq!(pac.detail, "test");
+    }
+
+    #[tokio::test]
+    async fn test_trojan_inbound_unknown_password() {
+        let req = Request {
+            hash: password_hash("unknown"),
+            command: COMMAND_TCP,
+            destination: ServiceAddress::new("127.0.0.1".into(), 1234),
+        };
+        let s = Cursor::new(req.into_buf(None).unwrap());
+
+        let ti = inbound();
+        let err = ti.handshake(s).await.unwrap_err();
+
+        assert!(matches!(
+            err,
+            InboundError::Handshake(crate::error::ProtocolError::Trojan(
+                TrojanError::InvalidPassword
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_trojan_inbound_unmap_v4() {
+        let req = Request {
+            hash: password_hash("test"),
+            command: COMMAND_TCP,
+            destination: ServiceAddress::new("::ffff:1.2.3.4".parse().unwrap(), 80),
+        };
+
+        for (unmap_v4, dest) in [(false, "[::ffff:1.2.3.4]:80"), (true, "1.2.3.4:80")] {
+            let ti = TrojanInbound::init(TrojanInboundOption {
+                users: vec![TrojanUserOption {
+                    user: "test".into(),
+                    password: "test".into(),
+                }],
+                unmap_v4,
+            })
+            .unwrap();
+            let s = Cursor::new(req.into_buf(None).unwrap());
+            let (_, pac) = ti.handshake(s).await.unwrap();
+            assert_eq!(pac.dest.to_string(), dest);
+        }
+    }
+}
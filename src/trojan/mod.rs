@@ -0,0 +1,16 @@
+//! Trojan protocol things
+
+pub mod option;
+pub use option::{TrojanInboundOption, TrojanOutboundOption};
+
+pub mod inbound;
+pub use inbound::TrojanInbound;
+
+pub mod outbound;
+pub use outbound::TrojanOutbound;
+
+pub mod protocol;
+pub use protocol::Request;
+
+pub mod error;
+pub use error::TrojanError;
@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrojanInboundOption {
+    pub users: Vec<TrojanUserOption>,
+    /// See `SocksInboundOption::unmap_v4`.
+    #[serde(default)]
+    pub unmap_v4: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrojanUserOption {
+    pub user: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrojanOutboundOption {
+    pub password: String,
+}
@@ -0,0 +1,111 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    address::NetworkType, OutboundError, OutboundPacket, OutboundResult, OutboundServiceTrait,
+};
+
+use super::{
+    protocol::{password_hash, COMMAND_TCP, COMMAND_UDP, HASH_LEN},
+    Request, TrojanOutboundOption,
+};
+
+#[derive(Debug)]
+pub struct TrojanOutbound {
+    hash: [u8; HASH_LEN],
+}
+
+impl TrojanOutboundOption {
+    pub fn validate(&self) -> Vec<OutboundError> {
+        let mut errors = vec![];
+        if self.password.is_empty() {
+            errors.push(OutboundError::Option("empty trojan password".to_string()));
+        }
+        errors
+    }
+}
+
+impl TrojanOutbound {
+    pub fn init(option: TrojanOutboundOption) -> OutboundResult<Self> {
+        if let Some(e) = option.validate().into_iter().next() {
+            return Err(e);
+        }
+
+        Ok(Self {
+            hash: password_hash(&option.password),
+        })
+    }
+}
+
+impl<S> OutboundServiceTrait<S> for TrojanOutbound
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    type Stream = S;
+
+    async fn handshake(
+        &self,
+        mut stream: S,
+        packet: OutboundPacket,
+    ) -> OutboundResult<Self::Stream> {
+        let command = match packet.typ {
+            NetworkType::Tcp => COMMAND_TCP,
+            NetworkType::Udp => COMMAND_UDP,
+        };
+
+        let req = Request {
+            hash: self.hash,
+            command,
+            destination: packet.dest,
+        };
+
+        req.write(&mut stream, None)
+            .await
+            .map_err(|e| OutboundError::Handshake(e.into()))?;
+
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        trojan::{option::TrojanUserOption, TrojanInbound, TrojanInboundOption},
+        InboundServiceTrait, ServiceAddress,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trojan_outbound() {
+        let (s1, s2) = duplex(4096);
+
+        let ti = TrojanInbound::init(TrojanInboundOption {
+            users: vec![TrojanUserOption {
+                user: "test".into(),
+                password: "test".into(),
+            }],
+            unmap_v4: false,
+        })
+        .unwrap();
+        let to = TrojanOutbound::init(TrojanOutboundOption {
+            password: "test".into(),
+        })
+        .unwrap();
+
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("example.com".into(), 443),
+        };
+        let mut out = to.handshake(s1, packet.clone()).await.unwrap();
+        out.write_all(b"hello").await.unwrap();
+
+        let (mut s, pac) = ti.handshake(s2).await.unwrap();
+        assert_eq!(pac.dest, packet.dest);
+
+        let mut buf = [0u8; 5];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
@@ -0,0 +1,173 @@
+//! trojan protocol
+
+use std::net::IpAddr;
+
+use bytes::{BufMut, BytesMut};
+use sha2::{Digest, Sha224};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{impl_addr_type, AddrType, AddrTypeConvert, Address, ServiceAddress};
+
+use super::TrojanError;
+
+pub const HASH_LEN: usize = 56;
+const CRLF: [u8; 2] = [b'\r', b'\n'];
+
+pub const COMMAND_TCP: u8 = 1;
+pub const COMMAND_UDP: u8 = 3;
+
+impl_addr_type! {
+    pub enum TrojanAddrType {
+        Ipv4 = 1,
+        Ipv6 = 4,
+        Fqdn = 3,
+        Unknown = 255,
+    }
+}
+
+/// Hex encoded SHA-224 of the password, as sent on the wire.
+pub fn password_hash(password: &str) -> [u8; HASH_LEN] {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    let digest = Sha224::digest(password.as_bytes());
+    let mut hash = [0u8; HASH_LEN];
+    for (i, b) in digest.iter().enumerate() {
+        hash[i * 2] = HEX[(b >> 4) as usize];
+        hash[i * 2 + 1] = HEX[(b & 0xf) as usize];
+    }
+
+    hash
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Request {
+    pub hash: [u8; HASH_LEN],
+    pub command: u8,
+    pub destination: ServiceAddress,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl Request {
+    pub fn len(&self) -> usize {
+        let addr_len = match &self.destination.addr {
+            Address::Domain(s) => 4 + s.len(),    // af + str_len + str + port
+            Address::Socket(IpAddr::V4(_)) => 7,  // af + ipv4 + port
+            Address::Socket(IpAddr::V6(_)) => 19, // af + ipv6 + port
+        };
+
+        HASH_LEN + 2 + 1 + addr_len + 2 // hash + crlf + command + addr + crlf
+    }
+
+    pub async fn read<R>(stream: &mut R) -> Result<Request, TrojanError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut hash = [0u8; HASH_LEN];
+        stream.read_exact(&mut hash).await?;
+        if !hash.iter().all(u8::is_ascii_hexdigit) {
+            return Err(TrojanError::InvalidPassword);
+        }
+        read_crlf(stream).await?;
+
+        let command = stream.read_u8().await?;
+        if command != COMMAND_TCP && command != COMMAND_UDP {
+            return Err(TrojanError::InvalidCommand(command));
+        }
+
+        let addr = Address::read::<R, TrojanAddrType>(stream).await?;
+        let port = stream.read_u16().await?;
+        read_crlf(stream).await?;
+
+        Ok(Request {
+            hash,
+            command,
+            destination: ServiceAddress::new(addr, port),
+        })
+    }
+
+    pub async fn write<W>(&self, writer: &mut W, payload: Option<&[u8]>) -> Result<(), TrojanError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        writer.write_all(&self.into_buf(payload)?).await?;
+
+        Ok(())
+    }
+
+    pub fn into_buf(&self, payload: Option<&[u8]>) -> Result<Vec<u8>, TrojanError> {
+        let request_len = self.len() + payload.map_or(0, |p| p.len());
+
+        let mut buf = BytesMut::with_capacity(request_len);
+        buf.put_slice(&self.hash);
+        buf.put_slice(&CRLF);
+
+        match self.command {
+            COMMAND_TCP | COMMAND_UDP => buf.put_u8(self.command),
+            other => return Err(TrojanError::InvalidCommand(other)),
+        }
+        self.destination
+            .addr
+            .put_to_buf::<BytesMut, TrojanAddrType>(&mut buf)?;
+        buf.put_u16(self.destination.port);
+        buf.put_slice(&CRLF);
+
+        if let Some(p) = payload {
+            buf.put(p);
+        }
+
+        Ok(buf.to_vec())
+    }
+}
+
+async fn read_crlf<R>(stream: &mut R) -> Result<(), TrojanError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut crlf = [0u8; 2];
+    stream.read_exact(&mut crlf).await?;
+    if crlf != CRLF {
+        return Err(TrojanError::InvalidCrlf);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use bytes::Buf;
+
+    use super::*;
+
+    #[test]
+    fn test_password_hash() {
+        assert_eq!(
+            &password_hash("password"),
+            b"d63dc919e201d7bc4c825630d2cf25fdc93d4b2f0d46706d29038d01"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trojan_protocol() -> Result<(), TrojanError> {
+        for addr in ["127.0.0.1", "::1", "example.com"] {
+            let req1 = Request {
+                hash: password_hash("test"),
+                command: COMMAND_TCP,
+                destination: ServiceAddress::new(addr.into(), 443),
+            };
+
+            let mut buf = Cursor::new(vec![]);
+            req1.write(&mut buf, Some(b"test")).await?;
+            assert_eq!(buf.get_ref().len(), req1.len() + 4);
+            buf.set_position(0);
+
+            let req2 = Request::read(&mut buf).await?;
+
+            assert_eq!(req1, req2);
+            assert_eq!(buf.chunk(), b"test");
+        }
+
+        Ok(())
+    }
+}
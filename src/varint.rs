@@ -34,12 +34,10 @@ pub fn write_varint<B: BufMut>(buf: &mut B, mut x: u64) {
 }
 
 pub fn variant_len(x: u64) -> usize {
-    if x < 1 << (7 * 1) {
+    if x < 1 << 7 {
         1
     } else if x < 1 << (7 * 2) {
         2
-    } else if x < 1 << (7 * 2) {
-        2
     } else if x < 1 << (7 * 3) {
         3
     } else if x < 1 << (7 * 4) {
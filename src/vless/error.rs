@@ -24,4 +24,32 @@ pub enum VlessError {
     InvalidUuid(String),
     #[error("invalid header: {0}")]
     InvalidHeader(u8),
+    #[error("truncated {0}")]
+    Truncated(&'static str),
+    #[error("truncated addons")]
+    TruncatedAddons,
+    #[error("unsupported flow {0}")]
+    UnsupportedFlow(String),
+    #[error("mux request with a destination")]
+    MuxDestination,
+    #[error("udp disabled")]
+    UdpDisabled,
+    #[error("invalid mux frame")]
+    InvalidMuxFrame,
+    #[error("mux session closed")]
+    MuxClosed,
+    #[error("mux session full")]
+    MuxFull,
+    #[error("mux session outside of VlessInbound::accept")]
+    MuxSession,
+}
+
+impl VlessError {
+    /// Map an unexpected EOF while reading `field` to `Truncated(field)`.
+    pub(crate) fn truncated(field: &'static str) -> impl FnOnce(std::io::Error) -> VlessError {
+        move |e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => VlessError::Truncated(field),
+            _ => VlessError::Io(e),
+        }
+    }
 }
@@ -1,21 +1,87 @@
-use std::{borrow::Cow, collections::HashMap, str::FromStr};
+use std::{borrow::Cow, collections::HashMap, str::FromStr, time::Duration};
 
 use tokio::io::{AsyncRead, AsyncWrite, BufStream};
 use uuid::Uuid;
 
 use crate::{
-    address::NetworkType, InboundError, InboundPacket, InboundResult, InboundServiceTrait,
+    address::NetworkType,
+    stream::{buf_stream, read_buffer_size, RecordReader},
+    write_all_timeout, CachedStream, InboundError, InboundPacket, InboundResult,
+    InboundServiceTrait, ServiceAddress,
 };
 
 use super::{
-    option::VlessInboundOption,
-    protocol::{Request, Response, COMMAND_TCP, COMMAND_UDP},
+    mux::VlessMuxStream,
+    option::{MuxOption, VlessInboundOption},
+    protocol::{is_known_flow, Request, Response, COMMAND_MUX, COMMAND_TCP, COMMAND_UDP},
     VlessError,
 };
 
 #[derive(Debug)]
 pub struct VlessInbound {
     users: HashMap<uuid::Uuid, String>,
+    udp_enabled: bool,
+    reply_timeout: Option<Duration>,
+    strict_flow: bool,
+    flows: Vec<String>,
+    read_buffer: usize,
+    fallback: Option<ServiceAddress>,
+    allow_any: bool,
+    max_substreams: usize,
+    keepalive: Option<u64>,
+    unmap_v4: bool,
+    strict_domain: bool,
+}
+
+/// User name of requests with an unknown uuid under `allow_any`.
+pub const ANONYMOUS_USER: &str = "anonymous";
+
+/// Outcome of `VlessInbound::accept`.
+#[derive(Debug)]
+pub enum VlessAccept<'a> {
+    Request(InboundPacket<'a>),
+    /// Not a request from a known user, the stream replays what was read
+    /// and is meant to be passed on to this address.
+    Fallback(ServiceAddress),
+    /// Mux session request, already answered. The stream is meant for
+    /// `VlessInbound::serve_mux`.
+    Mux,
+}
+
+impl VlessInboundOption {
+    pub fn validate(&self) -> Vec<InboundError> {
+        let mut errors = vec![];
+        errors.extend(read_buffer_size(self.read_buffer).err());
+
+        if self.allow_any && !cfg!(feature = "insecure-allow-any") {
+            errors.push(InboundError::Option(
+                "allow_any needs the insecure-allow-any feature".to_string(),
+            ));
+        } else if self.users.is_empty() && !self.allow_any {
+            errors.push(InboundError::Option("no vless users".to_string()));
+        }
+        for user in self.users.iter() {
+            if let Err(e) = Uuid::from_str(&user.uuid) {
+                errors.push(InboundError::Option(format!(
+                    "invalid uuid for user {} ({})",
+                    user.user, e
+                )));
+            }
+        }
+        for flow in self.flows.iter().filter(|f| !is_known_flow(f)) {
+            errors.push(InboundError::Option(format!("unknown flow {}", flow)));
+        }
+        if let Some(Err(e)) = self.fallback.as_deref().map(ServiceAddress::from_str) {
+            errors.push(InboundError::Option(format!("invalid fallback ({})", e)));
+        }
+        if self.max_substreams == 0 {
+            errors.push(InboundError::Option(
+                "max_substreams must be at least 1".to_string(),
+            ));
+        }
+
+        errors
+    }
 }
 
 impl VlessInbound {
@@ -24,61 +90,152 @@ impl VlessInbound {
     }
 
     pub fn init(option: VlessInboundOption) -> InboundResult<Self> {
-        let mut users = HashMap::new();
+        if let Some(e) = option.validate().into_iter().next() {
+            return Err(e);
+        }
 
+        let mut users = HashMap::new();
         for user in option.users {
-            let uuid =
-                Uuid::from_str(&user.uuid).map_err(|e| InboundError::Option(e.to_string()))?;
+            let uuid = Uuid::from_str(&user.uuid)
+                .map_err(|e| InboundError::Option(format!("invalid uuid ({})", e)))?;
             users.insert(uuid, user.user);
         }
 
-        Ok(Self { users })
+        #[cfg(feature = "insecure-allow-any")]
+        if option.allow_any {
+            log::warn!("vless inbound accepts any uuid (allow_any), do not use it in production");
+        }
+
+        Ok(Self {
+            users,
+            udp_enabled: option.udp_enabled,
+            reply_timeout: option.reply_timeout.map(Duration::from_millis),
+            strict_flow: option.strict_flow,
+            flows: option.flows,
+            read_buffer: read_buffer_size(option.read_buffer)?,
+            fallback: option
+                .fallback
+                .map(|f| ServiceAddress::from_str(&f))
+                .transpose()
+                .map_err(|e| InboundError::Option(e.to_string()))?,
+            allow_any: option.allow_any,
+            max_substreams: option.max_substreams,
+            keepalive: option.keepalive,
+            unmap_v4: option.unmap_v4,
+            strict_domain: option.strict_domain,
+        })
     }
-}
 
-impl<S> InboundServiceTrait<S> for VlessInbound
-where
-    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
-{
-    type Stream = BufStream<S>;
+    pub fn fallback(&self) -> Option<&ServiceAddress> {
+        self.fallback.as_ref()
+    }
 
-    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket)> {
-        let mut stream = BufStream::new(stream);
-        let request = Request::read(&mut stream)
-            .await
-            .map_err(|e| InboundError::Handshake(e.into()))?;
+    /// Whether any uuid is accepted, only possible with the
+    /// `insecure-allow-any` feature.
+    pub fn allow_any(&self) -> bool {
+        self.allow_any
+    }
+
+    /// Settings for serving a mux session from a client of this inbound,
+    /// with `VlessMuxStream::new`.
+    pub fn mux_option(&self) -> MuxOption {
+        MuxOption {
+            max_substreams: self.max_substreams,
+            keepalive: self.keepalive,
+            ..MuxOption::default()
+        }
+    }
+
+    /// Serve the mux session of a stream accepted as `VlessAccept::Mux`,
+    /// limited by `max_substreams` and kept alive by `keepalive`. The
+    /// sub-streams the client opens come from `VlessMuxStream::accept`.
+    pub fn serve_mux<S>(&self, stream: S) -> VlessMuxStream
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        VlessMuxStream::new(stream, &self.mux_option())
+    }
+
+    /// Like `handshake`, telling a request apart from a connection handed to
+    /// the fallback or a mux session.
+    pub async fn accept<S>(
+        &self,
+        stream: S,
+    ) -> InboundResult<(CachedStream<BufStream<S>>, VlessAccept<'_>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        let mut stream = buf_stream(stream, self.read_buffer);
 
-        let user = self
-            .users
-            .get(&request.uuid)
-            .ok_or(InboundError::Handshake(
-                VlessError::InvalidUuid(request.uuid.to_string()).into(),
-            ))?;
+        let mut reader = RecordReader::new(&mut stream);
+        let request = Request::read_with(&mut reader, self.strict_domain).await;
+        let consumed = reader.into_record();
 
-        let pac = match request.command {
+        let request = request.and_then(|request| match self.users.get(&request.uuid) {
+            Some(user) => Ok((request, user.as_str())),
+            None if self.allow_any => {
+                #[cfg(feature = "insecure-allow-any")]
+                log::warn!(
+                    "vless inbound let in unknown uuid {} as {} (allow_any)",
+                    request.uuid,
+                    ANONYMOUS_USER
+                );
+                Ok((request, ANONYMOUS_USER))
+            }
+            None => Err(VlessError::InvalidUuid(request.uuid.to_string())),
+        });
+        let (request, user) = match (request, &self.fallback) {
+            (Ok(request), _) => request,
+            (Err(_), Some(fallback)) => {
+                let stream = CachedStream::new(stream, Some(consumed));
+                return Ok((stream, VlessAccept::Fallback(fallback.clone())));
+            }
+            (Err(e), None) => return Err(InboundError::Handshake(e.into())),
+        };
+
+        if let Some(ref flow) = request.flow {
+            if self.strict_flow && !self.flows.contains(flow) {
+                return Err(InboundError::Handshake(
+                    VlessError::UnsupportedFlow(flow.clone()).into(),
+                ));
+            }
+        }
+
+        let mut destination = request.destination;
+        if self.unmap_v4 {
+            destination = destination.map(|dest| ServiceAddress {
+                addr: dest.addr.unmap_v4(),
+                port: dest.port,
+            });
+        }
+        let accepted = match request.command {
             COMMAND_TCP => {
-                let dest = match request.destination {
+                let dest = match destination {
                     Some(d) => d,
                     None => return Err(InboundError::Handshake(VlessError::NoDestination.into())),
                 };
-                InboundPacket {
+                VlessAccept::Request(InboundPacket {
                     typ: NetworkType::Tcp,
                     dest,
                     detail: Cow::Borrowed(user),
-                }
+                })
             }
             COMMAND_UDP => {
-                let dest = match request.destination {
+                if !self.udp_enabled {
+                    return Err(InboundError::Handshake(VlessError::UdpDisabled.into()));
+                }
+
+                let dest = match destination {
                     Some(d) => d,
                     None => return Err(InboundError::Handshake(VlessError::NoDestination.into())),
                 };
-                InboundPacket {
+                VlessAccept::Request(InboundPacket {
                     typ: NetworkType::Udp,
                     dest,
                     detail: Cow::Borrowed(user),
-                }
+                })
             }
-            //COMMAND_MUX => unimplemented!(),
+            COMMAND_MUX => VlessAccept::Mux,
             _ => {
                 return Err(InboundError::Handshake(
                     VlessError::InvalidCommand(request.command).into(),
@@ -86,10 +243,36 @@ where
             }
         };
 
-        let _ = Response::default()
-            .write(&mut stream, None)
-            .await
+        let resp = Response::default()
+            .into_buf(None)
             .map_err(|e| InboundError::Handshake(e.into()))?;
+        write_all_timeout(&mut stream, &resp, self.reply_timeout).await?;
+
+        Ok((CachedStream::new(stream, None), accepted))
+    }
+}
+
+impl<S> InboundServiceTrait<S> for VlessInbound
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    /// Replays the consumed request when handed to the fallback, whose
+    /// address is then the packet destination. A mux session has no single
+    /// destination and fails with `VlessError::MuxSession`, it is served
+    /// through `accept`.
+    type Stream = CachedStream<BufStream<S>>;
+
+    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+        let (stream, accept) = self.accept(stream).await?;
+        let pac = match accept {
+            VlessAccept::Request(pac) => pac,
+            VlessAccept::Fallback(dest) => InboundPacket {
+                typ: NetworkType::Tcp,
+                dest,
+                detail: Cow::Borrowed(""),
+            },
+            VlessAccept::Mux => return Err(InboundError::Handshake(VlessError::MuxSession.into())),
+        };
 
         Ok((stream, pac))
     }
@@ -99,7 +282,9 @@ where
 mod tests {
     use std::io::Cursor;
 
-    use crate::vless::option::VlessUserOption;
+    use tokio::io::AsyncReadExt;
+
+    use crate::{error::ProtocolError, vless::option::VlessUserOption};
 
     use super::*;
 
@@ -117,6 +302,7 @@ mod tests {
                 user: "test".into(),
                 uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
             }],
+            ..Default::default()
         };
 
         let vi = VlessInbound::init(opt).unwrap();
@@ -125,4 +311,312 @@ mod tests {
 
         println!("{:?}", result)
     }
+
+    #[tokio::test]
+    async fn test_vless_inbound_udp_disabled() {
+        let mut buf: Vec<u8> = vec![
+            0, 252, 66, 254, 52, 226, 103, 76, 105, 136, 97, 43, 196, 25, 5, 117, 25, 0, 2, 0, 53,
+            1, 127, 0, 0, 1,
+        ];
+
+        let opt = |udp_enabled| VlessInboundOption {
+            users: vec![VlessUserOption {
+                user: "test".into(),
+                uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+            }],
+            udp_enabled,
+            ..Default::default()
+        };
+
+        let vi = VlessInbound::init(opt(false)).unwrap();
+        let err = vi.handshake(Cursor::new(buf.clone())).await.unwrap_err();
+        assert!(matches!(
+            err,
+            InboundError::Handshake(ProtocolError::Vless(VlessError::UdpDisabled))
+        ));
+
+        buf.extend_from_slice(b"test");
+        let vi = VlessInbound::init(opt(true)).unwrap();
+        let (_, pac) = vi.handshake(Cursor::new(buf)).await.unwrap();
+        assert_eq!(pac.typ, NetworkType::Udp);
+    }
+
+    #[tokio::test]
+    async fn test_vless_inbound_strict_flow() {
+        let flow = b"xtls-rprx-unknown";
+        let mut buf: Vec<u8> = vec![
+            0, 252, 66, 254, 52, 226, 103, 76, 105, 136, 97, 43, 196, 25, 5, 117, 25,
+        ];
+        buf.extend_from_slice(&[2 + flow.len() as u8, 10, flow.len() as u8]);
+        buf.extend_from_slice(flow);
+        buf.extend_from_slice(&[1, 0, 80, 1, 127, 0, 0, 1]);
+
+        let opt = |strict_flow| VlessInboundOption {
+            users: vec![VlessUserOption {
+                user: "test".into(),
+                uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+            }],
+            strict_flow,
+            flows: vec!["xtls-rprx-vision".into()],
+            ..Default::default()
+        };
+
+        let vi = VlessInbound::init(opt(true)).unwrap();
+        let err = vi.handshake(Cursor::new(buf.clone())).await.unwrap_err();
+        assert!(matches!(
+            err,
+            InboundError::Handshake(ProtocolError::Vless(VlessError::UnsupportedFlow(ref f)))
+                if f == "xtls-rprx-unknown"
+        ));
+
+        let vi = VlessInbound::init(opt(false)).unwrap();
+        let (_, pac) = vi.handshake(Cursor::new(buf)).await.unwrap();
+        assert_eq!(pac.dest.port, 80);
+    }
+
+    #[test]
+    fn test_vless_inbound_unknown_flow() {
+        let opt = VlessInboundOption {
+            users: vec![VlessUserOption {
+                user: "test".into(),
+                uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+            }],
+            strict_flow: true,
+            flows: vec!["xtls-rprx-vision".into(), "xtls-rprx-splice".into()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            VlessInbound::init(opt.clone()),
+            Err(InboundError::Option(ref e)) if e.contains("xtls-rprx-splice")
+        ));
+
+        // init stops at the first problem validate reports
+        let opt = VlessInboundOption {
+            flows: vec![],
+            max_substreams: 0,
+            ..opt
+        };
+        assert_eq!(opt.validate().len(), 1);
+        assert!(matches!(
+            VlessInbound::init(opt),
+            Err(InboundError::Option(ref e)) if e.contains("max_substreams")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_vless_inbound_fallback() {
+        // an unknown uuid and a request that is not vless at all
+        let mut unknown: Vec<u8> = vec![0; 17];
+        unknown.extend_from_slice(&[0, 1, 0, 80, 1, 127, 0, 0, 1]);
+        let probes = [
+            unknown,
+            b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec(),
+        ];
+
+        let opt = |fallback: Option<&str>| VlessInboundOption {
+            users: vec![VlessUserOption {
+                user: "test".into(),
+                uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+            }],
+            fallback: fallback.map(|f| f.to_string()),
+            ..Default::default()
+        };
+
+        let vi = VlessInbound::init(opt(Some("127.0.0.1:8080"))).unwrap();
+        for probe in probes.iter() {
+            let (mut stream, accept) = vi.accept(Cursor::new(probe.clone())).await.unwrap();
+            assert!(matches!(
+                accept,
+                VlessAccept::Fallback(ref dest) if dest.to_string() == "127.0.0.1:8080"
+            ));
+
+            let mut replayed = vec![];
+            stream.read_to_end(&mut replayed).await.unwrap();
+            assert_eq!(&replayed, probe);
+        }
+
+        let (_, pac) = vi.handshake(Cursor::new(probes[0].clone())).await.unwrap();
+        assert_eq!(pac.dest.to_string(), "127.0.0.1:8080");
+
+        let vi = VlessInbound::init(opt(None)).unwrap();
+        assert!(vi.handshake(Cursor::new(probes[0].clone())).await.is_err());
+        assert!(VlessInbound::init(opt(Some("no port"))).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_vless_inbound_allow_any() {
+        let mut request: Vec<u8> = vec![0];
+        request.extend_from_slice(&[7; 16]);
+        request.extend_from_slice(&[0, 1, 0, 80, 1, 127, 0, 0, 1]);
+
+        let opt = |allow_any| VlessInboundOption {
+            users: vec![VlessUserOption {
+                user: "test".into(),
+                uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+            }],
+            allow_any,
+            ..Default::default()
+        };
+
+        #[cfg(feature = "insecure-allow-any")]
+        {
+            let vi = VlessInbound::init(opt(true)).unwrap();
+            let (_, pac) = vi.handshake(Cursor::new(request.clone())).await.unwrap();
+            assert_eq!(pac.detail, ANONYMOUS_USER);
+            assert_eq!(pac.dest.to_string(), "127.0.0.1:80");
+        }
+        #[cfg(not(feature = "insecure-allow-any"))]
+        assert!(VlessInbound::init(opt(true)).is_err());
+
+        let vi = VlessInbound::init(opt(false)).unwrap();
+        let err = vi.handshake(Cursor::new(request)).await.unwrap_err();
+        assert!(matches!(
+            err,
+            InboundError::Handshake(ProtocolError::Vless(VlessError::InvalidUuid(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_vless_inbound_unmap_v4() {
+        let mapped: std::net::Ipv6Addr = "::ffff:1.2.3.4".parse().unwrap();
+        let uuid: Uuid = "fc42fe34-e267-4c69-8861-2bc419057519".parse().unwrap();
+        let mut request: Vec<u8> = vec![0];
+        request.extend_from_slice(uuid.as_bytes());
+        request.extend_from_slice(&[0, 1, 0, 80, 3]);
+        request.extend_from_slice(&mapped.octets());
+
+        for (unmap_v4, dest) in [(false, "[::ffff:1.2.3.4]:80"), (true, "1.2.3.4:80")] {
+            let vi = VlessInbound::init(VlessInboundOption {
+                users: vec![VlessUserOption {
+                    user: "test".into(),
+                    uuid: uuid.to_string(),
+                }],
+                unmap_v4,
+                ..Default::default()
+            })
+            .unwrap();
+            let (_, pac) = vi.handshake(Cursor::new(request.clone())).await.unwrap();
+            assert_eq!(pac.dest.to_string(), dest);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_vless_inbound_mux() {
+        use tokio::io::AsyncWriteExt;
+
+        use crate::{
+            vless::{option::VlessOutboundOption, VlessOutbound},
+            OutboundPacket,
+        };
+
+        let uuid = "fc42fe34-e267-4c69-8861-2bc419057519";
+        let vi = VlessInbound::init(VlessInboundOption {
+            users: vec![VlessUserOption {
+                user: "test".into(),
+                uuid: uuid.into(),
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+        let vo = VlessOutbound::init(VlessOutboundOption {
+            uuid: uuid.into(),
+            flow: None,
+            combine_first_packet: false,
+            mux: Some(MuxOption::default()),
+        })
+        .unwrap();
+
+        let (client, server) = tokio::io::duplex(4096);
+        let client = tokio::spawn(async move {
+            let session = vo.connect_mux(client).await.unwrap();
+            let mut sub = session
+                .open(OutboundPacket {
+                    typ: NetworkType::Tcp,
+                    dest: ServiceAddress::new("example.com".into(), 80),
+                })
+                .unwrap();
+            sub.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            sub.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+
+        let (stream, accepted) = vi.accept(server).await.unwrap();
+        assert!(matches!(accepted, VlessAccept::Mux));
+        let session = vi.serve_mux(stream);
+        let (pac, mut sub) = session.accept().await.unwrap();
+        assert_eq!(pac.dest.to_string(), "example.com:80");
+        let mut buf = [0u8; 4];
+        sub.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        sub.write_all(b"pong").await.unwrap();
+
+        assert_eq!(&client.await.unwrap(), b"pong");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_vless_inbound_mux_keepalive() {
+        use crate::vless::mux::Frame;
+
+        let uuid: Uuid = "fc42fe34-e267-4c69-8861-2bc419057519".parse().unwrap();
+        let vi = VlessInbound::init(VlessInboundOption {
+            users: vec![VlessUserOption {
+                user: "test".into(),
+                uuid: uuid.to_string(),
+            }],
+            keepalive: Some(1000),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        Request {
+            uuid,
+            flow: None,
+            command: COMMAND_MUX,
+            destination: None,
+        }
+        .write(&mut client, None)
+        .await
+        .unwrap();
+
+        let (stream, accepted) = vi.accept(server).await.unwrap();
+        assert!(matches!(accepted, VlessAccept::Mux));
+        let session = vi.serve_mux(stream);
+        Response::read(&mut client).await.unwrap();
+
+        // the idle session is kept alive at the configured interval
+        let frame = tokio::time::timeout(Duration::from_millis(1500), Frame::read(&mut client))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame, Frame::keepalive());
+        assert!(!session.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_vless_inbound_strict_domain() {
+        let uuid: Uuid = "fc42fe34-e267-4c69-8861-2bc419057519".parse().unwrap();
+        let mut request: Vec<u8> = vec![0];
+        request.extend_from_slice(uuid.as_bytes());
+        request.extend_from_slice(&[0, 1, 0, 80, 2, 3]);
+        request.extend_from_slice(b"a b");
+
+        for strict_domain in [false, true] {
+            let vi = VlessInbound::init(VlessInboundOption {
+                users: vec![VlessUserOption {
+                    user: "test".into(),
+                    uuid: uuid.to_string(),
+                }],
+                strict_domain,
+                ..Default::default()
+            })
+            .unwrap();
+            let res = vi.handshake(Cursor::new(request.clone())).await;
+            assert_eq!(res.is_err(), strict_domain);
+            if let Ok((_, pac)) = res {
+                assert_eq!(pac.dest.to_string(), "a b:80");
+            }
+        }
+    }
 }
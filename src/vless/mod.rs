@@ -1,14 +1,17 @@
 //! Vless protocol things
 
 pub mod option;
-pub use option::{VlessInboundOption, VlessOutboundOption};
+pub use option::{MuxOption, VlessInboundOption, VlessOutboundOption};
 
 pub mod inbound;
-pub use inbound::VlessInbound;
+pub use inbound::{VlessAccept, VlessInbound};
 
 pub mod outbound;
 pub use outbound::{VlessOutbound, VlessOutboundStream};
 
+pub mod mux;
+pub use mux::{MuxSubStream, VlessMuxStream};
+
 pub mod protocol;
 pub use protocol::Request;
 
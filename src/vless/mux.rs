@@ -0,0 +1,956 @@
+//! Vless mux sessions
+//!
+//! A `COMMAND_MUX` request turns the connection into a Mux.Cool session
+//! carrying many sub-streams, each with its own destination.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{mpsc, Notify},
+    time::{Instant, Interval},
+};
+use tokio_util::sync::PollSender;
+
+use crate::{address::NetworkType, Address, OutboundPacket, ServiceAddress};
+
+use super::{option::MuxOption, protocol::VlessAddrType, VlessError};
+
+pub const STATUS_NEW: u8 = 1;
+pub const STATUS_KEEP: u8 = 2;
+pub const STATUS_END: u8 = 3;
+pub const STATUS_KEEPALIVE: u8 = 4;
+
+pub const OPTION_DATA: u8 = 1;
+pub const OPTION_ERROR: u8 = 2;
+
+const NETWORK_TCP: u8 = 1;
+const NETWORK_UDP: u8 = 2;
+
+/// Most payload one frame carries.
+const MAX_FRAME_DATA: usize = u16::MAX as usize;
+
+/// Frames buffered for a sub-stream, one slot is kept for an error.
+const SUBSTREAM_BUFFER: usize = 16;
+
+/// Time a sub-stream may keep its buffer full, holding up the session,
+/// before it is reset.
+const SUBSTREAM_STALL: Duration = Duration::from_secs(30);
+
+/// Data frames queued for the connection before sub-stream writes wait.
+const FRAME_BUFFER: usize = 64;
+
+/// Keepalive intervals without a frame from the peer before the session is
+/// considered dead.
+const KEEPALIVE_MISSES: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub id: u16,
+    pub status: u8,
+    pub option: u8,
+    /// Destination of a `STATUS_NEW` frame.
+    pub target: Option<OutboundPacket>,
+    pub data: Bytes,
+}
+
+impl Frame {
+    pub fn new(id: u16, target: OutboundPacket) -> Self {
+        Self {
+            id,
+            status: STATUS_NEW,
+            option: 0,
+            target: Some(target),
+            data: Bytes::new(),
+        }
+    }
+
+    pub fn keep(id: u16, data: Bytes) -> Self {
+        Self {
+            id,
+            status: STATUS_KEEP,
+            option: OPTION_DATA,
+            target: None,
+            data,
+        }
+    }
+
+    pub fn end(id: u16, error: bool) -> Self {
+        Self {
+            id,
+            status: STATUS_END,
+            option: if error { OPTION_ERROR } else { 0 },
+            target: None,
+            data: Bytes::new(),
+        }
+    }
+
+    /// No-op frame keeping an idle session alive.
+    pub fn keepalive() -> Self {
+        Self {
+            id: 0,
+            status: STATUS_KEEPALIVE,
+            option: 0,
+            target: None,
+            data: Bytes::new(),
+        }
+    }
+
+    pub async fn read<R>(reader: &mut R) -> Result<Frame, VlessError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let meta_len = reader.read_u16().await? as usize;
+        if meta_len < 4 {
+            return Err(VlessError::InvalidMuxFrame);
+        }
+        let mut meta = vec![0u8; meta_len];
+        reader.read_exact(&mut meta).await?;
+
+        let mut cur = &meta[..];
+        let id = cur.get_u16();
+        let status = cur.get_u8();
+        let option = cur.get_u8();
+
+        let target = match status {
+            STATUS_NEW => {
+                if cur.remaining() < 3 {
+                    return Err(VlessError::InvalidMuxFrame);
+                }
+                let typ = match cur.get_u8() {
+                    NETWORK_TCP => NetworkType::Tcp,
+                    NETWORK_UDP => NetworkType::Udp,
+                    _ => return Err(VlessError::InvalidMuxFrame),
+                };
+                let port = cur.get_u16();
+                let addr = Address::read::<_, VlessAddrType>(&mut cur).await?;
+                Some(OutboundPacket {
+                    typ,
+                    dest: ServiceAddress::new(addr, port),
+                })
+            }
+            STATUS_KEEP | STATUS_END | STATUS_KEEPALIVE => None,
+            _ => return Err(VlessError::InvalidMuxFrame),
+        };
+
+        let data = if option & OPTION_DATA != 0 {
+            let len = reader.read_u16().await? as usize;
+            let mut data = vec![0u8; len];
+            reader.read_exact(&mut data).await?;
+            Bytes::from(data)
+        } else {
+            Bytes::new()
+        };
+
+        Ok(Frame {
+            id,
+            status,
+            option,
+            target,
+            data,
+        })
+    }
+
+    pub fn into_buf(&self) -> Result<Vec<u8>, VlessError> {
+        let mut meta = BytesMut::with_capacity(4);
+        meta.put_u16(self.id);
+        meta.put_u8(self.status);
+        meta.put_u8(self.option);
+        if let Some(ref target) = self.target {
+            meta.put_u8(match target.typ {
+                NetworkType::Tcp => NETWORK_TCP,
+                NetworkType::Udp => NETWORK_UDP,
+            });
+            meta.put_u16(target.dest.port);
+            target
+                .dest
+                .addr
+                .put_to_buf::<BytesMut, VlessAddrType>(&mut meta)?;
+        }
+
+        let mut buf = BytesMut::with_capacity(2 + meta.len() + 2 + self.data.len());
+        buf.put_u16(meta.len() as u16);
+        buf.put(meta);
+        if self.option & OPTION_DATA != 0 {
+            if self.data.len() > MAX_FRAME_DATA {
+                return Err(VlessError::InvalidMuxFrame);
+            }
+            buf.put_u16(self.data.len() as u16);
+            buf.put(&self.data[..]);
+        }
+
+        Ok(buf.to_vec())
+    }
+}
+
+type SubStreamSender = mpsc::Sender<io::Result<Bytes>>;
+
+/// Senders of frames to the connection. Control frames are never held back,
+/// data frames wait for room in a bounded queue.
+#[derive(Debug, Clone)]
+struct FrameSender {
+    control: mpsc::UnboundedSender<Frame>,
+    data: mpsc::Sender<Frame>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    streams: Mutex<HashMap<u16, SubStreamSender>>,
+    /// Ids of the sub-streams in `streams` the peer opened, the ones
+    /// `max_substreams` limits.
+    peer_opened: Mutex<HashSet<u16>>,
+    next_id: Mutex<u16>,
+    closed: Mutex<bool>,
+    /// Signalled when the last sub-stream goes away.
+    idle: Notify,
+    /// When the last frame arrived from the peer, `None` while reading waits
+    /// on a full sub-stream.
+    last_read: Mutex<Option<Instant>>,
+}
+
+impl Shared {
+    fn remove(&self, id: u16) {
+        let mut streams = self.streams.lock().unwrap();
+        self.peer_opened.lock().unwrap().remove(&id);
+        if streams.remove(&id).is_some() && streams.is_empty() {
+            self.idle.notify_one();
+        }
+    }
+
+    /// Remove `id` if its receiver is gone, leaving a newer sub-stream that
+    /// reused the id of a reset one in place.
+    fn remove_closed(&self, id: u16) {
+        let mut streams = self.streams.lock().unwrap();
+        if !streams.get(&id).is_some_and(|tx| tx.is_closed()) {
+            return;
+        }
+        streams.remove(&id);
+        self.peer_opened.lock().unwrap().remove(&id);
+        if streams.is_empty() {
+            self.idle.notify_one();
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        *self.closed.lock().unwrap()
+    }
+}
+
+/// A mux session over one connection.
+///
+/// The connection is driven by a background task. Clients `open` sub-streams,
+/// servers `accept` the ones the peer opened. The session ends when the
+/// connection fails, when it stays without sub-streams for the idle timeout,
+/// or when the handle and all sub-streams are dropped.
+#[derive(Debug)]
+pub struct VlessMuxStream {
+    shared: Arc<Shared>,
+    frames: FrameSender,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<(OutboundPacket, MuxSubStream)>>,
+    max_streams: usize,
+}
+
+impl VlessMuxStream {
+    /// Start a session on `stream`, which has already been through the
+    /// vless handshake.
+    pub fn new<S>(stream: S, option: &MuxOption) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let shared = Arc::new(Shared::default());
+        let (control, control_rx) = mpsc::unbounded_channel();
+        let (data, data_rx) = mpsc::channel(FRAME_BUFFER);
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+
+        let (reader, writer) = tokio::io::split(stream);
+        let idle_timeout = option.idle_timeout.map(Duration::from_millis);
+        let keepalive = option
+            .keepalive
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis);
+        let driver = Driver {
+            shared: shared.clone(),
+            frames: control.downgrade(),
+            data: data.downgrade(),
+            incoming: incoming_tx,
+            max_substreams: option.max_substreams,
+        };
+        tokio::spawn(driver.run(
+            reader,
+            writer,
+            (control_rx, data_rx),
+            idle_timeout,
+            keepalive,
+        ));
+
+        Self {
+            shared,
+            frames: FrameSender { control, data },
+            incoming: tokio::sync::Mutex::new(incoming),
+            max_streams: option.max_streams,
+        }
+    }
+
+    /// Open a sub-stream to the destination of `packet`.
+    pub fn open(&self, packet: OutboundPacket) -> Result<MuxSubStream, VlessError> {
+        if self.shared.is_closed() {
+            return Err(VlessError::MuxClosed);
+        }
+
+        let (tx, rx) = mpsc::channel(SUBSTREAM_BUFFER);
+        let id = {
+            let mut streams = self.shared.streams.lock().unwrap();
+            if streams.len() >= self.max_streams {
+                return Err(VlessError::MuxFull);
+            }
+
+            // ids opened by the peer count too, give up after one round
+            let mut next_id = self.shared.next_id.lock().unwrap();
+            let id = (0..u16::MAX)
+                .find_map(|_| {
+                    *next_id = next_id.wrapping_add(1);
+                    (*next_id != 0 && !streams.contains_key(&next_id)).then_some(*next_id)
+                })
+                .ok_or(VlessError::MuxFull)?;
+            streams.insert(id, tx);
+            id
+        };
+
+        self.frames
+            .control
+            .send(Frame::new(id, packet))
+            .map_err(|_| VlessError::MuxClosed)?;
+
+        Ok(MuxSubStream::new(
+            id,
+            self.shared.clone(),
+            self.frames.clone(),
+            rx,
+        ))
+    }
+
+    /// Wait for the next sub-stream opened by the peer, `None` once the
+    /// session has ended.
+    pub async fn accept(&self) -> Option<(OutboundPacket, MuxSubStream)> {
+        self.incoming.lock().await.recv().await
+    }
+
+    /// Whether another sub-stream can be opened, callers dial a new
+    /// connection for their next packet when it cannot.
+    pub fn can_open(&self) -> bool {
+        !self.shared.is_closed() && self.num_streams() < self.max_streams
+    }
+
+    pub fn num_streams(&self) -> usize {
+        self.shared.streams.lock().unwrap().len()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.is_closed()
+    }
+}
+
+struct Driver {
+    shared: Arc<Shared>,
+    frames: mpsc::WeakUnboundedSender<Frame>,
+    data: mpsc::WeakSender<Frame>,
+    incoming: mpsc::UnboundedSender<(OutboundPacket, MuxSubStream)>,
+    max_substreams: usize,
+}
+
+impl Driver {
+    async fn run<R, W>(
+        self,
+        mut reader: R,
+        mut writer: W,
+        (mut control, mut data): (mpsc::UnboundedReceiver<Frame>, mpsc::Receiver<Frame>),
+        idle_timeout: Option<Duration>,
+        keepalive: Option<Duration>,
+    ) where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let shared = self.shared.clone();
+        *shared.last_read.lock().unwrap() = Some(Instant::now());
+
+        let write = async {
+            let mut interval =
+                keepalive.map(|period| tokio::time::interval_at(Instant::now() + period, period));
+
+            loop {
+                // control frames first, a sub-stream's NEW goes out before
+                // its data
+                let frame = tokio::select! {
+                    biased;
+                    frame = control.recv() => match frame {
+                        Some(frame) => Some(frame),
+                        // every handle is gone, flush the data they queued
+                        None => data.try_recv().ok(),
+                    },
+                    Some(frame) = data.recv() => Some(frame),
+                    _ = wait_idle(&shared, idle_timeout) => None,
+                    _ = tick(&mut interval) => {
+                        let deadline = keepalive.unwrap_or_default() * KEEPALIVE_MISSES;
+                        let last_read = *shared.last_read.lock().unwrap();
+                        match last_read {
+                            Some(last_read) if last_read.elapsed() > deadline => None,
+                            _ => Some(Frame::keepalive()),
+                        }
+                    }
+                };
+                let Some(frame) = frame else {
+                    break;
+                };
+
+                let Ok(buf) = frame.into_buf() else {
+                    continue;
+                };
+                if writer.write_all(&buf).await.is_err() || writer.flush().await.is_err() {
+                    break;
+                }
+            }
+            let _ = writer.shutdown().await;
+        };
+
+        tokio::select! {
+            _ = self.read_loop(&mut reader) => {}
+            _ = write => {}
+        }
+
+        *shared.closed.lock().unwrap() = true;
+        // dropping the senders ends every sub-stream
+        shared.streams.lock().unwrap().clear();
+        shared.peer_opened.lock().unwrap().clear();
+    }
+
+    /// Queue `data` for sub-stream `id`, waiting for room so a slow reader
+    /// gets every frame. A sub-stream that is gone, or stalls for
+    /// `SUBSTREAM_STALL`, is reset.
+    async fn push(&self, id: u16, sender: &SubStreamSender, data: Bytes) {
+        // the peer is not read meanwhile, which must not look like silence
+        *self.shared.last_read.lock().unwrap() = None;
+        // two slots, leaving one free for an error
+        let reserved = tokio::time::timeout(SUBSTREAM_STALL, sender.reserve_many(2)).await;
+        *self.shared.last_read.lock().unwrap() = Some(Instant::now());
+
+        match reserved {
+            Ok(Ok(mut permits)) => {
+                if let Some(permit) = permits.next() {
+                    permit.send(Ok(data));
+                }
+                return;
+            }
+            Ok(Err(_)) => {}
+            Err(_) => {
+                let _ = sender.try_send(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+        }
+        self.shared.remove(id);
+        self.send(Frame::end(id, true));
+    }
+
+    async fn read_loop<R>(&self, reader: &mut R)
+    where
+        R: AsyncRead + Unpin,
+    {
+        while let Ok(frame) = Frame::read(reader).await {
+            *self.shared.last_read.lock().unwrap() = Some(Instant::now());
+
+            match frame.status {
+                STATUS_NEW => self.on_new(frame),
+                STATUS_KEEP => {
+                    let sender = self.shared.streams.lock().unwrap().get(&frame.id).cloned();
+                    match sender {
+                        Some(sender) => {
+                            if !frame.data.is_empty() {
+                                self.push(frame.id, &sender, frame.data).await;
+                            }
+                        }
+                        None => self.send(Frame::end(frame.id, false)),
+                    }
+                }
+                STATUS_END => {
+                    let sender = self.shared.streams.lock().unwrap().get(&frame.id).cloned();
+                    if let Some(sender) = sender {
+                        if frame.option & OPTION_ERROR != 0 {
+                            let _ = sender.try_send(Err(io::ErrorKind::ConnectionReset.into()));
+                        }
+                    }
+                    self.shared.remove(frame.id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn on_new(&self, frame: Frame) {
+        let (Some(target), Some(frames)) = (frame.target, self.frames.upgrade()) else {
+            return;
+        };
+
+        let (tx, rx) = mpsc::channel(SUBSTREAM_BUFFER);
+        if !frame.data.is_empty() {
+            let _ = tx.try_send(Ok(frame.data));
+        }
+        {
+            // an id in use, possibly by a sub-stream opened here, is refused,
+            // only the peer's own sub-streams count against its limit
+            let mut streams = self.shared.streams.lock().unwrap();
+            let mut peer_opened = self.shared.peer_opened.lock().unwrap();
+            if peer_opened.len() >= self.max_substreams || streams.contains_key(&frame.id) {
+                drop((streams, peer_opened));
+                self.send(Frame::end(frame.id, true));
+                return;
+            }
+            streams.insert(frame.id, tx);
+            peer_opened.insert(frame.id);
+        }
+
+        let Some(data) = self.data.upgrade() else {
+            return;
+        };
+        let frames = FrameSender {
+            control: frames,
+            data,
+        };
+        let stream = MuxSubStream::new(frame.id, self.shared.clone(), frames, rx);
+        // an unaccepted sub-stream is dropped, which ends it
+        let _ = self.incoming.send((target, stream));
+    }
+
+    fn send(&self, frame: Frame) {
+        if let Some(frames) = self.frames.upgrade() {
+            let _ = frames.send(frame);
+        }
+    }
+}
+
+/// Next tick of the keepalive interval, never without one.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once the session has been without sub-streams for `timeout`.
+async fn wait_idle(shared: &Shared, timeout: Option<Duration>) {
+    let Some(timeout) = timeout else {
+        return std::future::pending().await;
+    };
+
+    loop {
+        if shared.streams.lock().unwrap().is_empty() {
+            tokio::time::sleep(timeout).await;
+            if shared.streams.lock().unwrap().is_empty() {
+                return;
+            }
+        } else {
+            shared.idle.notified().await;
+        }
+    }
+}
+
+/// One sub-stream of a mux session.
+#[derive(Debug)]
+pub struct MuxSubStream {
+    id: u16,
+    shared: Arc<Shared>,
+    control: mpsc::UnboundedSender<Frame>,
+    data: PollSender<Frame>,
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+    read_buf: Bytes,
+    write_closed: bool,
+}
+
+impl MuxSubStream {
+    fn new(
+        id: u16,
+        shared: Arc<Shared>,
+        frames: FrameSender,
+        rx: mpsc::Receiver<io::Result<Bytes>>,
+    ) -> Self {
+        Self {
+            id,
+            shared,
+            control: frames.control,
+            data: PollSender::new(frames.data),
+            rx,
+            read_buf: Bytes::new(),
+            write_closed: false,
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+}
+
+impl Drop for MuxSubStream {
+    fn drop(&mut self) {
+        if !self.write_closed {
+            // behind the queued data if there is room, the stream is cut
+            // short anyway
+            let end = Frame::end(self.id, false);
+            let end = match self.data.get_ref() {
+                Some(data) => match data.try_send(end) {
+                    Err(mpsc::error::TrySendError::Full(end)) => Some(end),
+                    _ => None,
+                },
+                None => Some(end),
+            };
+            if let Some(end) = end {
+                let _ = self.control.send(end);
+            }
+        }
+        self.rx.close();
+        self.shared.remove_closed(self.id);
+    }
+}
+
+impl AsyncRead for MuxSubStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.read_buf.is_empty() {
+            match ready!(this.rx.poll_recv(cx)) {
+                Some(Ok(data)) => this.read_buf = data,
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let n = this.read_buf.len().min(buf.remaining());
+        buf.put_slice(&this.read_buf.split_to(n));
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxSubStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        ready!(this.data.poll_reserve(cx))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        let n = buf.len().min(MAX_FRAME_DATA);
+        this.data
+            .send_item(Frame::keep(this.id, Bytes::copy_from_slice(&buf[..n])))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.write_closed {
+            // queued behind the data written before
+            let res = ready!(this.data.poll_reserve(cx));
+            this.write_closed = true;
+            if res.is_ok() {
+                let _ = this.data.send_item(Frame::end(this.id, false));
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mux_frame_round_trip() {
+        let frames = [
+            Frame::new(
+                1,
+                OutboundPacket {
+                    typ: NetworkType::Udp,
+                    dest: ServiceAddress::new("example.com".into(), 53),
+                },
+            ),
+            Frame::keep(1, Bytes::from_static(b"data")),
+            Frame::end(1, true),
+        ];
+
+        for frame in frames {
+            let buf = frame.into_buf().unwrap();
+            assert_eq!(Frame::read(&mut &buf[..]).await.unwrap(), frame);
+        }
+
+        // unknown status
+        assert!(Frame::read(&mut &[0, 4, 0, 1, 9, 0][..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mux_max_substreams() {
+        let (s1, s2) = tokio::io::duplex(4096);
+        let client = VlessMuxStream::new(
+            s1,
+            &MuxOption {
+                max_streams: 16,
+                ..MuxOption::default()
+            },
+        );
+        let server = VlessMuxStream::new(
+            s2,
+            &MuxOption {
+                max_substreams: 2,
+                ..MuxOption::default()
+            },
+        );
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("example.com".into(), 80),
+        };
+
+        let mut subs = vec![];
+        for _ in 0..3 {
+            subs.push(client.open(packet.clone()).unwrap());
+        }
+        let mut accepted = vec![];
+        for _ in 0..2 {
+            accepted.push(server.accept().await.unwrap().1);
+        }
+
+        // the one over the limit is refused
+        let err = subs[2].read(&mut [0u8; 4]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(server.num_streams(), 2);
+
+        // a finished sub-stream frees its slot
+        subs[0].shutdown().await.unwrap();
+        assert_eq!(accepted[0].read(&mut [0u8; 4]).await.unwrap(), 0);
+        assert_eq!(server.num_streams(), 1);
+
+        let mut sub = client.open(packet).unwrap();
+        let (_, mut next) = server.accept().await.unwrap();
+        sub.write_all(b"data").await.unwrap();
+        let mut buf = [0u8; 4];
+        next.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"data");
+    }
+
+    #[tokio::test]
+    async fn test_mux_max_substreams_peer_only() {
+        let (s1, s2) = tokio::io::duplex(4096);
+        let client = VlessMuxStream::new(s1, &MuxOption::default());
+        let server = VlessMuxStream::new(
+            s2,
+            &MuxOption {
+                max_substreams: 1,
+                ..MuxOption::default()
+            },
+        );
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("example.com".into(), 80),
+        };
+
+        let mut sub = client.open(packet.clone()).unwrap();
+        let (_, mut accepted) = server.accept().await.unwrap();
+        sub.shutdown().await.unwrap();
+        assert_eq!(accepted.read(&mut [0u8; 4]).await.unwrap(), 0);
+        drop((sub, accepted));
+
+        // sub-streams opened by the server itself leave the client's slot free
+        let _local = [
+            server.open(packet.clone()).unwrap(),
+            server.open(packet.clone()).unwrap(),
+        ];
+        let _remote = [
+            client.accept().await.unwrap(),
+            client.accept().await.unwrap(),
+        ];
+        let mut sub = client.open(packet).unwrap();
+        let (_, mut accepted) = tokio::time::timeout(Duration::from_secs(1), server.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        sub.write_all(b"data").await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"data");
+        assert_eq!(server.num_streams(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mux_keepalive() {
+        let (s1, mut s2) = tokio::io::duplex(4096);
+        let session = VlessMuxStream::new(
+            s1,
+            &MuxOption {
+                keepalive: Some(1000),
+                ..MuxOption::default()
+            },
+        );
+
+        // an idle session still sends frames, and answers keep it open
+        for _ in 0..5 {
+            let frame = Frame::read(&mut s2).await.unwrap();
+            assert_eq!(frame, Frame::keepalive());
+            s2.write_all(&Frame::keepalive().into_buf().unwrap())
+                .await
+                .unwrap();
+        }
+        assert!(!session.is_closed());
+
+        // a silent peer ends it
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(session.is_closed());
+    }
+
+    fn tcp_packet() -> OutboundPacket {
+        OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("example.com".into(), 80),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mux_write_backpressure() {
+        // nobody reads the other end
+        let (s1, _s2) = tokio::io::duplex(1024);
+        let client = VlessMuxStream::new(s1, &MuxOption::default());
+        let mut sub = client.open(tcp_packet()).unwrap();
+
+        let data = vec![0u8; 16 << 20];
+        let res = tokio::time::timeout(Duration::from_secs(1), sub.write_all(&data)).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mux_slow_substream() {
+        let (s1, s2) = tokio::io::duplex(1 << 20);
+        let client = VlessMuxStream::new(s1, &MuxOption::default());
+        let server = VlessMuxStream::new(s2, &MuxOption::default());
+
+        // a reader slower than the writer still gets every frame
+        let mut slow = client.open(tcp_packet()).unwrap();
+        let (_, mut reader) = server.accept().await.unwrap();
+        let write = async {
+            for _ in 0..SUBSTREAM_BUFFER * 4 {
+                slow.write_all(b"data").await.unwrap();
+            }
+            slow.shutdown().await.unwrap();
+        };
+        let read = async {
+            let mut read = vec![];
+            let mut buf = [0u8; 4];
+            loop {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                match reader.read(&mut buf).await.unwrap() {
+                    0 => break read,
+                    n => read.extend_from_slice(&buf[..n]),
+                }
+            }
+        };
+        let (_, read) = tokio::join!(write, read);
+        assert_eq!(read, b"data".repeat(SUBSTREAM_BUFFER * 4));
+        assert!(!server.is_closed());
+
+        // one that stops reading is reset after the stall timeout, and the
+        // session serves the others again
+        let mut stalled = client.open(tcp_packet()).unwrap();
+        let (_, mut unread) = server.accept().await.unwrap();
+        for _ in 0..SUBSTREAM_BUFFER * 2 {
+            stalled.write_all(b"data").await.unwrap();
+        }
+        let mut buf = [0u8; 4];
+        let err = stalled.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        let mut read = vec![];
+        let err = unread.read_to_end(&mut read).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(read.len(), (SUBSTREAM_BUFFER - 1) * 4);
+
+        let mut sub = client.open(tcp_packet()).unwrap();
+        let (_, mut next) = server.accept().await.unwrap();
+        sub.write_all(b"ping").await.unwrap();
+        next.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_mux_new_id_in_use() {
+        let (s1, mut s2) = tokio::io::duplex(4096);
+        let client = VlessMuxStream::new(s1, &MuxOption::default());
+        let mut sub = client.open(tcp_packet()).unwrap();
+        assert_eq!(
+            Frame::read(&mut s2).await.unwrap(),
+            Frame::new(1, tcp_packet())
+        );
+
+        // the peer opening the same id is refused
+        s2.write_all(&Frame::new(1, tcp_packet()).into_buf().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(Frame::read(&mut s2).await.unwrap(), Frame::end(1, true));
+
+        // and the local sub-stream is left alone
+        s2.write_all(
+            &Frame::keep(1, Bytes::from_static(b"data"))
+                .into_buf()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let mut buf = [0u8; 4];
+        sub.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"data");
+        assert_eq!(client.num_streams(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mux_idle_timeout() {
+        let (s1, _s2) = tokio::io::duplex(4096);
+        let client = VlessMuxStream::new(
+            s1,
+            &MuxOption {
+                idle_timeout: Some(1000),
+                ..MuxOption::default()
+            },
+        );
+
+        // open sub-streams keep the session
+        let sub = client.open(tcp_packet()).unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(!client.is_closed());
+
+        drop(sub);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!client.is_closed());
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        assert!(client.is_closed());
+        assert!(client.open(tcp_packet()).is_err());
+    }
+}
@@ -1,8 +1,68 @@
 use serde::{Deserialize, Serialize};
 
+use crate::option::default_udp_enabled;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VlessInboundOption {
     pub users: Vec<VlessUserOption>,
+    #[serde(default = "default_udp_enabled")]
+    pub udp_enabled: bool,
+    /// Timeout in milliseconds for writing the handshake response.
+    #[serde(default)]
+    pub reply_timeout: Option<u64>,
+    /// Reject requests whose flow is not listed in `flows`.
+    #[serde(default)]
+    pub strict_flow: bool,
+    /// Flows accepted under `strict_flow`, each must be a known flow. When
+    /// empty a strict inbound only takes requests without a flow.
+    #[serde(default)]
+    pub flows: Vec<String>,
+    /// Read buffer size in bytes for the buffered connection, 8 KiB if unset.
+    #[serde(default)]
+    pub read_buffer: Option<usize>,
+    /// `host:port` that connections with an unknown uuid or a malformed
+    /// request are handed to, instead of being closed.
+    #[serde(default)]
+    pub fallback: Option<String>,
+    /// Accept any uuid, unknown ones as the user `anonymous`. Insecure,
+    /// for tests and local development only. Rejected unless built with
+    /// the `insecure-allow-any` feature, which logs a warning when it is
+    /// enabled and for every request it lets in.
+    #[serde(default)]
+    pub allow_any: bool,
+    /// Most sub-streams a client may have open on one mux session, new
+    /// ones beyond it are refused.
+    #[serde(default = "default_max_substreams")]
+    pub max_substreams: usize,
+    /// Interval in milliseconds of keepalive frames on mux sessions.
+    #[serde(default)]
+    pub keepalive: Option<u64>,
+    /// See `SocksInboundOption::unmap_v4`.
+    #[serde(default)]
+    pub unmap_v4: bool,
+    /// Refuse requested domains that fail `check_domain`, by default any
+    /// domain is handed on.
+    #[serde(default)]
+    pub strict_domain: bool,
+}
+
+impl Default for VlessInboundOption {
+    fn default() -> Self {
+        Self {
+            users: vec![],
+            udp_enabled: default_udp_enabled(),
+            reply_timeout: None,
+            strict_flow: false,
+            flows: vec![],
+            read_buffer: None,
+            fallback: None,
+            allow_any: false,
+            max_substreams: default_max_substreams(),
+            keepalive: None,
+            unmap_v4: false,
+            strict_domain: false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,4 +75,51 @@ pub struct VlessUserOption {
 pub struct VlessOutboundOption {
     pub uuid: String,
     pub flow: Option<String>,
+    /// Hold the request header back and send it with the first payload.
+    #[serde(default)]
+    pub combine_first_packet: bool,
+    /// Settings for sessions from `VlessOutbound::connect_mux`. The service
+    /// handshake spends a connection on each packet, so `OutboundService`
+    /// rejects it.
+    #[serde(default)]
+    pub mux: Option<MuxOption>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MuxOption {
+    /// Most sub-streams open at once on one session.
+    #[serde(default = "default_max_streams")]
+    pub max_streams: usize,
+    /// Idle time in milliseconds after which a session without sub-streams
+    /// is closed.
+    #[serde(default)]
+    pub idle_timeout: Option<u64>,
+    /// Most sub-streams opened by the peer open at once, new ones beyond it
+    /// are ended with an error. Sub-streams opened here do not count.
+    #[serde(default = "default_max_substreams")]
+    pub max_substreams: usize,
+    /// Interval in milliseconds of keepalive frames, which keep NAT
+    /// mappings of idle sessions open. The session is closed when nothing
+    /// arrives from the peer for three intervals, so both ends need it.
+    #[serde(default)]
+    pub keepalive: Option<u64>,
+}
+
+impl Default for MuxOption {
+    fn default() -> Self {
+        Self {
+            max_streams: default_max_streams(),
+            idle_timeout: None,
+            max_substreams: default_max_substreams(),
+            keepalive: None,
+        }
+    }
+}
+
+fn default_max_streams() -> usize {
+    8
+}
+
+fn default_max_substreams() -> usize {
+    256
 }
@@ -1,5 +1,10 @@
-use std::{pin::Pin, str::FromStr, task::Poll};
+use std::{
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Poll},
+};
 
+use bytes::{Buf, BytesMut};
 use tokio::io::{AsyncRead, AsyncWrite};
 use uuid::Uuid;
 
@@ -9,26 +14,96 @@ use crate::{
 };
 
 use super::{
-    protocol::{Response, COMMAND_TCP, COMMAND_UDP},
-    Request, VlessOutboundOption,
+    option::MuxOption,
+    protocol::{is_known_flow, Response, COMMAND_MUX, COMMAND_TCP, COMMAND_UDP},
+    Request, VlessError, VlessMuxStream, VlessOutboundOption,
 };
 
 #[derive(Debug)]
 pub struct VlessOutbound {
     uuid: uuid::Uuid,
     flow: Option<String>,
+    combine_first_packet: bool,
+    mux: Option<MuxOption>,
+}
+
+impl VlessOutboundOption {
+    pub fn validate(&self) -> Vec<OutboundError> {
+        let mut errors = vec![];
+        if let Err(e) = Uuid::from_str(&self.uuid) {
+            errors.push(OutboundError::Option(format!("invalid uuid ({})", e)));
+        }
+        if let Some(flow) = self.flow.as_deref().filter(|f| !is_known_flow(f)) {
+            errors.push(OutboundError::Option(format!("unknown flow {}", flow)));
+        }
+        if self.mux.as_ref().is_some_and(|mux| mux.max_streams == 0) {
+            errors.push(OutboundError::Option(
+                "mux max_streams must be at least 1".to_string(),
+            ));
+        }
+        errors
+    }
+
+    /// `validate` for the option used through `OutboundService`, whose
+    /// handshake does not use `mux`.
+    pub(crate) fn validate_service(&self) -> Vec<OutboundError> {
+        let mut errors = self.validate();
+        if self.mux.is_some() {
+            errors.push(OutboundError::Option(
+                "mux is only used by VlessOutbound::connect_mux".to_string(),
+            ));
+        }
+        errors
+    }
 }
 
 impl VlessOutbound {
     pub fn init(option: VlessOutboundOption) -> OutboundResult<Self> {
-        let uuid =
-            Uuid::from_str(&option.uuid).map_err(|e| OutboundError::Option(e.to_string()))?;
+        if let Some(e) = option.validate().into_iter().next() {
+            return Err(e);
+        }
+
+        let uuid = Uuid::from_str(&option.uuid)
+            .map_err(|e| OutboundError::Option(format!("invalid uuid ({})", e)))?;
 
         Ok(Self {
             uuid,
-            flow: option.flow,
+            flow: option.flow.filter(|flow| !flow.is_empty()),
+            combine_first_packet: option.combine_first_packet,
+            mux: option.mux,
         })
     }
+
+    /// Start a mux session on `stream`.
+    ///
+    /// `handshake` always spends a connection on a single destination. With
+    /// mux, callers keep the returned session and `open` a sub-stream for
+    /// each packet, dialing a new connection for another session once
+    /// `can_open` is false.
+    pub async fn connect_mux<S>(&self, mut stream: S) -> OutboundResult<VlessMuxStream>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        let option = self
+            .mux
+            .as_ref()
+            .ok_or_else(|| OutboundError::Option("mux is not enabled".to_string()))?;
+
+        let req = Request {
+            uuid: self.uuid,
+            flow: self.flow.clone(),
+            command: COMMAND_MUX,
+            destination: None,
+        };
+        req.write(&mut stream, None)
+            .await
+            .map_err(|e| OutboundError::Handshake(e.into()))?;
+
+        Ok(VlessMuxStream::new(
+            VlessOutboundStream::new(stream),
+            option,
+        ))
+    }
 }
 
 impl<S> OutboundServiceTrait<S> for VlessOutbound
@@ -54,8 +129,14 @@ where
             destination: Some(packet.dest),
         };
 
-        let _ = req
-            .write(&mut stream, None)
+        if self.combine_first_packet {
+            let header = req
+                .into_buf(None)
+                .map_err(|e| OutboundError::Handshake(e.into()))?;
+            return Ok(VlessOutboundStream::with_header(stream, header));
+        }
+
+        req.write(&mut stream, None)
             .await
             .map_err(|e| OutboundError::Handshake(e.into()))?;
 
@@ -70,6 +151,12 @@ where
 {
     inner: S,
     check_resp: bool,
+    /// Response header bytes read so far.
+    resp_buf: BytesMut,
+    read_closed: bool,
+    /// Unsent bytes, starting with the deferred request header.
+    write_buf: BytesMut,
+    header_pending: bool,
 }
 
 impl<S> VlessOutboundStream<S>
@@ -80,8 +167,38 @@ where
         Self {
             inner,
             check_resp: true,
+            resp_buf: BytesMut::new(),
+            read_closed: false,
+            write_buf: BytesMut::new(),
+            header_pending: false,
+        }
+    }
+
+    /// The request `header` goes out together with the first write.
+    pub fn with_header(inner: S, header: Vec<u8>) -> Self {
+        Self {
+            write_buf: BytesMut::from(&header[..]),
+            header_pending: true,
+            ..Self::new(inner)
         }
     }
+
+    fn poll_write_buf(&mut self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        self.header_pending = false;
+        while !self.write_buf.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))? {
+                0 => return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into())),
+                n => self.write_buf.advance(n),
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Whether the read side has reached EOF.
+    pub fn is_read_closed(&self) -> bool {
+        self.read_closed
+    }
 }
 
 impl<S> From<VlessOutboundStream<S>> for OutboundServiceStream<S>
@@ -103,22 +220,53 @@ where
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
         let this = self.get_mut();
+        // the server answers only after it got the header
+        ready!(this.poll_write_buf(cx))?;
+
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        // the response header may arrive over several reads
+        while this.check_resp {
+            let filled = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            if buf.filled().len() == filled {
+                this.read_closed = true;
+                if this.resp_buf.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    VlessError::Truncated("response"),
+                )));
+            }
 
-        match Pin::new(&mut this.inner).poll_read(cx, buf) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
-            Poll::Ready(Ok(_)) => {
-                if this.check_resp {
-                    let resp =
-                        Response::read_buf(buf.filled()).map_err(|e| std::io::Error::other(e))?;
-                    let data = buf.filled()[resp.len()..].to_vec();
-                    buf.clear();
-                    buf.put_slice(&data);
-                    this.check_resp = false;
+            this.resp_buf.extend_from_slice(&buf.filled()[filled..]);
+            buf.set_filled(filled);
+
+            if let Some((_, len)) =
+                Response::read_partial(&this.resp_buf).map_err(std::io::Error::other)?
+            {
+                this.resp_buf.advance(len);
+                this.check_resp = false;
+
+                // the response came alone, an empty read would look like EOF
+                if !this.resp_buf.is_empty() {
+                    buf.put_slice(&this.resp_buf);
+                    this.resp_buf.clear();
+                    return Poll::Ready(Ok(()));
                 }
-                Poll::Ready(Ok(()))
             }
         }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if buf.filled().len() == filled {
+            this.read_closed = true;
+        }
+
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -131,29 +279,46 @@ where
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, std::io::Error>> {
-        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+        let this = self.get_mut();
+
+        if this.header_pending {
+            this.write_buf.extend_from_slice(buf);
+            if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+                return Poll::Ready(Err(e));
+            }
+            return Poll::Ready(Ok(buf.len()));
+        }
+
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_write(cx, buf)
     }
 
     fn poll_flush(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
     }
 
     fn poll_shutdown(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
+    use std::{io::Cursor, time::Duration};
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
 
-    use crate::ServiceAddress;
+    use crate::{stream::tests::WriteLog, vless::protocol::FLOWS, ServiceAddress};
 
     use super::*;
 
@@ -165,6 +330,8 @@ mod tests {
         let opt = VlessOutboundOption {
             uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
             flow: None,
+            combine_first_packet: false,
+            mux: None,
         };
 
         let vo = VlessOutbound::init(opt).unwrap();
@@ -181,4 +348,166 @@ mod tests {
 
         println!("{:?}", result);
     }
+
+    #[test]
+    fn test_vless_outbound_flow() {
+        let opt = |flow: &str| VlessOutboundOption {
+            uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+            flow: Some(flow.into()),
+            combine_first_packet: false,
+            mux: None,
+        };
+
+        for flow in FLOWS {
+            assert!(VlessOutbound::init(opt(flow)).is_ok(), "{}", flow);
+        }
+        assert!(matches!(
+            VlessOutbound::init(opt("xtls-rprx-direct")),
+            Err(OutboundError::Option(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_vless_combine_first_packet() {
+        let vo = VlessOutbound::init(VlessOutboundOption {
+            uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+            flow: None,
+            combine_first_packet: true,
+            mux: None,
+        })
+        .unwrap();
+
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("example.com".into(), 443),
+        };
+        let req = Request {
+            uuid: vo.uuid,
+            flow: None,
+            command: COMMAND_TCP,
+            destination: Some(packet.dest.clone()),
+        };
+
+        let mut stream = vo.handshake(WriteLog::default(), packet).await.unwrap();
+        assert!(stream.inner.0.is_empty());
+
+        stream.write_all(b"hello").await.unwrap();
+        stream.write_all(b"world").await.unwrap();
+        assert_eq!(
+            stream.inner.0,
+            vec![req.into_buf(Some(b"hello")).unwrap(), b"world".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vless_outbound_read_closed() {
+        let (s1, mut s2) = duplex(64);
+        let mut stream = VlessOutboundStream::new(s1);
+
+        s2.write_all(&Response::default().into_buf(Some(b"data")).unwrap())
+            .await
+            .unwrap();
+        s2.shutdown().await.unwrap();
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"data");
+        assert!(stream.is_read_closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_vless_outbound_split_response() {
+        let (s1, mut s2) = duplex(64);
+        let mut stream = VlessOutboundStream::new(s1);
+
+        let resp = Response {
+            flow: Some(FLOWS[1].to_string()),
+        }
+        .into_buf(Some(b"data"))
+        .unwrap();
+        let (head, tail) = resp.split_at(3);
+
+        // the header is incomplete, nothing is returned yet
+        s2.write_all(head).await.unwrap();
+        let mut buf = [0u8; 16];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), stream.read(&mut buf))
+                .await
+                .is_err()
+        );
+
+        s2.write_all(tail).await.unwrap();
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"data");
+    }
+
+    #[tokio::test]
+    async fn test_vless_mux_two_destinations() {
+        let vo = VlessOutbound::init(VlessOutboundOption {
+            uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+            flow: None,
+            combine_first_packet: false,
+            mux: Some(MuxOption {
+                max_streams: 2,
+                ..MuxOption::default()
+            }),
+        })
+        .unwrap();
+
+        let (s1, mut s2) = duplex(4096);
+        let session = vo.connect_mux(s1).await.unwrap();
+
+        let req = Request::read(&mut s2).await.unwrap();
+        assert_eq!(req.command, COMMAND_MUX);
+        assert_eq!(req.destination, None);
+        s2.write_all(&Response::default().into_buf(None).unwrap())
+            .await
+            .unwrap();
+        let server = VlessMuxStream::new(s2, &MuxOption::default());
+
+        let dests = [
+            ServiceAddress::new("example.com".into(), 443),
+            ServiceAddress::new("10.0.0.1".into(), 53),
+        ];
+        let mut subs = vec![];
+        for dest in &dests {
+            let packet = OutboundPacket {
+                typ: NetworkType::Tcp,
+                dest: dest.clone(),
+            };
+            subs.push(session.open(packet).unwrap());
+        }
+        assert!(!session.can_open());
+        assert!(session
+            .open(OutboundPacket {
+                typ: NetworkType::Tcp,
+                dest: dests[0].clone(),
+            })
+            .is_err());
+
+        for (i, sub) in subs.iter_mut().enumerate() {
+            sub.write_all(format!("hello {}", i).as_bytes())
+                .await
+                .unwrap();
+        }
+
+        for (i, dest) in dests.iter().enumerate() {
+            let (packet, mut sub) = server.accept().await.unwrap();
+            assert_eq!(&packet.dest, dest);
+
+            let mut buf = [0u8; 7];
+            sub.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, format!("hello {}", i).as_bytes());
+            sub.write_all(&buf).await.unwrap();
+        }
+
+        for (i, sub) in subs.iter_mut().enumerate() {
+            let mut buf = [0u8; 7];
+            sub.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, format!("hello {}", i).as_bytes());
+        }
+
+        drop(subs);
+        assert!(session.can_open());
+    }
 }
@@ -15,12 +15,19 @@ use crate::{
 
 use super::VlessError;
 
-const VERSION: u8 = 0;
+pub(crate) const VERSION: u8 = 0;
 
 pub const COMMAND_TCP: u8 = 1;
 pub const COMMAND_UDP: u8 = 2;
 pub const COMMAND_MUX: u8 = 3;
 
+/// Flows this implementation knows, the empty flow being none.
+pub const FLOWS: &[&str] = &["", "xtls-rprx-vision", "xtls-rprx-vision-udp443"];
+
+pub fn is_known_flow(flow: &str) -> bool {
+    FLOWS.contains(&flow)
+}
+
 impl_addr_type! {
     pub enum VlessAddrType {
         Ipv4 = 1,
@@ -38,11 +45,12 @@ pub struct Request {
     pub destination: Option<ServiceAddress>,
 }
 
+#[allow(clippy::len_without_is_empty)]
 impl Request {
     pub fn len(&self) -> usize {
         let mut request_len: usize = 1 + 16 + 1; // version + uuid + addons length
 
-        if let Some(ref flow) = self.flow {
+        if let Some(flow) = addon_flow(&self.flow) {
             request_len += 1 + variant_len(flow.len() as u64) + flow.len(); // header + flow_len + flow
         }
 
@@ -61,17 +69,32 @@ impl Request {
         request_len
     }
 
+    /// Read a request, rejecting implausible domains with `check_domain`.
     pub async fn read<R>(stream: &mut R) -> Result<Request, VlessError>
     where
         R: AsyncRead + Unpin,
     {
-        let version = stream.read_u8().await?;
+        Self::read_with(stream, true).await
+    }
+
+    /// Read a request, `strict` turns the domain check on.
+    pub async fn read_with<R>(stream: &mut R, strict: bool) -> Result<Request, VlessError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let version = stream
+            .read_u8()
+            .await
+            .map_err(VlessError::truncated("version"))?;
         if version != VERSION {
-            return Err(VlessError::InvalidVersion(version).into());
+            return Err(VlessError::InvalidVersion(version));
         }
 
         let mut uuid = [0u8; 16];
-        let _ = stream.read_exact(&mut uuid).await?;
+        stream
+            .read_exact(&mut uuid)
+            .await
+            .map_err(VlessError::truncated("uuid"))?;
 
         let mut flow = None;
         let addons_len = stream.read_u8().await?;
@@ -83,11 +106,17 @@ impl Request {
         }
 
         let mut destination = None;
-        let command = stream.read_u8().await?;
+        let command = stream
+            .read_u8()
+            .await
+            .map_err(VlessError::truncated("command"))?;
         match command {
             COMMAND_TCP | COMMAND_UDP => {
-                let port = stream.read_u16().await?;
-                let addr = Address::read::<R, VlessAddrType>(stream).await?;
+                let port = stream
+                    .read_u16()
+                    .await
+                    .map_err(VlessError::truncated("port"))?;
+                let addr = Address::read_with::<R, VlessAddrType>(stream, strict).await?;
                 destination = Some(ServiceAddress::new(addr, port));
             }
             COMMAND_MUX => {}
@@ -106,22 +135,47 @@ impl Request {
     where
         W: AsyncWrite + Unpin,
     {
-        let _ = writer.write_all(&self.into_buf(payload)?).await?;
+        writer.write_all_buf(&mut self.encode(payload)?).await?;
         //let _ = writer.flush().await?;
 
         Ok(())
     }
 
+    /// Write the header, then copy `payload` until its EOF, returning the
+    /// payload bytes copied.
+    ///
+    /// Large payloads go out as they are read instead of being put into
+    /// one buffer with the header. To send the payload some other way,
+    /// `write` with no payload writes the header alone.
+    pub async fn write_header_then<W, R>(
+        &self,
+        writer: &mut W,
+        payload: &mut R,
+    ) -> Result<u64, VlessError>
+    where
+        W: AsyncWrite + Unpin,
+        R: AsyncRead + Unpin,
+    {
+        self.write(writer, None).await?;
+        Ok(tokio::io::copy(payload, writer).await?)
+    }
+
     pub fn into_buf(&self, payload: Option<&[u8]>) -> Result<Vec<u8>, VlessError> {
+        // takes over the buffer's allocation, nothing is copied
+        Ok(self.encode(payload)?.into())
+    }
+
+    /// Header followed by `payload`, in a buffer of exactly that size.
+    pub fn encode(&self, payload: Option<&[u8]>) -> Result<BytesMut, VlessError> {
         let request_len = self.len() + payload.map_or(0, |p| p.len());
 
         let mut buf = BytesMut::with_capacity(request_len);
         buf.put_u8(VERSION);
         buf.put(self.uuid.as_ref());
 
-        match self.flow {
-            Some(ref flow) => {
-                buf.put_u8(flow.len() as u8);
+        match addon_flow(&self.flow) {
+            Some(flow) => {
+                buf.put_u8((1 + variant_len(flow.len() as u64) + flow.len()) as u8);
                 buf.put_u8(10);
                 write_varint(&mut buf, flow.len() as u64);
                 buf.put(flow.as_bytes());
@@ -138,6 +192,7 @@ impl Request {
                     ap.addr.put_to_buf::<BytesMut, VlessAddrType>(&mut buf)?;
                 }
             }
+            COMMAND_MUX if self.destination.is_some() => return Err(VlessError::MuxDestination),
             COMMAND_MUX => {}
             other => return Err(VlessError::InvalidCommand(other)),
         }
@@ -146,26 +201,26 @@ impl Request {
             buf.put(p);
         }
 
-        Ok(buf.to_vec())
+        Ok(buf)
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Response {
-    pub flow: Option<String>,
+/// The flow to put into the addons, an empty one is the same as none.
+fn addon_flow(flow: &Option<String>) -> Option<&str> {
+    flow.as_deref().filter(|flow| !flow.is_empty())
 }
 
-impl Default for Response {
-    fn default() -> Self {
-        Self { flow: None }
-    }
+#[derive(Debug, Clone, Default)]
+pub struct Response {
+    pub flow: Option<String>,
 }
 
+#[allow(clippy::len_without_is_empty)]
 impl Response {
     pub fn len(&self) -> usize {
         let mut resp_len: usize = 2; // version + addons_header
 
-        if let Some(ref flow) = self.flow {
+        if let Some(flow) = addon_flow(&self.flow) {
             resp_len += 1 + variant_len(flow.len() as u64) + flow.len(); // header + flow_len + flow
         }
 
@@ -178,7 +233,7 @@ impl Response {
     {
         let version = stream.read_u8().await?;
         if version != VERSION {
-            return Err(VlessError::InvalidVersion(version).into());
+            return Err(VlessError::InvalidVersion(version));
         }
 
         let mut resp = Response { flow: None };
@@ -194,49 +249,60 @@ impl Response {
     }
 
     pub fn read_buf(buf: &[u8]) -> Result<Response, VlessError> {
-        if buf.len() < 2 {
-            return Err(VlessError::UnknownVersion);
+        match Response::read_partial(buf)? {
+            Some((resp, _)) => Ok(resp),
+            None if buf.len() < 2 => Err(VlessError::UnknownVersion),
+            None => Err(VlessError::TruncatedAddons),
         }
+    }
 
-        let version = buf[0];
+    /// The response at the start of `buf` and its length in bytes, `None`
+    /// while `buf` does not hold all of it yet.
+    pub fn read_partial(buf: &[u8]) -> Result<Option<(Response, usize)>, VlessError> {
+        let Some(&version) = buf.first() else {
+            return Ok(None);
+        };
         if version != VERSION {
-            return Err(VlessError::InvalidVersion(version).into());
+            return Err(VlessError::InvalidVersion(version));
         }
 
-        let mut resp = Response { flow: None };
-        let addons_len = buf[1];
-        if addons_len > 0 {
-            if buf.len() - 2 > addons_len as usize {
-                return Err(VlessError::InvalidHeader(addons_len));
-            }
-
-            let addons = Addons::parse(&buf[..addons_len as usize])?;
-            resp.flow = addons.flow;
-        }
+        let Some(&addons_len) = buf.get(1) else {
+            return Ok(None);
+        };
+        let len = 2 + addons_len as usize;
+        let Some(addons) = buf.get(2..len) else {
+            return Ok(None);
+        };
 
-        Ok(resp)
+        let addons = Addons::parse(addons)?;
+        Ok(Some((Response { flow: addons.flow }, len)))
     }
 
     pub async fn write<W>(&self, writer: &mut W, payload: Option<&[u8]>) -> Result<(), VlessError>
     where
         W: AsyncWrite + Unpin,
     {
-        let _ = writer.write_all(&self.into_buf(payload)?).await?;
+        writer.write_all_buf(&mut self.encode(payload)?).await?;
         //let _ = writer.flush().await?;
 
         Ok(())
     }
 
     pub fn into_buf(&self, payload: Option<&[u8]>) -> Result<Vec<u8>, VlessError> {
+        Ok(self.encode(payload)?.into())
+    }
+
+    /// Header followed by `payload`, in a buffer of exactly that size.
+    pub fn encode(&self, payload: Option<&[u8]>) -> Result<BytesMut, VlessError> {
         let resp_len = self.len() + payload.map_or(0, |p| p.len());
 
         let mut buf = BytesMut::with_capacity(resp_len);
 
         buf.put_u8(VERSION);
 
-        match self.flow {
-            Some(ref flow) => {
-                buf.put_u8(flow.len() as u8);
+        match addon_flow(&self.flow) {
+            Some(flow) => {
+                buf.put_u8((1 + variant_len(flow.len() as u64) + flow.len()) as u8);
                 buf.put_u8(10);
                 write_varint(&mut buf, flow.len() as u64);
                 buf.put(flow.as_bytes());
@@ -248,7 +314,7 @@ impl Response {
             buf.put(p);
         }
 
-        Ok(buf.to_vec())
+        Ok(buf)
     }
 }
 
@@ -265,6 +331,9 @@ impl Addons {
         B: AsRef<[u8]>,
     {
         let mut buf = Cursor::new(b);
+        if !buf.has_remaining() {
+            return Ok(Addons::default());
+        }
 
         let proto_header = buf.get_u8();
         if proto_header != 10 {
@@ -275,15 +344,19 @@ impl Addons {
             Ok(n) => n,
             Err(e) => {
                 if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    return Ok(Addons::default());
+                    return Err(VlessError::TruncatedAddons);
                 } else {
                     return Err(e.into());
                 }
             }
         };
 
+        if flow_len > buf.remaining() as u64 {
+            return Err(VlessError::TruncatedAddons);
+        }
+
         let mut flow_bytes = vec![0u8; flow_len as usize];
-        let _ = buf.read_exact(&mut flow_bytes)?;
+        buf.read_exact(&mut flow_bytes)?;
         let flow = Some(String::from_utf8(flow_bytes)?);
 
         let seed_len = match read_varint(&mut buf) {
@@ -298,7 +371,7 @@ impl Addons {
         };
 
         let mut seed_bytes = vec![0u8; seed_len as usize];
-        let _ = buf.read_exact(&mut seed_bytes)?;
+        buf.read_exact(&mut seed_bytes)?;
         let seed = Some(String::from_utf8(seed_bytes)?);
 
         Ok(Addons { flow, seed })
@@ -324,7 +397,7 @@ mod tests {
         };
 
         let mut buf1 = Cursor::new(vec![]);
-        let _ = req1.write(&mut buf1, Some("test".as_bytes())).await?;
+        req1.write(&mut buf1, Some("test".as_bytes())).await?;
         buf1.set_position(0);
         println!("{:?}", buf1);
 
@@ -335,4 +408,162 @@ mod tests {
 
         Ok(())
     }
+
+    const UUID: [u8; 16] = [
+        252, 66, 254, 52, 226, 103, 76, 105, 136, 97, 43, 196, 25, 5, 117, 25,
+    ];
+
+    fn request_bytes(addons: &[u8]) -> Vec<u8> {
+        let mut buf = vec![VERSION];
+        buf.extend_from_slice(&UUID);
+        buf.extend_from_slice(addons);
+        buf.extend_from_slice(&[COMMAND_TCP, 0x1f, 0x90, 1, 127, 0, 0, 1]);
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_vless_request_addons() {
+        // addons_len = 0, no flow
+        let mut buf = Cursor::new(request_bytes(&[0]));
+        let req = Request::read(&mut buf).await.unwrap();
+        assert_eq!(req.flow, None);
+        assert_eq!(req.command, COMMAND_TCP);
+        assert_eq!(req.destination.unwrap().port, 8080);
+
+        // addons_len > 0 with a valid flow
+        let flow = b"xtls-rprx-vision";
+        let mut addons = vec![2 + flow.len() as u8, 10, flow.len() as u8];
+        addons.extend_from_slice(flow);
+        let mut buf = Cursor::new(request_bytes(&addons));
+        let req = Request::read(&mut buf).await.unwrap();
+        assert_eq!(req.flow.as_deref(), Some("xtls-rprx-vision"));
+        assert_eq!(req.command, COMMAND_TCP);
+
+        // addons_len > 0 but the flow is cut short
+        let mut buf = Cursor::new(request_bytes(&[4, 10, 16, b'x', b't']));
+        let err = Request::read(&mut buf).await.unwrap_err();
+        assert!(matches!(err, VlessError::TruncatedAddons));
+
+        // addons_len > 0 but the flow length is missing
+        let mut buf = Cursor::new(request_bytes(&[1, 10]));
+        let err = Request::read(&mut buf).await.unwrap_err();
+        assert!(matches!(err, VlessError::TruncatedAddons));
+    }
+
+    #[tokio::test]
+    async fn test_vless_request_truncated() {
+        let mut buf = vec![VERSION];
+        buf.extend_from_slice(&UUID[..10]);
+        let err = Request::read(&mut Cursor::new(buf)).await.unwrap_err();
+        assert!(matches!(err, VlessError::Truncated("uuid")));
+        assert_eq!(err.to_string(), "truncated uuid");
+
+        let mut buf = request_bytes(&[0]);
+        buf.truncate(1 + 16 + 1 + 1 + 1);
+        let err = Request::read(&mut Cursor::new(buf)).await.unwrap_err();
+        assert!(matches!(err, VlessError::Truncated("port")));
+
+        let err = Request::read(&mut Cursor::new(vec![])).await.unwrap_err();
+        assert!(matches!(err, VlessError::Truncated("version")));
+    }
+
+    #[tokio::test]
+    async fn test_vless_request_flow_round_trip() -> Result<(), VlessError> {
+        let req1 = Request {
+            flow: Some("xtls-rprx-vision".into()),
+            uuid: uuid::Uuid::from_bytes(UUID),
+            destination: Some(ServiceAddress::new("example.com".into(), 443)),
+            command: COMMAND_TCP,
+        };
+
+        let mut buf = Cursor::new(vec![]);
+        req1.write(&mut buf, None).await?;
+        assert_eq!(buf.get_ref().len(), req1.len());
+        buf.set_position(0);
+
+        let req2 = Request::read(&mut buf).await?;
+        assert_eq!(req1, req2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vless_request_empty_flow() -> Result<(), VlessError> {
+        let mut req = Request {
+            flow: Some(String::new()),
+            uuid: uuid::Uuid::from_bytes(UUID),
+            destination: Some(ServiceAddress::new("example.com".into(), 443)),
+            command: COMMAND_TCP,
+        };
+
+        let buf = req.into_buf(None)?;
+        assert_eq!(buf.len(), req.len());
+        assert_eq!(buf[17], 0, "no addons");
+
+        req.flow = None;
+        assert_eq!(buf, req.into_buf(None)?);
+        assert_eq!(Request::read(&mut Cursor::new(buf)).await?, req);
+
+        let resp = Response {
+            flow: Some(String::new()),
+        };
+        assert_eq!(resp.into_buf(None)?, [VERSION, 0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vless_request_mux_destination() {
+        let mut req = Request {
+            flow: None,
+            uuid: uuid::Uuid::from_bytes(UUID),
+            destination: Some(ServiceAddress::new("example.com".into(), 443)),
+            command: COMMAND_MUX,
+        };
+        assert!(matches!(
+            req.into_buf(None).unwrap_err(),
+            VlessError::MuxDestination
+        ));
+
+        req.destination = None;
+        assert_eq!(req.into_buf(None).unwrap().len(), req.len());
+    }
+
+    #[tokio::test]
+    async fn test_vless_request_encode() -> Result<(), VlessError> {
+        let req = Request {
+            flow: Some("xtls-rprx-vision".to_string()),
+            uuid: uuid::Uuid::from_bytes(UUID),
+            destination: Some(ServiceAddress::new("example.com".into(), 443)),
+            command: COMMAND_TCP,
+        };
+        let payload = vec![7u8; 100_000];
+
+        // the buffer is handed over, not copied
+        let buf = req.encode(Some(&payload))?;
+        assert_eq!(buf.len(), req.len() + payload.len());
+        let ptr = buf.as_ptr();
+        let vec: Vec<u8> = buf.into();
+        assert_eq!(vec.as_ptr(), ptr);
+
+        let mut written = vec![];
+        req.write(&mut written, Some(&payload)).await?;
+        assert_eq!(written, vec);
+
+        let mut streamed = vec![];
+        let n = req
+            .write_header_then(&mut streamed, &mut Cursor::new(&payload))
+            .await?;
+        assert_eq!(n, payload.len() as u64);
+        assert_eq!(streamed, vec);
+
+        let resp = Response {
+            flow: Some("xtls-rprx-vision".to_string()),
+        };
+        let mut written = vec![];
+        resp.write(&mut written, Some(b"data")).await?;
+        assert_eq!(written, resp.encode(Some(b"data"))?);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,194 @@
+//! WebSocket transport
+
+pub mod option;
+pub use option::WsOption;
+
+pub mod protocol;
+pub use protocol::{Role, WsStream};
+
+use http::StatusCode;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    error::ProtocolError, http::HttpError, InboundError, InboundPacket, InboundResult,
+    InboundServiceTrait, OutboundError, OutboundPacket, OutboundResult, OutboundServiceTrait,
+};
+
+#[derive(Debug, Error)]
+pub enum WsError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Http(#[from] HttpError),
+    #[error("invalid path {0}")]
+    InvalidPath(String),
+    #[error("invalid host")]
+    InvalidHost,
+    #[error("invalid upgrade request")]
+    InvalidUpgrade,
+    #[error("invalid status {0}")]
+    InvalidStatus(StatusCode),
+    #[error("invalid accept key")]
+    InvalidAccept,
+    #[error("invalid opcode {0:x}")]
+    InvalidOpcode(u8),
+    #[error("invalid frame: {0}")]
+    InvalidFrame(&'static str),
+}
+
+impl From<http::Error> for WsError {
+    fn from(value: http::Error) -> Self {
+        WsError::Http(value.into())
+    }
+}
+
+impl From<WsError> for std::io::Error {
+    fn from(value: WsError) -> Self {
+        match value {
+            WsError::Io(e) => e,
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// Runs an inbound over a websocket accepted on the configured path.
+#[derive(Debug)]
+pub struct WsInbound<I> {
+    inner: I,
+    option: WsOption,
+}
+
+impl<I> WsInbound<I> {
+    pub fn new(inner: I, option: WsOption) -> Self {
+        Self { inner, option }
+    }
+
+    pub fn inner(&self) -> &I {
+        &self.inner
+    }
+}
+
+impl<S, I> InboundServiceTrait<S> for WsInbound<I>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    I: InboundServiceTrait<WsStream<S>>,
+{
+    type Stream = I::Stream;
+
+    async fn handshake(&self, stream: S) -> InboundResult<(Self::Stream, InboundPacket<'_>)> {
+        let stream = WsStream::accept(stream, &self.option)
+            .await
+            .map_err(|e| InboundError::Handshake(e.into()))?;
+
+        self.inner.handshake(stream).await
+    }
+}
+
+/// Runs an outbound over a websocket connected on the configured path.
+#[derive(Debug)]
+pub struct WsOutbound<O> {
+    inner: O,
+    option: WsOption,
+}
+
+impl<O> WsOutbound<O> {
+    pub fn new(inner: O, option: WsOption) -> Self {
+        Self { inner, option }
+    }
+
+    pub fn inner(&self) -> &O {
+        &self.inner
+    }
+}
+
+impl<S, O> OutboundServiceTrait<S> for WsOutbound<O>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    O: OutboundServiceTrait<WsStream<S>>,
+{
+    type Stream = O::Stream;
+
+    async fn handshake(&self, stream: S, packet: OutboundPacket) -> OutboundResult<Self::Stream> {
+        let stream = WsStream::connect(stream, &self.option)
+            .await
+            .map_err(|e| OutboundError::Handshake(ProtocolError::from(e)))?;
+
+        self.inner.handshake(stream, packet).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        address::NetworkType,
+        vless::{
+            option::VlessUserOption, VlessInbound, VlessInboundOption, VlessOutbound,
+            VlessOutboundOption,
+        },
+        ServiceAddress,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_vless_over_ws() {
+        let (s1, s2) = duplex(4096);
+
+        let option = WsOption {
+            path: "/vless".into(),
+            host: "example.com".into(),
+            headers: [("User-Agent".to_string(), "kapibara".to_string())].into(),
+        };
+
+        let inbound = WsInbound::new(
+            VlessInbound::init(VlessInboundOption {
+                users: vec![VlessUserOption {
+                    user: "test".into(),
+                    uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+                }],
+                ..Default::default()
+            })
+            .unwrap(),
+            option.clone(),
+        );
+        let outbound = WsOutbound::new(
+            VlessOutbound::init(VlessOutboundOption {
+                uuid: "fc42fe34-e267-4c69-8861-2bc419057519".into(),
+                flow: None,
+                combine_first_packet: false,
+                mux: None,
+            })
+            .unwrap(),
+            option,
+        );
+
+        let packet = OutboundPacket {
+            typ: NetworkType::Tcp,
+            dest: ServiceAddress::new("example.com".into(), 443),
+        };
+
+        let (out, inb) = tokio::join!(
+            outbound.handshake(s1, packet.clone()),
+            inbound.handshake(s2)
+        );
+        let mut out = out.unwrap();
+        let (mut inb, pac) = inb.unwrap();
+        assert_eq!(pac.dest, packet.dest);
+        assert_eq!(pac.detail, "test");
+
+        out.write_all(b"hello").await.unwrap();
+        out.flush().await.unwrap();
+        let mut buf = [0u8; 5];
+        inb.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        inb.write_all(b"world").await.unwrap();
+        inb.flush().await.unwrap();
+        let mut buf = [0u8; 5];
+        out.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+}
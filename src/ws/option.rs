@@ -0,0 +1,31 @@
+//! WebSocket transport option
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsOption {
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Host header sent by the client and, when not empty, required by the server.
+    #[serde(default)]
+    pub host: String,
+    /// Extra headers sent with the upgrade request.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl Default for WsOption {
+    fn default() -> Self {
+        Self {
+            path: default_path(),
+            host: String::new(),
+            headers: HashMap::new(),
+        }
+    }
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
@@ -0,0 +1,619 @@
+//! websocket protocol - upgrade handshake and binary message framing
+
+use std::{pin::Pin, task::Poll};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bytes::{Buf, BufMut, BytesMut};
+use http::{header, Method, Request, Response, StatusCode, Version};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+
+use crate::http::{format_request, format_response, read_request, read_response};
+use crate::http::{MAX_HEADER, MAX_HEADER_SIZE};
+
+use super::{WsError, WsOption};
+
+const WS_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const FIN: u8 = 0x80;
+const MASK: u8 = 0x80;
+
+/// Maximum payload of a frame written by `WsStream`.
+pub const MAX_FRAME_SIZE: usize = 0x4000;
+/// Maximum payload of a control frame (RFC 6455 5.5).
+const MAX_CONTROL_SIZE: usize = 125;
+
+/// `Sec-WebSocket-Accept` value for a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key);
+    sha1.update(WS_GUID);
+    BASE64_STANDARD.encode(sha1.finalize())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ReadState {
+    Header,
+    Payload {
+        remaining: u64,
+        mask: Option<[u8; 4]>,
+        offset: usize,
+    },
+    Closed,
+}
+
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    len: u64,
+    header_len: usize,
+}
+
+impl FrameHeader {
+    /// Parse a frame header, `None` when more bytes are needed.
+    fn parse(buf: &[u8]) -> Result<Option<FrameHeader>, WsError> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = buf[0] & FIN != 0;
+        if buf[0] & 0x70 != 0 {
+            return Err(WsError::InvalidFrame("reserved bits set"));
+        }
+        let opcode = buf[0] & 0x0F;
+        let masked = buf[1] & MASK != 0;
+
+        let (len, mut header_len) = match buf[1] & 0x7F {
+            126 => {
+                if buf.len() < 4 {
+                    return Ok(None);
+                }
+                (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4)
+            }
+            127 => {
+                if buf.len() < 10 {
+                    return Ok(None);
+                }
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            n => (n as u64, 2),
+        };
+
+        let mask = if masked {
+            if buf.len() < header_len + 4 {
+                return Ok(None);
+            }
+            let mut mask = [0u8; 4];
+            mask.copy_from_slice(&buf[header_len..header_len + 4]);
+            header_len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+
+        Ok(Some(FrameHeader {
+            fin,
+            opcode,
+            mask,
+            len,
+            header_len,
+        }))
+    }
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4], offset: usize) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[(offset + i) % 4];
+    }
+}
+
+/// Stream carrying bytes as binary websocket messages after the upgrade handshake.
+#[derive(Debug)]
+pub struct WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    inner: BufReader<S>,
+    role: Role,
+    read_state: ReadState,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    close_sent: bool,
+}
+
+impl<S> WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    /// Wrap a stream whose upgrade handshake is already done.
+    pub fn new(inner: BufReader<S>, role: Role) -> Self {
+        Self {
+            inner,
+            role,
+            read_state: ReadState::Header,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            close_sent: false,
+        }
+    }
+
+    /// Send the upgrade request and wait for `101 Switching Protocols`.
+    pub async fn connect(stream: S, option: &WsOption) -> Result<Self, WsError> {
+        let mut stream = BufReader::new(stream);
+
+        let key = BASE64_STANDARD.encode(rand::random::<[u8; 16]>());
+        let mut builder = Request::builder()
+            .method(Method::GET)
+            .uri(option.path.as_str())
+            .version(Version::HTTP_11)
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, key.as_str());
+        if !option.host.is_empty() {
+            builder = builder.header(header::HOST, option.host.as_str());
+        }
+        for (k, v) in option.headers.iter() {
+            builder = builder.header(k.as_str(), v.as_str());
+        }
+        let req = builder.body(())?;
+
+        stream.write_all(&format_request(&req)?).await?;
+        stream.flush().await?;
+
+        let resp = read_response(&mut stream, MAX_HEADER, MAX_HEADER_SIZE).await?;
+        if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return Err(WsError::InvalidStatus(resp.status()));
+        }
+
+        let accept = resp
+            .headers()
+            .get(header::SEC_WEBSOCKET_ACCEPT)
+            .ok_or(WsError::InvalidAccept)?;
+        if accept.as_bytes() != accept_key(key.as_bytes()).as_bytes() {
+            return Err(WsError::InvalidAccept);
+        }
+
+        Ok(Self::new(stream, Role::Client))
+    }
+
+    /// Read the upgrade request, check it against the option and answer
+    /// `101 Switching Protocols`.
+    pub async fn accept(stream: S, option: &WsOption) -> Result<Self, WsError> {
+        let mut stream = BufReader::new(stream);
+
+        let req = read_request(&mut stream, MAX_HEADER, MAX_HEADER_SIZE).await?;
+
+        if let Err(e) = check_request(&req, option) {
+            let status = match e {
+                WsError::InvalidPath(_) => StatusCode::NOT_FOUND,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            let resp = Response::builder()
+                .version(req.version())
+                .status(status)
+                .body(())?;
+            let _ = stream.write_all(&format_response(&resp, None)?).await;
+            let _ = stream.flush().await;
+            return Err(e);
+        }
+
+        let key = req
+            .headers()
+            .get(header::SEC_WEBSOCKET_KEY)
+            .ok_or(WsError::InvalidUpgrade)?;
+        let resp = Response::builder()
+            .version(Version::HTTP_11)
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_ACCEPT, accept_key(key.as_bytes()))
+            .body(())?;
+
+        stream.write_all(&format_response(&resp, None)?).await?;
+        stream.flush().await?;
+
+        Ok(Self::new(stream, Role::Server))
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    fn put_frame(&mut self, opcode: u8, payload: &[u8]) {
+        self.write_buf.put_u8(FIN | opcode);
+
+        let mask_bit = if self.role == Role::Client { MASK } else { 0 };
+        match payload.len() {
+            n if n < 126 => self.write_buf.put_u8(mask_bit | n as u8),
+            n if n <= u16::MAX as usize => {
+                self.write_buf.put_u8(mask_bit | 126);
+                self.write_buf.put_u16(n as u16);
+            }
+            n => {
+                self.write_buf.put_u8(mask_bit | 127);
+                self.write_buf.put_u64(n as u64);
+            }
+        }
+
+        if self.role == Role::Client {
+            // client frames are always masked (RFC 6455 5.3)
+            let mask = rand::random::<[u8; 4]>();
+            self.write_buf.put_slice(&mask);
+            let start = self.write_buf.len();
+            self.write_buf.put_slice(payload);
+            apply_mask(&mut self.write_buf[start..], mask, 0);
+        } else {
+            self.write_buf.put_slice(payload);
+        }
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &self.write_buf) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Read more bytes from the inner stream, `false` on EOF.
+    fn poll_fill(&mut self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<bool>> {
+        let start = self.read_buf.len();
+        self.read_buf.resize(start + 4096, 0);
+        let mut rb = ReadBuf::new(&mut self.read_buf[start..]);
+        match Pin::new(&mut self.inner).poll_read(cx, &mut rb) {
+            Poll::Ready(Ok(())) => {
+                let n = rb.filled().len();
+                self.read_buf.truncate(start + n);
+                Poll::Ready(Ok(n != 0))
+            }
+            Poll::Ready(Err(e)) => {
+                self.read_buf.truncate(start);
+                Poll::Ready(Err(e))
+            }
+            Poll::Pending => {
+                self.read_buf.truncate(start);
+                Poll::Pending
+            }
+        }
+    }
+
+    fn handle_control(&mut self, opcode: u8, mut payload: BytesMut) -> Result<(), WsError> {
+        match opcode {
+            OPCODE_CLOSE => {
+                self.read_state = ReadState::Closed;
+                if !self.close_sent {
+                    // echo the status code back
+                    let code = if payload.len() >= 2 {
+                        &payload[..2]
+                    } else {
+                        &[]
+                    };
+                    let code = code.to_vec();
+                    self.put_frame(OPCODE_CLOSE, &code);
+                    self.close_sent = true;
+                }
+            }
+            OPCODE_PING => {
+                payload.truncate(MAX_CONTROL_SIZE);
+                self.put_frame(OPCODE_PONG, &payload);
+            }
+            OPCODE_PONG => {}
+            other => return Err(WsError::InvalidOpcode(other)),
+        }
+
+        Ok(())
+    }
+}
+
+fn check_request(req: &Request<()>, option: &WsOption) -> Result<(), WsError> {
+    if req.method() != Method::GET {
+        return Err(WsError::InvalidUpgrade);
+    }
+
+    if req.uri().path() != option.path {
+        return Err(WsError::InvalidPath(req.uri().path().to_string()));
+    }
+
+    if !option.host.is_empty() {
+        let host = req.headers().get(header::HOST).map(|h| h.as_bytes());
+        if host != Some(option.host.as_bytes()) {
+            return Err(WsError::InvalidHost);
+        }
+    }
+
+    let upgrade = req
+        .headers()
+        .get(header::UPGRADE)
+        .ok_or(WsError::InvalidUpgrade)?;
+    if !upgrade.as_bytes().eq_ignore_ascii_case(b"websocket") {
+        return Err(WsError::InvalidUpgrade);
+    }
+
+    if !req.headers().contains_key(header::SEC_WEBSOCKET_KEY) {
+        return Err(WsError::InvalidUpgrade);
+    }
+
+    Ok(())
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            match this.read_state {
+                ReadState::Closed => {
+                    // best effort to deliver a pending close or pong
+                    let _ = this.poll_write_buf(cx);
+                    return Poll::Ready(Ok(()));
+                }
+                ReadState::Payload { remaining: 0, .. } => {
+                    this.read_state = ReadState::Header;
+                }
+                ReadState::Payload {
+                    remaining,
+                    mask,
+                    offset,
+                } => {
+                    if this.read_buf.is_empty() {
+                        match this.poll_fill(cx) {
+                            Poll::Ready(Ok(true)) => {}
+                            Poll::Ready(Ok(false)) => {
+                                return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()))
+                            }
+                            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                            Poll::Pending => return Poll::Pending,
+                        }
+                    }
+
+                    let n = (remaining.min(usize::MAX as u64) as usize)
+                        .min(this.read_buf.len())
+                        .min(buf.remaining());
+                    let mut data = this.read_buf.split_to(n);
+                    if let Some(mask) = mask {
+                        apply_mask(&mut data, mask, offset);
+                    }
+                    buf.put_slice(&data);
+
+                    this.read_state = ReadState::Payload {
+                        remaining: remaining - n as u64,
+                        mask,
+                        offset: (offset + n) % 4,
+                    };
+
+                    return Poll::Ready(Ok(()));
+                }
+                ReadState::Header => {
+                    let header = match FrameHeader::parse(&this.read_buf)? {
+                        Some(h) => h,
+                        None => match this.poll_fill(cx) {
+                            Poll::Ready(Ok(true)) => continue,
+                            Poll::Ready(Ok(false)) => {
+                                if this.read_buf.is_empty() {
+                                    return Poll::Ready(Ok(()));
+                                }
+                                return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+                            }
+                            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                            Poll::Pending => return Poll::Pending,
+                        },
+                    };
+
+                    match header.opcode {
+                        OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                            this.read_buf.advance(header.header_len);
+                            this.read_state = ReadState::Payload {
+                                remaining: header.len,
+                                mask: header.mask,
+                                offset: 0,
+                            };
+                        }
+                        opcode => {
+                            if !header.fin || header.len > MAX_CONTROL_SIZE as u64 {
+                                return Poll::Ready(Err(WsError::InvalidFrame(
+                                    "invalid control frame",
+                                )
+                                .into()));
+                            }
+
+                            let total = header.header_len + header.len as usize;
+                            while this.read_buf.len() < total {
+                                match this.poll_fill(cx) {
+                                    Poll::Ready(Ok(true)) => {}
+                                    Poll::Ready(Ok(false)) => {
+                                        return Poll::Ready(Err(
+                                            std::io::ErrorKind::UnexpectedEof.into()
+                                        ))
+                                    }
+                                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                                    Poll::Pending => return Poll::Pending,
+                                }
+                            }
+
+                            this.read_buf.advance(header.header_len);
+                            let mut payload = this.read_buf.split_to(header.len as usize);
+                            if let Some(mask) = header.mask {
+                                apply_mask(&mut payload, mask, 0);
+                            }
+                            this.handle_control(opcode, payload)?;
+                            if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+                                return Poll::Ready(Err(e));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = self.get_mut();
+
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        if !this.write_buf.is_empty() {
+            return Poll::Pending;
+        }
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(MAX_FRAME_SIZE);
+        this.put_frame(OPCODE_BINARY, &buf[..n]);
+
+        // the frame is accepted, the rest is written on the next call or flush
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let this = self.get_mut();
+
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let this = self.get_mut();
+
+        if !this.close_sent {
+            // normal closure
+            this.put_frame(OPCODE_CLOSE, &1000u16.to_be_bytes());
+            this.close_sent = true;
+        }
+
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt};
+
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // RFC 6455 1.3
+        assert_eq!(
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ws_stream() {
+        let (s1, s2) = duplex(1024);
+
+        let opt = WsOption {
+            path: "/ws".into(),
+            host: "example.com".into(),
+            ..Default::default()
+        };
+
+        let (client, server) =
+            tokio::join!(WsStream::connect(s1, &opt), WsStream::accept(s2, &opt));
+        let mut client = client.unwrap();
+        let mut server = server.unwrap();
+
+        let data = vec![7u8; MAX_FRAME_SIZE * 3 + 5];
+        let write = async {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+            client
+        };
+        let read = async {
+            let mut buf = vec![];
+            server.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let (mut client, buf) = tokio::join!(write, read);
+        assert_eq!(buf, data);
+
+        // the server echoes the close frame
+        server.flush().await.unwrap();
+        let mut buf = vec![];
+        assert_eq!(client.read_to_end(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_ws_invalid_path() {
+        let (s1, s2) = duplex(1024);
+
+        let cli_opt = WsOption {
+            path: "/other".into(),
+            ..Default::default()
+        };
+        let srv_opt = WsOption {
+            path: "/ws".into(),
+            ..Default::default()
+        };
+
+        let (client, server) = tokio::join!(
+            WsStream::connect(s1, &cli_opt),
+            WsStream::accept(s2, &srv_opt)
+        );
+        assert!(matches!(
+            client.unwrap_err(),
+            WsError::InvalidStatus(StatusCode::NOT_FOUND)
+        ));
+        assert!(matches!(server.unwrap_err(), WsError::InvalidPath(_)));
+    }
+}